@@ -1,17 +1,237 @@
+//! Generates the linker memory layouts and the partition constants from
+//! `layout.toml`.
+//!
+//! - `memory.x` places the bootloader in its partition, before the pages
+//!   reserved for the user page backup and the partition table
+//! - `memory-app.x` places an application in BOOT, after the image header
+//! - `layout.rs` is included by `atsam4l::atsam4lc8c_constants`
+//!
+//! `memory.x` is the one `cortex-m-rt` links this crate with. Setting
+//! `ATSAMBLINKY_LINK=app` links the application layout instead.
+
+use std::convert::TryFrom;
 use std::env;
-use std::fs::File;
-use std::io::Write;
+use std::fmt::Write as _;
+use std::fs;
 use std::path::PathBuf;
 
+use toml::{Table, Value};
+
+/// Partitions in the order they appear in flash, with the names of their
+/// constants. `data` is optional.
+const PARTITIONS: [(&str, &str, &str); 5] = [
+    ("bootloader", "BOOTLOADER_ADDR", "BOOTLOADER_SIZE"),
+    ("boot", "BASE_ADDR", "PARTITION_SIZE"),
+    ("update", "UPDATE_ADDR", "UPDATE_SIZE"),
+    ("swap", "SWAP_ADDR", "SWAP_SIZE"),
+    ("data", "DATA_ADDR", "DATA_SIZE"),
+];
+
+fn fail(msg: String) -> ! {
+    panic!("layout.toml: {}", msg)
+}
+
+fn table<'a>(parent: &'a Table, key: &str) -> Option<&'a Table> {
+    match parent.get(key) {
+        None => None,
+        Some(Value::Table(t)) => Some(t),
+        Some(_) => fail(format!("`{}` must be a table", key)),
+    }
+}
+
+fn number(t: &Table, section: &str, key: &str) -> Option<u32> {
+    match t.get(key) {
+        None => None,
+        Some(Value::Integer(n)) => match u32::try_from(*n) {
+            Ok(n) => Some(n),
+            Err(_) => fail(format!("{}.{} does not fit in 32 bits", section, key)),
+        },
+        Some(_) => fail(format!("{}.{} must be a number", section, key)),
+    }
+}
+
+fn required(t: &Table, section: &str, key: &str) -> u32 {
+    number(t, section, key).unwrap_or_else(|| fail(format!("{}.{} is missing", section, key)))
+}
+
+struct Layout {
+    flash: (u32, u32),
+    ram: (u32, u32),
+    page_size: u32,
+    header_size: u32,
+    /// Origin and size of each entry of `PARTITIONS`; a missing data
+    /// partition has size 0.
+    partitions: Vec<(u32, u32)>,
+    /// Page holding the partition table, at the end of the bootloader
+    /// partition.
+    table: u32,
+    /// The two pages before the partition table, holding a copy of the
+    /// user page while it is rewritten.
+    user_page_backup: u32,
+}
+
+fn parse(text: &str) -> Layout {
+    let root: Table = text.parse().unwrap_or_else(|e| fail(format!("{}", e)));
+    let section =
+        |name: &str| table(&root, name).unwrap_or_else(|| fail(format!("[{}] is missing", name)));
+    let (flash_t, ram_t) = (section("flash"), section("ram"));
+    let flash = (
+        required(flash_t, "flash", "origin"),
+        required(flash_t, "flash", "size"),
+    );
+    let page_size = required(flash_t, "flash", "page-size");
+    if !page_size.is_power_of_two() {
+        fail("flash.page-size must be a power of two".into());
+    }
+    let ram = (
+        required(ram_t, "ram", "origin"),
+        required(ram_t, "ram", "size"),
+    );
+    let header_size = required(section("image"), "image", "header-size");
+    let parts = section("partitions");
+    let flash_end = flash.0 as u64 + flash.1 as u64;
+
+    if let Some(name) = parts
+        .keys()
+        .find(|k| !PARTITIONS.iter().any(|p| p.0 == k.as_str()))
+    {
+        fail(format!("unknown partition `{}`", name));
+    }
+    let mut next = flash.0;
+    let mut partitions = Vec::new();
+    for (name, _, _) in PARTITIONS {
+        let section = format!("partitions.{}", name);
+        let Some(t) = table(parts, name) else {
+            if name != "data" {
+                fail(format!("[{}] is missing", section));
+            }
+            partitions.push((next, 0));
+            continue;
+        };
+        let origin = number(t, &section, "origin").unwrap_or(next);
+        let size = match t.get("size") {
+            Some(Value::String(s)) if s == "rest" => match flash_end.checked_sub(origin as u64) {
+                Some(size) if size > 0 => size as u32,
+                _ => fail(format!(
+                    "{} has size \"rest\" but starts at or past the end of flash",
+                    section
+                )),
+            },
+            _ => required(t, &section, "size"),
+        };
+        if origin < next {
+            fail(format!("{} overlaps the partition before it", section));
+        }
+        if (origin | size) & (page_size - 1) != 0 || size == 0 {
+            fail(format!("{} is not a whole number of pages", section));
+        }
+        if origin as u64 + size as u64 > flash_end {
+            fail(format!("{} does not fit in flash", section));
+        }
+        next = origin + size;
+        partitions.push((origin, size));
+    }
+    if partitions[1].1 != partitions[2].1 {
+        fail("boot and update must be the same size".into());
+    }
+    if partitions[1].1 <= header_size {
+        fail("boot is too small for the image header".into());
+    }
+    let (bootloader_origin, bootloader_size) = partitions[0];
+    let table = match table(&root, "partition-table") {
+        Some(t) => required(t, "partition-table", "origin"),
+        None => bootloader_origin + bootloader_size - page_size,
+    };
+    if table & (page_size - 1) != 0
+        || table <= bootloader_origin
+        || table >= bootloader_origin + bootloader_size
+    {
+        fail("partition-table.origin must be a page inside the bootloader partition".into());
+    }
+    let user_page_backup = match table.checked_sub(2 * page_size) {
+        Some(origin) if origin > bootloader_origin => origin,
+        _ => fail("no room for the user page backup before partition-table.origin".into()),
+    };
+    Layout {
+        flash,
+        ram,
+        page_size,
+        header_size,
+        partitions,
+        table,
+        user_page_backup,
+    }
+}
+
+fn memory_x(what: &str, origin: u32, size: u32, ram: (u32, u32)) -> String {
+    format!(
+        "/* Generated by build.rs from layout.toml: {} */\n\
+         MEMORY\n\
+         {{\n  \
+           FLASH : ORIGIN = {:#010x}, LENGTH = {:#x}\n  \
+           RAM : ORIGIN = {:#010x}, LENGTH = {:#x}\n\
+         }}\n",
+        what, origin, size, ram.0, ram.1
+    )
+}
+
+fn constants(layout: &Layout) -> String {
+    let mut out = String::from("// Generated by build.rs from layout.toml\n");
+    let mut constant = |name: &str, value: u32| {
+        writeln!(out, "pub const {}: u32 = {:#x};", name, value).unwrap();
+    };
+    constant("FLASH_BASE", layout.flash.0);
+    constant("FLASH_SIZE", layout.flash.1);
+    constant("FLASH_PAGE_SIZE", layout.page_size);
+    constant("STACK_LOW", layout.ram.0);
+    constant("STACK_UP", layout.ram.0 + layout.ram.1);
+    constant("RB_HDR_SIZE", layout.header_size);
+    for ((_, addr, size), (origin, len)) in PARTITIONS.iter().zip(&layout.partitions) {
+        constant(addr, *origin);
+        constant(size, *len);
+    }
+    constant("PTABLE_ADDR", layout.table);
+    constant("UP_BACKUP_ADDR", layout.user_page_backup);
+    out
+}
+
 fn main() {
-    // Put the linker script somewhere the linker can find it
-    let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
-    File::create(out.join("memory.x"))
-        .unwrap()
-        .write_all(include_bytes!("memory.x"))
-        .unwrap();
+    let out = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    let text = fs::read_to_string("layout.toml").unwrap_or_else(|e| fail(format!("{}", e)));
+    let layout = parse(&text);
+
+    // the bootloader ends where the user page backup starts
+    let origin = layout.partitions[0].0;
+    let bootloader = memory_x(
+        "bootloader",
+        origin,
+        layout.user_page_backup - origin,
+        layout.ram,
+    );
+    let (origin, size) = layout.partitions[1];
+    let app = memory_x(
+        "application in BOOT, after the image header",
+        origin + layout.header_size,
+        size - layout.header_size,
+        layout.ram,
+    );
+    let linked = match env::var("ATSAMBLINKY_LINK").as_deref() {
+        Ok("app") => &app,
+        Ok("bootloader") | Err(_) => &bootloader,
+        Ok(other) => panic!(
+            "ATSAMBLINKY_LINK must be `bootloader` or `app`, not `{}`",
+            other
+        ),
+    };
+
+    // Put the linker scripts somewhere the linker can find them
+    fs::write(out.join("memory.x"), linked).unwrap();
+    fs::write(out.join("memory-bootloader.x"), &bootloader).unwrap();
+    fs::write(out.join("memory-app.x"), &app).unwrap();
+    fs::write(out.join("layout.rs"), constants(&layout)).unwrap();
     println!("cargo:rustc-link-search={}", out.display());
 
     println!("cargo:rerun-if-changed=build.rs");
-    println!("cargo:rerun-if-changed=memory.x");
+    println!("cargo:rerun-if-changed=layout.toml");
+    println!("cargo:rerun-if-env-changed=ATSAMBLINKY_LINK");
 }
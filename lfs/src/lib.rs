@@ -0,0 +1,87 @@
+//! littlefs on a flash partition.
+//!
+//! [`LfsStorage`] implements the `littlefs2` [`Storage`] trait over a
+//! partition of [`FlashWriterEraser`], so log files and certificates can
+//! live in a real filesystem, usually in the data partition. A littlefs
+//! block is one flash page. littlefs only programs erased space, in
+//! doubleword steps, so writes go through
+//! [`Partition::program`] and never erase on their own.
+//!
+//! The caches and the lookahead buffer are sized for the 64 KiB of RAM of
+//! the SAM4L: with 128-byte caches littlefs needs about 128 bytes for
+//! reading, 128 for programming and 128 per open file, and the 16-byte
+//! lookahead buffer tracks 128 blocks, more than the data partition has, so
+//! a single scan finds all free blocks.
+#![no_std]
+
+use atsamblinky::atsam4l::atsam4lc8c_constants::FLASH_PAGE_SIZE;
+use atsamblinky::atsam4l::partition::Partition;
+use atsamblinky::atsam4l::{FlashError, FlashWriterEraser, Hflashc, TraceSink};
+use littlefs2::consts::{U128, U2};
+use littlefs2::driver::Storage;
+use littlefs2::io::{Error, Result};
+
+/// A partition of exactly `BLOCKS` pages, as littlefs storage.
+pub struct LfsStorage<'a, H, T, const BLOCKS: usize> {
+    flash: &'a FlashWriterEraser<H, T>,
+    partition: Partition,
+}
+
+impl<'a, H: Hflashc, T: TraceSink, const BLOCKS: usize> LfsStorage<'a, H, T, BLOCKS> {
+    /// This method is used to wrap a partition for littlefs
+    ///
+    /// Method arguments:
+    /// -   flash: the flash driver
+    /// -   partition: the pages holding the filesystem
+    ///
+    /// Returns:
+    /// -  `FlashError::OutOfBounds` unless the partition has `BLOCKS` pages
+    pub fn new(
+        flash: &'a FlashWriterEraser<H, T>,
+        partition: Partition,
+    ) -> core::result::Result<Self, FlashError> {
+        if partition.page_count() as usize != BLOCKS {
+            return Err(FlashError::OutOfBounds);
+        }
+        Ok(LfsStorage { flash, partition })
+    }
+
+    pub fn partition(&self) -> Partition {
+        self.partition
+    }
+}
+
+fn io(result: core::result::Result<(), FlashError>, len: usize) -> Result<usize> {
+    result.map(|_| len).map_err(|_| Error::Io)
+}
+
+impl<H: Hflashc, T: TraceSink, const BLOCKS: usize> Storage for LfsStorage<'_, H, T, BLOCKS> {
+    const READ_SIZE: usize = 8;
+    const WRITE_SIZE: usize = 8;
+    const BLOCK_SIZE: usize = FLASH_PAGE_SIZE as usize;
+    const BLOCK_COUNT: usize = BLOCKS;
+    // move metadata after this many erases to level wear
+    const BLOCK_CYCLES: isize = 500;
+
+    type CACHE_SIZE = U128;
+    // in units of 8 bytes, one bit per block
+    type LOOKAHEAD_SIZE = U2;
+
+    fn read(&mut self, off: usize, buf: &mut [u8]) -> Result<usize> {
+        io(self.partition.read(self.flash, off as u32, buf), buf.len())
+    }
+
+    fn write(&mut self, off: usize, data: &[u8]) -> Result<usize> {
+        io(
+            self.partition.program(self.flash, off as u32, data),
+            data.len(),
+        )
+    }
+
+    fn erase(&mut self, off: usize, len: usize) -> Result<usize> {
+        io(
+            self.partition.erase(self.flash, off as u32, len as u32),
+            len,
+        )
+    }
+}
@@ -0,0 +1,71 @@
+use atsamblinky::atsam4l::atsam4lc8c_constants::*;
+use atsamblinky::atsam4l::partition::Partition;
+use atsamblinky::atsam4l::sim::SimFlash;
+use atsamblinky::atsam4l::{FlashError, FlashWriterEraser, PartitionKind};
+use atsamblinky_lfs::LfsStorage;
+use littlefs2::fs::Filesystem;
+use littlefs2::path;
+use littlefs2::path::Path;
+
+const BLOCKS: usize = 32;
+const FS: Partition = Partition::new(
+    PartitionKind::Data,
+    0x7_0000,
+    BLOCKS as u32 * FLASH_PAGE_SIZE,
+);
+
+fn certificate() -> Vec<u8> {
+    (0..1500u32).map(|i| (i * 37 % 251) as u8).collect()
+}
+
+#[test]
+fn format_write_and_remount() {
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+    let mut storage = LfsStorage::<_, _, BLOCKS>::new(&flash, FS).unwrap();
+    assert!(!Filesystem::is_mountable(&mut storage));
+    Filesystem::format(&mut storage).unwrap();
+
+    Filesystem::mount_and_then(&mut storage, |fs| {
+        fs.create_dir(path!("certs"))?;
+        fs.write(path!("certs/device.der"), &certificate())?;
+        fs.write(path!("boot.log"), b"boot 1\n")?;
+        Ok(())
+    })
+    .unwrap();
+
+    // a fresh storage over the same flash, as after a reset
+    let mut storage = LfsStorage::<_, _, BLOCKS>::new(&flash, FS).unwrap();
+    Filesystem::mount_and_then(&mut storage, |fs| {
+        let cert = fs.read::<2048>(path!("certs/device.der"))?;
+        assert_eq!(&cert[..], &certificate()[..]);
+        fs.write(path!("boot.log"), b"boot 1\nboot 2\n")?;
+        Ok(())
+    })
+    .unwrap();
+
+    let mut storage = LfsStorage::<_, _, BLOCKS>::new(&flash, FS).unwrap();
+    Filesystem::mount_and_then(&mut storage, |fs| {
+        let log = fs.read::<64>(path!("boot.log"))?;
+        assert_eq!(&log[..], b"boot 1\nboot 2\n");
+        assert!(fs.available_space()? > 8 * FLASH_PAGE_SIZE as usize);
+        Ok(())
+    })
+    .unwrap();
+
+    // nothing outside the partition was touched
+    let flash_contents = flash.nvm.flash();
+    let end = (FS.start() + FS.size()) as usize;
+    assert!(flash_contents[..FS.start() as usize]
+        .iter()
+        .all(|b| *b == 0xFF));
+    assert!(flash_contents[end..].iter().all(|b| *b == 0xFF));
+}
+
+#[test]
+fn partition_must_match_the_block_count() {
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+    assert!(matches!(
+        LfsStorage::<_, _, 16>::new(&flash, FS),
+        Err(FlashError::OutOfBounds)
+    ));
+}
@@ -0,0 +1,60 @@
+//! Consistent Overhead Byte Stuffing.
+//!
+//! Replaces every zero byte so that zero can delimit frames, at a cost of
+//! one byte per 254 bytes of data.
+
+use crate::Error;
+
+/// Returns the worst-case encoded length of `len` bytes.
+pub const fn cobs_max_len(len: usize) -> usize {
+    len + len / 254 + 1
+}
+
+/// Encodes `data` into `out`, returning the encoded length. No delimiter
+/// is added.
+pub fn cobs_encode(data: &[u8], out: &mut [u8]) -> Result<usize, Error> {
+    if out.len() < cobs_max_len(data.len()) {
+        return Err(Error::BufferTooSmall);
+    }
+    let mut code_at = 0;
+    let mut len = 1;
+    let mut code = 1u8;
+    for byte in data {
+        if *byte != 0 {
+            out[len] = *byte;
+            len += 1;
+            code += 1;
+        }
+        if *byte == 0 || code == 0xFF {
+            out[code_at] = code;
+            code_at = len;
+            len += 1;
+            code = 1;
+        }
+    }
+    out[code_at] = code;
+    Ok(len)
+}
+
+/// Decodes a frame in place, returning the decoded length.
+pub fn cobs_decode(buf: &mut [u8]) -> Result<usize, Error> {
+    let mut read = 0;
+    let mut write = 0;
+    while read < buf.len() {
+        let code = buf[read] as usize;
+        if code == 0 || read + code > buf.len() {
+            return Err(Error::Framing);
+        }
+        read += 1;
+        for _ in 1..code {
+            buf[write] = buf[read];
+            write += 1;
+            read += 1;
+        }
+        if code != 0xFF && read < buf.len() {
+            buf[write] = 0;
+            write += 1;
+        }
+    }
+    Ok(write)
+}
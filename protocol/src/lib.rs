@@ -0,0 +1,414 @@
+//! Framed command protocol for programming flash over a serial line.
+//!
+//! A frame is a message followed by its CRC-32, COBS encoded and terminated
+//! by a zero byte, so a receiver can always find the start of the next frame
+//! after line noise. Each message starts with an opcode; responses echo the
+//! opcode with the top bit set, followed by a [`Status`] byte and, on
+//! success, the result. Multi-byte fields are little endian.
+//!
+//! | request                      | response body          |
+//! |------------------------------|------------------------|
+//! | `Info`                       | [`Info`]               |
+//! | `Read { address, len }`      | the data               |
+//! | `Write { address, data }`    | empty                  |
+//! | `Erase { address, len }`     | empty                  |
+//! | `BlankCheck { address }`     | 1 if blank, else 0     |
+//! | `Crc { address, len }`       | CRC-32 of the range    |
+//! | `Reboot`                     | empty, sent before the reset |
+//!
+//! `Write` has the semantics of the target's `hal_flash_write`: every page
+//! it touches is erased first.
+
+#![no_std]
+
+mod cobs;
+mod crc;
+
+pub use cobs::{cobs_decode, cobs_encode, cobs_max_len};
+pub use crc::{crc32, crc32_update};
+
+/// Largest data payload of a `Read` or `Write`.
+pub const MAX_DATA: usize = 512;
+/// Largest message: opcode, address, length and data.
+pub const MAX_MESSAGE: usize = 1 + 4 + 2 + MAX_DATA;
+/// Largest encoded frame, CRC and delimiter included.
+pub const MAX_FRAME: usize = cobs_max_len(MAX_MESSAGE + 4) + 1;
+
+const OP_INFO: u8 = 0x01;
+const OP_READ: u8 = 0x02;
+const OP_WRITE: u8 = 0x03;
+const OP_ERASE: u8 = 0x04;
+const OP_BLANK_CHECK: u8 = 0x05;
+const OP_CRC: u8 = 0x06;
+const OP_REBOOT: u8 = 0x07;
+const RESPONSE: u8 = 0x80;
+
+/// Errors in encoding or decoding a frame or message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The output buffer is too small.
+    BufferTooSmall,
+    /// The frame is not valid COBS.
+    Framing,
+    /// The frame CRC does not match.
+    Crc,
+    /// The message is shorter or longer than its opcode requires.
+    Length,
+    /// The opcode or status is unknown.
+    Unknown(u8),
+}
+
+/// Outcome of a request, as reported by the target.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Status {
+    Ok = 0,
+    /// The address or range is outside flash.
+    OutOfBounds = 1,
+    /// The range touches a locked region.
+    Locked = 2,
+    /// The flash controller reported a programming error.
+    Programming = 3,
+    /// Data read back does not match what was written.
+    Verify = 4,
+    /// The request frame was damaged.
+    BadFrame = 5,
+    /// The request was not understood.
+    BadRequest = 6,
+}
+
+impl Status {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            0 => Status::Ok,
+            1 => Status::OutOfBounds,
+            2 => Status::Locked,
+            3 => Status::Programming,
+            4 => Status::Verify,
+            5 => Status::BadFrame,
+            6 => Status::BadRequest,
+            _ => return None,
+        })
+    }
+}
+
+/// A request from the host.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Request<'a> {
+    Info,
+    Read { address: u32, len: u16 },
+    Write { address: u32, data: &'a [u8] },
+    Erase { address: u32, len: u32 },
+    BlankCheck { address: u32 },
+    Crc { address: u32, len: u32 },
+    Reboot,
+}
+
+/// Device description returned by `Info`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Info {
+    pub flash_base: u32,
+    pub flash_size: u32,
+    pub page_size: u32,
+    pub user_page_base: u32,
+    pub max_data: u32,
+}
+
+/// A response from the target.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Response<'a> {
+    Info(Info),
+    Data(&'a [u8]),
+    Done,
+    Blank(bool),
+    Crc(u32),
+    /// The request failed.
+    Failed(Status),
+}
+
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Writer<'_> {
+    fn put(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        let end = self.len + bytes.len();
+        self.buf
+            .get_mut(self.len..end)
+            .ok_or(Error::BufferTooSmall)?
+            .copy_from_slice(bytes);
+        self.len = end;
+        Ok(())
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if self.bytes.len() < len {
+            return Err(Error::Length);
+        }
+        let (head, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(head)
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        let b = self.take(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        let b = self.take(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn end(&self) -> Result<(), Error> {
+        if self.bytes.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Length)
+        }
+    }
+}
+
+impl<'a> Request<'a> {
+    fn opcode(&self) -> u8 {
+        match self {
+            Request::Info => OP_INFO,
+            Request::Read { .. } => OP_READ,
+            Request::Write { .. } => OP_WRITE,
+            Request::Erase { .. } => OP_ERASE,
+            Request::BlankCheck { .. } => OP_BLANK_CHECK,
+            Request::Crc { .. } => OP_CRC,
+            Request::Reboot => OP_REBOOT,
+        }
+    }
+
+    /// Encodes the message into `buf`, returning its length.
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut w = Writer { buf, len: 0 };
+        w.put(&[self.opcode()])?;
+        match *self {
+            Request::Info | Request::Reboot => {}
+            Request::Read { address, len } => {
+                w.put(&address.to_le_bytes())?;
+                w.put(&len.to_le_bytes())?;
+            }
+            Request::Write { address, data } => {
+                if data.len() > MAX_DATA {
+                    return Err(Error::Length);
+                }
+                w.put(&address.to_le_bytes())?;
+                w.put(&(data.len() as u16).to_le_bytes())?;
+                w.put(data)?;
+            }
+            Request::BlankCheck { address } => w.put(&address.to_le_bytes())?,
+            Request::Erase { address, len } | Request::Crc { address, len } => {
+                w.put(&address.to_le_bytes())?;
+                w.put(&len.to_le_bytes())?;
+            }
+        }
+        Ok(w.len)
+    }
+
+    /// Decodes a message, borrowing `Write` data from it.
+    pub fn decode(message: &'a [u8]) -> Result<Self, Error> {
+        let mut r = Reader { bytes: message };
+        let request = match r.take(1)?[0] {
+            OP_INFO => Request::Info,
+            OP_READ => {
+                let address = r.u32()?;
+                let len = r.u16()?;
+                if len as usize > MAX_DATA {
+                    return Err(Error::Length);
+                }
+                Request::Read { address, len }
+            }
+            OP_WRITE => {
+                let address = r.u32()?;
+                let len = r.u16()? as usize;
+                if len > MAX_DATA {
+                    return Err(Error::Length);
+                }
+                Request::Write {
+                    address,
+                    data: r.take(len)?,
+                }
+            }
+            OP_ERASE => Request::Erase {
+                address: r.u32()?,
+                len: r.u32()?,
+            },
+            OP_BLANK_CHECK => Request::BlankCheck { address: r.u32()? },
+            OP_CRC => Request::Crc {
+                address: r.u32()?,
+                len: r.u32()?,
+            },
+            OP_REBOOT => Request::Reboot,
+            op => return Err(Error::Unknown(op)),
+        };
+        r.end()?;
+        Ok(request)
+    }
+}
+
+impl<'a> Response<'a> {
+    /// Encodes the response to a request with `opcode` into `buf`.
+    fn encode_for(&self, opcode: u8, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut w = Writer { buf, len: 0 };
+        w.put(&[opcode | RESPONSE])?;
+        match *self {
+            Response::Failed(status) => w.put(&[status as u8])?,
+            ok => {
+                w.put(&[Status::Ok as u8])?;
+                match ok {
+                    Response::Info(info) => {
+                        for field in [
+                            info.flash_base,
+                            info.flash_size,
+                            info.page_size,
+                            info.user_page_base,
+                            info.max_data,
+                        ] {
+                            w.put(&field.to_le_bytes())?;
+                        }
+                    }
+                    Response::Data(data) => w.put(data)?,
+                    Response::Blank(blank) => w.put(&[blank as u8])?,
+                    Response::Crc(crc) => w.put(&crc.to_le_bytes())?,
+                    Response::Done | Response::Failed(_) => {}
+                }
+            }
+        }
+        Ok(w.len)
+    }
+
+    /// Encodes the response to `request` into `buf`, returning its length.
+    pub fn encode(&self, request: &Request, buf: &mut [u8]) -> Result<usize, Error> {
+        self.encode_for(request.opcode(), buf)
+    }
+
+    /// Encodes a response to a frame that could not be decoded.
+    pub fn encode_error(status: Status, buf: &mut [u8]) -> Result<usize, Error> {
+        Response::Failed(status).encode_for(0, buf)
+    }
+
+    /// Decodes the response to `request`.
+    pub fn decode(request: &Request, message: &'a [u8]) -> Result<Self, Error> {
+        let mut r = Reader { bytes: message };
+        let opcode = r.take(1)?[0];
+        let status = r.take(1)?[0];
+        let status = Status::from_u8(status).ok_or(Error::Unknown(status))?;
+        if status != Status::Ok {
+            return Ok(Response::Failed(status));
+        }
+        if opcode != request.opcode() | RESPONSE {
+            return Err(Error::Unknown(opcode));
+        }
+        let response = match request {
+            Request::Info => Response::Info(Info {
+                flash_base: r.u32()?,
+                flash_size: r.u32()?,
+                page_size: r.u32()?,
+                user_page_base: r.u32()?,
+                max_data: r.u32()?,
+            }),
+            Request::Read { len, .. } => Response::Data(r.take(*len as usize)?),
+            Request::BlankCheck { .. } => Response::Blank(r.take(1)?[0] != 0),
+            Request::Crc { .. } => Response::Crc(r.u32()?),
+            Request::Write { .. } | Request::Erase { .. } | Request::Reboot => Response::Done,
+        };
+        r.end()?;
+        Ok(response)
+    }
+}
+
+/// This method is used to wrap a message in a frame
+///
+/// Method arguments:
+/// -   message: encoded request or response
+/// -   frame: receives the frame, up to `MAX_FRAME` bytes
+///
+/// Returns:
+/// -  the frame length, delimiter included
+pub fn encode_frame(message: &[u8], frame: &mut [u8]) -> Result<usize, Error> {
+    let mut raw = [0u8; MAX_MESSAGE + 4];
+    let len = message.len();
+    if len > MAX_MESSAGE {
+        return Err(Error::Length);
+    }
+    raw[..len].copy_from_slice(message);
+    raw[len..len + 4].copy_from_slice(&crc32(message).to_le_bytes());
+    let encoded = cobs_encode(&raw[..len + 4], frame)?;
+    *frame.get_mut(encoded).ok_or(Error::BufferTooSmall)? = 0;
+    Ok(encoded + 1)
+}
+
+/// Collects bytes from the line into frames.
+pub struct FrameDecoder {
+    buf: [u8; MAX_FRAME],
+    len: usize,
+    overflow: bool,
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameDecoder {
+    pub const fn new() -> Self {
+        FrameDecoder {
+            buf: [0; MAX_FRAME],
+            len: 0,
+            overflow: false,
+        }
+    }
+
+    /// This method is used to feed one received byte
+    ///
+    /// Method arguments:
+    /// -   byte: the next byte from the line
+    ///
+    /// Returns:
+    /// -  `None` until a delimiter arrives, then the checked message or why
+    ///    the frame was rejected
+    pub fn push(&mut self, byte: u8) -> Option<Result<&[u8], Error>> {
+        if byte != 0 {
+            if self.len < self.buf.len() {
+                self.buf[self.len] = byte;
+                self.len += 1;
+            } else {
+                self.overflow = true;
+            }
+            return None;
+        }
+        let len = core::mem::replace(&mut self.len, 0);
+        if core::mem::replace(&mut self.overflow, false) {
+            return Some(Err(Error::Length));
+        }
+        // a lone delimiter is idle line, not an empty frame
+        if len == 0 {
+            return None;
+        }
+        Some(self.finish(len))
+    }
+
+    fn finish(&mut self, len: usize) -> Result<&[u8], Error> {
+        let decoded = cobs_decode(&mut self.buf[..len])?;
+        if decoded < 4 {
+            return Err(Error::Framing);
+        }
+        let (message, crc) = self.buf[..decoded].split_at(decoded - 4);
+        if crc32(message).to_le_bytes() != crc {
+            return Err(Error::Crc);
+        }
+        Ok(message)
+    }
+}
@@ -0,0 +1,139 @@
+use flash_protocol::*;
+
+fn frame(message: &[u8]) -> Vec<u8> {
+    let mut out = [0u8; MAX_FRAME];
+    let len = encode_frame(message, &mut out).unwrap();
+    out[..len].to_vec()
+}
+
+fn deframe(decoder: &mut FrameDecoder, bytes: &[u8]) -> Vec<Result<Vec<u8>, Error>> {
+    bytes
+        .iter()
+        .filter_map(|b| decoder.push(*b).map(|r| r.map(|m| m.to_vec())))
+        .collect()
+}
+
+#[test]
+fn cobs_round_trip() {
+    let cases: Vec<Vec<u8>> = vec![
+        vec![],
+        vec![0],
+        vec![0, 0],
+        vec![1, 2, 0, 3],
+        (1..=254).collect(),
+        (1..=255).collect(),
+        (0..600).map(|i| (i % 7) as u8).collect(),
+    ];
+    for data in cases {
+        let mut encoded = vec![0u8; cobs_max_len(data.len())];
+        let len = cobs_encode(&data, &mut encoded).unwrap();
+        assert!(!encoded[..len].contains(&0));
+        let decoded = cobs_decode(&mut encoded[..len]).unwrap();
+        assert_eq!(&encoded[..decoded], &data[..]);
+    }
+    // worked example from the COBS paper
+    let mut out = [0u8; 8];
+    let len = cobs_encode(&[0x11, 0x22, 0x00, 0x33], &mut out).unwrap();
+    assert_eq!(&out[..len], &[0x03, 0x11, 0x22, 0x02, 0x33]);
+}
+
+#[test]
+fn crc32_check_value() {
+    assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    assert_eq!(crc32_update(crc32(b"1234"), b"56789"), 0xCBF4_3926);
+}
+
+#[test]
+fn requests_round_trip() {
+    let data = [0u8, 1, 2, 0, 0xFF];
+    let requests = [
+        Request::Info,
+        Request::Read {
+            address: 0x1_0000,
+            len: 512,
+        },
+        Request::Write {
+            address: 0x4_8300,
+            data: &data,
+        },
+        Request::Erase {
+            address: 0,
+            len: 0x2000,
+        },
+        Request::BlankCheck { address: 0x200 },
+        Request::Crc {
+            address: 0x10,
+            len: 0x20,
+        },
+        Request::Reboot,
+    ];
+    let mut decoder = FrameDecoder::new();
+    for request in requests {
+        let mut message = [0u8; MAX_MESSAGE];
+        let len = request.encode(&mut message).unwrap();
+        let frames = deframe(&mut decoder, &frame(&message[..len]));
+        assert_eq!(frames.len(), 1);
+        let message = frames[0].as_ref().unwrap();
+        assert_eq!(Request::decode(message), Ok(request));
+    }
+}
+
+#[test]
+fn responses_round_trip() {
+    let read = Request::Read { address: 0, len: 3 };
+    let mut buf = [0u8; MAX_MESSAGE];
+    let len = Response::Data(&[7, 8, 9]).encode(&read, &mut buf).unwrap();
+    assert_eq!(
+        Response::decode(&read, &buf[..len]),
+        Ok(Response::Data(&[7, 8, 9]))
+    );
+
+    let crc = Request::Crc { address: 0, len: 1 };
+    let len = Response::Crc(0xDEAD_BEEF).encode(&crc, &mut buf).unwrap();
+    assert_eq!(
+        Response::decode(&crc, &buf[..len]),
+        Ok(Response::Crc(0xDEAD_BEEF))
+    );
+
+    let len = Response::Failed(Status::Locked)
+        .encode(&crc, &mut buf)
+        .unwrap();
+    assert_eq!(
+        Response::decode(&crc, &buf[..len]),
+        Ok(Response::Failed(Status::Locked))
+    );
+    // a response to a different request is rejected
+    let len = Response::Done.encode(&Request::Reboot, &mut buf).unwrap();
+    assert_eq!(
+        Response::decode(&crc, &buf[..len]),
+        Err(Error::Unknown(0x87))
+    );
+}
+
+#[test]
+fn damaged_frames_are_rejected() {
+    let mut decoder = FrameDecoder::new();
+    let mut bad = frame(b"\x01");
+    bad[1] ^= 0x10;
+    let good = frame(b"\x07");
+
+    // idle delimiters, a damaged frame, then a good one
+    let mut line = vec![0, 0];
+    line.extend_from_slice(&bad);
+    line.extend_from_slice(&good);
+    let frames = deframe(&mut decoder, &line);
+    assert_eq!(frames, vec![Err(Error::Crc), Ok(b"\x07".to_vec())]);
+
+    // noise longer than any frame
+    let mut line = vec![0x55; MAX_FRAME + 10];
+    line.push(0);
+    line.extend_from_slice(&good);
+    let frames = deframe(&mut decoder, &line);
+    assert_eq!(frames, vec![Err(Error::Length), Ok(b"\x07".to_vec())]);
+
+    assert_eq!(
+        Request::decode(&[0x03, 0, 0, 0, 0, 4, 0, 1]),
+        Err(Error::Length)
+    );
+    assert_eq!(Request::decode(&[0x42]), Err(Error::Unknown(0x42)));
+}
@@ -1,34 +1,70 @@
 use atsam4lc8c_pac as pac;
-use core::convert::TryInto;
-use pac::{
-    smap::{addr, data, length},
-    Peripherals,
-};
-use panic_halt as _;
-
-use atsam4lc8c_constants::*;
+use pac::Peripherals;
+
+pub mod address;
+pub use address::{FlashAddress, FlashRange, PageNumber, PageSpan, RefinedUsize};
+pub mod aesa;
+pub use aesa::Aesa;
+pub mod boot;
+pub use boot::{boot_from, BootError};
+pub mod partition;
+pub use partition::{Partition, PartitionKind};
+pub mod regs;
+pub use regs::{Command, Hflashc};
+#[cfg(not(target_os = "none"))]
+pub mod sim;
+pub mod trace;
+pub use trace::{TraceEntry, TraceSink};
+
 // use core::{convert::TryInto, ptr::write_volatile, str::pattern::CharSearcher};
-use core::ptr;
+use atsam4lc8c_constants::{FLASH_PAGE_SIZE, UP_BACKUP_ADDR, USER_PAGE_BASE};
+use flash_protocol::crc32;
 use pac::HFLASHC;
 
+/// Marks a complete copy of the user page in the backup pages.
+const UP_BACKUP_MAGIC: u32 = u32::from_le_bytes(*b"RBUP");
+/// The backup page holding the marker, after the copy.
+const UP_BACKUP_MARKER: u32 = UP_BACKUP_ADDR + FLASH_PAGE_SIZE;
+
 #[rustfmt::skip]
 // ATSAM4LC8CA has page size = 512 Bytes
 // Flash size = 512KB
 // No. of pages = 1024
 pub mod atsam4lc8c_constants {
-    pub const FLASH_PAGE_SIZE : u32 = 512;   // 1 page size = 512 Bytes   
-    pub const STACK_LOW       : u32 = 0x2000_0000;
-    pub const STACK_UP        : u32 = 0x2002_0000;
-    pub const RB_HDR_SIZE     : u32 = 0x100;
-    pub const BASE_ADDR       : u32 = 0x08020000;   //  sector 5 starting address
+    // Memory map and partition layout, generated from layout.toml:
+    // FLASH_BASE, FLASH_SIZE, FLASH_PAGE_SIZE, STACK_LOW, STACK_UP,
+    // RB_HDR_SIZE and the *_ADDR/*_SIZE pair of every partition, with
+    // BASE_ADDR/PARTITION_SIZE for BOOT, PTABLE_ADDR, the page holding
+    // the partition table, and UP_BACKUP_ADDR, the two pages before it that
+    // back up the user page. See `partition::LAYOUT`.
+    include!(concat!(env!("OUT_DIR"), "/layout.rs"));
+
+    pub const FLASH_END       : u32 = FLASH_BASE + FLASH_SIZE - 1;   // last valid flash address
+    pub const FLASH_PAGE_COUNT: u32 = FLASH_SIZE / FLASH_PAGE_SIZE;
+    pub const USER_PAGE_BASE  : u32 = 0x0080_0000;   // 512-byte user page, outside the main array
     pub const VTR_TABLE_SIZE  : u32 = 0x100;
-    pub const FW_RESET_VTR    : u32 = BASE_ADDR + RB_HDR_SIZE + VTR_TABLE_SIZE + 0x99;
-    pub const UNLOCKKEY1      : u32 = 0x45670123;
-    pub const UNLOCKKEY2      : u32 = 0xCDEF89AB;
-    pub const PSIZE_X8        : u8  = 0b00;
-    pub const PSIZE_X16       : u8  = 0b01;
-    pub const PSIZE_X32       : u8  = 0b10;
-    pub const PSIZE_X64       : u8  = 0b11;
+
+    // User page layout, offsets from USER_PAGE_BASE. The first words hold the
+    // BOD and watchdog fuse settings loaded at reset and are never touched.
+    pub const UP_COUNTER_OFFSET : u32 = 0x100;   // anti-rollback counter slots
+    pub const UP_COUNTER_SIZE   : u32 = 0x100;
+    pub const UP_BOOT_OFFSET    : u32 = 0x80;    // trial boot state records
+    pub const UP_BOOT_SIZE      : u32 = 0x80;
+    pub const UP_KEY_OFFSET     : u32 = 0x40;    // image decryption key and its complement
+    pub const UP_KEY_SIZE       : u32 = 0x20;
+}
+
+/// Errors reported by [`FlashWriterEraser`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlashError {
+    /// The address, page or range does not lie inside the flash array.
+    OutOfBounds,
+    /// The command touched a locked region (FSR.LOCKE).
+    Locked,
+    /// The controller rejected the command (FSR.PROGE).
+    Programming,
+    /// Data read back after a write does not match what was written.
+    Verify,
 }
 
 // TO ERASE the FLASH
@@ -36,29 +72,40 @@ pub mod atsam4lc8c_constants {
 // use Flash Command Register (FCMD).
 // FCMD has 3 fields: KEY, PAGEN, CMD. Refer Datasheet Chapter 14 to know more
 
-pub struct FlashWriterEraser {
-    pub nvm: HFLASHC,
+pub struct FlashWriterEraser<H = HFLASHC, T = ()> {
+    pub nvm: H,
+    pub trace: T,
 }
 
 impl FlashWriterEraser {
+    // Not `Default`: this takes the device peripherals and panics if called twice
+    #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
         FlashWriterEraser {
             nvm: Peripherals::take().unwrap().HFLASHC,
+            trace: (),
         }
     }
 }
-fn copy_data_from_raw_pointer(raw_ptr: *const u8, len: usize) -> [u8; 8] {
-    let mut arr: [u8; 8] = [0xFF; 8];
 
-    unsafe {
-        // Copy the data from the raw pointer to the array
-        for i in 0..len {
-            arr[i] = *raw_ptr.add(i);
-        }
+impl<H: Hflashc> FlashWriterEraser<H> {
+    /// Creates a driver on top of any [`Hflashc`] implementation, such as
+    /// the host-side simulator.
+    pub fn with_nvm(nvm: H) -> Self {
+        FlashWriterEraser { nvm, trace: () }
     }
+}
 
-    arr
+impl<H: Hflashc, T: TraceSink> FlashWriterEraser<H, T> {
+    /// Reports every FCMD command issued from now on to `trace`.
+    pub fn with_trace<S: TraceSink>(self, trace: S) -> FlashWriterEraser<H, S> {
+        FlashWriterEraser {
+            nvm: self.nvm,
+            trace,
+        }
+    }
 }
+
 // fn copy_data_from_raw_pointer(raw_ptr: *mut u8, len: usize) -> [u8; 8] {
 //     let mut arr: [u8; 8] = [0xFF; 8];
 //     let write_bytes = (len/8);
@@ -104,17 +151,7 @@ fn copy_data_from_raw_pointer(raw_ptr: *const u8, len: usize) -> [u8; 8] {
 //     arr
 // }
 
-impl FlashWriterEraser {
-    /// This method is to write data on flash
-    ///
-    /// Method arguments:
-    /// -   address: It holds the address of flash where data has to be written
-    /// -   data: u8 pointer holding the holding data.
-    /// -   len :  number of bytes
-    ///
-    /// Returns:
-    /// -  NONE
-
+impl<H: Hflashc, T: TraceSink> FlashWriterEraser<H, T> {
     // pub fn write_nvm_word(&mut self, address: u32, word: &[u8], len: usize) {
     //     // defmt::println!("What's the problem?");
     //         // assert_eq!(word.len(), 8);
@@ -318,83 +355,163 @@ impl FlashWriterEraser {
     //     }
     //  }
 
-pub fn hal_flash_write(&self, address: usize, data: *const u8, len: usize) {
-    let address = address as u32;
-    let len = len as u32;
-    let starting_page = (address / 512) as u32;
-    let ending_page = ((address + len) / 512) as u32;
-    let mut idx = 0u32;
-    let mut src = data;
-    let mut dst = address as *mut u8;
-
-    // Perform erase operation on pages to be written
-    for addr in starting_page..=ending_page {
-        self.nvm.fcmd.write(|s| unsafe {
-            s.key()
-                .bits(0xA5)
-                .pagen()
-                .bits(addr.try_into().unwrap())
-                .cmd()
-                .bits(0x02)
-        });
-        while !self.nvm.fsr.read().frdy().bit() {}
+    /// This method is to write data on flash
+    ///
+    /// Every page touched by the write is erased first, so bytes of those
+    /// pages outside `address..address + data.len()` read back as 0xFF.
+    ///
+    /// Method arguments:
+    /// -   address: It holds the address of flash where data has to be written
+    /// -   data: bytes to be written
+    ///
+    /// Returns:
+    /// -  `FlashError::OutOfBounds` if the data does not fit in flash; empty
+    ///    data is a no-op
+    pub fn hal_flash_write(&self, address: FlashAddress, data: &[u8]) -> Result<(), FlashError> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let range = FlashRange::new(address, data.len() as u32)?;
+        for span in range.page_spans() {
+            self.issue(Command::Ep, span.page)?;
+            self.program_span(span, data)?;
+        }
+        Ok(())
     }
 
-    // Before writing to FCMD, first write to "Page Buffer"
-    let mut pg_num = starting_page;
-    while idx < len {
-        let bytes_to_write = core::cmp::min(8 - (address & 0x07), len - idx);
-
-        // Clear the page buffer
-        self.nvm.fcmd.write(|s| unsafe {
-            s.key()
-                .bits(0xA5)
-                .cmd()
-                .bits(0x03)
-        });
-        while !self.nvm.fsr.read().frdy().bit() {}
-
-        // Fill the page buffer with existing data and padding (0xFF)
-        for i in 0..8 {
-            if i < bytes_to_write {
-                unsafe {
-                    *dst = *src;
-                    src = src.add(1);
-                }
-            } else {
-                unsafe {
-                    *dst = 0xFF;
+    /// This method is to program data on flash without erasing it first
+    ///
+    /// Programming can only clear bits, so each byte ends up as the AND of
+    /// its old value and the new one. Writing to bytes that are still 0xFF,
+    /// or clearing further bits of a marker, is safe; anything else needs an
+    /// erase.
+    ///
+    /// Method arguments:
+    /// -   address: It holds the address of flash where data has to be written
+    /// -   data: bytes to be programmed
+    ///
+    /// Returns:
+    /// -  `FlashError::OutOfBounds` if the data does not fit in flash; empty
+    ///    data is a no-op
+    pub fn hal_flash_program(&self, address: FlashAddress, data: &[u8]) -> Result<(), FlashError> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let range = FlashRange::new(address, data.len() as u32)?;
+        for span in range.page_spans() {
+            self.program_span(span, data)?;
+        }
+        Ok(())
+    }
+
+    /// Programs the part of `data` that falls into one page.
+    fn program_span(&self, span: PageSpan, data: &[u8]) -> Result<(), FlashError> {
+        let page_start = span.page.start().get();
+
+        // Clear the page buffer to all ones
+        self.issue(Command::Cpb, span.page)?;
+        let end = (span.data_offset + span.len) as usize;
+        self.fill_page_buffer(
+            page_start,
+            span.offset,
+            &data[span.data_offset as usize..end],
+        );
+
+        // Flash write command
+        self.issue(Command::Wp, span.page).map(|_| ())
+    }
+
+    /// Loads `data` into the page buffer at `offset` of the page at `base`.
+    ///
+    /// Fills one doubleword at a time. Bytes outside `data` are left at 0xFF
+    /// so they do not change what is in flash.
+    fn fill_page_buffer(&self, base: u32, offset: u32, data: &[u8]) {
+        let end = offset + data.len() as u32;
+        let first = offset & !0x07;
+        let last = (end + 0x07) & !0x07;
+        for word_offset in (first..last).step_by(4) {
+            let mut word = [0xFFu8; 4];
+            for (i, byte) in word.iter_mut().enumerate() {
+                let at = word_offset + i as u32;
+                if at >= offset && at < end {
+                    *byte = data[(at - offset) as usize];
                 }
             }
-            unsafe{
-            dst = dst.add(1);
-            }
-            idx += 1;
+            self.nvm
+                .write_page_buffer(base + word_offset, u32::from_le_bytes(word));
+        }
+    }
 
-            // Break when all bytes are written
-            if idx == len {
-                break;
+    /// This method is used to check that flash holds the given data
+    ///
+    /// Method arguments:
+    /// -   address: address of the first byte to compare
+    /// -   data: expected contents
+    ///
+    /// Returns:
+    /// -  `FlashError::Verify` on the first mismatching chunk
+    pub fn hal_flash_verify(&self, address: FlashAddress, data: &[u8]) -> Result<(), FlashError> {
+        let mut buf = [0u8; 64];
+        let mut address = address;
+        for chunk in data.chunks(buf.len()) {
+            let buf = &mut buf[..chunk.len()];
+            self.hal_flash_read(address, buf)?;
+            if buf != chunk {
+                return Err(FlashError::Verify);
             }
+            address = match address.offset(chunk.len() as u32) {
+                Ok(next) => next,
+                // the last chunk ended exactly at the end of flash
+                Err(_) => break,
+            };
         }
+        Ok(())
+    }
 
-        // Flash write command
-        self.nvm.fcmd.write(|s| unsafe {
-            s.key()
-                .bits(0xA5)
-                .pagen()
-                .bits(pg_num.try_into().unwrap())
-                .cmd()
-                .bits(0x01)
-        });
-        while !self.nvm.fsr.read().frdy().bit() {}
-
-        // Update the page number if a new page is reached
-        if (idx % 512) == 0 {
-            pg_num += 1;
+    /// This method is used to read data back from flash
+    ///
+    /// Method arguments:
+    /// -   address: address of the first byte to read
+    /// -   buf: filled with `buf.len()` bytes of flash
+    ///
+    /// Returns:
+    /// -  `FlashError::OutOfBounds` if the read would run past the end of flash
+    pub fn hal_flash_read(&self, address: FlashAddress, buf: &mut [u8]) -> Result<(), FlashError> {
+        if buf.is_empty() {
+            return Ok(());
         }
+        FlashRange::new(address, buf.len() as u32)?;
+        self.nvm.read_flash(address.get(), buf);
+        Ok(())
     }
-}
 
+    /// Writes `cmd` to FCMD for `page`, waits for FRDY and returns FSR.
+    ///
+    /// LOCKE and PROGE are cleared by every FSR read, so they are collected
+    /// across the whole wait rather than taken from the final read.
+    fn issue(&self, cmd: Command, page: PageNumber) -> Result<u32, FlashError> {
+        self.nvm.write_fcmd(cmd.fcmd(page));
+        let mut errors = 0;
+        let fsr = loop {
+            let fsr = self.nvm.read_fsr();
+            errors |= fsr;
+            if fsr & regs::FSR_FRDY != 0 {
+                break fsr;
+            }
+        };
+        self.trace.record(TraceEntry {
+            command: cmd,
+            page,
+            fsr: fsr | errors & (regs::FSR_LOCKE | regs::FSR_PROGE),
+        });
+        if errors & regs::FSR_LOCKE != 0 {
+            Err(FlashError::Locked)
+        } else if errors & regs::FSR_PROGE != 0 {
+            Err(FlashError::Programming)
+        } else {
+            Ok(fsr)
+        }
+    }
 
     // ... (other methods)
 
@@ -465,111 +582,203 @@ pub fn hal_flash_write(&self, address: usize, data: *const u8, len: usize) {
     /// First, we need to find the page number and specify the page number in PAGEN field of FCMD register
     ///
     /// Method arguments:
-    /// -   addr: Address where data has to be erased
-    /// -   len :  number of bytes to be erased
+    /// -   range: every page touched by this range is erased
     ///
     /// Returns:
-    /// -  NONE
-
-    pub fn hal_flash_erase(&self, addr: usize, len: usize) {
+    /// -  `FlashError::Locked` if a page lies in a locked region
+    pub fn hal_flash_erase(&self, range: FlashRange) -> Result<(), FlashError> {
         // 1 page size = 512. address / 512 (Integer part of the result) = page number.
         // Feed the page number to FCMD register in PAGEN field.
+        for page in range.pages() {
+            self.issue(Command::Ep, page)?;
+        }
+        Ok(())
+    }
 
-        let starting_page = (addr / 512) as u32;
-        let ending_page = ((addr + len) / 512) as u32;
-
-        for addr in starting_page..ending_page {
-            // defmt::println!("addr value in for loop: {}", addr);
-            self.nvm.fcmd.write(|s| unsafe {
-                s.key()
-                    .bits(0xA5)
-                    .pagen()
-                    .bits(addr.try_into().unwrap())
-                    .cmd()
-                    .bits(0x02)
-            });
-
-            loop {
-                let fsr_status = self.nvm.fsr.read().frdy().bit();
-                if fsr_status == true {
-                    // defmt::println!("Erase successful");
-                    break;
-                }
+    /// This method is used to check whether a page is erased
+    ///
+    /// Uses the quick page read command, which is much faster than reading
+    /// the page back.
+    ///
+    /// Method arguments:
+    /// -   page: page to check
+    ///
+    /// Returns:
+    /// -  `true` if every byte of the page is 0xFF
+    pub fn hal_flash_is_blank(&self, page: PageNumber) -> Result<bool, FlashError> {
+        let fsr = self.issue(Command::Qpr, page)?;
+        Ok(fsr & regs::FSR_QPRR != 0)
+    }
+
+    /// This method is used to lock the flash
+    ///
+    /// Once a region is locked, writes and erases to any of its pages fail
+    /// with `FlashError::Locked`. Each of the 16 regions spans 64 pages.
+    ///
+    /// Method arguments:
+    /// -   page: any page in the region to lock
+    ///
+    /// Returns:
+    /// -  `FlashError::Programming` if the controller rejects the command
+    pub fn hal_flash_lock(&self, page: PageNumber) -> Result<(), FlashError> {
+        self.issue(Command::Lp, page).map(|_| ())
+    }
+
+    /// This method is used to unlock the flash
+    ///
+    /// Method arguments:
+    /// -   page: any page in the region to unlock
+    ///
+    /// Returns:
+    /// -  `FlashError::Programming` if the controller rejects the command
+    pub fn hal_flash_unlock(&self, page: PageNumber) -> Result<(), FlashError> {
+        self.issue(Command::Up, page).map(|_| ())
+    }
+
+    /// This method is used to read from the user page
+    ///
+    /// While a rewrite of the user page is unfinished, the bytes come from
+    /// the copy saved before the erase, see
+    /// [`hal_user_page_write`](FlashWriterEraser::hal_user_page_write).
+    ///
+    /// Method arguments:
+    /// -   offset: offset of the first byte in the user page
+    /// -   buf: filled with `buf.len()` bytes of the user page
+    ///
+    /// Returns:
+    /// -  `FlashError::OutOfBounds` if the read would run past the user page
+    pub fn hal_user_page_read(&self, offset: u32, buf: &mut [u8]) -> Result<(), FlashError> {
+        user_page_check(offset, buf.len())?;
+        let base = if self.user_page_backup_valid() {
+            UP_BACKUP_ADDR
+        } else {
+            USER_PAGE_BASE
+        };
+        self.nvm.read_flash(base + offset, buf);
+        Ok(())
+    }
+
+    /// This method is used to program the user page without erasing it
+    ///
+    /// Like `hal_flash_program`, this can only clear bits. An unfinished
+    /// rewrite of the user page is completed first.
+    ///
+    /// Method arguments:
+    /// -   offset: offset of the first byte in the user page
+    /// -   data: bytes to program
+    ///
+    /// Returns:
+    /// -  `FlashError::OutOfBounds` if the data would run past the user page
+    pub fn hal_user_page_program(&self, offset: u32, data: &[u8]) -> Result<(), FlashError> {
+        user_page_check(offset, data.len())?;
+        self.hal_user_page_recover()?;
+        self.program_user_page(offset, data)
+    }
+
+    fn program_user_page(&self, offset: u32, data: &[u8]) -> Result<(), FlashError> {
+        // PAGEN is ignored by the user page commands
+        let page = PageNumber::new(0)?;
+        self.issue(Command::Cpb, page)?;
+        self.fill_page_buffer(USER_PAGE_BASE, offset, data);
+        self.issue(Command::Wup, page).map(|_| ())
+    }
+
+    /// This method is used to erase the user page
+    ///
+    /// This wipes the fuse words too. Use
+    /// [`hal_user_page_write`](FlashWriterEraser::hal_user_page_write) to
+    /// rewrite part of the page.
+    ///
+    /// Returns:
+    /// -  `FlashError::Programming` if the controller rejects the command
+    pub fn hal_user_page_erase(&self) -> Result<(), FlashError> {
+        self.issue(Command::Eup, PageNumber::new(0)?).map(|_| ())
+    }
+
+    /// This method is used to write part of the user page, erasing it if
+    /// needed
+    ///
+    /// If the data only clears bits it is programmed in place. Otherwise the
+    /// page has to be erased as a whole, so the new contents are first saved
+    /// to the two backup pages at `UP_BACKUP_ADDR`: the copy, then a page
+    /// holding a marker and the CRC of the copy. Only then is the user page
+    /// erased and programmed from the copy, and the backup erased. A reset
+    /// at any point leaves either the old contents or a complete copy, which
+    /// reads use and the next write puts back, so the fuses, the key, the
+    /// boot records and the security counter survive.
+    ///
+    /// Method arguments:
+    /// -   offset: offset of the first byte to write
+    /// -   data: the new bytes
+    ///
+    /// Returns:
+    /// -  `FlashError::OutOfBounds` if the data would run past the user page
+    pub fn hal_user_page_write(&self, offset: u32, data: &[u8]) -> Result<(), FlashError> {
+        user_page_check(offset, data.len())?;
+        self.hal_user_page_recover()?;
+        let mut page = [0u8; FLASH_PAGE_SIZE as usize];
+        self.nvm.read_flash(USER_PAGE_BASE, &mut page);
+        let old = &mut page[offset as usize..offset as usize + data.len()];
+        if old.iter().zip(data).all(|(o, d)| o & d == *d) {
+            if old != data {
+                self.program_user_page(offset, data)?;
             }
+            return Ok(());
+        }
+        old.copy_from_slice(data);
+
+        let mut marker = [0u8; 8];
+        marker[..4].copy_from_slice(&UP_BACKUP_MAGIC.to_le_bytes());
+        marker[4..].copy_from_slice(&crc32(&page).to_le_bytes());
+        self.hal_flash_write(FlashAddress::new(UP_BACKUP_ADDR)?, &page)?;
+        self.hal_flash_write(FlashAddress::new(UP_BACKUP_MARKER)?, &marker)?;
+        self.hal_user_page_recover()
+    }
+
+    /// This method is used to finish a rewrite of the user page cut short
+    /// by a reset
+    ///
+    /// Writes to the user page do this on their own. The bootloader calls it
+    /// early on every boot, so the fuse words are back in place for the
+    /// next reset.
+    ///
+    /// Returns:
+    /// -  `Ok` once the user page holds its latest contents and no backup is
+    ///    pending
+    pub fn hal_user_page_recover(&self) -> Result<(), FlashError> {
+        if !self.user_page_backup_valid() {
+            return Ok(());
         }
+        let mut page = [0u8; FLASH_PAGE_SIZE as usize];
+        self.nvm.read_flash(UP_BACKUP_ADDR, &mut page);
+        self.hal_user_page_erase()?;
+        self.program_user_page(0, &page)?;
+        // the marker goes first, the copy may hold the key
+        self.hal_flash_erase(FlashRange::new(FlashAddress::new(UP_BACKUP_MARKER)?, 1)?)?;
+        self.hal_flash_erase(FlashRange::new(FlashAddress::new(UP_BACKUP_ADDR)?, 1)?)
+    }
+
+    /// Returns whether the backup pages hold a complete copy of the user
+    /// page.
+    fn user_page_backup_valid(&self) -> bool {
+        let mut marker = [0u8; 8];
+        self.nvm.read_flash(UP_BACKUP_MARKER, &mut marker);
+        if marker[..4] != UP_BACKUP_MAGIC.to_le_bytes() {
+            return false;
+        }
+        let mut page = [0u8; FLASH_PAGE_SIZE as usize];
+        self.nvm.read_flash(UP_BACKUP_ADDR, &mut page);
+        marker[4..] == crc32(&page).to_le_bytes()
+    }
+}
+
+fn user_page_check(offset: u32, len: usize) -> Result<(), FlashError> {
+    match offset.checked_add(len as u32) {
+        Some(end) if end <= FLASH_PAGE_SIZE => Ok(()),
+        _ => Err(FlashError::OutOfBounds),
     }
 }
 
-//     /// This method is used to lock the flash
-//     ///
-//     /// Once the flash is locked no operation on flash can be perfomed.
-//     /// Method arguments:
-//     /// -   NONE
-//     /// Returns:
-//     /// -  NONE
-//     fn hal_flash_lock(&self) {
-//         self.nvm.cr.modify(|_, w| w.lock().set_bit());
-//     }
-//     /// This method is used to unlock the flash
-//     ///
-//     /// Flash has to be unlocked to do any operation on it.
-//     /// Method arguments:
-//     /// -   NONE
-//     /// Returns:
-//     /// -  NONE
-//     fn hal_flash_unlock(&self) {
-//         self.nvm.keyr.write(|w| unsafe { w.key().bits(UNLOCKKEY1) });
-//         self.nvm.keyr.write(|w| unsafe { w.key().bits(UNLOCKKEY2) });
-//     }
 //     fn hal_init() {}
 
 // pub fn preboot() {}
-
-// struct RefinedUsize<const MIN: u32, const MAX: u32, const VAL: u32>(u32);
-
-// impl<const MIN: u32, const MAX: u32, const VAL: u32> RefinedUsize<MIN, MAX, VAL> {
-//     /// This method is used to check the address bound of stack pointer
-//     ///
-//     /// Method arguments:
-//     /// -   i : starting address of stack
-//     /// Returns:
-//     /// -  It returns u32 address of stack pointer
-//     pub fn bounded_int(i: u32) -> Self {
-//         assert!(i >= MIN && i <= MAX);
-//         RefinedUsize(i)
-//     }
-//     /// This method is used to check the address of reset pointer
-//     ///
-//     /// Method arguments:
-//     /// -   i : starting address of reset
-//     /// Returns:
-//     /// -  It returns u32 address of reset pointer
-//     pub fn single_valued_int(i: u32) -> Self {
-//         assert!(i == VAL);
-//         RefinedUsize(i)
-//     }
-// }
-
-// /// This method is used to boot the firmware from a particular address
-//     ///
-//     /// Method arguments:
-//     /// -   fw_base_address  : address of the firmware
-//     /// Returns:
-//     /// -  NONE
-// #[rustfmt::skip]
-// pub fn boot_from(fw_base_address: usize) -> ! {
-//        let address = fw_base_address as u32;
-//        let scb = hal::pac::SCB::ptr();
-//        unsafe {
-//        let sp = RefinedUsize::<STACK_LOW, STACK_UP, 0>::bounded_int(
-//         *(fw_base_address as *const u32)).0;
-//        let rv = RefinedUsize::<0, 0, FW_RESET_VTR>::single_valued_int(
-//         *((fw_base_address + 4) as *const u32)).0;
-//        let jump_vector = core::mem::transmute::<usize, extern "C" fn() -> !>(rv as usize);
-//        (*scb).vtor.write(address);
-//        cortex_m::register::msp::write(sp);
-//        jump_vector();
-
-//        }
-//        loop{}
-// }
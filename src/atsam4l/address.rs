@@ -0,0 +1,189 @@
+//! Bounds-checked flash addresses, page numbers and ranges.
+//!
+//! Every public method of [`FlashWriterEraser`](super::FlashWriterEraser) takes
+//! one of these types instead of a raw `usize`, so the page and offset math is
+//! done once here and an out-of-range address is rejected before any FCMD
+//! command is issued.
+
+use super::atsam4lc8c_constants::*;
+use super::FlashError;
+
+/// A `u32` that is known to lie in `MIN..=MAX`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct RefinedUsize<const MIN: u32, const MAX: u32>(u32);
+
+impl<const MIN: u32, const MAX: u32> RefinedUsize<MIN, MAX> {
+    /// Returns `Some` if `i` lies within `MIN..=MAX`.
+    pub const fn bounded_int(i: u32) -> Option<Self> {
+        if i >= MIN && i <= MAX {
+            Some(RefinedUsize(i))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the wrapped value.
+    pub const fn get(self) -> u32 {
+        self.0
+    }
+}
+
+/// A byte address inside the main flash array.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FlashAddress(RefinedUsize<FLASH_BASE, FLASH_END>);
+
+impl FlashAddress {
+    /// Checks that `address` lies inside the flash array.
+    pub const fn new(address: u32) -> Result<Self, FlashError> {
+        match RefinedUsize::bounded_int(address) {
+            Some(address) => Ok(FlashAddress(address)),
+            None => Err(FlashError::OutOfBounds),
+        }
+    }
+
+    /// Returns the raw address.
+    pub const fn get(self) -> u32 {
+        self.0.get()
+    }
+
+    /// Returns the page that contains this address.
+    pub const fn page(self) -> PageNumber {
+        PageNumber(((self.get() - FLASH_BASE) / FLASH_PAGE_SIZE) as u16)
+    }
+
+    /// Returns the byte offset of this address within its page.
+    pub const fn page_offset(self) -> u32 {
+        (self.get() - FLASH_BASE) % FLASH_PAGE_SIZE
+    }
+
+    /// Advances the address by `offset` bytes, staying inside flash.
+    pub const fn offset(self, offset: u32) -> Result<Self, FlashError> {
+        match self.get().checked_add(offset) {
+            Some(address) => FlashAddress::new(address),
+            None => Err(FlashError::OutOfBounds),
+        }
+    }
+}
+
+/// The number of a page in the main flash array, as written to `FCMD.PAGEN`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PageNumber(u16);
+
+impl PageNumber {
+    /// Checks that `page` is a valid page number.
+    pub const fn new(page: u32) -> Result<Self, FlashError> {
+        if page < FLASH_PAGE_COUNT {
+            Ok(PageNumber(page as u16))
+        } else {
+            Err(FlashError::OutOfBounds)
+        }
+    }
+
+    /// Returns the raw page number.
+    pub const fn get(self) -> u16 {
+        self.0
+    }
+
+    /// Returns the address of the first byte of the page.
+    pub const fn start(self) -> FlashAddress {
+        FlashAddress(RefinedUsize(FLASH_BASE + self.0 as u32 * FLASH_PAGE_SIZE))
+    }
+}
+
+/// A non-empty run of bytes that lies entirely inside the main flash array.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FlashRange {
+    start: FlashAddress,
+    len: u32,
+}
+
+impl FlashRange {
+    /// Checks that `len` bytes starting at `start` fit inside flash.
+    pub const fn new(start: FlashAddress, len: u32) -> Result<Self, FlashError> {
+        if len == 0 {
+            return Err(FlashError::OutOfBounds);
+        }
+        match start.offset(len - 1) {
+            Ok(_) => Ok(FlashRange { start, len }),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Returns the range covering pages `first..=last`.
+    pub const fn from_pages(first: PageNumber, last: PageNumber) -> Result<Self, FlashError> {
+        if last.get() < first.get() {
+            return Err(FlashError::OutOfBounds);
+        }
+        let pages = (last.get() - first.get()) as u32 + 1;
+        FlashRange::new(first.start(), pages * FLASH_PAGE_SIZE)
+    }
+
+    /// Returns the first address in the range.
+    pub const fn start(self) -> FlashAddress {
+        self.start
+    }
+
+    /// Returns the last address in the range.
+    pub const fn last(self) -> FlashAddress {
+        FlashAddress(RefinedUsize(self.start.get() + self.len - 1))
+    }
+
+    /// Returns the length of the range in bytes.
+    #[allow(clippy::len_without_is_empty)] // ranges are never empty
+    pub const fn len(self) -> u32 {
+        self.len
+    }
+
+    /// Returns the first page touched by the range.
+    pub const fn first_page(self) -> PageNumber {
+        self.start.page()
+    }
+
+    /// Returns the last page touched by the range.
+    pub const fn last_page(self) -> PageNumber {
+        self.last().page()
+    }
+
+    /// Iterates over every page touched by the range.
+    pub fn pages(self) -> impl Iterator<Item = PageNumber> {
+        (self.first_page().get()..=self.last_page().get()).map(PageNumber)
+    }
+
+    /// Splits the range at page boundaries.
+    pub fn page_spans(self) -> impl Iterator<Item = PageSpan> {
+        let start = self.start.get();
+        let end = start + self.len;
+        self.pages().map(move |page| {
+            let page_start = page.start().get();
+            let from = if start > page_start {
+                start
+            } else {
+                page_start
+            };
+            let to = if end < page_start + FLASH_PAGE_SIZE {
+                end
+            } else {
+                page_start + FLASH_PAGE_SIZE
+            };
+            PageSpan {
+                page,
+                offset: from - page_start,
+                len: to - from,
+                data_offset: from - start,
+            }
+        })
+    }
+}
+
+/// The part of a [`FlashRange`] that falls inside a single page.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PageSpan {
+    /// The page this span lies in.
+    pub page: PageNumber,
+    /// Byte offset of the span within the page.
+    pub offset: u32,
+    /// Number of bytes in the span.
+    pub len: u32,
+    /// Byte offset of the span from the start of the whole range.
+    pub data_offset: u32,
+}
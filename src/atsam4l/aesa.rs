@@ -0,0 +1,86 @@
+//! The AESA hardware accelerator as a [`BlockCipher`].
+//!
+//! The peripheral runs in ECB mode and only ever encrypts: counter mode
+//! needs nothing else, and [`Ctr`](crate::crypt::Ctr) builds the counter
+//! blocks itself so that the hardware and the software backend produce the
+//! same keystream. Data and key words are little endian, matching the byte
+//! order of the block in memory.
+//!
+//! The AESA clock (CLK_AESA on the HSB, and GCLK4 for the countermeasures)
+//! must be enabled in the PM and SCIF before [`Aesa::new`] is called.
+
+use atsam4lc8c_pac as pac;
+
+use crate::crypt::{BlockCipher, BLOCK_SIZE};
+
+/// MODE.OPMODE for ECB.
+const OPMODE_ECB: u8 = 0;
+/// MODE.CTYPE with every countermeasure enabled.
+const CTYPE_ALL: u8 = 0x0F;
+
+/// AES-128 encryption with the AESA.
+pub struct Aesa {
+    aesa: pac::AESA,
+}
+
+impl Aesa {
+    /// This method is used to set up the AESA for AES-128 encryption
+    ///
+    /// Method arguments:
+    /// -   aesa: the peripheral, taken from `Peripherals`
+    /// -   key: the 128-bit key
+    ///
+    /// Returns:
+    /// -  the backend, ready to encrypt blocks
+    pub fn new(aesa: pac::AESA, key: &[u8; 16]) -> Self {
+        aesa.ctrl.write(|w| w.swrst().set_bit());
+        aesa.ctrl.write(|w| w.enable().set_bit());
+        aesa.mode.write(|w| unsafe {
+            w.encrypt()
+                .set_bit()
+                .keysize()
+                .bits(0)
+                .dma()
+                .clear_bit()
+                .opmode()
+                .bits(OPMODE_ECB)
+                .ctype()
+                .bits(CTYPE_ALL)
+        });
+        for (reg, word) in aesa.key.iter().zip(key.chunks_exact(4)) {
+            reg.write(|w| unsafe {
+                w.bits(u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+            });
+        }
+        Aesa { aesa }
+    }
+
+    /// This method is used to give the peripheral back
+    ///
+    /// The key registers are cleared by a software reset first.
+    ///
+    /// Returns:
+    /// -  the AESA peripheral
+    pub fn release(self) -> pac::AESA {
+        self.aesa.ctrl.write(|w| w.swrst().set_bit());
+        self.aesa
+    }
+}
+
+impl BlockCipher for Aesa {
+    fn encrypt_block(&mut self, block: &mut [u8; BLOCK_SIZE]) {
+        let aesa = &self.aesa;
+        aesa.ctrl.write(|w| w.enable().set_bit().newmsg().set_bit());
+        aesa.databufptr
+            .write(|w| unsafe { w.idataw().bits(0).odataw().bits(0) });
+        for word in block.chunks_exact(4) {
+            aesa.idata.write(|w| unsafe {
+                w.bits(u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+            });
+        }
+        while aesa.sr.read().odatardy().bit_is_clear() {}
+        for word in block.chunks_exact_mut(4) {
+            word.copy_from_slice(&aesa.odata.read().bits().to_le_bytes());
+        }
+    }
+}
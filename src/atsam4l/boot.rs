@@ -0,0 +1,192 @@
+//! Handing control from the bootloader to a firmware image.
+
+use core::convert::Infallible;
+
+use super::atsam4lc8c_constants::*;
+use super::pac;
+use super::TraceSink;
+use super::{FlashAddress, FlashError, FlashRange, FlashWriterEraser, Hflashc, RefinedUsize};
+
+/// VTOR.TBLOFF only implements bits 29:7, so a vector table must be aligned
+/// to 128 bytes.
+pub const VECTOR_TABLE_ALIGN: u32 = 0x80;
+
+/// Reasons an image is not started.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BootError {
+    /// The vector table is not aligned to [`VECTOR_TABLE_ALIGN`].
+    VectorTableAlignment(u32),
+    /// The initial stack pointer is not a word-aligned address inside SRAM.
+    StackPointer(u32),
+    /// The reset vector is not a Thumb address inside the image.
+    ResetVector(u32),
+    /// The vector table could not be read.
+    Flash(FlashError),
+}
+
+impl From<FlashError> for BootError {
+    fn from(e: FlashError) -> Self {
+        BootError::Flash(e)
+    }
+}
+
+/// The first two entries of a validated vector table.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VectorTable {
+    /// Address the vector table was read from, which is written to VTOR.
+    pub address: FlashAddress,
+    /// Initial value of the main stack pointer.
+    pub initial_sp: RefinedUsize<STACK_LOW, STACK_UP>,
+    /// Address of the reset handler, with the Thumb bit set.
+    pub reset_vector: u32,
+}
+
+/// This method is used to check the vector table at the start of an image
+///
+/// Method arguments:
+/// -   flash: driver used to read the vector table
+/// -   image: the firmware, starting with its vector table
+///
+/// Returns:
+/// -  the stack pointer and reset vector, or why they are unusable
+pub fn check_vector_table<H: Hflashc, T: TraceSink>(
+    flash: &FlashWriterEraser<H, T>,
+    image: FlashRange,
+) -> Result<VectorTable, BootError> {
+    let address = image.start();
+    if address.get() & (VECTOR_TABLE_ALIGN - 1) != 0 {
+        return Err(BootError::VectorTableAlignment(address.get()));
+    }
+
+    let mut words = [0u8; 8];
+    flash.hal_flash_read(address, &mut words)?;
+    let sp = u32::from_le_bytes([words[0], words[1], words[2], words[3]]);
+    let rv = u32::from_le_bytes([words[4], words[5], words[6], words[7]]);
+
+    let initial_sp = match RefinedUsize::bounded_int(sp) {
+        Some(sp) if sp.get() & 0x03 == 0 => sp,
+        _ => return Err(BootError::StackPointer(sp)),
+    };
+    // The handler must be Thumb code past the vector table and inside the image
+    let handler = rv & !1;
+    if rv & 1 == 0 || handler < address.get() + 8 || handler > image.last().get() {
+        return Err(BootError::ResetVector(rv));
+    }
+
+    Ok(VectorTable {
+        address,
+        initial_sp,
+        reset_vector: rv,
+    })
+}
+
+/// This method is used to boot the firmware from a particular address
+///
+/// The vector table is validated first. If it is usable, interrupts are
+/// masked, every NVIC interrupt is disabled and un-pended, SysTick is
+/// stopped, the AESA and any clocked USART are reset and disabled and the
+/// PM clock masks are restored, so peripherals are as after a reset. Then
+/// VTOR and MSP are pointed at the image and its reset handler is entered
+/// with interrupts unmasked, as after a reset.
+///
+/// Method arguments:
+/// -   flash: the flash driver, which is not used again by the bootloader
+/// -   image: the firmware, starting with its vector table
+///
+/// Returns:
+/// -  only if validation fails
+///
+/// # Safety
+///
+/// The image must be a complete firmware that is safe to run. Nothing of
+/// the bootloader's own state survives the jump.
+pub unsafe fn boot_from<H: Hflashc, T: TraceSink>(
+    flash: FlashWriterEraser<H, T>,
+    image: FlashRange,
+) -> Result<Infallible, BootError> {
+    let vt = check_vector_table(&flash, image)?;
+    drop(flash);
+
+    cortex_m::interrupt::disable();
+    let mut cp = cortex_m::Peripherals::steal();
+    cp.SYST.disable_interrupt();
+    cp.SYST.disable_counter();
+    for (icer, icpr) in cp.NVIC.icer.iter().zip(cp.NVIC.icpr.iter()) {
+        icer.write(0xFFFF_FFFF);
+        icpr.write(0xFFFF_FFFF);
+    }
+    cortex_m::peripheral::SCB::clear_pendsv();
+    cortex_m::peripheral::SCB::clear_pendst();
+    reset_peripherals();
+
+    cp.SCB.vtor.write(vt.address.get());
+    cortex_m::asm::dsb();
+    cortex_m::asm::isb();
+    cortex_m::interrupt::enable();
+    cortex_m::asm::bootstrap(
+        vt.initial_sp.get() as *const u32,
+        vt.reset_vector as *const u32,
+    )
+}
+
+/// PM registers are write-protected; each write must directly follow an
+/// UNLOCK write with this key and the register offset.
+const PM_UNLOCK_KEY: u32 = 0xAA << 24;
+
+/// Puts the peripherals the bootloader may have used back in their reset
+/// state: the AESA and the USARTs of a transfer protocol are reset and
+/// disabled, then the PM clock masks are restored, which gates their
+/// clocks again. A peripheral whose clock is masked was never used and is
+/// not touched, since its registers cannot be written.
+///
+/// # Safety
+///
+/// Nothing may use these peripherals afterwards.
+unsafe fn reset_peripherals() {
+    let p = pac::Peripherals::steal();
+    let pm = &p.PM;
+
+    if pm.hsbmask.read().aesa_().bit_is_set() {
+        p.AESA.ctrl.write(|w| w.swrst().set_bit());
+        p.AESA.ctrl.reset();
+    }
+    let pbamask = pm.pbamask.read();
+    let usarts: [(&pac::usart0::RegisterBlock, bool); 4] = [
+        (&p.USART0, pbamask.usart0_().bit_is_set()),
+        (&p.USART1, pbamask.usart1_().bit_is_set()),
+        (&p.USART2, pbamask.usart2_().bit_is_set()),
+        (&p.USART3, pbamask.usart3_().bit_is_set()),
+    ];
+    for (usart, clocked) in usarts {
+        if clocked {
+            usart.usart_mode_idr_usart().write(|w| w.bits(0xFFFF_FFFF));
+            usart.usart_mode_cr_usart().write(|w| {
+                w.rstrx()
+                    .set_bit()
+                    .rsttx()
+                    .set_bit()
+                    .rxdis()
+                    .set_bit()
+                    .txdis()
+                    .set_bit()
+                    .rststa()
+                    .set_bit()
+            });
+            usart.usart_mode_mr().reset();
+        }
+    }
+
+    let unlock = |offset: u32| pm.unlock.write(|w| w.bits(PM_UNLOCK_KEY | offset));
+    unlock(0x20);
+    pm.cpumask.reset();
+    unlock(0x24);
+    pm.hsbmask.reset();
+    unlock(0x28);
+    pm.pbamask.reset();
+    unlock(0x2C);
+    pm.pbbmask.reset();
+    unlock(0x30);
+    pm.pbcmask.reset();
+    unlock(0x34);
+    pm.pbdmask.reset();
+}
@@ -0,0 +1,254 @@
+//! Flash partitions for the bootloader and its A/B firmware slots.
+//!
+//! The layout comes from `layout.toml`, which `build.rs` also turns into the
+//! linker scripts. By default the 512 KiB array is split into:
+//!
+//! | partition  | start       | size    | contents                              |
+//! |------------|-------------|---------|---------------------------------------|
+//! | bootloader | 0x0000_0000 | 64 KiB  | this bootloader                       |
+//! | BOOT       | 0x0001_0000 | 200 KiB | the running image (header + firmware) |
+//! | UPDATE     | 0x0004_2000 | 200 KiB | the image to be installed             |
+//! | SWAP       | 0x0007_4000 | 2 KiB   | scratch page and swap progress        |
+//! | data       | 0x0007_4800 | 46 KiB  | application data, optional            |
+//!
+//! [`LAYOUT`] is checked when the crate is compiled: every partition must be
+//! page aligned, lie inside flash and not overlap any other, and BOOT and
+//! UPDATE must be the same size so they can be swapped.
+
+use super::atsam4lc8c_constants::*;
+use super::{FlashAddress, FlashError, FlashRange, FlashWriterEraser, Hflashc, TraceSink};
+
+/// What a partition is used for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PartitionKind {
+    Bootloader,
+    Boot,
+    Update,
+    Swap,
+    Data,
+}
+
+/// A page-aligned region of flash.
+///
+/// Offsets passed to [`read`](Partition::read), [`write`](Partition::write)
+/// and [`erase`](Partition::erase) are relative to the start of the
+/// partition, and an access that would leave it fails with
+/// `FlashError::OutOfBounds` before touching flash.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Partition {
+    pub kind: PartitionKind,
+    start: u32,
+    size: u32,
+}
+
+impl Partition {
+    /// Describes a partition, failing to compile when used in a constant
+    /// that is not page aligned or does not fit in flash.
+    pub const fn new(kind: PartitionKind, start: u32, size: u32) -> Self {
+        assert!(
+            start & (FLASH_PAGE_SIZE - 1) == 0,
+            "partition start is not page aligned"
+        );
+        assert!(
+            size & (FLASH_PAGE_SIZE - 1) == 0,
+            "partition size is not a whole number of pages"
+        );
+        assert!(size > 0, "partition is empty");
+        assert!(
+            start - FLASH_BASE <= FLASH_SIZE && size <= FLASH_SIZE - (start - FLASH_BASE),
+            "partition does not fit in flash"
+        );
+        Partition { kind, start, size }
+    }
+
+    /// Describes a partition read at runtime, checking what [`new`]
+    /// asserts.
+    ///
+    /// [`new`]: Partition::new
+    pub fn try_new(kind: PartitionKind, start: u32, size: u32) -> Result<Self, FlashError> {
+        let fits = start
+            .checked_sub(FLASH_BASE)
+            .is_some_and(|offset| offset <= FLASH_SIZE && size <= FLASH_SIZE - offset);
+        if (start | size) & (FLASH_PAGE_SIZE - 1) != 0 || size == 0 || !fits {
+            return Err(FlashError::OutOfBounds);
+        }
+        Ok(Partition { kind, start, size })
+    }
+
+    /// Returns the address of the first byte of the partition.
+    pub const fn start(&self) -> u32 {
+        self.start
+    }
+
+    /// Returns the size of the partition in bytes.
+    pub const fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// Returns the number of pages in the partition.
+    pub const fn page_count(&self) -> u32 {
+        self.size / FLASH_PAGE_SIZE
+    }
+
+    /// Returns `true` if the two partitions share at least one byte.
+    pub const fn overlaps(&self, other: &Partition) -> bool {
+        self.start < other.start + other.size && other.start < self.start + self.size
+    }
+
+    /// Returns the whole partition as a range.
+    pub fn range(&self) -> FlashRange {
+        // `new` guarantees this cannot fail
+        FlashRange::new(FlashAddress::new(self.start).unwrap(), self.size).unwrap()
+    }
+
+    /// Returns the range of `len` bytes at `offset`, if it lies inside the
+    /// partition.
+    pub fn subrange(&self, offset: u32, len: u32) -> Result<FlashRange, FlashError> {
+        match offset.checked_add(len) {
+            Some(end) if len > 0 && end <= self.size => {
+                FlashRange::new(FlashAddress::new(self.start + offset)?, len)
+            }
+            _ => Err(FlashError::OutOfBounds),
+        }
+    }
+
+    /// Checks that `len` bytes at `offset` lie inside the partition, where
+    /// unlike [`Partition::subrange`] an empty range is fine.
+    pub fn check_range(&self, offset: u32, len: u32) -> Result<(), FlashError> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.size => Ok(()),
+            _ => Err(FlashError::OutOfBounds),
+        }
+    }
+
+    /// Reads `buf.len()` bytes starting `offset` bytes into the partition.
+    pub fn read<H: Hflashc, T: TraceSink>(
+        &self,
+        flash: &FlashWriterEraser<H, T>,
+        offset: u32,
+        buf: &mut [u8],
+    ) -> Result<(), FlashError> {
+        if buf.is_empty() {
+            return self.check_range(offset, 0);
+        }
+        let range = self.subrange(offset, buf.len() as u32)?;
+        flash.hal_flash_read(range.start(), buf)
+    }
+
+    /// Writes `data` starting `offset` bytes into the partition.
+    ///
+    /// Like [`FlashWriterEraser::hal_flash_write`], every page touched is
+    /// erased first.
+    pub fn write<H: Hflashc, T: TraceSink>(
+        &self,
+        flash: &FlashWriterEraser<H, T>,
+        offset: u32,
+        data: &[u8],
+    ) -> Result<(), FlashError> {
+        if data.is_empty() {
+            return self.check_range(offset, 0);
+        }
+        let range = self.subrange(offset, data.len() as u32)?;
+        flash.hal_flash_write(range.start(), data)
+    }
+
+    /// Programs `data` starting `offset` bytes into the partition without
+    /// erasing, see [`FlashWriterEraser::hal_flash_program`].
+    pub fn program<H: Hflashc, T: TraceSink>(
+        &self,
+        flash: &FlashWriterEraser<H, T>,
+        offset: u32,
+        data: &[u8],
+    ) -> Result<(), FlashError> {
+        if data.is_empty() {
+            return self.check_range(offset, 0);
+        }
+        let range = self.subrange(offset, data.len() as u32)?;
+        flash.hal_flash_program(range.start(), data)
+    }
+
+    /// Returns `true` if the page holding `offset` is erased, using the
+    /// quick page read rather than reading the page back.
+    pub fn is_blank<H: Hflashc, T: TraceSink>(
+        &self,
+        flash: &FlashWriterEraser<H, T>,
+        offset: u32,
+    ) -> Result<bool, FlashError> {
+        flash.hal_flash_is_blank(self.subrange(offset, 1)?.start().page())
+    }
+
+    /// Erases every page touched by the `len` bytes at `offset`.
+    pub fn erase<H: Hflashc, T: TraceSink>(
+        &self,
+        flash: &FlashWriterEraser<H, T>,
+        offset: u32,
+        len: u32,
+    ) -> Result<(), FlashError> {
+        flash.hal_flash_erase(self.subrange(offset, len)?)
+    }
+}
+
+/// The set of partitions the bootloader works with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Layout {
+    pub bootloader: Partition,
+    pub boot: Partition,
+    pub update: Partition,
+    pub swap: Partition,
+    pub data: Option<Partition>,
+}
+
+impl Layout {
+    /// Panics, at compile time when used in a constant, if the layout cannot
+    /// work.
+    pub const fn check(&self) {
+        let all = [
+            Some(self.bootloader),
+            Some(self.boot),
+            Some(self.update),
+            Some(self.swap),
+            self.data,
+        ];
+        let mut i = 0;
+        while i < all.len() {
+            let mut j = i + 1;
+            while j < all.len() {
+                if let (Some(a), Some(b)) = (&all[i], &all[j]) {
+                    assert!(!a.overlaps(b), "partitions overlap");
+                }
+                j += 1;
+            }
+            i += 1;
+        }
+        assert!(
+            self.boot.size == self.update.size,
+            "BOOT and UPDATE must be the same size"
+        );
+    }
+
+    /// Returns the partition of the given kind, if the layout has one.
+    pub fn get(&self, kind: PartitionKind) -> Option<Partition> {
+        match kind {
+            PartitionKind::Bootloader => Some(self.bootloader),
+            PartitionKind::Boot => Some(self.boot),
+            PartitionKind::Update => Some(self.update),
+            PartitionKind::Swap => Some(self.swap),
+            PartitionKind::Data => self.data,
+        }
+    }
+}
+
+/// The partition layout of the ATSAM4LC8C.
+pub const LAYOUT: Layout = Layout {
+    bootloader: Partition::new(PartitionKind::Bootloader, BOOTLOADER_ADDR, BOOTLOADER_SIZE),
+    boot: Partition::new(PartitionKind::Boot, BASE_ADDR, PARTITION_SIZE),
+    update: Partition::new(PartitionKind::Update, UPDATE_ADDR, UPDATE_SIZE),
+    swap: Partition::new(PartitionKind::Swap, SWAP_ADDR, SWAP_SIZE),
+    data: if DATA_SIZE == 0 {
+        None
+    } else {
+        Some(Partition::new(PartitionKind::Data, DATA_ADDR, DATA_SIZE))
+    },
+};
+
+const _: () = LAYOUT.check();
@@ -0,0 +1,157 @@
+//! Register-level access to the HFLASHC flash controller.
+//!
+//! [`FlashWriterEraser`](super::FlashWriterEraser) only talks to the flash
+//! controller through the [`Hflashc`] trait. On the SAM4L it is implemented
+//! by the PAC's `HFLASHC` peripheral; on the host it is implemented by
+//! [`SimFlash`](super::sim::SimFlash), so the write/erase algorithms can be
+//! exercised by `cargo test`.
+
+use atsam4lc8c_pac::HFLASHC;
+use core::ptr;
+
+use super::PageNumber;
+
+/// Value of the FCMD.KEY field that makes the controller accept a command.
+pub const FCMD_KEY: u8 = 0xA5;
+
+/// FSR: the controller is ready for a new command.
+pub const FSR_FRDY: u32 = 1 << 0;
+/// FSR: a command tried to modify a locked region.
+pub const FSR_LOCKE: u32 = 1 << 2;
+/// FSR: an invalid command or a bad key was written to FCMD.
+pub const FSR_PROGE: u32 = 1 << 3;
+/// FSR: the security bit is set.
+pub const FSR_SECURITY: u32 = 1 << 4;
+/// FSR: result of the last quick page read, set when the page is blank.
+pub const FSR_QPRR: u32 = 1 << 5;
+/// FSR: first of the sixteen region lock bits.
+pub const FSR_LOCK0: u32 = 1 << 16;
+
+/// Commands accepted by FCMD.CMD (datasheet section 14.6).
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command {
+    /// No operation.
+    Nop = 0x00,
+    /// Write page.
+    Wp = 0x01,
+    /// Erase page.
+    Ep = 0x02,
+    /// Clear page buffer.
+    Cpb = 0x03,
+    /// Lock region containing the given page.
+    Lp = 0x04,
+    /// Unlock region containing the given page.
+    Up = 0x05,
+    /// Erase all.
+    Ea = 0x06,
+    /// Write general-purpose fuse bit.
+    Wgpb = 0x07,
+    /// Erase general-purpose fuse bit.
+    Egpb = 0x08,
+    /// Set security fuses.
+    Ssb = 0x09,
+    /// Program general-purpose fuse byte.
+    Pgpfb = 0x0A,
+    /// Erase all general-purpose fuses.
+    Eagpf = 0x0B,
+    /// Quick page read.
+    Qpr = 0x0C,
+    /// Write user page.
+    Wup = 0x0D,
+    /// Erase user page.
+    Eup = 0x0E,
+    /// Quick page read user page.
+    Qprup = 0x0F,
+    /// High speed mode enable.
+    Hsen = 0x10,
+    /// High speed mode disable.
+    Hsdis = 0x11,
+}
+
+impl Command {
+    /// Decodes the FCMD.CMD field, returning `None` for reserved values.
+    pub fn from_bits(bits: u8) -> Option<Self> {
+        use Command::*;
+        let cmd = match bits {
+            0x00 => Nop,
+            0x01 => Wp,
+            0x02 => Ep,
+            0x03 => Cpb,
+            0x04 => Lp,
+            0x05 => Up,
+            0x06 => Ea,
+            0x07 => Wgpb,
+            0x08 => Egpb,
+            0x09 => Ssb,
+            0x0A => Pgpfb,
+            0x0B => Eagpf,
+            0x0C => Qpr,
+            0x0D => Wup,
+            0x0E => Eup,
+            0x0F => Qprup,
+            0x10 => Hsen,
+            0x11 => Hsdis,
+            _ => return None,
+        };
+        Some(cmd)
+    }
+
+    /// Encodes a full FCMD value with the correct key for `page`.
+    pub fn fcmd(self, page: PageNumber) -> u32 {
+        (FCMD_KEY as u32) << 24 | (page.get() as u32) << 8 | self as u32
+    }
+}
+
+/// The registers and memory the flash driver needs from the HFLASHC.
+pub trait Hflashc {
+    /// Writes a raw value to FCMD, starting a command.
+    fn write_fcmd(&self, fcmd: u32);
+
+    /// Reads the flash status register.
+    ///
+    /// As on the hardware, reading FSR clears LOCKE and PROGE.
+    fn read_fsr(&self) -> u32;
+
+    /// Reads the flash control register.
+    fn read_fcr(&self) -> u32;
+
+    /// Reads the flash parameter register.
+    fn read_fpr(&self) -> u32;
+
+    /// Writes one word of the page buffer through the flash address space.
+    ///
+    /// `address` must be word aligned.
+    fn write_page_buffer(&self, address: u32, word: u32);
+
+    /// Reads `buf.len()` bytes of flash starting at `address`.
+    fn read_flash(&self, address: u32, buf: &mut [u8]);
+}
+
+impl Hflashc for HFLASHC {
+    fn write_fcmd(&self, fcmd: u32) {
+        self.fcmd.write(|w| unsafe { w.bits(fcmd) });
+    }
+
+    fn read_fsr(&self) -> u32 {
+        self.fsr.read().bits()
+    }
+
+    fn read_fcr(&self) -> u32 {
+        self.fcr.read().bits()
+    }
+
+    fn read_fpr(&self) -> u32 {
+        self.fpr.read().bits()
+    }
+
+    fn write_page_buffer(&self, address: u32, word: u32) {
+        unsafe { ptr::write_volatile(address as *mut u32, word) }
+    }
+
+    fn read_flash(&self, address: u32, buf: &mut [u8]) {
+        for (i, byte) in buf.iter_mut().enumerate() {
+            *byte = unsafe { ptr::read_volatile((address as usize + i) as *const u8) };
+        }
+    }
+}
@@ -0,0 +1,364 @@
+//! A behavioural model of the HFLASHC for running the driver under `cargo test`.
+//!
+//! The model follows the command semantics of datasheet chapter 14 rather
+//! than just storing bytes:
+//!
+//! - page buffer writes are latched and only reach the array on WP/WUP, where
+//!   they are ANDed into the existing contents (programming can only clear bits)
+//! - EP/EUP/EA set bytes back to 0xFF and CPB sets the page buffer to 0xFF
+//! - the sixteen lowest general-purpose fuses are the region lock bits; WP and
+//!   EP on a locked region are dropped and raise LOCKE
+//! - a bad key, a reserved command or an out-of-range page raises PROGE
+//! - QPR/QPRUP report whether a page is blank in FSR.QPRR
+//! - reading FSR clears LOCKE and PROGE
+//!
+//! For power-fail testing a [`PowerFault`] can be armed to cut power before
+//! or in the middle of a later command. Once power is lost every further
+//! register access is ignored until [`SimFlash::power_cycle`], which keeps
+//! the non-volatile state and resets everything else, as a reset would.
+//! [`SimFlash::flip_bits`] corrupts stored data for testing integrity checks.
+
+extern crate alloc;
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::{Ref, RefCell};
+
+use super::atsam4lc8c_constants::*;
+use super::regs::*;
+use super::trace::{TraceEntry, TraceSink};
+use super::{FlashRange, PageNumber};
+
+/// Number of pages covered by one lock region.
+const PAGES_PER_REGION: u32 = FLASH_PAGE_COUNT / 16;
+
+struct State {
+    flash: Vec<u8>,
+    user_page: [u8; FLASH_PAGE_SIZE as usize],
+    page_buffer: [u8; FLASH_PAGE_SIZE as usize],
+    fuses: u64,
+    fsr: u32,
+    fault: Option<PowerFault>,
+    commands: u32,
+    powered: bool,
+}
+
+/// When a simulated power loss hits, counted in FCMD commands from the
+/// moment it is armed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PowerFault {
+    /// `n` commands complete, then power is lost before the next one runs.
+    AfterCommands(u32),
+    /// `n` commands complete and the next one is cut short: a WP/WUP only
+    /// programs the first `bytes` bytes of the page, an EP/EUP only erases
+    /// them. Any other command does not run at all.
+    DuringCommand { n: u32, bytes: usize },
+}
+
+impl State {
+    fn locked(&self, page: u32) -> bool {
+        self.fuses & 1 << (page / PAGES_PER_REGION) == 0
+    }
+
+    /// Runs the first `bytes` bytes of a page program or erase.
+    fn execute_partial(&mut self, cmd: Command, page: u32, bytes: usize) {
+        let bytes = bytes.min(FLASH_PAGE_SIZE as usize);
+        let start = (page * FLASH_PAGE_SIZE) as usize;
+        match cmd {
+            Command::Wp | Command::Ep if page >= FLASH_PAGE_COUNT || self.locked(page) => {}
+            Command::Wp => program(&mut self.flash[start..start + bytes], &self.page_buffer),
+            Command::Ep => self.flash[start..start + bytes].fill(0xFF),
+            Command::Wup => program(&mut self.user_page[..bytes], &self.page_buffer),
+            Command::Eup => self.user_page[..bytes].fill(0xFF),
+            _ => {}
+        }
+    }
+
+    fn execute(&mut self, cmd: Command, page: u32) -> u32 {
+        let start = (page * FLASH_PAGE_SIZE) as usize;
+        let end = start + FLASH_PAGE_SIZE as usize;
+        match cmd {
+            Command::Wp | Command::Ep | Command::Qpr if page >= FLASH_PAGE_COUNT => FSR_PROGE,
+            Command::Wp | Command::Ep if self.locked(page) => FSR_LOCKE,
+            Command::Wp => {
+                program(&mut self.flash[start..end], &self.page_buffer);
+                0
+            }
+            Command::Ep => {
+                self.flash[start..end].fill(0xFF);
+                0
+            }
+            Command::Cpb => {
+                self.page_buffer.fill(0xFF);
+                0
+            }
+            Command::Lp | Command::Up if page >= FLASH_PAGE_COUNT => FSR_PROGE,
+            Command::Lp => {
+                self.fuses &= !(1 << (page / PAGES_PER_REGION));
+                0
+            }
+            Command::Up => {
+                self.fuses |= 1 << (page / PAGES_PER_REGION);
+                0
+            }
+            Command::Ea if self.fuses & 0xFFFF != 0xFFFF => FSR_LOCKE,
+            Command::Ea => {
+                self.flash.fill(0xFF);
+                0
+            }
+            Command::Wgpb | Command::Egpb if page >= 64 => FSR_PROGE,
+            Command::Wgpb => {
+                self.fuses &= !(1 << page);
+                0
+            }
+            Command::Egpb => {
+                self.fuses |= 1 << page;
+                0
+            }
+            Command::Pgpfb => {
+                // PAGEN[2:0] selects the byte, PAGEN[10:3] holds the value
+                let shift = (page & 0x07) * 8;
+                let byte = ((page >> 3) & 0xFF) as u64;
+                self.fuses &= !(0xFF << shift) | byte << shift;
+                0
+            }
+            Command::Eagpf => {
+                self.fuses = u64::MAX;
+                0
+            }
+            Command::Qpr => qprr(&self.flash[start..end]),
+            Command::Wup => {
+                program(&mut self.user_page, &self.page_buffer);
+                0
+            }
+            Command::Eup => {
+                self.user_page.fill(0xFF);
+                0
+            }
+            Command::Qprup => qprr(&self.user_page),
+            Command::Ssb => {
+                self.fsr |= FSR_SECURITY;
+                0
+            }
+            Command::Nop | Command::Hsen | Command::Hsdis => 0,
+        }
+    }
+}
+
+fn program(cells: &mut [u8], page_buffer: &[u8]) {
+    for (cell, byte) in cells.iter_mut().zip(page_buffer) {
+        *cell &= *byte;
+    }
+}
+
+fn qprr(page: &[u8]) -> u32 {
+    if page.iter().all(|b| *b == 0xFF) {
+        FSR_QPRR
+    } else {
+        0
+    }
+}
+
+/// A RAM-backed [`Hflashc`] for host tests.
+pub struct SimFlash {
+    state: RefCell<State>,
+}
+
+impl SimFlash {
+    /// Creates a simulated controller with erased flash, user page and fuses.
+    pub fn new() -> Self {
+        SimFlash {
+            state: RefCell::new(State {
+                flash: vec![0xFF; FLASH_SIZE as usize],
+                user_page: [0xFF; FLASH_PAGE_SIZE as usize],
+                page_buffer: [0xFF; FLASH_PAGE_SIZE as usize],
+                fuses: u64::MAX,
+                fsr: FSR_FRDY,
+                fault: None,
+                commands: 0,
+                powered: true,
+            }),
+        }
+    }
+
+    /// Arms a power loss, replacing any fault that has not fired yet.
+    pub fn inject_power_loss(&self, fault: PowerFault) {
+        let mut state = self.state.borrow_mut();
+        state.fault = Some(fault);
+        state.commands = 0;
+    }
+
+    /// Returns `true` once an armed power loss has fired.
+    pub fn lost_power(&self) -> bool {
+        !self.state.borrow().powered
+    }
+
+    /// Restores power after a simulated loss, as a reset would.
+    ///
+    /// Flash, the user page and the fuses keep their contents; the page buffer
+    /// reads as erased and FSR starts clean.
+    pub fn power_cycle(&self) {
+        let mut state = self.state.borrow_mut();
+        state.page_buffer.fill(0xFF);
+        state.fsr = FSR_FRDY;
+        state.fault = None;
+        state.commands = 0;
+        state.powered = true;
+    }
+
+    /// Returns the number of FCMD commands written since the last power
+    /// cycle or armed fault.
+    ///
+    /// Power-fail tests run an operation once to count its commands, then
+    /// replay it with a fault at every step.
+    pub fn command_count(&self) -> u32 {
+        self.state.borrow().commands
+    }
+
+    /// Flips `count` bits at pseudo-random positions inside `range`.
+    ///
+    /// The positions only depend on `seed`, so a failing test reproduces.
+    pub fn flip_bits(&self, range: FlashRange, count: usize, seed: u64) {
+        let mut state = self.state.borrow_mut();
+        // xorshift64, which must not start at zero
+        let mut x = seed | 1;
+        for _ in 0..count {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            let bit = x % (range.len() as u64 * 8);
+            let byte = (range.start().get() - FLASH_BASE) as usize + (bit / 8) as usize;
+            state.flash[byte] ^= 1 << (bit % 8);
+        }
+    }
+
+    /// Returns the current contents of the whole flash array.
+    pub fn flash(&self) -> Ref<'_, [u8]> {
+        Ref::map(self.state.borrow(), |s| &s.flash[..])
+    }
+
+    /// Returns the current contents of the user page.
+    pub fn user_page(&self) -> Ref<'_, [u8]> {
+        Ref::map(self.state.borrow(), |s| &s.user_page[..])
+    }
+
+    /// Returns the 64 general-purpose fuse bits; bits 0..16 are the lock bits.
+    pub fn fuses(&self) -> u64 {
+        self.state.borrow().fuses
+    }
+}
+
+impl Default for SimFlash {
+    fn default() -> Self {
+        SimFlash::new()
+    }
+}
+
+impl Hflashc for SimFlash {
+    fn write_fcmd(&self, fcmd: u32) {
+        let mut state = self.state.borrow_mut();
+        if !state.powered {
+            return;
+        }
+        let key = (fcmd >> 24) as u8;
+        let page = (fcmd >> 8) & 0xFFFF;
+        let command = Command::from_bits((fcmd & 0x3F) as u8);
+
+        let issued = state.commands;
+        state.commands += 1;
+        match state.fault {
+            Some(PowerFault::AfterCommands(n)) if issued == n => {
+                state.powered = false;
+                return;
+            }
+            Some(PowerFault::DuringCommand { n, bytes }) if issued == n => {
+                if let (Some(cmd), FCMD_KEY) = (command, key) {
+                    state.execute_partial(cmd, page, bytes);
+                }
+                state.powered = false;
+                return;
+            }
+            _ => {}
+        }
+
+        let status = match command {
+            Some(cmd) if key == FCMD_KEY => state.execute(cmd, page),
+            _ => FSR_PROGE,
+        };
+        state.fsr = (state.fsr & !FSR_QPRR) | status;
+    }
+
+    fn read_fsr(&self) -> u32 {
+        let mut state = self.state.borrow_mut();
+        let locks = ((!state.fuses & 0xFFFF) as u32) << 16;
+        let fsr = state.fsr | locks;
+        state.fsr &= !(FSR_LOCKE | FSR_PROGE);
+        fsr
+    }
+
+    fn read_fcr(&self) -> u32 {
+        0
+    }
+
+    fn read_fpr(&self) -> u32 {
+        // FSZ = 512 KiB, PSZ = 512 bytes
+        11 | 4 << 8
+    }
+
+    fn write_page_buffer(&self, address: u32, word: u32) {
+        let mut state = self.state.borrow_mut();
+        if !state.powered {
+            return;
+        }
+        let offset = (address % FLASH_PAGE_SIZE) as usize & !0x03;
+        state.page_buffer[offset..offset + 4].copy_from_slice(&word.to_le_bytes());
+    }
+
+    fn read_flash(&self, address: u32, buf: &mut [u8]) {
+        let state = self.state.borrow();
+        let (memory, start) = if address >= USER_PAGE_BASE {
+            (&state.user_page[..], address - USER_PAGE_BASE)
+        } else {
+            (&state.flash[..], address - FLASH_BASE)
+        };
+        let start = start as usize;
+        buf.copy_from_slice(&memory[start..start + buf.len()]);
+    }
+}
+
+/// A [`TraceSink`] that keeps every entry for later assertions.
+#[derive(Default)]
+pub struct TraceLog {
+    entries: RefCell<Vec<TraceEntry>>,
+}
+
+impl TraceLog {
+    pub fn new() -> Self {
+        TraceLog::default()
+    }
+
+    /// Returns everything recorded so far.
+    pub fn entries(&self) -> Vec<TraceEntry> {
+        self.entries.borrow().clone()
+    }
+
+    /// Returns the recorded commands and pages, without FSR.
+    pub fn commands(&self) -> Vec<(Command, PageNumber)> {
+        self.entries
+            .borrow()
+            .iter()
+            .map(|e| (e.command, e.page))
+            .collect()
+    }
+
+    /// Forgets everything recorded so far.
+    pub fn clear(&self) {
+        self.entries.borrow_mut().clear();
+    }
+}
+
+impl TraceSink for TraceLog {
+    fn record(&self, entry: TraceEntry) {
+        self.entries.borrow_mut().push(entry);
+    }
+}
@@ -0,0 +1,52 @@
+//! Optional recording of every FCMD command the driver issues.
+//!
+//! A [`FlashWriterEraser`](super::FlashWriterEraser) reports each command to
+//! its [`TraceSink`] once the controller is ready again. The default sink is
+//! `()`, which records nothing and compiles away. Host tests use
+//! [`TraceLog`](super::sim::TraceLog) to assert on exact command sequences;
+//! on the target [`DefmtTrace`] prints them over RTT.
+
+use super::{Command, PageNumber};
+
+/// One command issued to the HFLASHC.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TraceEntry {
+    /// The command written to FCMD.CMD.
+    pub command: Command,
+    /// The page written to FCMD.PAGEN.
+    pub page: PageNumber,
+    /// FSR once the command finished, including any LOCKE or PROGE seen
+    /// while waiting.
+    pub fsr: u32,
+}
+
+/// Receives a [`TraceEntry`] for every command the driver issues.
+pub trait TraceSink {
+    fn record(&self, entry: TraceEntry);
+}
+
+impl TraceSink for () {
+    fn record(&self, _entry: TraceEntry) {}
+}
+
+impl<T: TraceSink + ?Sized> TraceSink for &T {
+    fn record(&self, entry: TraceEntry) {
+        (**self).record(entry)
+    }
+}
+
+/// Prints every command with `defmt`.
+#[cfg(target_os = "none")]
+pub struct DefmtTrace;
+
+#[cfg(target_os = "none")]
+impl TraceSink for DefmtTrace {
+    fn record(&self, entry: TraceEntry) {
+        defmt::println!(
+            "FCMD cmd={=u8:#x} page={=u16} fsr={=u32:#x}",
+            entry.command as u8,
+            entry.page.get(),
+            entry.fsr
+        );
+    }
+}
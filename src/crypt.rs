@@ -0,0 +1,264 @@
+//! Encrypted images, decrypted while they are programmed.
+//!
+//! Images travel encrypted with AES-128 in counter mode, so the firmware is
+//! never exposed on the link. An encrypted image is a short header followed
+//! by the ciphertext of the signed image:
+//!
+//! | field  | size | contents                                       |
+//! |--------|------|------------------------------------------------|
+//! | magic  | 4    | `RBE1`                                         |
+//! | IV     | 16   | first counter block, big endian                |
+//! | data   | ...  | the signed image XORed with the keystream      |
+//!
+//! Counter mode gives confidentiality only. The decrypted image is checked
+//! like any other, with [`verify_image`](crate::image::verify_image), before
+//! it is installed.
+//!
+//! The block cipher is a [`BlockCipher`]: [`Aesa`](crate::atsam4l::Aesa) on
+//! the target and [`SoftAes`] on the host. The key is provisioned into the
+//! user page with [`store_key`]; set the security bit so a debugger cannot
+//! read it back.
+
+use crate::atsam4l::atsam4lc8c_constants::*;
+use crate::atsam4l::{FlashError, FlashWriterEraser, Hflashc, TraceSink};
+use crate::writer::PageWriter;
+
+pub const MAGIC: &[u8; 4] = b"RBE1";
+pub const BLOCK_SIZE: usize = 16;
+pub const HEADER_SIZE: usize = 4 + BLOCK_SIZE;
+pub const KEY_SIZE: usize = 16;
+
+/// Why an image could not be decrypted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CryptError {
+    /// The user page holds no valid key.
+    NoKey,
+    BadMagic,
+    /// The input ended inside the header.
+    Truncated,
+    Flash(FlashError),
+}
+
+impl From<FlashError> for CryptError {
+    fn from(e: FlashError) -> Self {
+        CryptError::Flash(e)
+    }
+}
+
+/// A block cipher that can encrypt single blocks, all counter mode needs.
+pub trait BlockCipher {
+    fn encrypt_block(&mut self, block: &mut [u8; BLOCK_SIZE]);
+}
+
+/// AES-128 in software, for host tests.
+#[cfg(not(target_os = "none"))]
+pub struct SoftAes(aes::Aes128);
+
+#[cfg(not(target_os = "none"))]
+impl SoftAes {
+    pub fn new(key: &[u8; KEY_SIZE]) -> Self {
+        use aes::cipher::KeyInit;
+        SoftAes(aes::Aes128::new(key.into()))
+    }
+}
+
+#[cfg(not(target_os = "none"))]
+impl BlockCipher for SoftAes {
+    fn encrypt_block(&mut self, block: &mut [u8; BLOCK_SIZE]) {
+        use aes::cipher::BlockEncrypt;
+        self.0.encrypt_block(block.into());
+    }
+}
+
+/// Counter mode keystream with a 128-bit big-endian counter.
+pub struct Ctr<C> {
+    cipher: C,
+    counter: [u8; BLOCK_SIZE],
+    keystream: [u8; BLOCK_SIZE],
+    /// Keystream bytes of the current block already used.
+    used: usize,
+}
+
+impl<C: BlockCipher> Ctr<C> {
+    pub fn new(cipher: C, iv: &[u8; BLOCK_SIZE]) -> Self {
+        Ctr {
+            cipher,
+            counter: *iv,
+            keystream: [0; BLOCK_SIZE],
+            used: BLOCK_SIZE,
+        }
+    }
+
+    /// This method is used to encrypt or decrypt the next bytes of a message
+    ///
+    /// Method arguments:
+    /// -   data: XORed in place with the keystream
+    pub fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            if self.used == BLOCK_SIZE {
+                self.keystream = self.counter;
+                self.cipher.encrypt_block(&mut self.keystream);
+                for b in self.counter.iter_mut().rev() {
+                    *b = b.wrapping_add(1);
+                    if *b != 0 {
+                        break;
+                    }
+                }
+                self.used = 0;
+            }
+            *byte ^= self.keystream[self.used];
+            self.used += 1;
+        }
+    }
+
+    /// Starts a new message.
+    fn restart(&mut self, iv: &[u8; BLOCK_SIZE]) {
+        self.counter = *iv;
+        self.used = BLOCK_SIZE;
+    }
+
+    /// Gives the cipher back, for example to release the AESA.
+    pub fn release(self) -> C {
+        self.cipher
+    }
+}
+
+/// Decrypts an image into a partition as the input arrives.
+pub struct Decryptor<C> {
+    ctr: Ctr<C>,
+    header: [u8; HEADER_SIZE],
+    header_len: usize,
+    written: u32,
+}
+
+impl<C: BlockCipher> Decryptor<C> {
+    /// This method is used to prepare decrypting an image
+    ///
+    /// Method arguments:
+    /// -   cipher: the block cipher, already holding the key
+    ///
+    /// Returns:
+    /// -  a decryptor waiting for the header
+    pub fn new(cipher: C) -> Self {
+        Decryptor {
+            ctr: Ctr::new(cipher, &[0; BLOCK_SIZE]),
+            header: [0; HEADER_SIZE],
+            header_len: 0,
+            written: 0,
+        }
+    }
+
+    /// This method is used to feed the next piece of the encrypted image
+    ///
+    /// Method arguments:
+    /// -   input: any number of bytes, split anywhere
+    /// -   writer: receives the image, which starts at offset 0
+    ///
+    /// Returns:
+    /// -  `FlashError::OutOfBounds`, as `CryptError::Flash`, if the image
+    ///    does not fit the partition
+    pub fn feed<H: Hflashc, T: TraceSink>(
+        &mut self,
+        mut input: &[u8],
+        writer: &mut PageWriter<'_, H, T>,
+    ) -> Result<(), CryptError> {
+        if self.header_len < HEADER_SIZE {
+            let n = (HEADER_SIZE - self.header_len).min(input.len());
+            self.header[self.header_len..self.header_len + n].copy_from_slice(&input[..n]);
+            self.header_len += n;
+            input = &input[n..];
+            if self.header_len < HEADER_SIZE {
+                return Ok(());
+            }
+            if &self.header[..4] != MAGIC {
+                return Err(CryptError::BadMagic);
+            }
+            let mut iv = [0; BLOCK_SIZE];
+            iv.copy_from_slice(&self.header[4..]);
+            self.ctr.restart(&iv);
+        }
+        let mut buf = [0u8; 64];
+        for chunk in input.chunks(buf.len()) {
+            let buf = &mut buf[..chunk.len()];
+            buf.copy_from_slice(chunk);
+            self.ctr.apply(buf);
+            writer.write(self.written, buf)?;
+            self.written += buf.len() as u32;
+        }
+        Ok(())
+    }
+
+    /// This method is used once the whole image has been fed
+    ///
+    /// Method arguments:
+    /// -   writer: flushed so flash holds the whole image
+    ///
+    /// Returns:
+    /// -  the size of the decrypted image
+    pub fn finish<H: Hflashc, T: TraceSink>(
+        &mut self,
+        writer: &mut PageWriter<'_, H, T>,
+    ) -> Result<u32, CryptError> {
+        if self.header_len < HEADER_SIZE {
+            return Err(CryptError::Truncated);
+        }
+        writer.flush()?;
+        Ok(self.written)
+    }
+
+    /// Gives the cipher back.
+    pub fn release(self) -> C {
+        self.ctr.release()
+    }
+}
+
+/// This method is used to read the image key from the user page
+///
+/// Method arguments:
+/// -   flash: the flash driver
+///
+/// Returns:
+/// -  `CryptError::NoKey` if the key was never stored or is damaged
+pub fn load_key<H: Hflashc, T: TraceSink>(
+    flash: &FlashWriterEraser<H, T>,
+) -> Result<[u8; KEY_SIZE], CryptError> {
+    let mut stored = [0u8; 2 * KEY_SIZE];
+    flash.hal_user_page_read(UP_KEY_OFFSET, &mut stored)?;
+    let (key, check) = stored.split_at(KEY_SIZE);
+    if key.iter().zip(check).any(|(k, c)| *k != !*c) {
+        return Err(CryptError::NoKey);
+    }
+    let mut out = [0; KEY_SIZE];
+    out.copy_from_slice(key);
+    Ok(out)
+}
+
+/// This method is used to provision the image key into the user page
+///
+/// The key is stored next to its complement, so an erased or partly
+/// written key is never used. Provisioning a blank key area only programs
+/// it; replacing a key goes through
+/// [`hal_user_page_write`](FlashWriterEraser::hal_user_page_write), so a
+/// reset leaves the old key or the new one and the rest of the user page.
+///
+/// Method arguments:
+/// -   flash: the flash driver
+/// -   key: the AES-128 key
+///
+/// Returns:
+/// -  `Ok` once the key reads back correctly
+pub fn store_key<H: Hflashc, T: TraceSink>(
+    flash: &FlashWriterEraser<H, T>,
+    key: &[u8; KEY_SIZE],
+) -> Result<(), CryptError> {
+    let mut stored = [0u8; 2 * KEY_SIZE];
+    for (i, k) in key.iter().enumerate() {
+        stored[i] = *k;
+        stored[KEY_SIZE + i] = !*k;
+    }
+    flash.hal_user_page_write(UP_KEY_OFFSET, &stored)?;
+    match load_key(flash) {
+        Ok(read) if read == *key => Ok(()),
+        _ => Err(CryptError::Flash(FlashError::Verify)),
+    }
+}
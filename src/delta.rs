@@ -0,0 +1,329 @@
+//! Differential updates.
+//!
+//! A delta rebuilds a new image from the one in BOOT, so only the changes
+//! need to cross the serial link. [`Patcher`] takes the delta in pieces of
+//! any size, reads the old image from flash as it goes and writes the new
+//! one through a [`PageWriter`], usually into UPDATE.
+//!
+//! The format follows bsdiff: the new image is built front to back from
+//! runs copied from the old image, runs of the old image with a byte-wise
+//! difference added, and literal bytes. A source cursor tracks the position
+//! in the old image and only moves forward through copies and additions,
+//! or by an explicit seek, so a delta between two builds of the same
+//! program is mostly short instructions.
+//!
+//! | field        | size | contents                                   |
+//! |--------------|------|--------------------------------------------|
+//! | magic        | 4    | `RBD1`                                     |
+//! | source size  | 4    | bytes of the old image the delta applies to |
+//! | source CRC   | 4    | CRC-32 of those bytes                      |
+//! | target size  | 4    | size of the new image                      |
+//! | instructions | ...  | until [`OP_END`]                           |
+//!
+//! Integers in the header are little endian. Each instruction is an opcode
+//! byte followed by an unsigned LEB128 length, or a zigzag-encoded offset
+//! for [`OP_SEEK`], then the data it carries.
+
+use flash_protocol::crc32_update;
+
+use crate::atsam4l::atsam4lc8c_constants::*;
+use crate::atsam4l::partition::Partition;
+use crate::atsam4l::{FlashError, FlashWriterEraser, Hflashc, TraceSink};
+use crate::writer::PageWriter;
+
+pub const MAGIC: &[u8; 4] = b"RBD1";
+pub const HEADER_SIZE: usize = 16;
+
+/// Ends the delta; the new image must be complete.
+pub const OP_END: u8 = 0x00;
+/// Copies `len` bytes from the source cursor.
+pub const OP_COPY: u8 = 0x01;
+/// Adds the `len` bytes that follow to `len` bytes at the source cursor.
+pub const OP_ADD: u8 = 0x02;
+/// Writes the `len` bytes that follow as they are.
+pub const OP_INSERT: u8 = 0x03;
+/// Moves the source cursor by a signed offset.
+pub const OP_SEEK: u8 = 0x04;
+
+/// Bytes of source or delta handled at a time.
+const CHUNK: usize = 64;
+
+/// Why a delta could not be applied.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeltaError {
+    BadMagic,
+    /// The old image is not the one the delta was made from.
+    SourceMismatch,
+    /// The new image does not fit in the target partition.
+    TooLarge,
+    /// An unknown opcode or an over-long length.
+    Corrupt,
+    /// An instruction reads outside the old image.
+    SourceRange,
+    /// The instructions write past the target size, or end before it.
+    TargetSize,
+    /// The delta ended before [`OP_END`].
+    Truncated,
+    Flash(FlashError),
+}
+
+impl From<FlashError> for DeltaError {
+    fn from(e: FlashError) -> Self {
+        DeltaError::Flash(e)
+    }
+}
+
+/// Whether the whole delta has been applied.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Progress {
+    NeedMore,
+    /// The new image is complete and `size` bytes long.
+    Done {
+        size: u32,
+    },
+}
+
+enum State {
+    Header,
+    Opcode,
+    Argument { op: u8, value: u32, shift: u32 },
+    Data { op: u8, remaining: u32 },
+    Done,
+}
+
+/// Applies a delta as it arrives.
+pub struct Patcher<'a, H: Hflashc, T: TraceSink> {
+    flash: &'a FlashWriterEraser<H, T>,
+    source: Partition,
+    state: State,
+    header: [u8; HEADER_SIZE],
+    header_len: usize,
+    source_size: u32,
+    target_size: u32,
+    /// Position in the old image.
+    cursor: u32,
+    /// Bytes of the new image written so far.
+    written: u32,
+}
+
+impl<'a, H: Hflashc, T: TraceSink> Patcher<'a, H, T> {
+    /// This method is used to prepare patching the image in a partition
+    ///
+    /// Method arguments:
+    /// -   flash: the flash driver
+    /// -   source: partition holding the old image, usually BOOT
+    ///
+    /// Returns:
+    /// -  a patcher waiting for the delta header
+    pub fn new(flash: &'a FlashWriterEraser<H, T>, source: Partition) -> Self {
+        Patcher {
+            flash,
+            source,
+            state: State::Header,
+            header: [0; HEADER_SIZE],
+            header_len: 0,
+            source_size: 0,
+            target_size: 0,
+            cursor: 0,
+            written: 0,
+        }
+    }
+
+    /// This method is used to feed the next piece of the delta
+    ///
+    /// Method arguments:
+    /// -   delta: any number of bytes, split anywhere
+    /// -   writer: receives the new image, which starts at offset 0
+    ///
+    /// Returns:
+    /// -  `Progress::Done` once the delta has ended and the writer has been
+    ///    flushed; any bytes after the end are ignored
+    pub fn feed(
+        &mut self,
+        mut delta: &[u8],
+        writer: &mut PageWriter<'_, H, T>,
+    ) -> Result<Progress, DeltaError> {
+        while !delta.is_empty() {
+            match self.state {
+                State::Header => {
+                    let n = (HEADER_SIZE - self.header_len).min(delta.len());
+                    self.header[self.header_len..self.header_len + n].copy_from_slice(&delta[..n]);
+                    self.header_len += n;
+                    delta = &delta[n..];
+                    if self.header_len == HEADER_SIZE {
+                        self.check_header(writer)?;
+                        self.state = State::Opcode;
+                    }
+                }
+                State::Opcode => {
+                    let op = delta[0];
+                    delta = &delta[1..];
+                    self.state = match op {
+                        OP_END => {
+                            if self.written != self.target_size {
+                                return Err(DeltaError::TargetSize);
+                            }
+                            writer.flush()?;
+                            State::Done
+                        }
+                        OP_COPY | OP_ADD | OP_INSERT | OP_SEEK => State::Argument {
+                            op,
+                            value: 0,
+                            shift: 0,
+                        },
+                        _ => return Err(DeltaError::Corrupt),
+                    };
+                }
+                State::Argument { op, value, shift } => {
+                    let byte = delta[0];
+                    delta = &delta[1..];
+                    if shift > 28 || (shift == 28 && byte > 0x0F) {
+                        return Err(DeltaError::Corrupt);
+                    }
+                    let value = value | ((byte & 0x7F) as u32) << shift;
+                    if byte & 0x80 != 0 {
+                        self.state = State::Argument {
+                            op,
+                            value,
+                            shift: shift + 7,
+                        };
+                    } else {
+                        self.state = self.start(op, value, writer)?;
+                    }
+                }
+                State::Data { op, remaining } => {
+                    let n = (remaining as usize).min(delta.len()).min(CHUNK);
+                    let (data, rest) = delta.split_at(n);
+                    delta = rest;
+                    let mut out = [0u8; CHUNK];
+                    let out = &mut out[..n];
+                    if op == OP_ADD {
+                        self.read_source(out)?;
+                        for (o, d) in out.iter_mut().zip(data) {
+                            *o = o.wrapping_add(*d);
+                        }
+                    } else {
+                        out.copy_from_slice(data);
+                    }
+                    self.emit(out, writer)?;
+                    let remaining = remaining - n as u32;
+                    self.state = if remaining == 0 {
+                        State::Opcode
+                    } else {
+                        State::Data { op, remaining }
+                    };
+                }
+                State::Done => break,
+            }
+        }
+        Ok(match self.state {
+            State::Done => Progress::Done {
+                size: self.target_size,
+            },
+            _ => Progress::NeedMore,
+        })
+    }
+
+    /// This method is used once the whole delta has been fed
+    ///
+    /// Returns:
+    /// -  the size of the new image, or `DeltaError::Truncated` if the delta
+    ///    did not end with [`OP_END`]
+    pub fn finish(&self) -> Result<u32, DeltaError> {
+        match self.state {
+            State::Done => Ok(self.target_size),
+            _ => Err(DeltaError::Truncated),
+        }
+    }
+
+    fn check_header(&mut self, writer: &PageWriter<'_, H, T>) -> Result<(), DeltaError> {
+        let h = &self.header;
+        let word = |i: usize| u32::from_le_bytes([h[i], h[i + 1], h[i + 2], h[i + 3]]);
+        if &h[..4] != MAGIC {
+            return Err(DeltaError::BadMagic);
+        }
+        self.source_size = word(4);
+        let source_crc = word(8);
+        self.target_size = word(12);
+        if self.source_size > self.source.size() {
+            return Err(DeltaError::SourceMismatch);
+        }
+        if self.target_size > writer.partition().size() {
+            return Err(DeltaError::TooLarge);
+        }
+
+        let mut crc = 0;
+        let mut buf = [0u8; FLASH_PAGE_SIZE as usize];
+        let mut offset = 0;
+        while offset < self.source_size {
+            let n = (self.source_size - offset).min(FLASH_PAGE_SIZE);
+            let chunk = &mut buf[..n as usize];
+            self.source.read(self.flash, offset, chunk)?;
+            crc = crc32_update(crc, chunk);
+            offset += n;
+        }
+        if crc != source_crc {
+            return Err(DeltaError::SourceMismatch);
+        }
+        Ok(())
+    }
+
+    /// Runs an instruction once its argument is known.
+    fn start(
+        &mut self,
+        op: u8,
+        value: u32,
+        writer: &mut PageWriter<'_, H, T>,
+    ) -> Result<State, DeltaError> {
+        match op {
+            OP_SEEK => {
+                // zigzag: 0, -1, 1, -2, ...
+                let offset = (value >> 1) as i32 ^ -((value & 1) as i32);
+                self.cursor = self
+                    .cursor
+                    .checked_add_signed(offset)
+                    .filter(|c| *c <= self.source_size)
+                    .ok_or(DeltaError::SourceRange)?;
+                return Ok(State::Opcode);
+            }
+            OP_COPY | OP_ADD if value > self.source_size - self.cursor => {
+                return Err(DeltaError::SourceRange)
+            }
+            _ => {}
+        }
+        if value > self.target_size - self.written {
+            return Err(DeltaError::TargetSize);
+        }
+        if op == OP_COPY {
+            let mut remaining = value;
+            let mut buf = [0u8; CHUNK];
+            while remaining > 0 {
+                let chunk = &mut buf[..(remaining as usize).min(CHUNK)];
+                self.read_source(chunk)?;
+                self.emit(chunk, writer)?;
+                remaining -= chunk.len() as u32;
+            }
+            return Ok(State::Opcode);
+        }
+        Ok(if value == 0 {
+            State::Opcode
+        } else {
+            State::Data {
+                op,
+                remaining: value,
+            }
+        })
+    }
+
+    fn read_source(&mut self, buf: &mut [u8]) -> Result<(), FlashError> {
+        self.source.read(self.flash, self.cursor, buf)?;
+        self.cursor += buf.len() as u32;
+        Ok(())
+    }
+
+    fn emit(&mut self, data: &[u8], writer: &mut PageWriter<'_, H, T>) -> Result<(), FlashError> {
+        writer.write(self.written, data)?;
+        self.written += data.len() as u32;
+        Ok(())
+    }
+}
@@ -0,0 +1,190 @@
+//! Target side of the framed flash command protocol.
+//!
+//! [`Dispatcher`] reads frames from a [`ByteStream`], runs each request
+//! against [`FlashWriterEraser`] and sends back the response. The frame
+//! format and the requests are defined in the `flash-protocol` crate, which
+//! the host's `flashctl` uses as well.
+//!
+//! Writes and erases that touch the bootloader partition, which also holds
+//! the partition table and the user page backup, are refused with
+//! `Status::Locked`, so the host cannot brick the device.
+
+use flash_protocol::{
+    crc32_update, encode_frame, FrameDecoder, Info, Request, Response, Status, MAX_DATA, MAX_FRAME,
+    MAX_MESSAGE,
+};
+
+use crate::atsam4l::atsam4lc8c_constants::*;
+use crate::atsam4l::partition::LAYOUT;
+use crate::atsam4l::{FlashAddress, FlashError, FlashRange, FlashWriterEraser, Hflashc, TraceSink};
+use crate::xmodem::ByteStream;
+
+/// What the caller has to do after [`Dispatcher::poll`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// Nothing, or a request was served.
+    Idle,
+    /// The host asked for a reset and has been answered.
+    Reboot,
+}
+
+impl From<FlashError> for Status {
+    fn from(e: FlashError) -> Self {
+        match e {
+            FlashError::OutOfBounds => Status::OutOfBounds,
+            FlashError::Locked => Status::Locked,
+            FlashError::Programming => Status::Programming,
+            FlashError::Verify => Status::Verify,
+        }
+    }
+}
+
+/// Serves flash requests from a host.
+pub struct Dispatcher<'a, H, T> {
+    flash: &'a FlashWriterEraser<H, T>,
+    decoder: FrameDecoder,
+    data: [u8; MAX_DATA],
+}
+
+impl<'a, H: Hflashc, T: TraceSink> Dispatcher<'a, H, T> {
+    pub fn new(flash: &'a FlashWriterEraser<H, T>) -> Self {
+        Dispatcher {
+            flash,
+            decoder: FrameDecoder::new(),
+            data: [0; MAX_DATA],
+        }
+    }
+
+    /// This method is used to serve requests arriving on a stream
+    ///
+    /// Reads bytes until the line stays quiet for `timeout_ms` or a reboot
+    /// is requested, answering each complete frame.
+    ///
+    /// Method arguments:
+    /// -   stream: the line to the host
+    /// -   timeout_ms: how long to wait for each byte
+    ///
+    /// Returns:
+    /// -  `Event::Reboot` once a reboot request has been answered
+    pub fn poll<S: ByteStream>(&mut self, stream: &mut S, timeout_ms: u32) -> Event {
+        while let Some(byte) = stream.read_byte(timeout_ms) {
+            let mut message = [0u8; MAX_MESSAGE];
+            let mut out = [0u8; MAX_MESSAGE];
+            let len = match self.decoder.push(byte) {
+                None => continue,
+                Some(Ok(frame)) => {
+                    message[..frame.len()].copy_from_slice(frame);
+                    frame.len()
+                }
+                Some(Err(_)) => {
+                    let len = Response::encode_error(Status::BadFrame, &mut out);
+                    send(stream, &out, len);
+                    continue;
+                }
+            };
+            let request = match Request::decode(&message[..len]) {
+                Ok(request) => request,
+                Err(_) => {
+                    let len = Response::encode_error(Status::BadRequest, &mut out);
+                    send(stream, &out, len);
+                    continue;
+                }
+            };
+
+            let len = self.handle(&request).encode(&request, &mut out);
+            send(stream, &out, len);
+            if request == Request::Reboot {
+                return Event::Reboot;
+            }
+        }
+        Event::Idle
+    }
+
+    /// This method is used to run one request
+    ///
+    /// Method arguments:
+    /// -   request: a decoded request
+    ///
+    /// Returns:
+    /// -  the response to send back
+    pub fn handle(&mut self, request: &Request) -> Response<'_> {
+        let result = match *request {
+            Request::Info => Ok(Response::Info(Info {
+                flash_base: FLASH_BASE,
+                flash_size: FLASH_SIZE,
+                page_size: FLASH_PAGE_SIZE,
+                user_page_base: USER_PAGE_BASE,
+                max_data: MAX_DATA as u32,
+            })),
+            Request::Read { address, len } => {
+                let flash = self.flash;
+                let buf = &mut self.data[..len as usize];
+                match FlashAddress::new(address)
+                    .and_then(|address| flash.hal_flash_read(address, buf))
+                {
+                    Ok(()) => Ok(Response::Data(buf)),
+                    Err(e) => Err(e),
+                }
+            }
+            Request::Write { address, data } => writable(address, data.len() as u32)
+                .and_then(|_| FlashAddress::new(address))
+                .and_then(|address| self.flash.hal_flash_write(address, data))
+                .map(|_| Response::Done),
+            Request::Erase { address, len } => writable(address, len)
+                .and_then(|_| range(address, len))
+                .and_then(|range| self.flash.hal_flash_erase(range))
+                .map(|_| Response::Done),
+            Request::BlankCheck { address } => FlashAddress::new(address)
+                .and_then(|address| self.flash.hal_flash_is_blank(address.page()))
+                .map(Response::Blank),
+            Request::Crc { address, len } => self.crc(address, len).map(Response::Crc),
+            Request::Reboot => Ok(Response::Done),
+        };
+        result.unwrap_or_else(|e| Response::Failed(e.into()))
+    }
+
+    fn crc(&mut self, address: u32, len: u32) -> Result<u32, FlashError> {
+        let range = range(address, len)?;
+        let mut crc = 0;
+        let mut address = range.start();
+        let mut left = len;
+        while left > 0 {
+            let chunk = core::cmp::min(left, MAX_DATA as u32);
+            let buf = &mut self.data[..chunk as usize];
+            self.flash.hal_flash_read(address, buf)?;
+            crc = crc32_update(crc, buf);
+            left -= chunk;
+            if left > 0 {
+                address = address.offset(chunk)?;
+            }
+        }
+        Ok(crc)
+    }
+}
+
+fn range(address: u32, len: u32) -> Result<FlashRange, FlashError> {
+    FlashRange::new(FlashAddress::new(address)?, len)
+}
+
+/// Refuses ranges that touch the bootloader partition.
+fn writable(address: u32, len: u32) -> Result<(), FlashError> {
+    let bootloader = LAYOUT.bootloader;
+    let end = address.saturating_add(len);
+    if len > 0 && address < bootloader.start() + bootloader.size() && bootloader.start() < end {
+        return Err(FlashError::Locked);
+    }
+    Ok(())
+}
+
+/// Frames an encoded response and writes it to the stream.
+fn send<S: ByteStream>(stream: &mut S, message: &[u8], len: Result<usize, flash_protocol::Error>) {
+    // responses always fit, so an encoding error cannot happen
+    if let Ok(len) = len {
+        let mut frame = [0u8; MAX_FRAME];
+        if let Ok(framed) = encode_frame(&message[..len], &mut frame) {
+            for byte in &frame[..framed] {
+                stream.write_byte(*byte);
+            }
+        }
+    }
+}
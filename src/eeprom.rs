@@ -0,0 +1,371 @@
+//! EEPROM emulation over flash pages.
+//!
+//! Code ported from parts with a real EEPROM reads and writes 32-bit words
+//! by address. Here every write appends an 8-byte record to the active page
+//! and a read returns the newest record for its address; words never
+//! written read as `0xFFFF_FFFF`, like erased EEPROM.
+//!
+//! When the active page is full, the newest value of every address is
+//! copied to the next page of the partition, which becomes the active page,
+//! and the old page is erased. The partition is used as a ring, so with
+//! more than two pages the erases are spread over all of them.
+//!
+//! Every page in use starts with a 16-byte header:
+//!
+//! | offset | size | contents                                    |
+//! |--------|------|---------------------------------------------|
+//! | 0x00   | 4    | magic `RBEE`                                |
+//! | 0x04   | 4    | sequence number, one more than the last     |
+//! | 0x08   | 8    | programmed to zero once the copy finished   |
+//!
+//! followed by records:
+//!
+//! | offset | size | contents                                    |
+//! |--------|------|---------------------------------------------|
+//! | 0x00   | 2    | virtual address                             |
+//! | 0x02   | 2    | low half of the CRC-32 of bytes 0-1 and 4-7 |
+//! | 0x04   | 4    | value                                       |
+//!
+//! A record is a single doubleword, so a reset leaves it either written,
+//! missing or failing its check. [`Eeprom::mount`] rolls back a page copy
+//! that did not finish, so the last `write` that returned is always kept.
+
+use core::convert::TryFrom;
+
+use flash_protocol::crc32_update;
+
+use crate::atsam4l::atsam4lc8c_constants::FLASH_PAGE_SIZE;
+use crate::atsam4l::partition::Partition;
+use crate::atsam4l::{FlashError, FlashWriterEraser, Hflashc, TraceSink};
+
+const PAGE: usize = FLASH_PAGE_SIZE as usize;
+const MAGIC: &[u8; 4] = b"RBEE";
+const PAGE_HEADER: usize = 16;
+const RECORD: usize = 8;
+
+/// Largest virtual address space: every word fits in one page, with room
+/// left for one more write.
+pub const MAX_WORDS: u16 = ((PAGE - PAGE_HEADER) / RECORD - 1) as u16;
+
+/// What an unwritten word reads as.
+pub const ERASED: u32 = 0xFFFF_FFFF;
+
+/// Why an EEPROM operation failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EepromError {
+    /// The partition has fewer than two pages.
+    TooFewPages,
+    /// The address space is empty or larger than [`MAX_WORDS`].
+    TooManyWords,
+    /// The address is outside the address space.
+    Address,
+    Flash(FlashError),
+}
+
+impl From<FlashError> for EepromError {
+    fn from(e: FlashError) -> Self {
+        EepromError::Flash(e)
+    }
+}
+
+fn check(address: u16, value: u32) -> u16 {
+    crc32_update(
+        crc32_update(0, &address.to_le_bytes()),
+        &value.to_le_bytes(),
+    ) as u16
+}
+
+/// Returns the address and value of the record at `offset`, or `None` at
+/// the end of the records.
+fn record_at(page: &[u8; PAGE], offset: usize) -> Option<(u16, u32)> {
+    if offset + RECORD > PAGE {
+        return None;
+    }
+    let r = &page[offset..offset + RECORD];
+    let address = u16::from_le_bytes([r[0], r[1]]);
+    let value = u32::from_le_bytes([r[4], r[5], r[6], r[7]]);
+    match u16::from_le_bytes([r[2], r[3]]) == check(address, value) {
+        true => Some((address, value)),
+        false => None,
+    }
+}
+
+/// Returns the number of valid records in a page.
+fn record_count(page: &[u8; PAGE]) -> usize {
+    (PAGE_HEADER..PAGE)
+        .step_by(RECORD)
+        .take_while(|offset| record_at(page, *offset).is_some())
+        .count()
+}
+
+/// Emulated EEPROM of 32-bit words over the pages of a partition.
+pub struct Eeprom<'a, H, T> {
+    flash: &'a FlashWriterEraser<H, T>,
+    partition: Partition,
+    pages: u32,
+    words: u16,
+    /// Page holding the current values.
+    active: u32,
+    seq: u32,
+    /// Where the next record goes in the active page.
+    offset: usize,
+}
+
+impl<'a, H: Hflashc, T: TraceSink> Eeprom<'a, H, T> {
+    /// This method is used to open the emulated EEPROM, formatting an
+    /// erased partition
+    ///
+    /// A page copy cut short by a reset is started again from the page it
+    /// was copying.
+    ///
+    /// Method arguments:
+    /// -   flash: the flash driver
+    /// -   partition: the pages to use, at least two
+    /// -   words: size of the virtual address space, up to [`MAX_WORDS`]
+    ///
+    /// Returns:
+    /// -  the EEPROM, ready to read and write
+    pub fn mount(
+        flash: &'a FlashWriterEraser<H, T>,
+        partition: Partition,
+        words: u16,
+    ) -> Result<Self, EepromError> {
+        let pages = partition.page_count();
+        if pages < 2 {
+            return Err(EepromError::TooFewPages);
+        }
+        if words == 0 || words > MAX_WORDS {
+            return Err(EepromError::TooManyWords);
+        }
+        let mut eeprom = Eeprom {
+            flash,
+            partition,
+            pages,
+            words,
+            active: 0,
+            seq: 0,
+            offset: PAGE_HEADER,
+        };
+
+        // the newest page, and the newest one that finished its copy
+        let mut newest: Option<(u32, u32, bool)> = None;
+        let mut valid: Option<(u32, u32)> = None;
+        for page in 0..pages {
+            if let Some((seq, done)) = eeprom.page_header(page)? {
+                if newest.is_none_or(|(_, s, _)| seq > s) {
+                    newest = Some((page, seq, done));
+                }
+                if done && valid.is_none_or(|(_, s)| seq > s) {
+                    valid = Some((page, seq));
+                }
+            }
+        }
+
+        match (newest, valid) {
+            (Some((page, seq, true)), _) => {
+                eeprom.active = page;
+                eeprom.seq = seq;
+                eeprom.erase_others()?;
+                let mut buf = [0u8; PAGE];
+                eeprom.read_page(page, &mut buf)?;
+                let end = PAGE_HEADER + record_count(&buf) * RECORD;
+                // a record cut short after the last one: the page is full
+                eeprom.offset = match buf[end..].iter().all(|b| *b == 0xFF) {
+                    true => end,
+                    false => PAGE,
+                };
+            }
+            (_, Some((page, seq))) => {
+                // the copy out of `page` did not finish: do it again
+                eeprom.active = page;
+                eeprom.seq = seq;
+                let next = eeprom.next();
+                eeprom.erase_page(next)?;
+                eeprom.copy()?;
+                eeprom.erase_others()?;
+            }
+            (_, None) => {
+                eeprom.erase_others()?;
+                eeprom.erase_page(0)?;
+                eeprom.open(0, 1)?;
+                eeprom.mark_done(0)?;
+            }
+        }
+        Ok(eeprom)
+    }
+
+    /// This method is used to read a word
+    ///
+    /// Method arguments:
+    /// -   address: virtual address of the word
+    ///
+    /// Returns:
+    /// -  the word last written, or [`ERASED`] if it never was
+    pub fn read(&self, address: u16) -> Result<u32, EepromError> {
+        self.check_address(address)?;
+        let mut buf = [0u8; PAGE];
+        self.read_page(self.active, &mut buf)?;
+        Ok(latest(&buf, address).unwrap_or(ERASED))
+    }
+
+    /// This method is used to write a word
+    ///
+    /// Writing the value a word already holds costs nothing.
+    ///
+    /// Method arguments:
+    /// -   address: virtual address of the word
+    /// -   value: the new contents
+    ///
+    /// Returns:
+    /// -  `Ok` once the word is in flash; it survives a reset from then on
+    pub fn write(&mut self, address: u16, value: u32) -> Result<(), EepromError> {
+        if self.read(address)? == value {
+            return Ok(());
+        }
+        if self.offset + RECORD > PAGE {
+            self.copy()?;
+            let old = (self.active + self.pages - 1) % self.pages;
+            self.erase_page(old)?;
+        }
+        Ok(self.program_record(address, value)?)
+    }
+
+    /// This method is used to read a byte, for code that addresses the
+    /// EEPROM by byte
+    ///
+    /// Method arguments:
+    /// -   address: byte address, four bytes to a word, little-endian
+    ///
+    /// Returns:
+    /// -  the byte
+    pub fn read_byte(&self, address: u32) -> Result<u8, EepromError> {
+        let word = self.read(word_address(address)?)?;
+        Ok(word.to_le_bytes()[(address & 3) as usize])
+    }
+
+    /// This method is used to write a byte, for code that addresses the
+    /// EEPROM by byte
+    ///
+    /// Method arguments:
+    /// -   address: byte address, four bytes to a word, little-endian
+    /// -   value: the new contents
+    ///
+    /// Returns:
+    /// -  `Ok` once the byte is in flash
+    pub fn write_byte(&mut self, address: u32, value: u8) -> Result<(), EepromError> {
+        let word_address = word_address(address)?;
+        let mut bytes = self.read(word_address)?.to_le_bytes();
+        bytes[(address & 3) as usize] = value;
+        self.write(word_address, u32::from_le_bytes(bytes))
+    }
+
+    fn check_address(&self, address: u16) -> Result<(), EepromError> {
+        match address < self.words {
+            true => Ok(()),
+            false => Err(EepromError::Address),
+        }
+    }
+
+    /// Returns the page after the active one.
+    fn next(&self) -> u32 {
+        (self.active + 1) % self.pages
+    }
+
+    fn read_page(&self, page: u32, buf: &mut [u8; PAGE]) -> Result<(), FlashError> {
+        self.partition.read(self.flash, page * FLASH_PAGE_SIZE, buf)
+    }
+
+    fn erase_page(&self, page: u32) -> Result<(), FlashError> {
+        let offset = page * FLASH_PAGE_SIZE;
+        match self.partition.is_blank(self.flash, offset)? {
+            true => Ok(()),
+            false => self.partition.erase(self.flash, offset, FLASH_PAGE_SIZE),
+        }
+    }
+
+    /// Erases every page but the active one.
+    fn erase_others(&self) -> Result<(), FlashError> {
+        for page in (0..self.pages).filter(|p| *p != self.active) {
+            self.erase_page(page)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the sequence number of a page in use and whether its copy
+    /// finished, or `None` for an erased or damaged page.
+    fn page_header(&self, page: u32) -> Result<Option<(u32, bool)>, FlashError> {
+        let offset = page * FLASH_PAGE_SIZE;
+        if self.partition.is_blank(self.flash, offset)? {
+            return Ok(None);
+        }
+        let mut h = [0u8; PAGE_HEADER];
+        self.partition.read(self.flash, offset, &mut h)?;
+        if &h[..4] != MAGIC {
+            return Ok(None);
+        }
+        let seq = u32::from_le_bytes([h[4], h[5], h[6], h[7]]);
+        Ok(Some((seq, h[8..16] == [0; 8])))
+    }
+
+    /// Writes the header of a new active page.
+    fn open(&mut self, page: u32, seq: u32) -> Result<(), FlashError> {
+        let mut h = [0xFFu8; 8];
+        h[..4].copy_from_slice(MAGIC);
+        h[4..].copy_from_slice(&seq.to_le_bytes());
+        self.partition
+            .program(self.flash, page * FLASH_PAGE_SIZE, &h)?;
+        self.active = page;
+        self.seq = seq;
+        self.offset = PAGE_HEADER;
+        Ok(())
+    }
+
+    fn mark_done(&self, page: u32) -> Result<(), FlashError> {
+        self.partition
+            .program(self.flash, page * FLASH_PAGE_SIZE + 8, &[0; 8])
+    }
+
+    /// Copies the current values to the next page, which must be erased,
+    /// and makes it the active page. The old page is left for the caller
+    /// to erase.
+    fn copy(&mut self) -> Result<(), FlashError> {
+        let mut old = [0u8; PAGE];
+        self.read_page(self.active, &mut old)?;
+        let next = self.next();
+        self.open(next, self.seq + 1)?;
+        for address in 0..self.words {
+            if let Some(value) = latest(&old, address) {
+                self.program_record(address, value)?;
+            }
+        }
+        self.mark_done(next)
+    }
+
+    fn program_record(&mut self, address: u16, value: u32) -> Result<(), FlashError> {
+        let mut record = [0u8; RECORD];
+        record[0..2].copy_from_slice(&address.to_le_bytes());
+        record[2..4].copy_from_slice(&check(address, value).to_le_bytes());
+        record[4..8].copy_from_slice(&value.to_le_bytes());
+        self.partition.program(
+            self.flash,
+            self.active * FLASH_PAGE_SIZE + self.offset as u32,
+            &record,
+        )?;
+        self.offset += RECORD;
+        Ok(())
+    }
+}
+
+/// Returns the newest value for `address` in a page.
+fn latest(page: &[u8; PAGE], address: u16) -> Option<u32> {
+    (PAGE_HEADER..PAGE)
+        .step_by(RECORD)
+        .map_while(|offset| record_at(page, offset))
+        .filter(|(a, _)| *a == address)
+        .map(|(_, value)| value)
+        .last()
+}
+
+fn word_address(address: u32) -> Result<u16, EepromError> {
+    u16::try_from(address >> 2).map_err(|_| EepromError::Address)
+}
@@ -0,0 +1,136 @@
+//! Streaming Intel HEX and Motorola S-record loaders.
+//!
+//! Both loaders take the file in pieces of any size, as they arrive from a
+//! serial line or a file system, and write the data through a
+//! [`PageWriter`](crate::writer::PageWriter). Records are decoded as soon as
+//! their line ends, so only one record is ever held in RAM. Addresses in
+//! the file are absolute flash addresses and must fall inside the writer's
+//! partition.
+
+pub mod ihex;
+pub mod srec;
+pub use ihex::IhexLoader;
+pub use srec::SrecLoader;
+
+use crate::atsam4l::FlashError;
+
+/// Longest record: a count byte plus 255 bytes of address, data and
+/// checksum.
+const MAX_RECORD: usize = 1 + 255 + 5;
+
+/// Why a file was rejected. `line` counts from 1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoadError {
+    /// A character that does not belong in a record.
+    BadChar { line: u32 },
+    /// The record checksum does not match.
+    Checksum { line: u32 },
+    /// The byte count does not match the record, or the record type
+    /// requires another length.
+    Length { line: u32 },
+    /// A record type the format does not define.
+    UnknownRecord { line: u32, kind: u8 },
+    /// Data lies outside the partition being written.
+    Address { line: u32, address: u32 },
+    /// The file ended without an end-of-file record.
+    Truncated,
+    /// The data could not be written.
+    Flash(FlashError),
+}
+
+/// Whether a loader has seen the end of the file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Progress {
+    /// More records are expected.
+    NeedMore,
+    /// The end-of-file record has been processed; anything after it is
+    /// ignored.
+    Done {
+        /// Start address from the file, if it gave one.
+        entry: Option<u32>,
+    },
+}
+
+/// Turns a stream of characters into record bytes, one line at a time.
+struct LineDecoder {
+    bytes: [u8; MAX_RECORD],
+    len: usize,
+    /// Upper nibble of a byte whose lower nibble has not arrived.
+    nibble: Option<u8>,
+    /// A record start marker has been seen on the current line.
+    started: bool,
+    line: u32,
+}
+
+/// What one character did to the current line.
+enum Step {
+    Pending,
+    /// The line ended; `len` bytes of record were decoded.
+    Record,
+    Blank,
+}
+
+impl LineDecoder {
+    const fn new() -> Self {
+        LineDecoder {
+            bytes: [0; MAX_RECORD],
+            len: 0,
+            nibble: None,
+            started: false,
+            line: 1,
+        }
+    }
+
+    /// Feeds one character after the record's start marker.
+    fn push(&mut self, c: u8) -> Result<Step, LoadError> {
+        let line = self.line;
+        if c == b'\n' || c == b'\r' {
+            if c == b'\n' {
+                self.line += 1;
+            }
+            if !self.started {
+                return Ok(Step::Blank);
+            }
+            self.started = false;
+            if self.nibble.take().is_some() {
+                return Err(LoadError::Length { line });
+            }
+            return Ok(Step::Record);
+        }
+        let value = match c {
+            b'0'..=b'9' => c - b'0',
+            b'a'..=b'f' => c - b'a' + 10,
+            b'A'..=b'F' => c - b'A' + 10,
+            _ => return Err(LoadError::BadChar { line }),
+        };
+        match self.nibble.take() {
+            None => self.nibble = Some(value),
+            Some(high) => {
+                if self.len == self.bytes.len() {
+                    return Err(LoadError::Length { line });
+                }
+                self.bytes[self.len] = high << 4 | value;
+                self.len += 1;
+            }
+        }
+        Ok(Step::Pending)
+    }
+
+    /// Starts a new record.
+    fn start(&mut self) {
+        self.len = 0;
+        self.nibble = None;
+        self.started = true;
+    }
+
+    fn record(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+fn address_error(line: u32, address: u32) -> impl FnOnce(FlashError) -> LoadError {
+    move |e| match e {
+        FlashError::OutOfBounds => LoadError::Address { line, address },
+        e => LoadError::Flash(e),
+    }
+}
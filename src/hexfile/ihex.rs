@@ -0,0 +1,159 @@
+//! Intel HEX.
+//!
+//! Supports data records and both the extended segment (type 02) and
+//! extended linear (type 04) address records, so files for any part of the
+//! 32-bit address space load correctly. Start address records are reported
+//! through [`Progress::Done`].
+
+use super::{address_error, LineDecoder, LoadError, Progress, Step};
+use crate::atsam4l::{Hflashc, TraceSink};
+use crate::writer::PageWriter;
+
+const DATA: u8 = 0x00;
+const END_OF_FILE: u8 = 0x01;
+const EXTENDED_SEGMENT: u8 = 0x02;
+const START_SEGMENT: u8 = 0x03;
+const EXTENDED_LINEAR: u8 = 0x04;
+const START_LINEAR: u8 = 0x05;
+
+/// Incremental Intel HEX loader.
+pub struct IhexLoader {
+    decoder: LineDecoder,
+    /// Added to the 16-bit offset of each data record.
+    base: u32,
+    entry: Option<u32>,
+    done: bool,
+}
+
+impl Default for IhexLoader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IhexLoader {
+    pub const fn new() -> Self {
+        IhexLoader {
+            decoder: LineDecoder::new(),
+            base: 0,
+            entry: None,
+            done: false,
+        }
+    }
+
+    /// This method is used to feed the next piece of the file
+    ///
+    /// Method arguments:
+    /// -   text: any number of characters, split anywhere
+    /// -   writer: receives the data records
+    ///
+    /// Returns:
+    /// -  `Progress::Done` once the end-of-file record has been seen
+    pub fn feed<H: Hflashc, T: TraceSink>(
+        &mut self,
+        text: &[u8],
+        writer: &mut PageWriter<H, T>,
+    ) -> Result<Progress, LoadError> {
+        for c in text {
+            if self.done {
+                break;
+            }
+            if !self.decoder.started && *c == b':' {
+                self.decoder.start();
+                continue;
+            }
+            if !self.decoder.started && !matches!(c, b'\r' | b'\n') {
+                return Err(LoadError::BadChar {
+                    line: self.decoder.line,
+                });
+            }
+            let line = self.decoder.line;
+            if let Step::Record = self.decoder.push(*c)? {
+                self.record(line, writer)?;
+            }
+        }
+        Ok(self.progress())
+    }
+
+    /// This method is used once the whole file has been fed
+    ///
+    /// Method arguments:
+    /// -   writer: flushed so flash holds every record
+    ///
+    /// Returns:
+    /// -  `LoadError::Truncated` if there was no end-of-file record
+    pub fn finish<H: Hflashc, T: TraceSink>(
+        &mut self,
+        writer: &mut PageWriter<H, T>,
+    ) -> Result<Option<u32>, LoadError> {
+        if !self.done {
+            // a last record without a line ending
+            if self.decoder.started {
+                let line = self.decoder.line;
+                self.decoder.push(b'\n')?;
+                self.record(line, writer)?;
+            }
+            if !self.done {
+                return Err(LoadError::Truncated);
+            }
+        }
+        writer.flush().map_err(LoadError::Flash)?;
+        Ok(self.entry)
+    }
+
+    fn progress(&self) -> Progress {
+        if self.done {
+            Progress::Done { entry: self.entry }
+        } else {
+            Progress::NeedMore
+        }
+    }
+
+    fn record<H: Hflashc, T: TraceSink>(
+        &mut self,
+        line: u32,
+        writer: &mut PageWriter<H, T>,
+    ) -> Result<(), LoadError> {
+        let record = self.decoder.record();
+        // count, offset (2), type, data, checksum
+        if record.len() < 5 || record[0] as usize != record.len() - 5 {
+            return Err(LoadError::Length { line });
+        }
+        if record.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+            return Err(LoadError::Checksum { line });
+        }
+        let offset = u16::from_be_bytes([record[1], record[2]]) as u32;
+        let kind = record[3];
+        let data = &record[4..record.len() - 1];
+        let word = |len: usize| -> Result<u32, LoadError> {
+            if data.len() != len {
+                return Err(LoadError::Length { line });
+            }
+            Ok(data.iter().fold(0u32, |acc, b| acc << 8 | *b as u32))
+        };
+
+        match kind {
+            // an empty data record writes nothing, wherever it points
+            DATA if data.is_empty() => {}
+            DATA => {
+                let address = self.base.wrapping_add(offset);
+                writer
+                    .write_at(address, data)
+                    .map_err(address_error(line, address))?;
+            }
+            END_OF_FILE => {
+                word(0)?;
+                self.done = true;
+            }
+            EXTENDED_SEGMENT => self.base = word(2)? << 4,
+            EXTENDED_LINEAR => self.base = word(2)? << 16,
+            START_SEGMENT => {
+                let cs_ip = word(4)?;
+                self.entry = Some((cs_ip >> 16) * 16 + (cs_ip & 0xFFFF));
+            }
+            START_LINEAR => self.entry = Some(word(4)?),
+            kind => return Err(LoadError::UnknownRecord { line, kind }),
+        }
+        Ok(())
+    }
+}
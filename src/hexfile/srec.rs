@@ -0,0 +1,170 @@
+//! Motorola S-records.
+//!
+//! Supports S1/S2/S3 data records with 16, 24 and 32-bit addresses, the
+//! S5/S6 record counts, which are checked against the data records seen,
+//! and the S7/S8/S9 termination records with their start address. The S0
+//! header is checked and otherwise ignored.
+
+use super::{address_error, LineDecoder, LoadError, Progress, Step};
+use crate::atsam4l::{Hflashc, TraceSink};
+use crate::writer::PageWriter;
+
+/// Incremental S-record loader.
+pub struct SrecLoader {
+    decoder: LineDecoder,
+    /// Record type digit of the current line, once `S` has been seen.
+    kind: Option<u8>,
+    /// Waiting for the type digit that follows `S`.
+    want_kind: bool,
+    data_records: u32,
+    entry: Option<u32>,
+    done: bool,
+}
+
+impl Default for SrecLoader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SrecLoader {
+    pub const fn new() -> Self {
+        SrecLoader {
+            decoder: LineDecoder::new(),
+            kind: None,
+            want_kind: false,
+            data_records: 0,
+            entry: None,
+            done: false,
+        }
+    }
+
+    /// This method is used to feed the next piece of the file
+    ///
+    /// Method arguments:
+    /// -   text: any number of characters, split anywhere
+    /// -   writer: receives the data records
+    ///
+    /// Returns:
+    /// -  `Progress::Done` once a termination record has been seen
+    pub fn feed<H: Hflashc, T: TraceSink>(
+        &mut self,
+        text: &[u8],
+        writer: &mut PageWriter<H, T>,
+    ) -> Result<Progress, LoadError> {
+        for c in text {
+            if self.done {
+                break;
+            }
+            let line = self.decoder.line;
+            if self.want_kind {
+                self.want_kind = false;
+                match c {
+                    b'0'..=b'9' => self.kind = Some(c - b'0'),
+                    _ => return Err(LoadError::BadChar { line }),
+                }
+                self.decoder.start();
+                continue;
+            }
+            if !self.decoder.started {
+                match c {
+                    b'S' => self.want_kind = true,
+                    b'\r' | b'\n' => {
+                        self.decoder.push(*c)?;
+                    }
+                    _ => return Err(LoadError::BadChar { line }),
+                }
+                continue;
+            }
+            if let Step::Record = self.decoder.push(*c)? {
+                self.record(line, writer)?;
+            }
+        }
+        Ok(if self.done {
+            Progress::Done { entry: self.entry }
+        } else {
+            Progress::NeedMore
+        })
+    }
+
+    /// This method is used once the whole file has been fed
+    ///
+    /// Method arguments:
+    /// -   writer: flushed so flash holds every record
+    ///
+    /// Returns:
+    /// -  the start address, or `LoadError::Truncated` if there was no
+    ///    termination record
+    pub fn finish<H: Hflashc, T: TraceSink>(
+        &mut self,
+        writer: &mut PageWriter<H, T>,
+    ) -> Result<Option<u32>, LoadError> {
+        if !self.done && self.decoder.started {
+            let line = self.decoder.line;
+            self.decoder.push(b'\n')?;
+            self.record(line, writer)?;
+        }
+        if !self.done {
+            return Err(LoadError::Truncated);
+        }
+        writer.flush().map_err(LoadError::Flash)?;
+        Ok(self.entry)
+    }
+
+    fn record<H: Hflashc, T: TraceSink>(
+        &mut self,
+        line: u32,
+        writer: &mut PageWriter<H, T>,
+    ) -> Result<(), LoadError> {
+        let kind = self.kind.take().unwrap_or(0);
+        let record = self.decoder.record();
+        // count, address, data, checksum
+        if record.len() < 2 || record[0] as usize != record.len() - 1 {
+            return Err(LoadError::Length { line });
+        }
+        let sum = record[..record.len() - 1]
+            .iter()
+            .fold(0u8, |sum, b| sum.wrapping_add(*b));
+        if !sum != record[record.len() - 1] {
+            return Err(LoadError::Checksum { line });
+        }
+        let address_len = match kind {
+            0 | 1 | 5 | 9 => 2,
+            2 | 6 | 8 => 3,
+            3 | 7 => 4,
+            kind => return Err(LoadError::UnknownRecord { line, kind }),
+        };
+        let body = &record[1..record.len() - 1];
+        if body.len() < address_len {
+            return Err(LoadError::Length { line });
+        }
+        let (address, data) = body.split_at(address_len);
+        let address = address.iter().fold(0u32, |acc, b| acc << 8 | *b as u32);
+
+        match kind {
+            0 => {}
+            1..=3 => {
+                // an empty data record writes nothing but still counts
+                if !data.is_empty() {
+                    writer
+                        .write_at(address, data)
+                        .map_err(address_error(line, address))?;
+                }
+                self.data_records += 1;
+            }
+            5 | 6 => {
+                if !data.is_empty() || address != self.data_records {
+                    return Err(LoadError::Length { line });
+                }
+            }
+            _ => {
+                if !data.is_empty() {
+                    return Err(LoadError::Length { line });
+                }
+                self.entry = Some(address);
+                self.done = true;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,351 @@
+//! Firmware image header.
+//!
+//! Every image in the BOOT and UPDATE partitions starts with a
+//! `RB_HDR_SIZE` (256) byte header, followed by the firmware with its vector
+//! table. All fields are little endian:
+//!
+//! | offset | size | field                                           |
+//! |--------|------|-------------------------------------------------|
+//! | 0x00   | 4    | magic, `b"RB4L"`                                |
+//! | 0x04   | 2    | header version, currently 1                     |
+//! | 0x06   | 2    | header size, always 0x100                       |
+//! | 0x08   | 4    | image size: firmware bytes after the header     |
+//! | 0x0C   | 4    | firmware version                                |
+//! | 0x10   | 8    | build timestamp, seconds since the Unix epoch   |
+//! | 0x18   | 2    | hash type, see [`HashType`]                     |
+//! | 0x1A   | 2    | reserved, 0xFFFF                                |
+//! | 0x1C   | 228  | TLVs                                            |
+//!
+//! Each TLV is a `u16` tag and a `u16` length followed by the value, and the
+//! next TLV starts at the following 4-byte boundary. The list ends at the
+//! end of the header or at a tag of [`TLV_END`] or 0xFFFF (erased flash).
+//! A [`TLV_DIGEST`] entry is mandatory; it holds the hash of the first
+//! `TLV_OFFSET` bytes of the header followed by the firmware, so the fixed
+//! fields are covered but the TLVs themselves, signatures included, are
+//! not.
+
+pub mod verify;
+pub use verify::{image_digest, VerifyError};
+#[cfg(any(feature = "ecdsa-p256", feature = "ed25519"))]
+pub use verify::{verify_image, PublicKey};
+
+use crate::atsam4l::atsam4lc8c_constants::RB_HDR_SIZE;
+use crate::atsam4l::partition::Partition;
+use crate::atsam4l::{FlashError, FlashWriterEraser, Hflashc, TraceSink};
+
+pub const HEADER_MAGIC: [u8; 4] = *b"RB4L";
+pub const HEADER_VERSION: u16 = 1;
+pub const HEADER_SIZE: usize = RB_HDR_SIZE as usize;
+/// Offset of the first TLV, and the number of header bytes covered by the
+/// digest.
+pub const TLV_OFFSET: usize = 0x1C;
+
+/// Marks the end of the TLV list.
+pub const TLV_END: u16 = 0x0000;
+/// Digest of the image, in the format given by the hash type.
+pub const TLV_DIGEST: u16 = 0x0001;
+/// SHA-256 of the public key the image was signed with.
+pub const TLV_PUBKEY_HINT: u16 = 0x0010;
+/// ECDSA P-256 signature of the digest, `r || s`.
+pub const TLV_SIGNATURE_P256: u16 = 0x0020;
+/// Ed25519 signature of the digest.
+pub const TLV_SIGNATURE_ED25519: u16 = 0x0021;
+
+/// The hash algorithm used for the digest TLV.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HashType {
+    Sha256,
+}
+
+impl HashType {
+    pub fn from_u16(value: u16) -> Option<Self> {
+        match value {
+            1 => Some(HashType::Sha256),
+            _ => None,
+        }
+    }
+
+    pub fn to_u16(self) -> u16 {
+        match self {
+            HashType::Sha256 => 1,
+        }
+    }
+
+    /// Returns the length of a digest of this type.
+    pub fn digest_len(self) -> usize {
+        match self {
+            HashType::Sha256 => 32,
+        }
+    }
+}
+
+/// Why a header was rejected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HeaderError {
+    /// The first four bytes are not [`HEADER_MAGIC`].
+    BadMagic([u8; 4]),
+    /// The header version is not [`HEADER_VERSION`].
+    UnsupportedVersion(u16),
+    /// The header size field is not [`HEADER_SIZE`].
+    BadHeaderSize(u16),
+    /// The image is empty or too short to hold a vector table.
+    ImageTooSmall(u32),
+    /// The image does not fit in the partition after the header.
+    ImageTooLarge { size: u32, capacity: u32 },
+    /// The hash type is not one this bootloader knows.
+    UnknownHashType(u16),
+    /// The TLV at this offset runs past the end of the header.
+    TlvOverrun { offset: usize },
+    /// There is no digest TLV.
+    MissingDigest,
+    /// The digest TLV does not match the length of the hash type.
+    DigestLength { expected: usize, found: usize },
+    /// A TLV that may only appear once appears twice.
+    DuplicateTlv(u16),
+    /// There is no room left in the header for another TLV.
+    HeaderFull,
+    /// The header could not be read.
+    Flash(FlashError),
+}
+
+impl From<FlashError> for HeaderError {
+    fn from(e: FlashError) -> Self {
+        HeaderError::Flash(e)
+    }
+}
+
+/// One TLV entry, borrowing its value from the header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Tlv<'a> {
+    pub tag: u16,
+    pub value: &'a [u8],
+}
+
+/// Iterates over the TLVs of a header.
+///
+/// Stops at the end marker; yields an error and then stops if an entry is
+/// malformed.
+#[derive(Clone)]
+pub struct Tlvs<'a> {
+    bytes: &'a [u8; HEADER_SIZE],
+    offset: usize,
+}
+
+impl<'a> Iterator for Tlvs<'a> {
+    type Item = Result<Tlv<'a>, HeaderError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let offset = self.offset;
+        if offset + 4 > HEADER_SIZE {
+            return None;
+        }
+        let tag = u16_at(self.bytes, offset);
+        if tag == TLV_END || tag == 0xFFFF {
+            return None;
+        }
+        let len = u16_at(self.bytes, offset + 2) as usize;
+        let end = offset + 4 + len;
+        if end > HEADER_SIZE {
+            self.offset = HEADER_SIZE;
+            return Some(Err(HeaderError::TlvOverrun { offset }));
+        }
+        self.offset = (end + 3) & !3;
+        Some(Ok(Tlv {
+            tag,
+            value: &self.bytes[offset + 4..end],
+        }))
+    }
+}
+
+/// A validated header, borrowing the bytes it was parsed from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ImageHeader<'a> {
+    bytes: &'a [u8; HEADER_SIZE],
+    hash_type: HashType,
+    digest: &'a [u8],
+}
+
+impl<'a> ImageHeader<'a> {
+    /// This method is used to parse and validate a header
+    ///
+    /// Method arguments:
+    /// -   bytes: the first `HEADER_SIZE` bytes of a partition
+    /// -   capacity: bytes available for the firmware after the header
+    ///
+    /// Returns:
+    /// -  the header, or the first problem found
+    pub fn parse(bytes: &'a [u8; HEADER_SIZE], capacity: u32) -> Result<Self, HeaderError> {
+        let magic = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        if magic != HEADER_MAGIC {
+            return Err(HeaderError::BadMagic(magic));
+        }
+        let version = u16_at(bytes, 0x04);
+        if version != HEADER_VERSION {
+            return Err(HeaderError::UnsupportedVersion(version));
+        }
+        let header_size = u16_at(bytes, 0x06);
+        if header_size as usize != HEADER_SIZE {
+            return Err(HeaderError::BadHeaderSize(header_size));
+        }
+        let size = u32_at(bytes, 0x08);
+        if size < 8 {
+            return Err(HeaderError::ImageTooSmall(size));
+        }
+        if size > capacity {
+            return Err(HeaderError::ImageTooLarge { size, capacity });
+        }
+        let hash = u16_at(bytes, 0x18);
+        let hash_type = HashType::from_u16(hash).ok_or(HeaderError::UnknownHashType(hash))?;
+
+        let mut digest = None;
+        for tlv in (Tlvs {
+            bytes,
+            offset: TLV_OFFSET,
+        }) {
+            let tlv = tlv?;
+            if tlv.tag == TLV_DIGEST {
+                if digest.is_some() {
+                    return Err(HeaderError::DuplicateTlv(TLV_DIGEST));
+                }
+                if tlv.value.len() != hash_type.digest_len() {
+                    return Err(HeaderError::DigestLength {
+                        expected: hash_type.digest_len(),
+                        found: tlv.value.len(),
+                    });
+                }
+                digest = Some(tlv.value);
+            }
+        }
+
+        Ok(ImageHeader {
+            bytes,
+            hash_type,
+            digest: digest.ok_or(HeaderError::MissingDigest)?,
+        })
+    }
+
+    /// Returns the number of firmware bytes after the header.
+    pub fn image_size(&self) -> u32 {
+        u32_at(self.bytes, 0x08)
+    }
+
+    pub fn firmware_version(&self) -> u32 {
+        u32_at(self.bytes, 0x0C)
+    }
+
+    /// Returns the build time in seconds since the Unix epoch.
+    pub fn timestamp(&self) -> u64 {
+        u32_at(self.bytes, 0x10) as u64 | (u32_at(self.bytes, 0x14) as u64) << 32
+    }
+
+    pub fn hash_type(&self) -> HashType {
+        self.hash_type
+    }
+
+    pub fn digest(&self) -> &'a [u8] {
+        self.digest
+    }
+
+    /// Returns the header bytes covered by the digest.
+    pub fn signed_fields(&self) -> &'a [u8] {
+        &self.bytes[..TLV_OFFSET]
+    }
+
+    /// Returns the value of the first TLV with the given tag.
+    pub fn tlv(&self, tag: u16) -> Option<&'a [u8]> {
+        self.tlvs()
+            .filter_map(Result::ok)
+            .find(|tlv| tlv.tag == tag)
+            .map(|tlv| tlv.value)
+    }
+
+    pub fn tlvs(&self) -> Tlvs<'a> {
+        Tlvs {
+            bytes: self.bytes,
+            offset: TLV_OFFSET,
+        }
+    }
+}
+
+/// This method is used to read and validate the header of the image in a
+/// partition
+///
+/// Method arguments:
+/// -   flash: driver used to read the header
+/// -   partition: BOOT or UPDATE
+/// -   buf: receives the raw header, which the result borrows
+///
+/// Returns:
+/// -  the header, or why it is not valid
+pub fn read_header<'b, H: Hflashc, T: TraceSink>(
+    flash: &FlashWriterEraser<H, T>,
+    partition: &Partition,
+    buf: &'b mut [u8; HEADER_SIZE],
+) -> Result<ImageHeader<'b>, HeaderError> {
+    partition.read(flash, 0, buf)?;
+    ImageHeader::parse(buf, partition.size() - RB_HDR_SIZE)
+}
+
+/// Builds a header, used by tests and the host packaging tool.
+pub struct HeaderBuilder {
+    bytes: [u8; HEADER_SIZE],
+    offset: usize,
+}
+
+impl HeaderBuilder {
+    pub fn new(
+        image_size: u32,
+        firmware_version: u32,
+        timestamp: u64,
+        hash_type: HashType,
+    ) -> Self {
+        let mut bytes = [0xFF; HEADER_SIZE];
+        bytes[0x00..0x04].copy_from_slice(&HEADER_MAGIC);
+        bytes[0x04..0x06].copy_from_slice(&HEADER_VERSION.to_le_bytes());
+        bytes[0x06..0x08].copy_from_slice(&(HEADER_SIZE as u16).to_le_bytes());
+        bytes[0x08..0x0C].copy_from_slice(&image_size.to_le_bytes());
+        bytes[0x0C..0x10].copy_from_slice(&firmware_version.to_le_bytes());
+        bytes[0x10..0x18].copy_from_slice(&timestamp.to_le_bytes());
+        bytes[0x18..0x1A].copy_from_slice(&hash_type.to_u16().to_le_bytes());
+        HeaderBuilder {
+            bytes,
+            offset: TLV_OFFSET,
+        }
+    }
+
+    /// Returns the header bytes covered by the digest.
+    pub fn signed_fields(&self) -> &[u8] {
+        &self.bytes[..TLV_OFFSET]
+    }
+
+    /// Appends a TLV.
+    pub fn push_tlv(&mut self, tag: u16, value: &[u8]) -> Result<(), HeaderError> {
+        let end = self.offset + 4 + value.len();
+        if end > HEADER_SIZE || value.len() > u16::MAX as usize {
+            return Err(HeaderError::HeaderFull);
+        }
+        let offset = self.offset;
+        self.bytes[offset..offset + 2].copy_from_slice(&tag.to_le_bytes());
+        self.bytes[offset + 2..offset + 4].copy_from_slice(&(value.len() as u16).to_le_bytes());
+        self.bytes[offset + 4..end].copy_from_slice(value);
+        self.offset = (end + 3) & !3;
+        Ok(())
+    }
+
+    pub fn finish(self) -> [u8; HEADER_SIZE] {
+        self.bytes
+    }
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
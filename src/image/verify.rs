@@ -0,0 +1,168 @@
+//! Image digest and signature verification.
+//!
+//! The digest is SHA-256 over the signed header fields followed by the
+//! firmware, read from flash `HASH_CHUNK` bytes at a time. The signature
+//! TLV signs that digest: Ed25519 uses the 32 digest bytes as the message,
+//! ECDSA P-256 uses them as the prehashed message.
+//!
+//! Each scheme is compiled in only with its cargo feature, `ed25519` or
+//! `ecdsa-p256`, so the bootloader carries only the code it needs. Without
+//! either feature only [`image_digest`] is available.
+
+use sha2::{Digest, Sha256};
+
+use super::{HashType, ImageHeader};
+use crate::atsam4l::atsam4lc8c_constants::RB_HDR_SIZE;
+use crate::atsam4l::partition::Partition;
+use crate::atsam4l::{FlashError, FlashWriterEraser, Hflashc, TraceSink};
+
+#[cfg(feature = "ed25519")]
+use super::TLV_SIGNATURE_ED25519;
+#[cfg(feature = "ecdsa-p256")]
+use super::TLV_SIGNATURE_P256;
+
+/// Bytes read from flash per hash update.
+pub const HASH_CHUNK: usize = 256;
+
+/// Why an image failed verification.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VerifyError {
+    /// The image does not hash to the digest in its header.
+    DigestMismatch,
+    /// The header has no signature TLV for the key's scheme.
+    MissingSignature,
+    /// The public key is not a valid point.
+    BadKey,
+    /// The signature is malformed or does not verify.
+    BadSignature,
+    /// The image could not be read.
+    Flash(FlashError),
+}
+
+impl From<FlashError> for VerifyError {
+    fn from(e: FlashError) -> Self {
+        VerifyError::Flash(e)
+    }
+}
+
+/// A public key the bootloader trusts.
+#[cfg(any(feature = "ecdsa-p256", feature = "ed25519"))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PublicKey<'a> {
+    /// SEC1 encoded P-256 point, compressed or uncompressed.
+    #[cfg(feature = "ecdsa-p256")]
+    P256(&'a [u8]),
+    #[cfg(feature = "ed25519")]
+    Ed25519(&'a [u8; 32]),
+}
+
+#[cfg(any(feature = "ecdsa-p256", feature = "ed25519"))]
+impl PublicKey<'_> {
+    /// Returns the TLV tag of signatures made with this key.
+    pub fn signature_tlv(&self) -> u16 {
+        match self {
+            #[cfg(feature = "ecdsa-p256")]
+            PublicKey::P256(_) => TLV_SIGNATURE_P256,
+            #[cfg(feature = "ed25519")]
+            PublicKey::Ed25519(_) => TLV_SIGNATURE_ED25519,
+        }
+    }
+
+    /// This method is used to verify a signature over a digest
+    ///
+    /// Method arguments:
+    /// -   digest: the SHA-256 digest that was signed
+    /// -   signature: raw signature, 64 bytes for both schemes
+    ///
+    /// Returns:
+    /// -  `Ok` if the signature is valid for this key
+    pub fn verify(&self, digest: &[u8; 32], signature: &[u8]) -> Result<(), VerifyError> {
+        match self {
+            #[cfg(feature = "ecdsa-p256")]
+            PublicKey::P256(key) => {
+                use p256::ecdsa::signature::hazmat::PrehashVerifier;
+                use p256::ecdsa::{Signature, VerifyingKey};
+
+                let key = VerifyingKey::from_sec1_bytes(key).map_err(|_| VerifyError::BadKey)?;
+                let signature =
+                    Signature::from_slice(signature).map_err(|_| VerifyError::BadSignature)?;
+                key.verify_prehash(digest, &signature)
+                    .map_err(|_| VerifyError::BadSignature)
+            }
+            #[cfg(feature = "ed25519")]
+            PublicKey::Ed25519(key) => {
+                use ed25519_dalek::{Signature, VerifyingKey};
+
+                let key = VerifyingKey::from_bytes(key).map_err(|_| VerifyError::BadKey)?;
+                let signature =
+                    Signature::from_slice(signature).map_err(|_| VerifyError::BadSignature)?;
+                key.verify_strict(digest, &signature)
+                    .map_err(|_| VerifyError::BadSignature)
+            }
+        }
+    }
+}
+
+/// This method is used to hash an image in place
+///
+/// Method arguments:
+/// -   flash: driver used to read the image
+/// -   partition: the partition holding the image
+/// -   header: the parsed header of that image
+///
+/// Returns:
+/// -  SHA-256 of the signed header fields followed by the firmware
+pub fn image_digest<H: Hflashc, T: TraceSink>(
+    flash: &FlashWriterEraser<H, T>,
+    partition: &Partition,
+    header: &ImageHeader,
+) -> Result<[u8; 32], VerifyError> {
+    match header.hash_type() {
+        HashType::Sha256 => {}
+    }
+    let mut hasher = Sha256::new();
+    hasher.update(header.signed_fields());
+
+    let mut chunk = [0u8; HASH_CHUNK];
+    let mut offset = 0;
+    let size = header.image_size();
+    while offset < size {
+        let len = core::cmp::min(HASH_CHUNK as u32, size - offset) as usize;
+        partition.read(flash, RB_HDR_SIZE + offset, &mut chunk[..len])?;
+        hasher.update(&chunk[..len]);
+        offset += len as u32;
+    }
+    Ok(hasher.finalize().into())
+}
+
+/// This method is used to check the digest and signature of an image
+///
+/// Method arguments:
+/// -   flash: driver used to read the image
+/// -   partition: the partition holding the image
+/// -   header: the parsed header of that image
+/// -   key: the key the image must be signed with
+///
+/// Returns:
+/// -  `Ok` if the image is intact and signed by `key`
+#[cfg(any(feature = "ecdsa-p256", feature = "ed25519"))]
+pub fn verify_image<H: Hflashc, T: TraceSink>(
+    flash: &FlashWriterEraser<H, T>,
+    partition: &Partition,
+    header: &ImageHeader,
+    key: &PublicKey,
+) -> Result<(), VerifyError> {
+    let digest = image_digest(flash, partition, header)?;
+    // compare every byte so the time taken does not depend on the contents
+    let diff = digest
+        .iter()
+        .zip(header.digest())
+        .fold(0u8, |acc, (a, b)| acc | (a ^ b));
+    if diff != 0 {
+        return Err(VerifyError::DigestMismatch);
+    }
+    let signature = header
+        .tlv(key.signature_tlv())
+        .ok_or(VerifyError::MissingSignature)?;
+    key.verify(&digest, signature)
+}
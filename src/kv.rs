@@ -0,0 +1,480 @@
+//! Wear-levelled key-value store.
+//!
+//! Settings are appended as records to the pages of a partition, used as a
+//! ring, instead of rewriting a page for every change. A new value for a
+//! key is simply a newer record; reads return the newest one.
+//!
+//! The page after the one being written, the head, is always kept erased.
+//! When the head fills up, that spare page becomes the new head and the
+//! page after it, the oldest in the ring, is garbage collected: its records
+//! that are still the newest for their key are copied to the new head and
+//! the page is erased, becoming the next spare. Pages are therefore erased
+//! strictly in turn, which spreads wear evenly over the partition.
+//!
+//! Every page starts with a 16-byte header:
+//!
+//! | offset | size | contents                                         |
+//! |--------|------|--------------------------------------------------|
+//! | 0x00   | 4    | magic `RBKV`                                     |
+//! | 0x04   | 4    | sequence number, one more than the previous head |
+//! | 0x08   | 8    | programmed to zero once garbage collection ended |
+//!
+//! followed by records, each starting on a doubleword:
+//!
+//! | offset | size | contents                                         |
+//! |--------|------|--------------------------------------------------|
+//! | 0x00   | 1    | key length                                       |
+//! | 0x01   | 1    | [`KIND_VALUE`] or [`KIND_DELETED`]               |
+//! | 0x02   | 2    | value length                                     |
+//! | 0x04   | 4    | CRC-32 of the other header bytes, key and value  |
+//! | 0x08   | ...  | key, then value, padded with 0xFF                |
+//!
+//! A record is programmed with a single page write, so a reset leaves it
+//! either complete, missing or with a bad CRC. [`KvStore::mount`] works out
+//! from the headers where a reset interrupted a page switch and finishes or
+//! undoes it, so every `set` or `remove` that returned before the reset is
+//! kept.
+
+use flash_protocol::crc32_update;
+
+use crate::atsam4l::atsam4lc8c_constants::FLASH_PAGE_SIZE;
+use crate::atsam4l::partition::Partition;
+use crate::atsam4l::{FlashError, FlashWriterEraser, Hflashc, TraceSink};
+
+const PAGE: usize = FLASH_PAGE_SIZE as usize;
+const MAGIC: &[u8; 4] = b"RBKV";
+const PAGE_HEADER: u32 = 16;
+const RECORD_HEADER: usize = 8;
+
+pub const MAX_KEY_LEN: usize = 32;
+pub const MAX_VALUE_LEN: usize = 256;
+pub const KIND_VALUE: u8 = 0x01;
+pub const KIND_DELETED: u8 = 0x02;
+
+/// Largest record, rounded up to a doubleword.
+const MAX_RECORD: usize = (RECORD_HEADER + MAX_KEY_LEN + MAX_VALUE_LEN + 7) & !7;
+
+/// Why a store operation failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KvError {
+    /// The partition has fewer than two pages.
+    TooFewPages,
+    /// The key is empty or longer than [`MAX_KEY_LEN`].
+    BadKey,
+    /// The value is longer than [`MAX_VALUE_LEN`].
+    ValueTooLong,
+    /// The live data no longer fits next to a spare page.
+    Full,
+    /// The value is longer than the buffer given to `get`.
+    BufferTooSmall {
+        len: usize,
+    },
+    Flash(FlashError),
+}
+
+impl From<FlashError> for KvError {
+    fn from(e: FlashError) -> Self {
+        KvError::Flash(e)
+    }
+}
+
+/// A record found in a page.
+#[derive(Clone, Copy)]
+struct Record {
+    offset: usize,
+    key_len: usize,
+    kind: u8,
+    value_len: usize,
+}
+
+impl Record {
+    fn key<'p>(&self, page: &'p [u8]) -> &'p [u8] {
+        &page[self.offset + RECORD_HEADER..][..self.key_len]
+    }
+
+    fn value<'p>(&self, page: &'p [u8]) -> &'p [u8] {
+        &page[self.offset + RECORD_HEADER + self.key_len..][..self.value_len]
+    }
+
+    /// Size in flash, including padding.
+    fn size(&self) -> usize {
+        record_size(self.key_len, self.value_len)
+    }
+}
+
+fn record_size(key_len: usize, value_len: usize) -> usize {
+    (RECORD_HEADER + key_len + value_len + 7) & !7
+}
+
+fn record_crc(head: &[u8], key: &[u8], value: &[u8]) -> u32 {
+    crc32_update(crc32_update(crc32_update(0, head), key), value)
+}
+
+/// Returns the record at `offset`, or `None` at the end of the records: an
+/// erased header, a bad CRC or a record that would overrun the page.
+fn record_at(page: &[u8; PAGE], offset: usize) -> Option<Record> {
+    if offset + RECORD_HEADER > PAGE {
+        return None;
+    }
+    let h = &page[offset..offset + RECORD_HEADER];
+    let record = Record {
+        offset,
+        key_len: h[0] as usize,
+        kind: h[1],
+        value_len: u16::from_le_bytes([h[2], h[3]]) as usize,
+    };
+    if record.key_len == 0
+        || record.key_len > MAX_KEY_LEN
+        || record.value_len > MAX_VALUE_LEN
+        || offset + record.size() > PAGE
+    {
+        return None;
+    }
+    let crc = u32::from_le_bytes([h[4], h[5], h[6], h[7]]);
+    match record_crc(&h[..4], record.key(page), record.value(page)) == crc {
+        true => Some(record),
+        false => None,
+    }
+}
+
+/// Iterates over the valid records of a page.
+fn records(page: &[u8; PAGE]) -> impl Iterator<Item = Record> + '_ {
+    let mut offset = PAGE_HEADER as usize;
+    core::iter::from_fn(move || {
+        let record = record_at(page, offset)?;
+        offset += record.size();
+        Some(record)
+    })
+}
+
+/// What the header of a page says.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum PageState {
+    Blank,
+    /// In use; `done` once garbage collection into it finished.
+    Active {
+        seq: u32,
+        done: bool,
+    },
+    /// Neither erased nor a valid page, left by an interrupted program or
+    /// erase.
+    Garbage,
+}
+
+/// A key-value store over the pages of a partition.
+pub struct KvStore<'a, H, T> {
+    flash: &'a FlashWriterEraser<H, T>,
+    partition: Partition,
+    pages: u32,
+    /// Page being appended to.
+    head: u32,
+    seq: u32,
+    /// Where the next record goes in the head page.
+    offset: u32,
+}
+
+impl<'a, H: Hflashc, T: TraceSink> KvStore<'a, H, T> {
+    /// This method is used to open the store, formatting an erased partition
+    ///
+    /// Any page switch interrupted by a reset is finished or rolled back, so
+    /// the store holds exactly the writes that completed.
+    ///
+    /// Method arguments:
+    /// -   flash: the flash driver
+    /// -   partition: the pages to use, at least two
+    ///
+    /// Returns:
+    /// -  the store, ready to read and write
+    pub fn mount(
+        flash: &'a FlashWriterEraser<H, T>,
+        partition: Partition,
+    ) -> Result<Self, KvError> {
+        let pages = partition.page_count();
+        if pages < 2 {
+            return Err(KvError::TooFewPages);
+        }
+        let mut store = KvStore {
+            flash,
+            partition,
+            pages,
+            head: 0,
+            seq: 0,
+            offset: PAGE_HEADER,
+        };
+
+        // the newest page, and the one before it
+        let mut newest: Option<(u32, u32, bool)> = None;
+        let mut previous: Option<(u32, u32)> = None;
+        for page in 0..pages {
+            if let PageState::Active { seq, done } = store.page_state(page)? {
+                if newest.is_none_or(|(_, s, _)| seq > s) {
+                    if let Some((p, s, _)) = newest {
+                        previous = Some((p, s));
+                    }
+                    newest = Some((page, seq, done));
+                } else if previous.is_none_or(|(_, s)| seq > s) {
+                    previous = Some((page, seq));
+                }
+            }
+        }
+
+        match newest {
+            None => {
+                for page in 0..pages {
+                    if store.page_state(page)? != PageState::Blank {
+                        store.erase(page)?;
+                    }
+                }
+                store.open(0, 1)?;
+                store.mark_done(0)?;
+            }
+            Some((head, _, false)) => {
+                // the switch to `head` was interrupted while collecting the
+                // oldest page, which is still intact: start it again
+                store.erase(head)?;
+                match previous {
+                    Some((page, seq)) => {
+                        store.head = page;
+                        store.seq = seq;
+                        store.switch()?;
+                    }
+                    None => {
+                        store.open(head, 1)?;
+                        store.mark_done(head)?;
+                    }
+                }
+            }
+            Some((head, seq, true)) => {
+                store.head = head;
+                store.seq = seq;
+                // collection finished but erasing the oldest page may not
+                let spare = store.next(head);
+                if spare != head && store.page_state(spare)? != PageState::Blank {
+                    store.erase(spare)?;
+                }
+                let mut page = [0u8; PAGE];
+                store.read_page(head, &mut page)?;
+                let end = records(&page)
+                    .last()
+                    .map_or(PAGE_HEADER as usize, |r| r.offset + r.size());
+                // anything after the last record means a write was cut
+                // short; leave the rest of the page alone
+                store.offset = match page[end..].iter().all(|b| *b == 0xFF) {
+                    true => end as u32,
+                    false => FLASH_PAGE_SIZE,
+                };
+            }
+        }
+        Ok(store)
+    }
+
+    /// This method is used to read the value of a key
+    ///
+    /// Method arguments:
+    /// -   key: the key
+    /// -   buf: receives the value
+    ///
+    /// Returns:
+    /// -  the length of the value, or `None` if the key is not set
+    pub fn get(&self, key: &str, buf: &mut [u8]) -> Result<Option<usize>, KvError> {
+        let mut page = [0u8; PAGE];
+        let (found, record) = match self.latest(key.as_bytes(), &mut page)? {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        if record.kind != KIND_VALUE {
+            return Ok(None);
+        }
+        self.read_page(found, &mut page)?;
+        let value = record.value(&page);
+        if value.len() > buf.len() {
+            return Err(KvError::BufferTooSmall { len: value.len() });
+        }
+        buf[..value.len()].copy_from_slice(value);
+        Ok(Some(value.len()))
+    }
+
+    /// This method is used to set the value of a key
+    ///
+    /// Writing the value a key already has costs nothing.
+    ///
+    /// Method arguments:
+    /// -   key: up to [`MAX_KEY_LEN`] bytes
+    /// -   value: up to [`MAX_VALUE_LEN`] bytes
+    ///
+    /// Returns:
+    /// -  `Ok` once the record is in flash
+    pub fn set(&mut self, key: &str, value: &[u8]) -> Result<(), KvError> {
+        if value.len() > MAX_VALUE_LEN {
+            return Err(KvError::ValueTooLong);
+        }
+        check_key(key)?;
+        let mut current = [0u8; MAX_VALUE_LEN];
+        if let Some(len) = self.get(key, &mut current)? {
+            if &current[..len] == value {
+                return Ok(());
+            }
+        }
+        self.append(key.as_bytes(), KIND_VALUE, value)
+    }
+
+    /// This method is used to delete a key
+    ///
+    /// Method arguments:
+    /// -   key: the key
+    ///
+    /// Returns:
+    /// -  `Ok` once the key is gone, whether or not it was set
+    pub fn remove(&mut self, key: &str) -> Result<(), KvError> {
+        check_key(key)?;
+        let mut page = [0u8; PAGE];
+        match self.latest(key.as_bytes(), &mut page)? {
+            Some((_, record)) if record.kind == KIND_VALUE => {
+                self.append(key.as_bytes(), KIND_DELETED, &[])
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the page after `page` in the ring.
+    fn next(&self, page: u32) -> u32 {
+        (page + 1) % self.pages
+    }
+
+    fn read_page(&self, page: u32, buf: &mut [u8; PAGE]) -> Result<(), FlashError> {
+        self.partition.read(self.flash, page * FLASH_PAGE_SIZE, buf)
+    }
+
+    fn erase(&self, page: u32) -> Result<(), FlashError> {
+        self.partition
+            .erase(self.flash, page * FLASH_PAGE_SIZE, FLASH_PAGE_SIZE)
+    }
+
+    fn page_state(&self, page: u32) -> Result<PageState, FlashError> {
+        if self
+            .partition
+            .is_blank(self.flash, page * FLASH_PAGE_SIZE)?
+        {
+            return Ok(PageState::Blank);
+        }
+        let mut h = [0u8; PAGE_HEADER as usize];
+        self.partition
+            .read(self.flash, page * FLASH_PAGE_SIZE, &mut h)?;
+        if &h[..4] != MAGIC {
+            return Ok(PageState::Garbage);
+        }
+        Ok(PageState::Active {
+            seq: u32::from_le_bytes([h[4], h[5], h[6], h[7]]),
+            done: h[8..16] == [0; 8],
+        })
+    }
+
+    /// Writes the header of a new head page.
+    fn open(&mut self, page: u32, seq: u32) -> Result<(), FlashError> {
+        let mut h = [0xFFu8; 8];
+        h[..4].copy_from_slice(MAGIC);
+        h[4..].copy_from_slice(&seq.to_le_bytes());
+        self.partition
+            .program(self.flash, page * FLASH_PAGE_SIZE, &h)?;
+        self.head = page;
+        self.seq = seq;
+        self.offset = PAGE_HEADER;
+        Ok(())
+    }
+
+    fn mark_done(&self, page: u32) -> Result<(), FlashError> {
+        self.partition
+            .program(self.flash, page * FLASH_PAGE_SIZE + 8, &[0; 8])
+    }
+
+    /// Moves the head to the spare page and collects the oldest page.
+    fn switch(&mut self) -> Result<(), KvError> {
+        let new = self.next(self.head);
+        let oldest = self.next(new);
+        if self.page_state(new)? != PageState::Blank {
+            self.erase(new)?;
+        }
+        self.open(new, self.seq + 1)?;
+
+        let collect = self.page_state(oldest)? != PageState::Blank;
+        if collect {
+            let mut old = [0u8; PAGE];
+            let mut scratch = [0u8; PAGE];
+            self.read_page(oldest, &mut old)?;
+            for record in records(&old) {
+                let key = record.key(&old);
+                let live = match self.latest(key, &mut scratch)? {
+                    Some((page, latest)) => page == oldest && latest.offset == record.offset,
+                    None => false,
+                };
+                if live && record.kind == KIND_VALUE {
+                    let bytes = &old[record.offset..record.offset + record.size()];
+                    if self.offset as usize + bytes.len() > PAGE {
+                        return Err(KvError::Full);
+                    }
+                    self.partition.program(
+                        self.flash,
+                        self.head * FLASH_PAGE_SIZE + self.offset,
+                        bytes,
+                    )?;
+                    self.offset += bytes.len() as u32;
+                }
+            }
+        }
+        self.mark_done(new)?;
+        if collect {
+            self.erase(oldest)?;
+        }
+        Ok(())
+    }
+
+    /// Finds the newest record for `key`, using `page` as scratch space.
+    fn latest(&self, key: &[u8], page: &mut [u8; PAGE]) -> Result<Option<(u32, Record)>, KvError> {
+        let mut found = None;
+        // from the oldest page to the head
+        for i in 1..=self.pages {
+            let index = (self.head + i) % self.pages;
+            if let PageState::Active { .. } = self.page_state(index)? {
+                self.read_page(index, page)?;
+                for record in records(page) {
+                    if record.key(page) == key {
+                        found = Some((index, record));
+                    }
+                }
+            }
+        }
+        Ok(found)
+    }
+
+    fn append(&mut self, key: &[u8], kind: u8, value: &[u8]) -> Result<(), KvError> {
+        let size = record_size(key.len(), value.len());
+        if self.offset as usize + size > PAGE {
+            self.switch()?;
+            if self.offset as usize + size > PAGE {
+                return Err(KvError::Full);
+            }
+        }
+        let mut record = [0xFFu8; MAX_RECORD];
+        record[0] = key.len() as u8;
+        record[1] = kind;
+        record[2..4].copy_from_slice(&(value.len() as u16).to_le_bytes());
+        let crc = record_crc(&record[..4], key, value);
+        record[4..8].copy_from_slice(&crc.to_le_bytes());
+        record[RECORD_HEADER..RECORD_HEADER + key.len()].copy_from_slice(key);
+        record[RECORD_HEADER + key.len()..][..value.len()].copy_from_slice(value);
+        self.partition.program(
+            self.flash,
+            self.head * FLASH_PAGE_SIZE + self.offset,
+            &record[..size],
+        )?;
+        self.offset += size as u32;
+        Ok(())
+    }
+}
+
+fn check_key(key: &str) -> Result<(), KvError> {
+    match key.len() {
+        1..=MAX_KEY_LEN => Ok(()),
+        _ => Err(KvError::BadKey),
+    }
+}
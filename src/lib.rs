@@ -0,0 +1,18 @@
+#![no_std]
+
+pub mod atsam4l;
+pub mod crypt;
+pub mod delta;
+pub mod dispatch;
+pub mod eeprom;
+pub mod hexfile;
+pub mod kv;
+pub mod log;
+pub mod image;
+pub mod lz4;
+pub mod ptable;
+pub mod rollback;
+pub mod swap;
+pub mod trial;
+pub mod writer;
+pub mod xmodem;
@@ -0,0 +1,289 @@
+//! Circular log of variable-length entries.
+//!
+//! Sensor events and fault records are appended to the pages of a partition
+//! in turn. When the last page is full the log wraps around and the oldest
+//! page is erased to make room, so the log always holds the most recent
+//! entries and every page is erased equally often.
+//!
+//! Every page in use starts with an 8-byte header:
+//!
+//! | offset | size | contents                                          |
+//! |--------|------|---------------------------------------------------|
+//! | 0x00   | 4    | magic `RBLG`                                      |
+//! | 0x04   | 4    | sequence number, one more than on the page before |
+//!
+//! followed by entries, each starting on a doubleword:
+//!
+//! | offset | size | contents                                          |
+//! |--------|------|---------------------------------------------------|
+//! | 0x00   | 2    | length of the data                                |
+//! | 0x02   | 2    | the length inverted                               |
+//! | 0x04   | 4    | CRC-32 of the data                                |
+//! | 0x08   | ...  | data, padded with 0xFF                            |
+//!
+//! [`Log::mount`] skips erased pages with the quick page read, finds the
+//! page with the highest sequence number and continues after its last
+//! valid entry. An entry cut short by a reset fails its CRC and ends the
+//! page; the next entry goes to a fresh page.
+
+use flash_protocol::crc32;
+
+use crate::atsam4l::atsam4lc8c_constants::FLASH_PAGE_SIZE;
+use crate::atsam4l::partition::Partition;
+use crate::atsam4l::{FlashError, FlashWriterEraser, Hflashc, TraceSink};
+
+const PAGE: usize = FLASH_PAGE_SIZE as usize;
+const MAGIC: &[u8; 4] = b"RBLG";
+const PAGE_HEADER: usize = 8;
+const ENTRY_HEADER: usize = 8;
+
+/// Largest entry, filling a page on its own.
+pub const MAX_ENTRY_LEN: usize = PAGE - PAGE_HEADER - ENTRY_HEADER;
+
+/// Why a log operation failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogError {
+    /// The partition has fewer than two pages.
+    TooFewPages,
+    /// The entry is longer than [`MAX_ENTRY_LEN`].
+    TooLong,
+    Flash(FlashError),
+}
+
+impl From<FlashError> for LogError {
+    fn from(e: FlashError) -> Self {
+        LogError::Flash(e)
+    }
+}
+
+fn entry_size(len: usize) -> usize {
+    (ENTRY_HEADER + len + 7) & !7
+}
+
+/// Returns the length of the entry at `offset`, or `None` at the end of the
+/// entries: an erased header, a bad CRC or an entry overrunning the page.
+fn entry_at(page: &[u8; PAGE], offset: usize) -> Option<usize> {
+    if offset + ENTRY_HEADER > PAGE {
+        return None;
+    }
+    let h = &page[offset..offset + ENTRY_HEADER];
+    let len = u16::from_le_bytes([h[0], h[1]]);
+    if !len != u16::from_le_bytes([h[2], h[3]]) || offset + entry_size(len as usize) > PAGE {
+        return None;
+    }
+    let data = &page[offset + ENTRY_HEADER..][..len as usize];
+    match crc32(data) == u32::from_le_bytes([h[4], h[5], h[6], h[7]]) {
+        true => Some(len as usize),
+        false => None,
+    }
+}
+
+/// An entry read back from the log.
+pub struct Entry {
+    buf: [u8; MAX_ENTRY_LEN],
+    len: usize,
+}
+
+impl Entry {
+    pub fn data(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+/// A circular log over the pages of a partition.
+pub struct Log<'a, H, T> {
+    flash: &'a FlashWriterEraser<H, T>,
+    partition: Partition,
+    pages: u32,
+    /// Page being appended to, if any page is in use.
+    head: Option<u32>,
+    seq: u32,
+    /// Where the next entry goes in the head page.
+    offset: usize,
+}
+
+impl<'a, H: Hflashc, T: TraceSink> Log<'a, H, T> {
+    /// This method is used to open the log and find where the next entry
+    /// goes
+    ///
+    /// Method arguments:
+    /// -   flash: the flash driver
+    /// -   partition: the pages to use, at least two
+    ///
+    /// Returns:
+    /// -  the log, ready to append to and read
+    pub fn mount(
+        flash: &'a FlashWriterEraser<H, T>,
+        partition: Partition,
+    ) -> Result<Self, LogError> {
+        let pages = partition.page_count();
+        if pages < 2 {
+            return Err(LogError::TooFewPages);
+        }
+        let mut log = Log {
+            flash,
+            partition,
+            pages,
+            head: None,
+            seq: 0,
+            offset: PAGE,
+        };
+        for page in 0..pages {
+            if let Some(seq) = log.page_seq(page)? {
+                if log.head.is_none() || seq > log.seq {
+                    log.head = Some(page);
+                    log.seq = seq;
+                }
+            }
+        }
+        if let Some(head) = log.head {
+            let mut buf = [0u8; PAGE];
+            log.read_page(head, &mut buf)?;
+            let mut end = PAGE_HEADER;
+            while let Some(len) = entry_at(&buf, end) {
+                end += entry_size(len);
+            }
+            // anything after the last entry is a write cut short: leave the
+            // rest of the page alone
+            if buf[end..].iter().all(|b| *b == 0xFF) {
+                log.offset = end;
+            }
+        }
+        Ok(log)
+    }
+
+    /// This method is used to add an entry after the newest one
+    ///
+    /// Starting a new page erases the oldest page once every page is in use.
+    ///
+    /// Method arguments:
+    /// -   data: up to [`MAX_ENTRY_LEN`] bytes
+    ///
+    /// Returns:
+    /// -  `Ok` once the entry is in flash
+    pub fn append(&mut self, data: &[u8]) -> Result<(), LogError> {
+        if data.len() > MAX_ENTRY_LEN {
+            return Err(LogError::TooLong);
+        }
+        let size = entry_size(data.len());
+        if self.offset + size > PAGE {
+            self.next_page()?;
+        }
+        let head = self.head.unwrap_or(0);
+        let mut entry = [0xFFu8; PAGE];
+        let len = data.len() as u16;
+        entry[0..2].copy_from_slice(&len.to_le_bytes());
+        entry[2..4].copy_from_slice(&(!len).to_le_bytes());
+        entry[4..8].copy_from_slice(&crc32(data).to_le_bytes());
+        entry[ENTRY_HEADER..ENTRY_HEADER + data.len()].copy_from_slice(data);
+        self.partition.program(
+            self.flash,
+            head * FLASH_PAGE_SIZE + self.offset as u32,
+            &entry[..size],
+        )?;
+        self.offset += size;
+        Ok(())
+    }
+
+    /// This method is used to read the entries from the oldest to the
+    /// newest
+    ///
+    /// Returns:
+    /// -  an iterator over the entries
+    pub fn entries(&self) -> Entries<'_, 'a, H, T> {
+        Entries {
+            log: self,
+            index: 0,
+            page: [0xFF; PAGE],
+            offset: None,
+        }
+    }
+
+    fn read_page(&self, page: u32, buf: &mut [u8; PAGE]) -> Result<(), FlashError> {
+        self.partition.read(self.flash, page * FLASH_PAGE_SIZE, buf)
+    }
+
+    /// Returns the sequence number of a page in use, or `None` for an
+    /// erased or damaged page.
+    fn page_seq(&self, page: u32) -> Result<Option<u32>, FlashError> {
+        if self
+            .partition
+            .is_blank(self.flash, page * FLASH_PAGE_SIZE)?
+        {
+            return Ok(None);
+        }
+        let mut h = [0u8; PAGE_HEADER];
+        self.partition
+            .read(self.flash, page * FLASH_PAGE_SIZE, &mut h)?;
+        match &h[..4] == MAGIC {
+            true => Ok(Some(u32::from_le_bytes([h[4], h[5], h[6], h[7]]))),
+            false => Ok(None),
+        }
+    }
+
+    /// Starts the page after the head, erasing it if it holds old entries.
+    fn next_page(&mut self) -> Result<(), FlashError> {
+        let page = match self.head {
+            Some(head) => (head + 1) % self.pages,
+            None => 0,
+        };
+        let offset = page * FLASH_PAGE_SIZE;
+        if !self.partition.is_blank(self.flash, offset)? {
+            self.partition.erase(self.flash, offset, FLASH_PAGE_SIZE)?;
+        }
+        let seq = self.seq.wrapping_add(1);
+        let mut h = [0u8; PAGE_HEADER];
+        h[..4].copy_from_slice(MAGIC);
+        h[4..].copy_from_slice(&seq.to_le_bytes());
+        self.partition.program(self.flash, offset, &h)?;
+        self.head = Some(page);
+        self.seq = seq;
+        self.offset = PAGE_HEADER;
+        Ok(())
+    }
+}
+
+/// Iterator over the entries of a [`Log`], oldest first.
+pub struct Entries<'l, 'a, H, T> {
+    log: &'l Log<'a, H, T>,
+    /// Pages visited so far, counting from the one after the head.
+    index: u32,
+    page: [u8; PAGE],
+    /// Next entry in `page`, if a page is loaded.
+    offset: Option<usize>,
+}
+
+impl<H: Hflashc, T: TraceSink> Iterator for Entries<'_, '_, H, T> {
+    type Item = Result<Entry, LogError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let head = self.log.head?;
+        loop {
+            if let Some(offset) = self.offset {
+                if let Some(len) = entry_at(&self.page, offset) {
+                    self.offset = Some(offset + entry_size(len));
+                    let mut entry = Entry {
+                        buf: [0; MAX_ENTRY_LEN],
+                        len,
+                    };
+                    entry.buf[..len].copy_from_slice(&self.page[offset + ENTRY_HEADER..][..len]);
+                    return Some(Ok(entry));
+                }
+                self.offset = None;
+            }
+            if self.index == self.log.pages {
+                return None;
+            }
+            let page = (head + 1 + self.index) % self.log.pages;
+            self.index += 1;
+            match self.log.page_seq(page) {
+                Ok(None) => {}
+                Ok(Some(_)) => match self.log.read_page(page, &mut self.page) {
+                    Ok(()) => self.offset = Some(PAGE_HEADER),
+                    Err(e) => return Some(Err(e.into())),
+                },
+                Err(e) => return Some(Err(e.into())),
+            }
+        }
+    }
+}
@@ -0,0 +1,290 @@
+//! Streaming installation of LZ4 compressed images.
+//!
+//! A compressed image is a short header followed by a single LZ4 block
+//! (the raw block format, without the frame wrapper):
+//!
+//! | field  | size | contents                           |
+//! |--------|------|------------------------------------|
+//! | magic  | 4    | `RBZ1`                             |
+//! | size   | 4    | size of the decompressed image     |
+//! | CRC    | 4    | CRC-32 of the decompressed image   |
+//! | block  | ...  | LZ4 sequences                      |
+//!
+//! LZ4 matches refer back up to 64 KiB into the output, more RAM than the
+//! bootloader can spare. [`Decompressor`] instead reads match sources back
+//! through the [`PageWriter`], from flash or from its page buffer, so it
+//! needs no window of its own.
+
+use flash_protocol::crc32_update;
+
+use crate::atsam4l::FlashError;
+use crate::atsam4l::{Hflashc, TraceSink};
+use crate::writer::PageWriter;
+
+pub const MAGIC: &[u8; 4] = b"RBZ1";
+pub const HEADER_SIZE: usize = 12;
+
+/// Bytes copied at a time.
+const CHUNK: usize = 64;
+/// Matches are at least this long; the token stores the excess.
+const MIN_MATCH: u32 = 4;
+
+/// Why a compressed image could not be installed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Lz4Error {
+    BadMagic,
+    /// The decompressed image does not fit in the partition.
+    TooLarge,
+    /// A match refers to data before the start of the image, or the
+    /// sequences produce more than the header announced.
+    Corrupt,
+    /// The decompressed image does not match the CRC in the header.
+    Crc,
+    /// The input ended before the image was complete.
+    Truncated,
+    Flash(FlashError),
+}
+
+impl From<FlashError> for Lz4Error {
+    fn from(e: FlashError) -> Self {
+        Lz4Error::Flash(e)
+    }
+}
+
+/// Whether the whole image has been decompressed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Progress {
+    NeedMore,
+    /// The image is complete, checked and flushed to flash.
+    Done {
+        size: u32,
+    },
+}
+
+#[derive(Clone, Copy)]
+enum State {
+    Header,
+    Token,
+    /// Extra bytes of the literal length, added while they are 255.
+    LiteralLength,
+    Literals,
+    Offset {
+        low: Option<u8>,
+    },
+    MatchLength {
+        offset: u32,
+    },
+    Done,
+}
+
+/// Decompresses an image into a partition as the input arrives.
+pub struct Decompressor {
+    state: State,
+    header: [u8; HEADER_SIZE],
+    header_len: usize,
+    size: u32,
+    crc: u32,
+    expected_crc: u32,
+    token: u8,
+    /// Length of the literal run or match being decoded.
+    length: u32,
+    written: u32,
+}
+
+impl Default for Decompressor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decompressor {
+    pub const fn new() -> Self {
+        Decompressor {
+            state: State::Header,
+            header: [0; HEADER_SIZE],
+            header_len: 0,
+            size: 0,
+            crc: 0,
+            expected_crc: 0,
+            token: 0,
+            length: 0,
+            written: 0,
+        }
+    }
+
+    /// This method is used to feed the next piece of the compressed image
+    ///
+    /// Method arguments:
+    /// -   input: any number of bytes, split anywhere
+    /// -   writer: receives the image, which starts at offset 0
+    ///
+    /// Returns:
+    /// -  `Progress::Done` once the image is complete; any bytes after it
+    ///    are ignored
+    pub fn feed<H: Hflashc, T: TraceSink>(
+        &mut self,
+        mut input: &[u8],
+        writer: &mut PageWriter<'_, H, T>,
+    ) -> Result<Progress, Lz4Error> {
+        while !input.is_empty() {
+            if let State::Literals = self.state {
+                let n = (self.length as usize).min(input.len()).min(CHUNK);
+                let (data, rest) = input.split_at(n);
+                input = rest;
+                self.emit(data, writer)?;
+                self.length -= n as u32;
+                if self.length == 0 {
+                    self.end_literals(writer)?;
+                }
+                continue;
+            }
+            let byte = input[0];
+            input = &input[1..];
+            match self.state {
+                State::Header => {
+                    self.header[self.header_len] = byte;
+                    self.header_len += 1;
+                    if self.header_len == HEADER_SIZE {
+                        self.check_header(writer)?;
+                    }
+                }
+                State::Token => {
+                    self.token = byte;
+                    self.length = (byte >> 4) as u32;
+                    if self.length == 15 {
+                        self.state = State::LiteralLength;
+                    } else {
+                        self.start_literals(writer)?;
+                    }
+                }
+                State::LiteralLength => {
+                    self.length += byte as u32;
+                    if byte != 255 {
+                        self.start_literals(writer)?;
+                    }
+                }
+                State::Offset { low: None } => self.state = State::Offset { low: Some(byte) },
+                State::Offset { low: Some(low) } => {
+                    let offset = u16::from_le_bytes([low, byte]) as u32;
+                    if offset == 0 || offset > self.written {
+                        return Err(Lz4Error::Corrupt);
+                    }
+                    self.length = (self.token & 0x0F) as u32;
+                    if self.length == 15 {
+                        self.state = State::MatchLength { offset };
+                    } else {
+                        self.copy_match(offset, writer)?;
+                    }
+                }
+                State::MatchLength { offset } => {
+                    self.length += byte as u32;
+                    if byte != 255 {
+                        self.copy_match(offset, writer)?;
+                    }
+                }
+                State::Literals => {}
+                State::Done => break,
+            }
+        }
+        Ok(match self.state {
+            State::Done => Progress::Done { size: self.size },
+            _ => Progress::NeedMore,
+        })
+    }
+
+    /// This method is used once the whole input has been fed
+    ///
+    /// Returns:
+    /// -  the size of the image, or `Lz4Error::Truncated` if it is not
+    ///    complete
+    pub fn finish(&self) -> Result<u32, Lz4Error> {
+        match self.state {
+            State::Done => Ok(self.size),
+            _ => Err(Lz4Error::Truncated),
+        }
+    }
+
+    fn check_header<H: Hflashc, T: TraceSink>(
+        &mut self,
+        writer: &mut PageWriter<'_, H, T>,
+    ) -> Result<(), Lz4Error> {
+        let h = &self.header;
+        if &h[..4] != MAGIC {
+            return Err(Lz4Error::BadMagic);
+        }
+        self.size = u32::from_le_bytes([h[4], h[5], h[6], h[7]]);
+        self.expected_crc = u32::from_le_bytes([h[8], h[9], h[10], h[11]]);
+        if self.size > writer.partition().size() {
+            return Err(Lz4Error::TooLarge);
+        }
+        self.state = State::Token;
+        Ok(())
+    }
+
+    fn start_literals<H: Hflashc, T: TraceSink>(
+        &mut self,
+        writer: &mut PageWriter<'_, H, T>,
+    ) -> Result<(), Lz4Error> {
+        if self.length > self.size - self.written {
+            return Err(Lz4Error::Corrupt);
+        }
+        if self.length == 0 {
+            self.end_literals(writer)
+        } else {
+            self.state = State::Literals;
+            Ok(())
+        }
+    }
+
+    /// The last sequence of a block has no match, so the image ends after
+    /// its literals.
+    fn end_literals<H: Hflashc, T: TraceSink>(
+        &mut self,
+        writer: &mut PageWriter<'_, H, T>,
+    ) -> Result<(), Lz4Error> {
+        if self.written < self.size {
+            self.state = State::Offset { low: None };
+            return Ok(());
+        }
+        if self.crc != self.expected_crc {
+            return Err(Lz4Error::Crc);
+        }
+        writer.flush()?;
+        self.state = State::Done;
+        Ok(())
+    }
+
+    fn copy_match<H: Hflashc, T: TraceSink>(
+        &mut self,
+        offset: u32,
+        writer: &mut PageWriter<'_, H, T>,
+    ) -> Result<(), Lz4Error> {
+        let mut remaining = self.length + MIN_MATCH;
+        if remaining > self.size - self.written {
+            return Err(Lz4Error::Corrupt);
+        }
+        let mut buf = [0u8; CHUNK];
+        while remaining > 0 {
+            // an overlapping match repeats the last `offset` bytes, so never
+            // read further ahead than that
+            let n = remaining.min(offset).min(CHUNK as u32);
+            let chunk = &mut buf[..n as usize];
+            writer.read(self.written - offset, chunk)?;
+            self.emit(chunk, writer)?;
+            remaining -= n;
+        }
+        self.state = State::Token;
+        Ok(())
+    }
+
+    fn emit<H: Hflashc, T: TraceSink>(
+        &mut self,
+        data: &[u8],
+        writer: &mut PageWriter<'_, H, T>,
+    ) -> Result<(), FlashError> {
+        writer.write(self.written, data)?;
+        self.crc = crc32_update(self.crc, data);
+        self.written += data.len() as u32;
+        Ok(())
+    }
+}
@@ -1,20 +1,34 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(target_os = "none", no_std)]
+#![cfg_attr(target_os = "none", no_main)]
 
+// The firmware only runs on the SAM4L. On any other target this binary is an
+// empty stub, so `cargo test` can build the package on the host.
+#[cfg(not(target_os = "none"))]
+fn main() {}
+
+#[cfg(target_os = "none")]
 use defmt_rtt as _;
+#[cfg(target_os = "none")]
 use atsam4lc8c_pac as pac;
-use pac::{Peripherals, aesa::databufptr, smap::length};
+#[cfg(target_os = "none")]
+use pac::{Peripherals, smap::length};
+#[cfg(target_os = "none")]
 use panic_halt as _;
+#[cfg(target_os = "none")]
 use defmt::println;
 
 
 
-pub mod atsam4l;
-use atsam4l::FlashWriterEraser;
+#[cfg(target_os = "none")]
+use atsamblinky::atsam4l::{FlashAddress, FlashWriterEraser};
+#[cfg(target_os = "none")]
 use core::ptr::write_volatile;
-use atsam4l::atsam4lc8c_constants::*;
+#[cfg(target_os = "none")]
+use atsamblinky::atsam4l::atsam4lc8c_constants::*;
+#[cfg(target_os = "none")]
 use pac::HFLASHC;
 
+#[cfg(target_os = "none")]
 #[cortex_m_rt::entry]
 fn main()->! {
    let addr = 0x00048300;
@@ -23,8 +37,11 @@ fn main()->! {
    // let mut data_length = data.len();
    let raw_ptr = data.as_ptr();
    let mut updater = FlashWriterEraser::new();
-   // updater.hal_flash_erase(addr, len);
-   updater.hal_flash_write(0x00048300, data.as_ptr(), len);
+   // finish a user page rewrite cut short by the last reset
+   updater.hal_user_page_recover().unwrap();
+   let address = FlashAddress::new(addr).unwrap();
+   // updater.hal_flash_erase(FlashRange::new(address, len).unwrap());
+   updater.hal_flash_write(address, &data[..len as usize]).unwrap();
    // updater.write_nvm_words(0x00043800, &data, len);
    // updater.write_nvm_word(0x00048300, &data, len);
    defmt::println!("Writing finished");
@@ -32,4 +49,3 @@ fn main()->! {
        //...
    }
 }
-
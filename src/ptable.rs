@@ -0,0 +1,322 @@
+//! Partition table stored in flash, for layouts decided after the build.
+//!
+//! [`LAYOUT`](crate::atsam4l::partition::LAYOUT) is fixed when the
+//! bootloader is compiled. Products that size their data partitions
+//! differently describe them in a table instead, written to the page at
+//! `PTABLE_ADDR` when the device is provisioned (`imgtool partition-table`
+//! generates it) and read by the application with [`PartitionTable::load`].
+//!
+//! | offset | size | contents                                   |
+//! |--------|------|--------------------------------------------|
+//! | 0x00   | 4    | magic `RBPT`                               |
+//! | 0x04   | 2    | version, 1                                 |
+//! | 0x06   | 2    | number of entries                          |
+//! | 0x08   | 32n  | entries                                    |
+//! | ...    | 4    | CRC-32 of everything before it             |
+//!
+//! Each entry is a name of up to 16 ASCII characters, padded with zeros,
+//! then the start address and size, then the partition kind as a byte and
+//! seven reserved zero bytes. Integers are little endian.
+
+use flash_protocol::crc32;
+
+use crate::atsam4l::atsam4lc8c_constants::*;
+use crate::atsam4l::partition::{Layout, Partition, PartitionKind};
+use crate::atsam4l::{FlashAddress, FlashError, FlashWriterEraser, Hflashc, TraceSink};
+
+pub const MAGIC: &[u8; 4] = b"RBPT";
+pub const VERSION: u16 = 1;
+pub const NAME_LEN: usize = 16;
+pub const ENTRY_SIZE: usize = 32;
+/// Entries that fit in one page with the header and the CRC.
+pub const MAX_ENTRIES: usize = 15;
+
+const HEADER_SIZE: usize = 8;
+const PAGE: usize = FLASH_PAGE_SIZE as usize;
+
+/// Why a table could not be loaded or built.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TableError {
+    /// The page is erased: no table was ever written.
+    Blank,
+    BadMagic,
+    UnsupportedVersion,
+    Crc,
+    /// The entry at this index has an unknown kind, a bad name or a range
+    /// outside flash.
+    BadEntry {
+        index: usize,
+    },
+    /// The name is empty, too long, not printable ASCII or already used.
+    BadName,
+    /// The partition overlaps one already in the table.
+    Overlap,
+    Full,
+    Flash(FlashError),
+}
+
+impl From<FlashError> for TableError {
+    fn from(e: FlashError) -> Self {
+        TableError::Flash(e)
+    }
+}
+
+/// A named partition.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Entry {
+    name: [u8; NAME_LEN],
+    len: usize,
+    partition: Partition,
+}
+
+impl Entry {
+    pub fn name(&self) -> &str {
+        // names are checked to be ASCII when the entry is made
+        core::str::from_utf8(&self.name[..self.len]).unwrap_or("")
+    }
+
+    pub fn partition(&self) -> Partition {
+        self.partition
+    }
+}
+
+/// The partitions of a device, looked up by name or kind.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PartitionTable {
+    entries: [Option<Entry>; MAX_ENTRIES],
+    count: usize,
+}
+
+impl Default for PartitionTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PartitionTable {
+    /// Creates an empty table.
+    pub const fn new() -> Self {
+        PartitionTable {
+            entries: [None; MAX_ENTRIES],
+            count: 0,
+        }
+    }
+
+    /// Creates a table with the partitions of a compile-time layout, named
+    /// after their kind.
+    pub fn from_layout(layout: &Layout) -> Self {
+        let mut table = PartitionTable::new();
+        let kinds = [
+            PartitionKind::Bootloader,
+            PartitionKind::Boot,
+            PartitionKind::Update,
+            PartitionKind::Swap,
+            PartitionKind::Data,
+        ];
+        for kind in kinds {
+            if let Some(partition) = layout.get(kind) {
+                // a checked layout never overlaps and has at most five entries
+                let _ = table.push(kind_name(kind), partition);
+            }
+        }
+        table
+    }
+
+    /// This method is used to read the table from its page in flash
+    ///
+    /// Method arguments:
+    /// -   flash: the flash driver
+    ///
+    /// Returns:
+    /// -  the table, or `TableError::Blank` if none was written
+    pub fn load<H: Hflashc, T: TraceSink>(
+        flash: &FlashWriterEraser<H, T>,
+    ) -> Result<Self, TableError> {
+        let mut page = [0u8; PAGE];
+        flash.hal_flash_read(FlashAddress::new(PTABLE_ADDR)?, &mut page)?;
+        Self::decode(&page)
+    }
+
+    /// This method is used to write the table to its page in flash
+    ///
+    /// Method arguments:
+    /// -   flash: the flash driver
+    ///
+    /// Returns:
+    /// -  `Ok` once the page is written
+    pub fn store<H: Hflashc, T: TraceSink>(
+        &self,
+        flash: &FlashWriterEraser<H, T>,
+    ) -> Result<(), TableError> {
+        let mut page = [0u8; PAGE];
+        self.encode(&mut page);
+        flash.hal_flash_write(FlashAddress::new(PTABLE_ADDR)?, &page)?;
+        Ok(())
+    }
+
+    /// This method is used to parse a table from the contents of its page
+    ///
+    /// Method arguments:
+    /// -   page: the page, at least up to the end of the CRC
+    ///
+    /// Returns:
+    /// -  the table, or why it was rejected
+    pub fn decode(page: &[u8]) -> Result<Self, TableError> {
+        if page.len() < HEADER_SIZE {
+            return Err(TableError::BadMagic);
+        }
+        if page[..4] == [0xFF; 4] {
+            return Err(TableError::Blank);
+        }
+        if &page[..4] != MAGIC {
+            return Err(TableError::BadMagic);
+        }
+        if u16::from_le_bytes([page[4], page[5]]) != VERSION {
+            return Err(TableError::UnsupportedVersion);
+        }
+        let count = u16::from_le_bytes([page[6], page[7]]) as usize;
+        let end = HEADER_SIZE + count * ENTRY_SIZE;
+        if count > MAX_ENTRIES || page.len() < end + 4 {
+            return Err(TableError::Crc);
+        }
+        let stored = u32::from_le_bytes([page[end], page[end + 1], page[end + 2], page[end + 3]]);
+        if crc32(&page[..end]) != stored {
+            return Err(TableError::Crc);
+        }
+
+        let mut table = PartitionTable::new();
+        for index in 0..count {
+            let e = &page[HEADER_SIZE + index * ENTRY_SIZE..][..ENTRY_SIZE];
+            let word = |i: usize| u32::from_le_bytes([e[i], e[i + 1], e[i + 2], e[i + 3]]);
+            let bad = TableError::BadEntry { index };
+            let len = e[..NAME_LEN]
+                .iter()
+                .position(|b| *b == 0)
+                .unwrap_or(NAME_LEN);
+            let name = core::str::from_utf8(&e[..len]).map_err(|_| bad)?;
+            let kind = kind_from_u8(e[24]).ok_or(bad)?;
+            let partition = Partition::try_new(kind, word(16), word(20)).map_err(|_| bad)?;
+            table.push(name, partition).map_err(|_| bad)?;
+        }
+        Ok(table)
+    }
+
+    /// This method is used to lay the table out as it is stored in flash
+    ///
+    /// Method arguments:
+    /// -   page: filled with the table, the rest erased to 0xFF
+    ///
+    /// Returns:
+    /// -  the number of bytes used, including the CRC
+    pub fn encode(&self, page: &mut [u8; PAGE]) -> usize {
+        page.fill(0xFF);
+        page[..4].copy_from_slice(MAGIC);
+        page[4..6].copy_from_slice(&VERSION.to_le_bytes());
+        page[6..8].copy_from_slice(&(self.count as u16).to_le_bytes());
+        for (i, entry) in self.entries().enumerate() {
+            let e = &mut page[HEADER_SIZE + i * ENTRY_SIZE..][..ENTRY_SIZE];
+            e.fill(0);
+            e[..NAME_LEN].copy_from_slice(&entry.name);
+            e[16..20].copy_from_slice(&entry.partition.start().to_le_bytes());
+            e[20..24].copy_from_slice(&entry.partition.size().to_le_bytes());
+            e[24] = kind_to_u8(entry.partition.kind);
+        }
+        let end = HEADER_SIZE + self.count * ENTRY_SIZE;
+        let crc = crc32(&page[..end]);
+        page[end..end + 4].copy_from_slice(&crc.to_le_bytes());
+        end + 4
+    }
+
+    /// This method is used to add a partition
+    ///
+    /// Method arguments:
+    /// -   name: up to 16 printable ASCII characters, unique in the table
+    /// -   partition: must not overlap any partition already added
+    ///
+    /// Returns:
+    /// -  `TableError::Full` once `MAX_ENTRIES` partitions are in the table
+    pub fn push(&mut self, name: &str, partition: Partition) -> Result<(), TableError> {
+        let bytes = name.as_bytes();
+        if bytes.is_empty()
+            || bytes.len() > NAME_LEN
+            || !bytes.iter().all(|b| b.is_ascii_graphic())
+            || self.find(name).is_some()
+        {
+            return Err(TableError::BadName);
+        }
+        if self.entries().any(|e| e.partition.overlaps(&partition)) {
+            return Err(TableError::Overlap);
+        }
+        if self.count == MAX_ENTRIES {
+            return Err(TableError::Full);
+        }
+        let mut entry = Entry {
+            name: [0; NAME_LEN],
+            len: bytes.len(),
+            partition,
+        };
+        entry.name[..bytes.len()].copy_from_slice(bytes);
+        self.entries[self.count] = Some(entry);
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Returns the partition with the given name.
+    pub fn find(&self, name: &str) -> Option<Partition> {
+        self.entries()
+            .find(|e| e.name() == name)
+            .map(|e| e.partition)
+    }
+
+    /// Returns the first partition of the given kind.
+    pub fn find_kind(&self, kind: PartitionKind) -> Option<Partition> {
+        self.entries()
+            .find(|e| e.partition.kind == kind)
+            .map(|e| e.partition)
+    }
+
+    /// Returns the entries in the order they were added.
+    pub fn entries(&self) -> impl Iterator<Item = &Entry> {
+        self.entries[..self.count].iter().flatten()
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+}
+
+/// Returns the name `from_layout` and `imgtool` use for a kind.
+pub fn kind_name(kind: PartitionKind) -> &'static str {
+    match kind {
+        PartitionKind::Bootloader => "bootloader",
+        PartitionKind::Boot => "boot",
+        PartitionKind::Update => "update",
+        PartitionKind::Swap => "swap",
+        PartitionKind::Data => "data",
+    }
+}
+
+fn kind_to_u8(kind: PartitionKind) -> u8 {
+    match kind {
+        PartitionKind::Bootloader => 0,
+        PartitionKind::Boot => 1,
+        PartitionKind::Update => 2,
+        PartitionKind::Swap => 3,
+        PartitionKind::Data => 4,
+    }
+}
+
+fn kind_from_u8(value: u8) -> Option<PartitionKind> {
+    Some(match value {
+        0 => PartitionKind::Bootloader,
+        1 => PartitionKind::Boot,
+        2 => PartitionKind::Update,
+        3 => PartitionKind::Swap,
+        4 => PartitionKind::Data,
+        _ => return None,
+    })
+}
@@ -0,0 +1,140 @@
+//! Anti-rollback protection.
+//!
+//! The bootloader keeps a monotonic security counter in the user page and
+//! refuses images whose firmware version is below it. The counter region is
+//! a row of 8-byte slots, each holding a value and its complement. Raising
+//! the counter programs the next erased slot, so no erase is needed until
+//! every slot is used. A slot torn by a power loss fails the complement
+//! check and is skipped, leaving the previous value in force.
+//!
+//! When the slots run out the counter region is rewritten with the new
+//! value in the first slot. The rewrite goes through
+//! [`hal_user_page_write`](FlashWriterEraser::hal_user_page_write), which
+//! keeps a copy of the page until it is done, so a power loss leaves either
+//! every old slot or the new value and the counter never goes back.
+
+use crate::atsam4l::atsam4lc8c_constants::{UP_COUNTER_OFFSET, UP_COUNTER_SIZE};
+use crate::atsam4l::{FlashError, FlashWriterEraser, Hflashc, TraceSink};
+use crate::image::ImageHeader;
+
+const SLOT_SIZE: u32 = 8;
+/// Number of raises before the user page has to be erased.
+pub const SLOTS: u32 = UP_COUNTER_SIZE / SLOT_SIZE;
+
+/// Why an image was refused.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RollbackError {
+    /// The image is older than the security counter.
+    Rollback { version: u32, counter: u32 },
+    /// The counter could not be read or written.
+    Flash(FlashError),
+}
+
+impl From<FlashError> for RollbackError {
+    fn from(e: FlashError) -> Self {
+        RollbackError::Flash(e)
+    }
+}
+
+/// The security counter in the user page.
+pub struct SecurityCounter<'a, H: Hflashc, T: TraceSink> {
+    flash: &'a FlashWriterEraser<H, T>,
+}
+
+enum Slot {
+    Erased,
+    Value(u32),
+    Torn,
+}
+
+impl<'a, H: Hflashc, T: TraceSink> SecurityCounter<'a, H, T> {
+    pub fn new(flash: &'a FlashWriterEraser<H, T>) -> Self {
+        SecurityCounter { flash }
+    }
+
+    fn slot(&self, index: u32) -> Result<Slot, FlashError> {
+        let mut raw = [0u8; SLOT_SIZE as usize];
+        self.flash
+            .hal_user_page_read(UP_COUNTER_OFFSET + index * SLOT_SIZE, &mut raw)?;
+        let value = u32::from_le_bytes([raw[0], raw[1], raw[2], raw[3]]);
+        let check = u32::from_le_bytes([raw[4], raw[5], raw[6], raw[7]]);
+        Ok(if raw.iter().all(|b| *b == 0xFF) {
+            Slot::Erased
+        } else if value == !check {
+            Slot::Value(value)
+        } else {
+            Slot::Torn
+        })
+    }
+
+    /// This method is used to read the counter
+    ///
+    /// Returns:
+    /// -  the highest value in a complete slot, 0 if there is none
+    pub fn value(&self) -> Result<u32, FlashError> {
+        let mut value = 0;
+        for index in 0..SLOTS {
+            if let Slot::Value(v) = self.slot(index)? {
+                value = value.max(v);
+            }
+        }
+        Ok(value)
+    }
+
+    /// This method is used to check an image against the counter
+    ///
+    /// Call this before installing an image and again before booting it.
+    ///
+    /// Method arguments:
+    /// -   header: header of the image
+    ///
+    /// Returns:
+    /// -  `RollbackError::Rollback` if the image version is below the counter
+    pub fn check(&self, header: &ImageHeader) -> Result<(), RollbackError> {
+        let counter = self.value()?;
+        let version = header.firmware_version();
+        if version < counter {
+            return Err(RollbackError::Rollback { version, counter });
+        }
+        Ok(())
+    }
+
+    /// This method is used to raise the counter
+    ///
+    /// Call this once an image is installed and confirmed, so older images
+    /// can no longer be installed. Lowering the counter is not possible.
+    ///
+    /// Method arguments:
+    /// -   version: the new counter value
+    ///
+    /// Returns:
+    /// -  `Ok` if the counter is at least `version` afterwards
+    pub fn raise(&self, version: u32) -> Result<(), FlashError> {
+        if version <= self.value()? {
+            return Ok(());
+        }
+        for index in 0..SLOTS {
+            if let Slot::Erased = self.slot(index)? {
+                return self.program_slot(index, version);
+            }
+        }
+        self.compact(version)
+    }
+
+    fn program_slot(&self, index: u32, value: u32) -> Result<(), FlashError> {
+        let mut raw = [0u8; SLOT_SIZE as usize];
+        raw[..4].copy_from_slice(&value.to_le_bytes());
+        raw[4..].copy_from_slice(&(!value).to_le_bytes());
+        self.flash
+            .hal_user_page_program(UP_COUNTER_OFFSET + index * SLOT_SIZE, &raw)
+    }
+
+    /// Rewrites the counter region with `value` in slot 0 and the other
+    /// slots erased.
+    fn compact(&self, value: u32) -> Result<(), FlashError> {
+        let mut region = [0xFFu8; UP_COUNTER_SIZE as usize];
+        region[..4].copy_from_slice(&value.to_le_bytes());
+        region[4..SLOT_SIZE as usize].copy_from_slice(&(!value).to_le_bytes());
+        self.flash.hal_user_page_write(UP_COUNTER_OFFSET, &region)
+    }
+}
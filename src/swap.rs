@@ -0,0 +1,193 @@
+//! Power-fail-safe exchange of the BOOT and UPDATE partitions.
+//!
+//! Installing an update swaps the two partitions page by page through the
+//! first page of the SWAP partition, in three steps per page:
+//!
+//! 1. UPDATE page -> scratch
+//! 2. BOOT page -> UPDATE page
+//! 3. scratch -> BOOT page
+//!
+//! Each step only overwrites a page whose contents are also held somewhere
+//! the step does not touch, so a step interrupted by a reset can simply be
+//! run again. After every step one byte of the progress log, kept in the
+//! remaining SWAP pages, is programmed from 0xFF to 0x00; on the next boot
+//! [`Swap::resume`] counts those bytes and carries on with the first step
+//! that was not recorded. Recording needs no erase, so the log is only
+//! erased when a new swap starts.
+//!
+//! Swapping is its own inverse: starting a second swap after the first one
+//! completed puts the previous image back into BOOT, which is how a failed
+//! update is rolled back.
+
+use crate::atsam4l::atsam4lc8c_constants::FLASH_PAGE_SIZE;
+use crate::atsam4l::partition::{Layout, Partition};
+use crate::atsam4l::{FlashError, FlashWriterEraser, Hflashc, TraceSink};
+
+const PAGE: usize = FLASH_PAGE_SIZE as usize;
+/// Written at the start of the progress log once it has been erased.
+const LOG_MAGIC: [u8; 8] = *b"RBSWAP01";
+const STEPS_PER_PAGE: u32 = 3;
+/// Offset of the first progress byte in the SWAP partition.
+const LOG_STEPS: u32 = FLASH_PAGE_SIZE + LOG_MAGIC.len() as u32;
+
+/// Why a [`Swap`] cannot be set up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SwapError {
+    /// BOOT and UPDATE differ in size.
+    SizeMismatch,
+    /// The SWAP partition cannot hold a scratch page plus one progress
+    /// byte per step.
+    LogTooSmall,
+}
+
+/// Progress of the swap recorded in flash.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SwapState {
+    /// No swap has been started since the log was last erased.
+    Idle,
+    /// `step` steps have completed and the rest still have to run.
+    InProgress { step: u32 },
+    /// Every step has completed.
+    Complete,
+}
+
+/// Swaps two equally sized partitions through a scratch partition.
+pub struct Swap<'a, H, T> {
+    flash: &'a FlashWriterEraser<H, T>,
+    boot: Partition,
+    update: Partition,
+    swap: Partition,
+}
+
+impl<'a, H: Hflashc, T: TraceSink> Swap<'a, H, T> {
+    /// Sets up a swap between `boot` and `update`, using `swap` for the
+    /// scratch page and the progress log.
+    pub fn new(
+        flash: &'a FlashWriterEraser<H, T>,
+        boot: Partition,
+        update: Partition,
+        swap: Partition,
+    ) -> Result<Self, SwapError> {
+        if boot.size() != update.size() {
+            return Err(SwapError::SizeMismatch);
+        }
+        if swap.size() < LOG_STEPS || swap.size() - LOG_STEPS < boot.page_count() * STEPS_PER_PAGE {
+            return Err(SwapError::LogTooSmall);
+        }
+        Ok(Swap {
+            flash,
+            boot,
+            update,
+            swap,
+        })
+    }
+
+    /// Sets up a swap between the BOOT and UPDATE partitions of `layout`.
+    pub fn from_layout(
+        flash: &'a FlashWriterEraser<H, T>,
+        layout: &Layout,
+    ) -> Result<Self, SwapError> {
+        Swap::new(flash, layout.boot, layout.update, layout.swap)
+    }
+
+    /// Returns the number of steps in a complete swap.
+    pub fn total_steps(&self) -> u32 {
+        self.boot.page_count() * STEPS_PER_PAGE
+    }
+
+    /// Reads the progress log.
+    pub fn state(&self) -> Result<SwapState, FlashError> {
+        let mut magic = [0u8; 8];
+        self.swap.read(self.flash, FLASH_PAGE_SIZE, &mut magic)?;
+        if magic != LOG_MAGIC {
+            return Ok(SwapState::Idle);
+        }
+
+        // Only a fully programmed byte counts; one left half-programmed by a
+        // reset marks a step that has to run again.
+        let total = self.total_steps();
+        let mut done = 0;
+        let mut buf = [0u8; 64];
+        while done < total {
+            let len = core::cmp::min(buf.len() as u32, total - done);
+            let buf = &mut buf[..len as usize];
+            self.swap.read(self.flash, LOG_STEPS + done, buf)?;
+            match buf.iter().position(|b| *b != 0x00) {
+                Some(i) => {
+                    return Ok(SwapState::InProgress {
+                        step: done + i as u32,
+                    })
+                }
+                None => done += len,
+            }
+        }
+        Ok(SwapState::Complete)
+    }
+
+    /// Starts a new swap and runs it to completion.
+    ///
+    /// If this is interrupted before the log has been set up, [`resume`]
+    /// reports [`SwapState::Idle`] and nothing has been swapped yet, so the
+    /// swap has to be started again.
+    ///
+    /// [`resume`]: Swap::resume
+    pub fn start(&self) -> Result<(), FlashError> {
+        // Pages are erased in order, so the magic on the first log page is
+        // gone before any progress byte is touched.
+        self.swap.erase(
+            self.flash,
+            FLASH_PAGE_SIZE,
+            self.swap.size() - FLASH_PAGE_SIZE,
+        )?;
+        self.swap.write(self.flash, FLASH_PAGE_SIZE, &LOG_MAGIC)?;
+        self.run(0)
+    }
+
+    /// Erases the log magic so the state reads [`SwapState::Idle`].
+    ///
+    /// Callers that record a decision to swap elsewhere clear the log first,
+    /// so that a completed earlier swap is not mistaken for the new one.
+    pub fn clear(&self) -> Result<(), FlashError> {
+        self.swap
+            .erase(self.flash, FLASH_PAGE_SIZE, FLASH_PAGE_SIZE)
+    }
+
+    /// Finishes a swap that was interrupted by a reset.
+    ///
+    /// Call this early on every boot. Returns the state after resuming,
+    /// which is never [`SwapState::InProgress`].
+    pub fn resume(&self) -> Result<SwapState, FlashError> {
+        match self.state()? {
+            SwapState::InProgress { step } => {
+                self.run(step)?;
+                Ok(SwapState::Complete)
+            }
+            state => Ok(state),
+        }
+    }
+
+    fn run(&self, first_step: u32) -> Result<(), FlashError> {
+        for step in first_step..self.total_steps() {
+            let page = (step / STEPS_PER_PAGE) * FLASH_PAGE_SIZE;
+            match step % STEPS_PER_PAGE {
+                0 => self.copy(self.update, page, self.swap, 0)?,
+                1 => self.copy(self.boot, page, self.update, page)?,
+                _ => self.copy(self.swap, 0, self.boot, page)?,
+            }
+            self.swap.program(self.flash, LOG_STEPS + step, &[0x00])?;
+        }
+        Ok(())
+    }
+
+    fn copy(
+        &self,
+        from: Partition,
+        from_offset: u32,
+        to: Partition,
+        to_offset: u32,
+    ) -> Result<(), FlashError> {
+        let mut page = [0u8; PAGE];
+        from.read(self.flash, from_offset, &mut page)?;
+        to.write(self.flash, to_offset, &page)
+    }
+}
@@ -0,0 +1,254 @@
+//! Trial boot with automatic rollback.
+//!
+//! A newly installed image first runs in a testing state. The application
+//! calls [`confirm_update`] once it is satisfied it works; if it has not
+//! done so after `max_attempts` boots, the bootloader swaps the previous
+//! image back into BOOT.
+//!
+//! The state lives in the user page as a row of 16-byte records, one per
+//! update. Byte 0 of a record marks it staged (`New`), byte 1 `Testing`,
+//! byte 2 `Confirmed` and byte 3 `Failed`; a marker counts once it is fully
+//! programmed to 0x00. Bytes 4..8 count boot attempts in unary, one cleared
+//! bit per attempt. Every transition only clears bits, so the user page is
+//! erased only when all records are used. Staging then rewrites the records
+//! with [`hal_user_page_write`](FlashWriterEraser::hal_user_page_write),
+//! which survives a power loss without losing the rest of the user page.
+//!
+//! The swap log tells the bootloader how far a swap got. Before recording a
+//! decision to swap, the log is cleared, so a `New` or `Failed` record with
+//! an idle log means the swap has not started, and with a complete log
+//! that it has finished.
+
+use crate::atsam4l::atsam4lc8c_constants::{UP_BOOT_OFFSET, UP_BOOT_SIZE};
+use crate::atsam4l::{FlashError, FlashWriterEraser, Hflashc, TraceSink};
+use crate::swap::{Swap, SwapState};
+
+const RECORD_SIZE: u32 = 16;
+const RECORDS: u32 = UP_BOOT_SIZE / RECORD_SIZE;
+const NEW: usize = 0;
+const TESTING: usize = 1;
+const CONFIRMED: usize = 2;
+const FAILED: usize = 3;
+const ATTEMPTS: usize = 4;
+
+/// Most boot attempts the record can count.
+pub const MAX_ATTEMPTS: u8 = 32;
+/// Boot attempts an update gets unless configured otherwise.
+pub const DEFAULT_ATTEMPTS: u8 = 3;
+
+/// State of the most recent update.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BootState {
+    /// No update has been staged.
+    None,
+    /// An update is in UPDATE and waits to be swapped into BOOT.
+    New,
+    /// The update is in BOOT and has been booted `attempts` times without
+    /// being confirmed.
+    Testing { attempts: u8 },
+    /// The application confirmed the update.
+    Confirmed,
+    /// The update was not confirmed in time and is being or has been
+    /// swapped back out.
+    Failed,
+}
+
+struct Record {
+    index: u32,
+    raw: [u8; RECORD_SIZE as usize],
+}
+
+impl Record {
+    fn state(&self) -> BootState {
+        let set = |byte: usize| self.raw[byte] == 0x00;
+        if set(FAILED) {
+            BootState::Failed
+        } else if set(CONFIRMED) {
+            BootState::Confirmed
+        } else if set(TESTING) {
+            let mut count = [0u8; 4];
+            count.copy_from_slice(&self.raw[ATTEMPTS..ATTEMPTS + 4]);
+            BootState::Testing {
+                attempts: u32::from_le_bytes(count).count_zeros() as u8,
+            }
+        } else if set(NEW) {
+            BootState::New
+        } else {
+            BootState::None
+        }
+    }
+}
+
+fn erased(raw: &[u8]) -> bool {
+    raw.iter().all(|b| *b == 0xFF)
+}
+
+/// Returns the last record in use, if any.
+fn current<H: Hflashc, T: TraceSink>(
+    flash: &FlashWriterEraser<H, T>,
+) -> Result<Option<Record>, FlashError> {
+    let mut last = None;
+    for index in 0..RECORDS {
+        let mut raw = [0u8; RECORD_SIZE as usize];
+        flash.hal_user_page_read(UP_BOOT_OFFSET + index * RECORD_SIZE, &mut raw)?;
+        if erased(&raw) {
+            break;
+        }
+        last = Some(Record { index, raw });
+    }
+    Ok(last)
+}
+
+fn mark<H: Hflashc, T: TraceSink>(
+    flash: &FlashWriterEraser<H, T>,
+    record: &Record,
+    byte: usize,
+) -> Result<(), FlashError> {
+    let offset = UP_BOOT_OFFSET + record.index * RECORD_SIZE + byte as u32;
+    flash.hal_user_page_program(offset, &[0x00])
+}
+
+/// This method is used to read the state of the most recent update
+///
+/// Method arguments:
+/// -   flash: driver used to read the user page
+///
+/// Returns:
+/// -  `BootState::None` if no update was ever staged
+pub fn boot_state<H: Hflashc, T: TraceSink>(
+    flash: &FlashWriterEraser<H, T>,
+) -> Result<BootState, FlashError> {
+    Ok(current(flash)?.map_or(BootState::None, |r| r.state()))
+}
+
+/// This method is used by the application to accept the running update
+///
+/// Does nothing unless the update is being tested, so it is safe to call
+/// on every start.
+///
+/// Method arguments:
+/// -   flash: driver used to program the user page
+///
+/// Returns:
+/// -  the state after confirming
+pub fn confirm_update<H: Hflashc, T: TraceSink>(
+    flash: &FlashWriterEraser<H, T>,
+) -> Result<BootState, FlashError> {
+    match current(flash)? {
+        Some(record) => {
+            if let BootState::Testing { .. } = record.state() {
+                mark(flash, &record, CONFIRMED)?;
+                return Ok(BootState::Confirmed);
+            }
+            Ok(record.state())
+        }
+        None => Ok(BootState::None),
+    }
+}
+
+/// Drives an update through staging, testing and confirmation or rollback.
+pub struct TrialBoot<'a, H, T> {
+    flash: &'a FlashWriterEraser<H, T>,
+    swap: Swap<'a, H, T>,
+    max_attempts: u8,
+}
+
+impl<'a, H: Hflashc, T: TraceSink> TrialBoot<'a, H, T> {
+    /// Sets up trial boots that roll back after `max_attempts` unconfirmed
+    /// boots, capped at [`MAX_ATTEMPTS`].
+    pub fn new(flash: &'a FlashWriterEraser<H, T>, swap: Swap<'a, H, T>, max_attempts: u8) -> Self {
+        TrialBoot {
+            flash,
+            swap,
+            max_attempts: max_attempts.clamp(1, MAX_ATTEMPTS),
+        }
+    }
+
+    /// This method is used to stage the image in UPDATE for installation
+    ///
+    /// Call this once the image has been written to UPDATE and verified.
+    /// The swap happens on the next call to [`boot`](TrialBoot::boot).
+    ///
+    /// Returns:
+    /// -  `Ok` once the new record is in flash
+    pub fn stage_update(&self) -> Result<(), FlashError> {
+        self.swap.clear()?;
+        let index = match current(self.flash)? {
+            Some(record) if record.index + 1 < RECORDS => record.index + 1,
+            Some(_) => {
+                // start over with a staged first record, in one rewrite
+                let mut records = [0xFFu8; UP_BOOT_SIZE as usize];
+                records[NEW] = 0x00;
+                return self.flash.hal_user_page_write(UP_BOOT_OFFSET, &records);
+            }
+            None => 0,
+        };
+        let record = Record {
+            index,
+            raw: [0xFF; RECORD_SIZE as usize],
+        };
+        mark(self.flash, &record, NEW)
+    }
+
+    /// This method is used by the bootloader before starting BOOT
+    ///
+    /// Completes any swap in progress, counts this boot as an attempt for
+    /// an update under test and rolls the update back once it runs out of
+    /// attempts. Call it on every boot, then boot the BOOT partition.
+    ///
+    /// Returns:
+    /// -  the state of the image that is now in BOOT
+    pub fn boot(&self) -> Result<BootState, FlashError> {
+        let record = match current(self.flash)? {
+            Some(record) => record,
+            None => {
+                self.swap.resume()?;
+                return Ok(BootState::None);
+            }
+        };
+        match record.state() {
+            BootState::New => {
+                self.finish_swap()?;
+                mark(self.flash, &record, TESTING)?;
+                self.count_attempt(&record, 0)?;
+                Ok(BootState::Testing { attempts: 1 })
+            }
+            BootState::Testing { attempts } if attempts >= self.max_attempts => {
+                self.swap.clear()?;
+                mark(self.flash, &record, FAILED)?;
+                self.swap.start()?;
+                Ok(BootState::Failed)
+            }
+            BootState::Testing { attempts } => {
+                self.count_attempt(&record, attempts)?;
+                Ok(BootState::Testing {
+                    attempts: attempts + 1,
+                })
+            }
+            BootState::Failed => {
+                self.finish_swap()?;
+                Ok(BootState::Failed)
+            }
+            state => {
+                self.swap.resume()?;
+                Ok(state)
+            }
+        }
+    }
+
+    /// Runs the recorded swap to completion, starting it if needed.
+    fn finish_swap(&self) -> Result<(), FlashError> {
+        if self.swap.resume()? == SwapState::Idle {
+            self.swap.start()?;
+        }
+        Ok(())
+    }
+
+    fn count_attempt(&self, record: &Record, attempts: u8) -> Result<(), FlashError> {
+        // clear the lowest `attempts + 1` bits
+        let count = !(u32::MAX >> (31 - attempts as u32));
+        let offset = UP_BOOT_OFFSET + record.index * RECORD_SIZE + ATTEMPTS as u32;
+        self.flash
+            .hal_user_page_program(offset, &count.to_le_bytes())
+    }
+}
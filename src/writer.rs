@@ -0,0 +1,136 @@
+//! Buffered page writer for streaming data into a partition.
+//!
+//! Transfer protocols and decoders deliver data in pieces that rarely line
+//! up with 512-byte pages, and every [`Partition::write`] erases the pages
+//! it touches. [`PageWriter`] collects the pieces in a RAM copy of one page
+//! and only erases and programs that page once the data moves on to another
+//! page or the writer is flushed. Bytes that are never written keep their
+//! previous contents, so pieces may arrive in any order; data arriving in
+//! order costs exactly one erase and one program per page.
+
+use crate::atsam4l::atsam4lc8c_constants::FLASH_PAGE_SIZE;
+use crate::atsam4l::partition::Partition;
+use crate::atsam4l::{FlashError, FlashWriterEraser, Hflashc, TraceSink};
+
+const PAGE: usize = FLASH_PAGE_SIZE as usize;
+
+/// Writes a partition through a one-page buffer.
+pub struct PageWriter<'a, H, T> {
+    flash: &'a FlashWriterEraser<H, T>,
+    partition: Partition,
+    buf: [u8; PAGE],
+    /// Offset of the buffered page in the partition, if one is loaded.
+    page: Option<u32>,
+    dirty: bool,
+}
+
+impl<'a, H: Hflashc, T: TraceSink> PageWriter<'a, H, T> {
+    pub fn new(flash: &'a FlashWriterEraser<H, T>, partition: Partition) -> Self {
+        PageWriter {
+            flash,
+            partition,
+            buf: [0xFF; PAGE],
+            page: None,
+            dirty: false,
+        }
+    }
+
+    pub fn partition(&self) -> Partition {
+        self.partition
+    }
+
+    /// This method is used to write data at an offset in the partition
+    ///
+    /// Method arguments:
+    /// -   offset: offset of the first byte in the partition
+    /// -   data: bytes to write
+    ///
+    /// Returns:
+    /// -  `FlashError::OutOfBounds` if the data does not fit the partition
+    pub fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), FlashError> {
+        self.partition.check_range(offset, data.len() as u32)?;
+        let mut offset = offset;
+        let mut data = data;
+        while !data.is_empty() {
+            let page = offset & !(FLASH_PAGE_SIZE - 1);
+            self.load(page)?;
+            let at = (offset - page) as usize;
+            let len = core::cmp::min(PAGE - at, data.len());
+            self.buf[at..at + len].copy_from_slice(&data[..len]);
+            self.dirty = true;
+            offset += len as u32;
+            data = &data[len..];
+        }
+        Ok(())
+    }
+
+    /// This method is used to write data at an absolute flash address
+    ///
+    /// Method arguments:
+    /// -   address: flash address of the first byte
+    /// -   data: bytes to write
+    ///
+    /// Returns:
+    /// -  `FlashError::OutOfBounds` unless the data lies inside the partition
+    pub fn write_at(&mut self, address: u32, data: &[u8]) -> Result<(), FlashError> {
+        let offset = address
+            .checked_sub(self.partition.start())
+            .ok_or(FlashError::OutOfBounds)?;
+        self.write(offset, data)
+    }
+
+    /// This method is used to read back data, including data still in the
+    /// page buffer
+    ///
+    /// Method arguments:
+    /// -   offset: offset of the first byte in the partition
+    /// -   buf: filled with the partition contents as written so far
+    ///
+    /// Returns:
+    /// -  `FlashError::OutOfBounds` if the range does not fit the partition
+    pub fn read(&self, offset: u32, buf: &mut [u8]) -> Result<(), FlashError> {
+        self.partition.check_range(offset, buf.len() as u32)?;
+        let mut offset = offset;
+        let mut buf = buf;
+        while !buf.is_empty() {
+            let page = offset & !(FLASH_PAGE_SIZE - 1);
+            let at = (offset - page) as usize;
+            let len = core::cmp::min(PAGE - at, buf.len());
+            let (head, rest) = buf.split_at_mut(len);
+            if self.page == Some(page) {
+                head.copy_from_slice(&self.buf[at..at + len]);
+            } else {
+                self.partition.read(self.flash, offset, head)?;
+            }
+            offset += len as u32;
+            buf = rest;
+        }
+        Ok(())
+    }
+
+    /// This method is used to program the buffered page
+    ///
+    /// Call this once all data has been written.
+    ///
+    /// Returns:
+    /// -  `Ok` once flash holds everything written so far
+    pub fn flush(&mut self) -> Result<(), FlashError> {
+        if let (Some(page), true) = (self.page, self.dirty) {
+            self.partition.write(self.flash, page, &self.buf)?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+
+    /// Makes `page` the buffered page, writing back the previous one.
+    fn load(&mut self, page: u32) -> Result<(), FlashError> {
+        if self.page == Some(page) {
+            return Ok(());
+        }
+        self.flush()?;
+        self.page = None;
+        self.partition.read(self.flash, page, &mut self.buf)?;
+        self.page = Some(page);
+        Ok(())
+    }
+}
@@ -0,0 +1,365 @@
+//! XMODEM-1K and YMODEM receiver.
+//!
+//! Receives a file over a byte stream such as a USART and streams it into a
+//! partition through a [`PageWriter`]. Only the CRC-16 variants are
+//! supported: the receiver starts the transfer by sending `C` and accepts
+//! both 128-byte (`SOH`) and 1024-byte (`STX`) blocks.
+//!
+//! - a block with a bad CRC or block number complement is NAKed and resent
+//! - a repeat of the previous block, sent because our ACK was lost, is
+//!   ACKed again and otherwise ignored
+//! - a block out of sequence cancels the transfer
+//! - a silent line is NAKed; after `MAX_ERRORS` errors in a row, or two
+//!   `CAN` bytes from the sender, the transfer is abandoned
+//!
+//! XMODEM has no length field, so the last block's padding (0x1A) is
+//! written along with the data. YMODEM sends the file name and size in
+//! block 0; only the first file of a batch is received and data past the
+//! size is dropped.
+
+use crate::atsam4l::{FlashError, Hflashc, TraceSink};
+use crate::writer::PageWriter;
+
+const SOH: u8 = 0x01;
+const STX: u8 = 0x02;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+const CRC_MODE: u8 = b'C';
+
+/// Time allowed between two bytes of a block.
+pub const BYTE_TIMEOUT_MS: u32 = 1_000;
+/// Time allowed for the next block to start.
+pub const BLOCK_TIMEOUT_MS: u32 = 10_000;
+/// Time between two `C`s while waiting for the sender to start.
+pub const START_TIMEOUT_MS: u32 = 3_000;
+/// Errors in a row before the transfer is abandoned.
+pub const MAX_ERRORS: u32 = 10;
+
+/// A bidirectional byte stream, typically a USART.
+pub trait ByteStream {
+    /// Waits up to `timeout_ms` for a byte. Returns `None` on timeout.
+    fn read_byte(&mut self, timeout_ms: u32) -> Option<u8>;
+    fn write_byte(&mut self, byte: u8);
+}
+
+/// Why a transfer failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum XmodemError {
+    /// `MAX_ERRORS` timeouts or bad blocks in a row.
+    TooManyErrors,
+    /// The sender cancelled the transfer.
+    Cancelled,
+    /// A block arrived out of sequence.
+    Sequence,
+    /// YMODEM block 0 is malformed.
+    BadHeader,
+    /// The file does not fit the partition.
+    TooLarge,
+    /// The file could not be written.
+    Flash(FlashError),
+}
+
+impl From<FlashError> for XmodemError {
+    fn from(e: FlashError) -> Self {
+        XmodemError::Flash(e)
+    }
+}
+
+/// The file announced in YMODEM block 0.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FileInfo {
+    name: [u8; 64],
+    name_len: usize,
+    /// Size from the header, if the sender gave one.
+    pub size: Option<u32>,
+    /// Bytes written to the partition.
+    pub received: u32,
+}
+
+impl FileInfo {
+    /// Returns the file name, cut to 64 bytes.
+    pub fn name(&self) -> &[u8] {
+        &self.name[..self.name_len]
+    }
+}
+
+/// CRC-16/XMODEM: polynomial 0x1021, initial value 0.
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                crc << 1 ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+enum Packet {
+    Block { number: u8, len: usize },
+    Eot,
+}
+
+/// Receives files from a [`ByteStream`].
+pub struct Receiver<S> {
+    stream: S,
+    buf: [u8; 1024],
+}
+
+impl<S: ByteStream> Receiver<S> {
+    pub fn new(stream: S) -> Self {
+        Receiver {
+            stream,
+            buf: [0; 1024],
+        }
+    }
+
+    /// Gives back the stream.
+    pub fn release(self) -> S {
+        self.stream
+    }
+
+    /// This method is used to receive a file with XMODEM-1K
+    ///
+    /// Method arguments:
+    /// -   writer: where the file goes, starting at partition offset 0
+    ///
+    /// Returns:
+    /// -  the number of bytes written, padding included
+    pub fn receive_xmodem<H: Hflashc, T: TraceSink>(
+        &mut self,
+        writer: &mut PageWriter<H, T>,
+    ) -> Result<u32, XmodemError> {
+        let first = self.start()?;
+        let received = self.receive_data(first, 1, writer, None)?;
+        self.stream.write_byte(ACK);
+        Ok(received)
+    }
+
+    /// This method is used to receive the first file of a YMODEM batch
+    ///
+    /// Method arguments:
+    /// -   writer: where the file goes, starting at partition offset 0
+    ///
+    /// Returns:
+    /// -  the name and size of the file
+    pub fn receive_ymodem<H: Hflashc, T: TraceSink>(
+        &mut self,
+        writer: &mut PageWriter<H, T>,
+    ) -> Result<FileInfo, XmodemError> {
+        let mut info = match self.start()? {
+            Packet::Block { number: 0, len } => {
+                let info = match self.file_info(len) {
+                    Ok(info) => info,
+                    Err(e) => return Err(self.cancel(e)),
+                };
+                self.stream.write_byte(ACK);
+                info
+            }
+            // a sender with nothing to send ends the batch at once
+            Packet::Eot => {
+                self.stream.write_byte(ACK);
+                return Err(XmodemError::BadHeader);
+            }
+            Packet::Block { .. } => return Err(self.cancel(XmodemError::Sequence)),
+        };
+        if let Some(size) = info.size {
+            if size > writer.partition().size() {
+                return Err(self.cancel(XmodemError::TooLarge));
+            }
+        }
+
+        // the data phase starts with another C
+        let first = self.start()?;
+        info.received = self.receive_data(first, 1, writer, info.size)?;
+
+        // YMODEM has the first EOT NAKed, to be sure it is one
+        self.stream.write_byte(NAK);
+        self.expect_eot()?;
+        self.stream.write_byte(ACK);
+
+        // end of batch: an empty block 0
+        match self.start()? {
+            Packet::Block { number: 0, .. } => self.stream.write_byte(ACK),
+            _ => return Err(self.cancel(XmodemError::Sequence)),
+        }
+        Ok(info)
+    }
+
+    /// Sends `C` until the sender answers with a packet.
+    fn start(&mut self) -> Result<Packet, XmodemError> {
+        for _ in 0..MAX_ERRORS {
+            self.stream.write_byte(CRC_MODE);
+            match self.packet(START_TIMEOUT_MS) {
+                Ok(Some(packet)) => return Ok(packet),
+                Ok(None) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Err(self.cancel(XmodemError::TooManyErrors))
+    }
+
+    /// Receives data blocks from `first` until EOT, without acknowledging
+    /// the EOT.
+    fn receive_data<H: Hflashc, T: TraceSink>(
+        &mut self,
+        first: Packet,
+        mut expected: u8,
+        writer: &mut PageWriter<H, T>,
+        size: Option<u32>,
+    ) -> Result<u32, XmodemError> {
+        let capacity = writer.partition().size();
+        let limit = size.unwrap_or(capacity);
+        let mut offset = 0u32;
+        let mut packet = Some(first);
+        let mut errors = 0;
+        loop {
+            match packet {
+                Some(Packet::Eot) => {
+                    writer.flush()?;
+                    return Ok(offset);
+                }
+                Some(Packet::Block { number, len }) if number == expected => {
+                    if offset + len as u32 > capacity && size.is_none() {
+                        return Err(self.cancel(XmodemError::TooLarge));
+                    }
+                    let keep = core::cmp::min(len as u32, limit.saturating_sub(offset));
+                    // blocks past the declared size are padding, ACK them all the same
+                    if keep > 0 {
+                        if let Err(e) = writer.write(offset, &self.buf[..keep as usize]) {
+                            return Err(self.cancel(e.into()));
+                        }
+                    }
+                    offset += keep;
+                    expected = expected.wrapping_add(1);
+                    errors = 0;
+                    self.stream.write_byte(ACK);
+                }
+                // our ACK was lost and the sender repeats the last block
+                Some(Packet::Block { number, .. }) if number == expected.wrapping_sub(1) => {
+                    self.stream.write_byte(ACK);
+                }
+                Some(Packet::Block { .. }) => return Err(self.cancel(XmodemError::Sequence)),
+                None => {
+                    errors += 1;
+                    if errors >= MAX_ERRORS {
+                        return Err(self.cancel(XmodemError::TooManyErrors));
+                    }
+                    self.stream.write_byte(NAK);
+                }
+            }
+            packet = self.packet(BLOCK_TIMEOUT_MS)?;
+        }
+    }
+
+    fn expect_eot(&mut self) -> Result<(), XmodemError> {
+        for _ in 0..MAX_ERRORS {
+            match self.packet(BLOCK_TIMEOUT_MS)? {
+                Some(Packet::Eot) => return Ok(()),
+                _ => self.stream.write_byte(NAK),
+            }
+        }
+        Err(self.cancel(XmodemError::TooManyErrors))
+    }
+
+    /// Reads one packet into `buf`.
+    ///
+    /// Returns `None` if nothing valid arrived in time; the line is drained
+    /// first so the retransmission starts clean.
+    fn packet(&mut self, timeout_ms: u32) -> Result<Option<Packet>, XmodemError> {
+        let len = match self.stream.read_byte(timeout_ms) {
+            Some(SOH) => 128,
+            Some(STX) => 1024,
+            Some(EOT) => return Ok(Some(Packet::Eot)),
+            Some(CAN) => {
+                if self.byte() == Some(CAN) {
+                    return Err(XmodemError::Cancelled);
+                }
+                return Ok(None);
+            }
+            Some(_) => {
+                self.drain();
+                return Ok(None);
+            }
+            None => return Ok(None),
+        };
+
+        let (number, complement) = match (self.byte(), self.byte()) {
+            (Some(n), Some(c)) => (n, c),
+            _ => return Ok(None),
+        };
+        for i in 0..len {
+            match self.byte() {
+                Some(b) => self.buf[i] = b,
+                None => return Ok(None),
+            }
+        }
+        let crc = match (self.byte(), self.byte()) {
+            (Some(hi), Some(lo)) => u16::from_be_bytes([hi, lo]),
+            _ => return Ok(None),
+        };
+        if number != !complement || crc != crc16(&self.buf[..len]) {
+            self.drain();
+            return Ok(None);
+        }
+        Ok(Some(Packet::Block { number, len }))
+    }
+
+    fn byte(&mut self) -> Option<u8> {
+        self.stream.read_byte(BYTE_TIMEOUT_MS)
+    }
+
+    fn drain(&mut self) {
+        while self.byte().is_some() {}
+    }
+
+    fn file_info(&self, len: usize) -> Result<FileInfo, XmodemError> {
+        let block = &self.buf[..len];
+        let name_len = block
+            .iter()
+            .position(|b| *b == 0)
+            .ok_or(XmodemError::BadHeader)?;
+        if name_len == 0 {
+            return Err(XmodemError::BadHeader);
+        }
+        let mut name = [0u8; 64];
+        let kept = core::cmp::min(name_len, name.len());
+        name[..kept].copy_from_slice(&block[..kept]);
+
+        let mut size = None;
+        for byte in block[name_len + 1..].iter() {
+            match byte {
+                b'0'..=b'9' => {
+                    let digit = (byte - b'0') as u32;
+                    size = Some(
+                        size.unwrap_or(0u32)
+                            .checked_mul(10)
+                            .and_then(|s: u32| s.checked_add(digit))
+                            .ok_or(XmodemError::BadHeader)?,
+                    );
+                }
+                _ => break,
+            }
+        }
+        Ok(FileInfo {
+            name,
+            name_len: kept,
+            size,
+            received: 0,
+        })
+    }
+
+    /// Tells the sender to stop and returns `error`.
+    fn cancel(&mut self, error: XmodemError) -> XmodemError {
+        for _ in 0..3 {
+            self.stream.write_byte(CAN);
+        }
+        error
+    }
+}
@@ -0,0 +1,65 @@
+use atsamblinky::atsam4l::atsam4lc8c_constants::*;
+use atsamblinky::atsam4l::boot::{check_vector_table, BootError};
+use atsamblinky::atsam4l::sim::SimFlash;
+use atsamblinky::atsam4l::{FlashAddress, FlashRange, FlashWriterEraser};
+
+const IMAGE: u32 = 0x0001_0000;
+
+fn flash_with_vectors(sp: u32, rv: u32) -> FlashWriterEraser<SimFlash> {
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+    let mut table = [0u8; 8];
+    table[..4].copy_from_slice(&sp.to_le_bytes());
+    table[4..].copy_from_slice(&rv.to_le_bytes());
+    flash
+        .hal_flash_write(FlashAddress::new(IMAGE).unwrap(), &table)
+        .unwrap();
+    flash
+}
+
+fn image(len: u32) -> FlashRange {
+    FlashRange::new(FlashAddress::new(IMAGE).unwrap(), len).unwrap()
+}
+
+#[test]
+fn accepts_a_valid_vector_table() {
+    let flash = flash_with_vectors(STACK_UP, IMAGE + 0x1C1);
+
+    let vt = check_vector_table(&flash, image(0x4000)).unwrap();
+
+    assert_eq!(vt.initial_sp.get(), STACK_UP);
+    assert_eq!(vt.reset_vector, IMAGE + 0x1C1);
+}
+
+#[test]
+fn rejects_a_stack_outside_sram() {
+    for sp in [0x2002_0000, 0x1FFF_FFFC, 0x2000_8002, 0xFFFF_FFFF] {
+        let flash = flash_with_vectors(sp, IMAGE + 0x1C1);
+        assert_eq!(
+            check_vector_table(&flash, image(0x4000)),
+            Err(BootError::StackPointer(sp))
+        );
+    }
+}
+
+#[test]
+fn rejects_a_reset_vector_outside_the_image() {
+    // past the end, inside the vector table, and an ARM-state address
+    for rv in [IMAGE + 0x4001, IMAGE + 0x5, IMAGE + 0x1C0] {
+        let flash = flash_with_vectors(STACK_UP, rv);
+        assert_eq!(
+            check_vector_table(&flash, image(0x4000)),
+            Err(BootError::ResetVector(rv))
+        );
+    }
+}
+
+#[test]
+fn rejects_a_misaligned_vector_table() {
+    let flash = flash_with_vectors(STACK_UP, IMAGE + 0x1C1);
+    let image = FlashRange::new(FlashAddress::new(IMAGE + 0x40).unwrap(), 0x4000).unwrap();
+
+    assert_eq!(
+        check_vector_table(&flash, image),
+        Err(BootError::VectorTableAlignment(IMAGE + 0x40))
+    );
+}
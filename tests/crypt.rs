@@ -0,0 +1,152 @@
+use atsamblinky::atsam4l::atsam4lc8c_constants::*;
+use atsamblinky::atsam4l::partition::LAYOUT;
+use atsamblinky::atsam4l::sim::{PowerFault, SimFlash, TraceLog};
+use atsamblinky::atsam4l::{Command, FlashWriterEraser};
+use atsamblinky::crypt::{load_key, store_key, CryptError, Ctr, Decryptor, SoftAes, MAGIC};
+use atsamblinky::writer::PageWriter;
+
+fn hex(s: &str) -> Vec<u8> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+const KEY: [u8; 16] = [
+    0x2b, 0x7e, 0x15, 0x16, 0x28, 0xae, 0xd2, 0xa6, 0xab, 0xf7, 0x15, 0x88, 0x09, 0xcf, 0x4f, 0x3c,
+];
+
+#[test]
+fn ctr_matches_sp800_38a() {
+    // F.5.1 CTR-AES128.Encrypt
+    let mut iv = [0u8; 16];
+    iv.copy_from_slice(&hex("f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff"));
+    let mut data = hex(concat!(
+        "6bc1bee22e409f96e93d7e117393172a",
+        "ae2d8a571e03ac9c9eb76fac45af8e51",
+        "30c81c46a35ce411e5fbc1191a0a52ef",
+        "f69f2445df4f9b17ad2b417be66c3710"
+    ));
+    let expected = hex(concat!(
+        "874d6191b620e3261bef6864990db6ce",
+        "9806f66b7970fdff8617187bb9fffdff",
+        "5ae4df3edbd5d35e5b4f09020db03eab",
+        "1e031dda2fbe03d1792170a0f3009cee"
+    ));
+
+    // in uneven pieces, so the keystream carries across calls
+    let mut ctr = Ctr::new(SoftAes::new(&KEY), &iv);
+    let (a, rest) = data.split_at_mut(5);
+    let (b, c) = rest.split_at_mut(30);
+    ctr.apply(a);
+    ctr.apply(b);
+    ctr.apply(c);
+    assert_eq!(data, expected);
+}
+
+#[test]
+fn key_is_stored_with_its_complement() {
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+    assert_eq!(load_key(&flash), Err(CryptError::NoKey));
+
+    flash
+        .hal_user_page_program(UP_BOOT_OFFSET, &[0x12; 4])
+        .unwrap();
+    store_key(&flash, &KEY).unwrap();
+    assert_eq!(load_key(&flash), Ok(KEY));
+    // the rest of the user page is untouched
+    assert_eq!(
+        &flash.nvm.user_page()[UP_BOOT_OFFSET as usize..][..4],
+        &[0x12; 4]
+    );
+
+    let mut other = KEY;
+    other[0] ^= 0xFF;
+    store_key(&flash, &other).unwrap();
+    assert_eq!(load_key(&flash), Ok(other));
+
+    // a damaged key is not used
+    flash
+        .hal_user_page_program(UP_KEY_OFFSET + 3, &[0x00])
+        .unwrap();
+    assert_eq!(load_key(&flash), Err(CryptError::NoKey));
+}
+
+#[test]
+fn provisioning_only_programs_and_replacing_survives_power_loss() {
+    let log = TraceLog::new();
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new()).with_trace(&log);
+    store_key(&flash, &KEY).unwrap();
+    assert!(log.commands().iter().all(|(c, _)| *c != Command::Eup));
+
+    let mut other = KEY;
+    other[0] ^= 0xFF;
+    let provisioned = || {
+        let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+        flash.hal_user_page_program(0, &[0x12, 0x34]).unwrap();
+        store_key(&flash, &KEY).unwrap();
+        flash
+    };
+    let flash = provisioned();
+    let before = flash.nvm.command_count();
+    store_key(&flash, &other).unwrap();
+    let commands = flash.nvm.command_count() - before;
+
+    for n in 0..commands {
+        for fault in [
+            PowerFault::AfterCommands(n),
+            PowerFault::DuringCommand { n, bytes: 3 },
+            PowerFault::DuringCommand { n, bytes: 300 },
+        ] {
+            let flash = provisioned();
+            flash.nvm.inject_power_loss(fault);
+            let _ = store_key(&flash, &other);
+            flash.nvm.power_cycle();
+
+            let key = load_key(&flash);
+            assert!(key == Ok(KEY) || key == Ok(other), "{:?}", fault);
+            let mut fuses = [0u8; 2];
+            flash.hal_user_page_read(0, &mut fuses).unwrap();
+            assert_eq!(fuses, [0x12, 0x34], "{:?}", fault);
+        }
+    }
+}
+
+#[test]
+fn images_are_decrypted_while_programming() {
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+    let image: Vec<u8> = (0..3000u32).map(|i| (i * 31 + 7) as u8).collect();
+    let iv = [0x42; 16];
+    let mut encrypted = MAGIC.to_vec();
+    encrypted.extend_from_slice(&iv);
+    let mut ciphertext = image.clone();
+    Ctr::new(SoftAes::new(&KEY), &iv).apply(&mut ciphertext);
+    encrypted.extend_from_slice(&ciphertext);
+
+    for piece in [1, 7, 200, encrypted.len()] {
+        let mut writer = PageWriter::new(&flash, LAYOUT.update);
+        let mut decryptor = Decryptor::new(SoftAes::new(&KEY));
+        for chunk in encrypted.chunks(piece) {
+            decryptor.feed(chunk, &mut writer).unwrap();
+        }
+        assert_eq!(decryptor.finish(&mut writer), Ok(image.len() as u32));
+        let mut buf = vec![0; image.len()];
+        LAYOUT.update.read(&flash, 0, &mut buf).unwrap();
+        assert_eq!(buf, image);
+    }
+
+    let mut writer = PageWriter::new(&flash, LAYOUT.update);
+    let mut decryptor = Decryptor::new(SoftAes::new(&KEY));
+    assert_eq!(
+        decryptor.feed(b"RBX1", &mut writer),
+        Ok(()),
+        "the header is not complete yet"
+    );
+    assert_eq!(
+        decryptor.feed(&[0; 16], &mut writer),
+        Err(CryptError::BadMagic)
+    );
+    let mut decryptor = Decryptor::new(SoftAes::new(&KEY));
+    decryptor.feed(&encrypted[..10], &mut writer).unwrap();
+    assert_eq!(decryptor.finish(&mut writer), Err(CryptError::Truncated));
+}
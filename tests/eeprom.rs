@@ -0,0 +1,137 @@
+use atsamblinky::atsam4l::atsam4lc8c_constants::*;
+use atsamblinky::atsam4l::partition::Partition;
+use atsamblinky::atsam4l::sim::{PowerFault, SimFlash, TraceLog};
+use atsamblinky::atsam4l::{Command, FlashWriterEraser, PageNumber, PartitionKind};
+use atsamblinky::eeprom::{Eeprom, EepromError, ERASED, MAX_WORDS};
+
+const EEPROM: Partition = Partition::new(PartitionKind::Data, 0x7_0000, 2 * FLASH_PAGE_SIZE);
+const WORDS: u16 = 16;
+
+#[test]
+fn words_and_bytes_read_back_after_remount() {
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+    let mut eeprom = Eeprom::mount(&flash, EEPROM, WORDS).unwrap();
+    assert_eq!(eeprom.read(3), Ok(ERASED));
+
+    eeprom.write(3, 0x1234_5678).unwrap();
+    eeprom.write(0, 42).unwrap();
+    eeprom.write(3, 0xCAFE_F00D).unwrap();
+    eeprom.write_byte(4 * 5 + 2, 0xAB).unwrap();
+    assert_eq!(eeprom.read(3), Ok(0xCAFE_F00D));
+    assert_eq!(eeprom.read(5), Ok(0xFFAB_FFFF));
+    assert_eq!(eeprom.read_byte(4 * 3 + 1), Ok(0xF0));
+
+    let eeprom = Eeprom::mount(&flash, EEPROM, WORDS).unwrap();
+    assert_eq!(eeprom.read(0), Ok(42));
+    assert_eq!(eeprom.read(3), Ok(0xCAFE_F00D));
+    assert_eq!(eeprom.read(5), Ok(0xFFAB_FFFF));
+    assert_eq!(eeprom.read(15), Ok(ERASED));
+
+    assert_eq!(eeprom.read(WORDS), Err(EepromError::Address));
+    assert_eq!(
+        eeprom.read_byte(4 * WORDS as u32),
+        Err(EepromError::Address)
+    );
+    assert!(matches!(
+        Eeprom::mount(&flash, EEPROM, MAX_WORDS + 1),
+        Err(EepromError::TooManyWords)
+    ));
+    let one_page = Partition::new(PartitionKind::Data, 0x7_0000, FLASH_PAGE_SIZE);
+    assert!(matches!(
+        Eeprom::mount(&flash, one_page, WORDS),
+        Err(EepromError::TooFewPages)
+    ));
+}
+
+#[test]
+fn page_swaps_keep_every_word_and_spread_erases() {
+    let trace = TraceLog::new();
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new()).with_trace(&trace);
+    let four_pages = Partition::new(PartitionKind::Data, 0x7_0000, 4 * FLASH_PAGE_SIZE);
+    let mut eeprom = Eeprom::mount(&flash, four_pages, MAX_WORDS).unwrap();
+    let mut expected = vec![ERASED; MAX_WORDS as usize];
+    for i in 0..3000u32 {
+        let address = (i * 7 % MAX_WORDS as u32) as u16;
+        eeprom.write(address, i).unwrap();
+        expected[address as usize] = i;
+    }
+
+    let first = four_pages.start() / FLASH_PAGE_SIZE;
+    let erases: Vec<usize> = (0..4)
+        .map(|p| {
+            let page = PageNumber::new(first + p).unwrap();
+            let commands = trace.commands();
+            commands
+                .iter()
+                .filter(|c| **c == (Command::Ep, page))
+                .count()
+        })
+        .collect();
+    let (min, max) = (erases.iter().min().unwrap(), erases.iter().max().unwrap());
+    assert!(*min > 10 && max - min <= 1, "{:?}", erases);
+
+    // writing the same value again is free
+    trace.clear();
+    eeprom.write(0, expected[0]).unwrap();
+    assert!(trace.commands().iter().all(|(c, _)| *c != Command::Wp));
+
+    for eeprom in [
+        eeprom,
+        Eeprom::mount(&flash, four_pages, MAX_WORDS).unwrap(),
+    ] {
+        for (address, value) in expected.iter().enumerate() {
+            assert_eq!(eeprom.read(address as u16), Ok(*value));
+        }
+    }
+}
+
+#[test]
+fn power_loss_at_every_command_keeps_the_last_completed_write() {
+    const WRITES: u32 = 150;
+    let address = |i: u32| (i * 5 % WORDS as u32) as u16;
+    let value = |i: u32| i.wrapping_mul(0x9E37_79B9);
+    // the value of each word after the first `n` writes
+    let after = |n: u32| {
+        let mut words = vec![ERASED; WORDS as usize];
+        for i in 0..n {
+            words[address(i) as usize] = value(i);
+        }
+        words
+    };
+
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+    let mut eeprom = Eeprom::mount(&flash, EEPROM, WORDS).unwrap();
+    let before = flash.nvm.command_count();
+    for i in 0..WRITES {
+        eeprom.write(address(i), value(i)).unwrap();
+    }
+    let commands = flash.nvm.command_count() - before;
+
+    for n in 0..commands {
+        for fault in [
+            PowerFault::AfterCommands(n),
+            PowerFault::DuringCommand { n, bytes: 3 },
+            PowerFault::DuringCommand { n, bytes: 300 },
+        ] {
+            let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+            let mut eeprom = Eeprom::mount(&flash, EEPROM, WORDS).unwrap();
+            flash.nvm.inject_power_loss(fault);
+            let mut done = 0;
+            for i in 0..WRITES {
+                eeprom.write(address(i), value(i)).unwrap();
+                if !flash.nvm.lost_power() {
+                    done = i + 1;
+                }
+            }
+
+            flash.nvm.power_cycle();
+            let eeprom = Eeprom::mount(&flash, EEPROM, WORDS).unwrap();
+            let words: Vec<u32> = (0..WORDS).map(|a| eeprom.read(a).unwrap()).collect();
+            assert!(
+                words == after(done) || words == after(done + 1),
+                "{:?}",
+                fault
+            );
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use atsamblinky::atsam4l::atsam4lc8c_constants::*;
+use atsamblinky::atsam4l::sim::SimFlash;
+use atsamblinky::atsam4l::{FlashAddress, FlashError, FlashRange, FlashWriterEraser};
+
+fn driver() -> FlashWriterEraser<SimFlash> {
+    FlashWriterEraser::with_nvm(SimFlash::new())
+}
+
+#[test]
+fn write_then_read_back() {
+    let flash = driver();
+    let address = FlashAddress::new(0x0004_8300).unwrap();
+    let data: Vec<u8> = (0..39).collect();
+
+    flash.hal_flash_write(address, &data).unwrap();
+
+    let mut buf = [0u8; 39];
+    flash.hal_flash_read(address, &mut buf).unwrap();
+    assert_eq!(&buf[..], &data[..]);
+}
+
+#[test]
+fn write_across_pages() {
+    let flash = driver();
+    let address = FlashAddress::new(3 * FLASH_PAGE_SIZE - 5).unwrap();
+    let data = [0x5Au8; 1030];
+
+    flash.hal_flash_write(address, &data).unwrap();
+
+    let start = address.get() as usize;
+    let contents = flash.nvm.flash();
+    assert_eq!(&contents[start..start + data.len()], &data[..]);
+    assert!(contents[start - 1] == 0xFF && contents[start + data.len()] == 0xFF);
+}
+
+#[test]
+fn erase_clears_every_touched_page() {
+    let flash = driver();
+    let address = FlashAddress::new(FLASH_PAGE_SIZE).unwrap();
+    flash.hal_flash_write(address, &[0u8; 1024]).unwrap();
+
+    flash
+        .hal_flash_erase(FlashRange::new(address.offset(10).unwrap(), 600).unwrap())
+        .unwrap();
+
+    assert!(flash.nvm.flash()[512..1536].iter().all(|b| *b == 0xFF));
+}
+
+#[test]
+fn unaligned_write_lands_on_the_right_pages() {
+    // Regression: the page number used to advance only when the byte count
+    // was a multiple of 512, so a write starting mid-page programmed the
+    // tail of its data into the first page again.
+    let flash = driver();
+    let address = FlashAddress::new(0x0004_8300).unwrap();
+    let data: Vec<u8> = (0..700u32).map(|i| (i % 251) as u8).collect();
+
+    flash.hal_flash_write(address, &data).unwrap();
+
+    flash.hal_flash_verify(address, &data).unwrap();
+    assert_eq!(
+        flash.hal_flash_verify(address, &[0u8; 4]),
+        Err(FlashError::Verify)
+    );
+}
+
+#[test]
+fn rejects_writes_past_the_end_of_flash() {
+    let flash = driver();
+    let address = FlashAddress::new(FLASH_END).unwrap();
+
+    assert_eq!(
+        flash.hal_flash_write(address, &[0, 0]),
+        Err(FlashError::OutOfBounds)
+    );
+    assert_eq!(
+        FlashAddress::new(FLASH_END + 1),
+        Err(FlashError::OutOfBounds)
+    );
+}
+
+#[test]
+fn empty_writes_do_nothing() {
+    let flash = driver();
+    let address = FlashAddress::new(FLASH_END).unwrap();
+    let before = flash.nvm.command_count();
+
+    assert_eq!(flash.hal_flash_write(address, &[]), Ok(()));
+    assert_eq!(flash.hal_flash_program(address, &[]), Ok(()));
+    assert_eq!(flash.hal_flash_read(address, &mut []), Ok(()));
+    assert_eq!(flash.nvm.command_count(), before);
+}
@@ -0,0 +1,225 @@
+use atsamblinky::atsam4l::partition::LAYOUT;
+use atsamblinky::atsam4l::sim::SimFlash;
+use atsamblinky::atsam4l::FlashWriterEraser;
+use atsamblinky::hexfile::{IhexLoader, LoadError, Progress, SrecLoader};
+use atsamblinky::writer::PageWriter;
+
+fn ihex_record(kind: u8, offset: u16, data: &[u8]) -> String {
+    let mut bytes = vec![data.len() as u8, (offset >> 8) as u8, offset as u8, kind];
+    bytes.extend_from_slice(data);
+    let sum = bytes.iter().fold(0u8, |s, b| s.wrapping_add(*b));
+    bytes.push(sum.wrapping_neg());
+    let hex: String = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+    format!(":{}\r\n", hex)
+}
+
+fn srec_record(kind: u8, address: &[u8], data: &[u8]) -> String {
+    let mut bytes = vec![(address.len() + data.len() + 1) as u8];
+    bytes.extend_from_slice(address);
+    bytes.extend_from_slice(data);
+    let sum = bytes.iter().fold(0u8, |s, b| s.wrapping_add(*b));
+    bytes.push(!sum);
+    let hex: String = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+    format!("S{}{}\n", kind, hex)
+}
+
+/// Intel HEX for `data` at `address`, 16 bytes per record, crossing a
+/// 64 KiB boundary when the data does.
+fn to_ihex(address: u32, data: &[u8]) -> String {
+    let mut out = String::new();
+    let mut upper = None;
+    for (i, chunk) in data.chunks(16).enumerate() {
+        let at = address + i as u32 * 16;
+        if upper != Some(at >> 16) {
+            upper = Some(at >> 16);
+            out += &ihex_record(4, 0, &((at >> 16) as u16).to_be_bytes());
+        }
+        out += &ihex_record(0, at as u16, chunk);
+    }
+    out += &ihex_record(5, 0, &(address + 1).to_be_bytes());
+    out += &ihex_record(1, 0, &[]);
+    out
+}
+
+fn pattern(len: usize) -> Vec<u8> {
+    (0..len as u32).map(|i| (i * 13 + 5) as u8).collect()
+}
+
+#[test]
+fn ihex_loads_in_pieces_of_any_size() {
+    // straddles the 0x0005_0000 boundary, so needs two type 04 records
+    let address = 0x0004_FF00;
+    let data = pattern(1200);
+    let text = to_ihex(address, &data);
+
+    for piece in [1, 3, 64, text.len()] {
+        let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+        let mut writer = PageWriter::new(&flash, LAYOUT.update);
+        let mut loader = IhexLoader::new();
+        let mut progress = Progress::NeedMore;
+        for chunk in text.as_bytes().chunks(piece) {
+            progress = loader.feed(chunk, &mut writer).unwrap();
+        }
+        assert_eq!(
+            progress,
+            Progress::Done {
+                entry: Some(address + 1)
+            }
+        );
+        assert_eq!(loader.finish(&mut writer).unwrap(), Some(address + 1));
+
+        let mut buf = vec![0; data.len()];
+        LAYOUT
+            .update
+            .read(&flash, address - LAYOUT.update.start(), &mut buf)
+            .unwrap();
+        assert_eq!(buf, data);
+    }
+}
+
+#[test]
+fn ihex_segment_addresses() {
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+    let mut writer = PageWriter::new(&flash, LAYOUT.update);
+    let mut loader = IhexLoader::new();
+    // an empty data record is skipped, even outside UPDATE; then
+    // 0x4200 << 4 is the start of UPDATE
+    let text = ihex_record(0, 0, &[])
+        + &ihex_record(2, 0, &[0x42, 0x00])
+        + &ihex_record(0, 0x0010, &[1, 2, 3, 4])
+        + &ihex_record(1, 0, &[]);
+    loader.feed(text.as_bytes(), &mut writer).unwrap();
+    loader.finish(&mut writer).unwrap();
+
+    let mut buf = [0; 4];
+    LAYOUT.update.read(&flash, 0x10, &mut buf).unwrap();
+    assert_eq!(buf, [1, 2, 3, 4]);
+}
+
+#[test]
+fn ihex_rejects_bad_records() {
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+    let good = ihex_record(4, 0, &[0x00, 0x04]);
+    let data = ihex_record(0, 0x2000, &[1, 2, 3, 4]);
+
+    let mut corrupt = data.clone().into_bytes();
+    corrupt[9] = if corrupt[9] == b'0' { b'1' } else { b'0' };
+    let short = data.replacen(":04", ":05", 1);
+    let cases = [
+        (
+            good.clone() + &String::from_utf8(corrupt).unwrap(),
+            LoadError::Checksum { line: 2 },
+        ),
+        (good.clone() + &short, LoadError::Length { line: 2 }),
+        (
+            good.clone() + ":00000006FA\r\n",
+            LoadError::UnknownRecord { line: 2, kind: 6 },
+        ),
+        (
+            good.clone() + ":0400G000\r\n",
+            LoadError::BadChar { line: 2 },
+        ),
+        (
+            ihex_record(4, 0, &[0x00, 0x00]) + &ihex_record(0, 0, &[1]),
+            LoadError::Address {
+                line: 2,
+                address: 0,
+            },
+        ),
+    ];
+    for (text, error) in cases {
+        let mut writer = PageWriter::new(&flash, LAYOUT.update);
+        let mut loader = IhexLoader::new();
+        assert_eq!(
+            loader.feed(text.as_bytes(), &mut writer),
+            Err(error),
+            "{}",
+            text
+        );
+    }
+
+    let mut writer = PageWriter::new(&flash, LAYOUT.update);
+    let mut loader = IhexLoader::new();
+    loader.feed((good + &data).as_bytes(), &mut writer).unwrap();
+    assert_eq!(loader.finish(&mut writer), Err(LoadError::Truncated));
+}
+
+#[test]
+fn srec_loads_every_address_width() {
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+    let mut writer = PageWriter::new(&flash, LAYOUT.update);
+    let mut loader = SrecLoader::new();
+    let data = pattern(600);
+    let start = LAYOUT.update.start();
+
+    let mut text = srec_record(0, &[0, 0], b"image");
+    // an empty data record is skipped, even outside UPDATE, but counted
+    text += &srec_record(1, &[0, 0], &[]);
+    let mut records = 1;
+    for (i, chunk) in data.chunks(32).enumerate() {
+        let at = start + i as u32 * 32;
+        text += &match i % 2 {
+            0 => srec_record(3, &at.to_be_bytes(), chunk),
+            _ => srec_record(2, &at.to_be_bytes()[1..], chunk),
+        };
+        records += 1;
+    }
+    text += &srec_record(5, &(records as u16).to_be_bytes(), &[]);
+    text += &srec_record(7, &(start + 1).to_be_bytes(), &[]);
+
+    for chunk in text.as_bytes().chunks(5) {
+        loader.feed(chunk, &mut writer).unwrap();
+    }
+    assert_eq!(loader.finish(&mut writer).unwrap(), Some(start + 1));
+
+    let mut buf = vec![0; data.len()];
+    LAYOUT.update.read(&flash, 0, &mut buf).unwrap();
+    assert_eq!(buf, data);
+}
+
+#[test]
+fn srec_rejects_bad_records() {
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+    let at = LAYOUT.update.start().to_be_bytes();
+    let data = srec_record(3, &at, &[1, 2, 3, 4]);
+
+    let mut corrupt = data.clone().into_bytes();
+    corrupt[12] ^= 0x01;
+    let cases = [
+        (
+            String::from_utf8(corrupt).unwrap(),
+            LoadError::Checksum { line: 1 },
+        ),
+        (
+            data.clone() + &srec_record(5, &[0, 2], &[]),
+            LoadError::Length { line: 2 },
+        ),
+        (
+            srec_record(4, &[0, 0], &[]),
+            LoadError::UnknownRecord { line: 1, kind: 4 },
+        ),
+        (
+            srec_record(1, &[0, 0], &[1]),
+            LoadError::Address {
+                line: 1,
+                address: 0,
+            },
+        ),
+        ("X1030000FC\n".to_string(), LoadError::BadChar { line: 1 }),
+    ];
+    for (text, error) in cases {
+        let mut writer = PageWriter::new(&flash, LAYOUT.update);
+        let mut loader = SrecLoader::new();
+        assert_eq!(
+            loader.feed(text.as_bytes(), &mut writer),
+            Err(error),
+            "{}",
+            text
+        );
+    }
+
+    let mut writer = PageWriter::new(&flash, LAYOUT.update);
+    let mut loader = SrecLoader::new();
+    loader.feed(data.as_bytes(), &mut writer).unwrap();
+    assert_eq!(loader.finish(&mut writer), Err(LoadError::Truncated));
+}
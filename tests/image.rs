@@ -0,0 +1,127 @@
+use atsamblinky::atsam4l::atsam4lc8c_constants::*;
+use atsamblinky::atsam4l::partition::LAYOUT;
+use atsamblinky::atsam4l::sim::SimFlash;
+use atsamblinky::atsam4l::FlashWriterEraser;
+use atsamblinky::image::*;
+
+fn header(image_size: u32) -> HeaderBuilder {
+    let mut builder = HeaderBuilder::new(image_size, 0x0001_0203, 1_700_000_000, HashType::Sha256);
+    builder.push_tlv(TLV_DIGEST, &[0xAB; 32]).unwrap();
+    builder
+}
+
+#[test]
+fn reads_a_header_from_flash() {
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+    let mut builder = header(0x1234);
+    builder.push_tlv(TLV_SIGNATURE_ED25519, &[7; 64]).unwrap();
+    LAYOUT.update.write(&flash, 0, &builder.finish()).unwrap();
+
+    let mut buf = [0; HEADER_SIZE];
+    let hdr = read_header(&flash, &LAYOUT.update, &mut buf).unwrap();
+    assert_eq!(hdr.image_size(), 0x1234);
+    assert_eq!(hdr.firmware_version(), 0x0001_0203);
+    assert_eq!(hdr.timestamp(), 1_700_000_000);
+    assert_eq!(hdr.hash_type(), HashType::Sha256);
+    assert_eq!(hdr.digest(), &[0xAB; 32]);
+    assert_eq!(hdr.tlv(TLV_SIGNATURE_ED25519), Some(&[7u8; 64][..]));
+    assert_eq!(hdr.tlv(TLV_SIGNATURE_P256), None);
+    assert_eq!(hdr.tlvs().count(), 2);
+    assert_eq!(&hdr.signed_fields()[..4], b"RB4L");
+}
+
+#[test]
+fn erased_partition_has_no_header() {
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+    let mut buf = [0; HEADER_SIZE];
+    assert_eq!(
+        read_header(&flash, &LAYOUT.boot, &mut buf),
+        Err(HeaderError::BadMagic([0xFF; 4]))
+    );
+}
+
+#[test]
+fn rejects_bad_fixed_fields() {
+    let capacity = PARTITION_SIZE - RB_HDR_SIZE;
+    let good = header(0x100).finish();
+    assert!(ImageHeader::parse(&good, capacity).is_ok());
+
+    let mut bytes = good;
+    bytes[0x04] = 2;
+    assert_eq!(
+        ImageHeader::parse(&bytes, capacity),
+        Err(HeaderError::UnsupportedVersion(2))
+    );
+
+    let mut bytes = good;
+    bytes[0x06] = 0x80;
+    assert_eq!(
+        ImageHeader::parse(&bytes, capacity),
+        Err(HeaderError::BadHeaderSize(0x180))
+    );
+
+    let bytes = header(4).finish();
+    assert_eq!(
+        ImageHeader::parse(&bytes, capacity),
+        Err(HeaderError::ImageTooSmall(4))
+    );
+
+    let bytes = header(capacity + 1).finish();
+    assert_eq!(
+        ImageHeader::parse(&bytes, capacity),
+        Err(HeaderError::ImageTooLarge {
+            size: capacity + 1,
+            capacity
+        })
+    );
+
+    let mut bytes = good;
+    bytes[0x18] = 9;
+    assert_eq!(
+        ImageHeader::parse(&bytes, capacity),
+        Err(HeaderError::UnknownHashType(9))
+    );
+}
+
+#[test]
+fn rejects_bad_tlvs() {
+    let capacity = PARTITION_SIZE - RB_HDR_SIZE;
+
+    let bytes = HeaderBuilder::new(0x100, 1, 0, HashType::Sha256).finish();
+    assert_eq!(
+        ImageHeader::parse(&bytes, capacity),
+        Err(HeaderError::MissingDigest)
+    );
+
+    let mut builder = HeaderBuilder::new(0x100, 1, 0, HashType::Sha256);
+    builder.push_tlv(TLV_DIGEST, &[0; 20]).unwrap();
+    assert_eq!(
+        ImageHeader::parse(&builder.finish(), capacity),
+        Err(HeaderError::DigestLength {
+            expected: 32,
+            found: 20
+        })
+    );
+
+    let mut builder = header(0x100);
+    builder.push_tlv(TLV_DIGEST, &[0; 32]).unwrap();
+    assert_eq!(
+        ImageHeader::parse(&builder.finish(), capacity),
+        Err(HeaderError::DuplicateTlv(TLV_DIGEST))
+    );
+
+    // a length running past the end of the header
+    let mut bytes = header(0x100).finish();
+    let offset = TLV_OFFSET + 4 + 32;
+    bytes[offset..offset + 4].copy_from_slice(&[0x20, 0x00, 0xFF, 0x00]);
+    assert_eq!(
+        ImageHeader::parse(&bytes, capacity),
+        Err(HeaderError::TlvOverrun { offset })
+    );
+
+    let mut builder = header(0x100);
+    assert_eq!(
+        builder.push_tlv(TLV_SIGNATURE_P256, &[0; 200]),
+        Err(HeaderError::HeaderFull)
+    );
+}
@@ -0,0 +1,219 @@
+use atsamblinky::atsam4l::atsam4lc8c_constants::*;
+use atsamblinky::atsam4l::partition::Partition;
+use atsamblinky::atsam4l::sim::{PowerFault, SimFlash, TraceLog};
+use atsamblinky::atsam4l::{Command, FlashWriterEraser, PageNumber, PartitionKind, TraceSink};
+use atsamblinky::kv::{KvError, KvStore, MAX_KEY_LEN, MAX_VALUE_LEN};
+
+const STORE: Partition = Partition::new(PartitionKind::Data, 0x7_0000, 4 * FLASH_PAGE_SIZE);
+
+fn value<T: TraceSink>(store: &KvStore<SimFlash, T>, key: &str) -> Option<Vec<u8>> {
+    let mut buf = [0; MAX_VALUE_LEN];
+    store
+        .get(key, &mut buf)
+        .unwrap()
+        .map(|len| buf[..len].to_vec())
+}
+
+#[test]
+fn set_get_remove_and_remount() {
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+    let mut store = KvStore::mount(&flash, STORE).unwrap();
+    assert_eq!(value(&store, "volume"), None);
+
+    store.set("volume", &[7]).unwrap();
+    store.set("name", b"blinky").unwrap();
+    store.set("volume", &[9]).unwrap();
+    store.set("empty", &[]).unwrap();
+    assert_eq!(value(&store, "volume"), Some(vec![9]));
+    assert_eq!(value(&store, "empty"), Some(vec![]));
+
+    store.remove("name").unwrap();
+    store.remove("never-set").unwrap();
+    assert_eq!(value(&store, "name"), None);
+
+    let store = KvStore::mount(&flash, STORE).unwrap();
+    assert_eq!(value(&store, "volume"), Some(vec![9]));
+    assert_eq!(value(&store, "name"), None);
+    assert_eq!(value(&store, "empty"), Some(vec![]));
+
+    let mut small = [0; 2];
+    let mut store = store;
+    store.set("name", b"blinky").unwrap();
+    assert_eq!(
+        store.get("name", &mut small),
+        Err(KvError::BufferTooSmall { len: 6 })
+    );
+    assert_eq!(store.set("", &[1]), Err(KvError::BadKey));
+    let long = "k".repeat(MAX_KEY_LEN + 1);
+    assert_eq!(store.set(&long, &[1]), Err(KvError::BadKey));
+    assert_eq!(
+        store.set("big", &[0; MAX_VALUE_LEN + 1]),
+        Err(KvError::ValueTooLong)
+    );
+    let one_page = Partition::new(PartitionKind::Data, 0x7_0000, FLASH_PAGE_SIZE);
+    assert!(matches!(
+        KvStore::mount(&flash, one_page),
+        Err(KvError::TooFewPages)
+    ));
+}
+
+#[test]
+fn unchanged_values_are_not_written() {
+    let log = TraceLog::new();
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new()).with_trace(&log);
+    let mut store = KvStore::mount(&flash, STORE).unwrap();
+    store.set("mode", b"fast").unwrap();
+    log.clear();
+
+    store.set("mode", b"fast").unwrap();
+    store.remove("other").unwrap();
+    assert!(log.commands().iter().all(|(c, _)| *c == Command::Qpr));
+}
+
+#[test]
+fn garbage_collection_keeps_data_and_spreads_erases() {
+    let log = TraceLog::new();
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new()).with_trace(&log);
+    let mut store = KvStore::mount(&flash, STORE).unwrap();
+    store.set("serial", b"RB-0001").unwrap();
+
+    for i in 0..2000u32 {
+        let key = ["a", "b", "c", "d", "e"][i as usize % 5];
+        store.set(key, &i.to_le_bytes()).unwrap();
+    }
+    store.remove("c").unwrap();
+    for (n, key) in ["a", "b", "d", "e"].iter().enumerate() {
+        let last = 1995 + [0, 1, 3, 4][n] as u32;
+        assert_eq!(value(&store, key), Some(last.to_le_bytes().to_vec()));
+    }
+    assert_eq!(value(&store, "c"), None);
+    assert_eq!(value(&store, "serial"), Some(b"RB-0001".to_vec()));
+
+    let first = STORE.start() / FLASH_PAGE_SIZE;
+    let erases: Vec<usize> = (0..STORE.page_count())
+        .map(|p| {
+            let page = PageNumber::new(first + p).unwrap();
+            log.commands()
+                .iter()
+                .filter(|c| **c == (Command::Ep, page))
+                .count()
+        })
+        .collect();
+    let (min, max) = (erases.iter().min().unwrap(), erases.iter().max().unwrap());
+    assert!(*min > 10, "{:?}", erases);
+    assert!(max - min <= 1, "{:?}", erases);
+
+    let store = KvStore::mount(&flash, STORE).unwrap();
+    assert_eq!(value(&store, "serial"), Some(b"RB-0001".to_vec()));
+    assert_eq!(value(&store, "e"), Some(1999u32.to_le_bytes().to_vec()));
+}
+
+#[test]
+fn full_store_is_reported() {
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+    let two_pages = Partition::new(PartitionKind::Data, 0x7_0000, 2 * FLASH_PAGE_SIZE);
+    let mut store = KvStore::mount(&flash, two_pages).unwrap();
+    let mut keys = 0;
+    let result = loop {
+        match store.set(&format!("key{}", keys), &[keys as u8; 100]) {
+            Ok(()) => keys += 1,
+            Err(e) => break e,
+        }
+    };
+    assert_eq!(result, KvError::Full);
+    assert!(keys >= 3);
+
+    let store = KvStore::mount(&flash, two_pages).unwrap();
+    for k in 0..keys {
+        assert_eq!(
+            value(&store, &format!("key{}", k)),
+            Some(vec![k as u8; 100])
+        );
+    }
+}
+
+/// One step of the workload, and the value each key must have after it.
+fn step(i: u32) -> (&'static str, Option<Vec<u8>>) {
+    let key = ["x", "y", "z"][i as usize % 3];
+    match i % 7 {
+        6 => (key, None),
+        _ => (key, Some(vec![i as u8; 20 + (i as usize * 13) % 90])),
+    }
+}
+
+fn apply(store: &mut KvStore<SimFlash, ()>, i: u32) -> Result<(), KvError> {
+    match step(i) {
+        (key, Some(v)) => store.set(key, &v),
+        (key, None) => store.remove(key),
+    }
+}
+
+/// The value of `key` after the first `steps` steps.
+fn expected(key: &str, steps: u32) -> Option<Vec<u8>> {
+    (0..steps)
+        .rev()
+        .map(step)
+        .find(|(k, _)| *k == key)
+        .and_then(|(_, v)| v)
+}
+
+#[test]
+fn power_loss_at_every_command_keeps_completed_writes() {
+    const STEPS: u32 = 40;
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+    let mut store = KvStore::mount(&flash, STORE).unwrap();
+    let before = flash.nvm.command_count();
+    for i in 0..STEPS {
+        apply(&mut store, i).unwrap();
+    }
+    let commands = flash.nvm.command_count() - before;
+
+    for n in 0..commands {
+        for fault in [
+            PowerFault::AfterCommands(n),
+            PowerFault::DuringCommand { n, bytes: 3 },
+            PowerFault::DuringCommand { n, bytes: 300 },
+        ] {
+            let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+            let mut store = KvStore::mount(&flash, STORE).unwrap();
+            flash.nvm.inject_power_loss(fault);
+            // writes keep returning Ok once power is gone; count the ones
+            // that finished while it was still on
+            let mut done = 0;
+            for i in 0..STEPS {
+                apply(&mut store, i).unwrap();
+                if !flash.nvm.lost_power() {
+                    done = i + 1;
+                }
+            }
+            assert!(flash.nvm.lost_power());
+
+            flash.nvm.power_cycle();
+            let mut store = KvStore::mount(&flash, STORE).unwrap();
+            for key in ["x", "y", "z"] {
+                let got = value(&store, key);
+                let (interrupted, _) = step(done);
+                if key == interrupted {
+                    // the write cut short either happened or it did not
+                    assert!(
+                        got == expected(key, done) || got == expected(key, done + 1),
+                        "{:?} {}",
+                        fault,
+                        key
+                    );
+                } else {
+                    assert_eq!(got, expected(key, done), "{:?} {}", fault, key);
+                }
+            }
+
+            // and the store still works
+            for i in done..STEPS {
+                apply(&mut store, i).unwrap();
+            }
+            let store = KvStore::mount(&flash, STORE).unwrap();
+            for key in ["x", "y", "z"] {
+                assert_eq!(value(&store, key), expected(key, STEPS), "{:?}", fault);
+            }
+        }
+    }
+}
@@ -0,0 +1,155 @@
+use atsamblinky::atsam4l::atsam4lc8c_constants::*;
+use atsamblinky::atsam4l::partition::Partition;
+use atsamblinky::atsam4l::sim::{PowerFault, SimFlash, TraceLog};
+use atsamblinky::atsam4l::{Command, FlashWriterEraser, PartitionKind, TraceSink};
+use atsamblinky::log::{Log, LogError, MAX_ENTRY_LEN};
+
+const LOG: Partition = Partition::new(PartitionKind::Data, 0x7_0000, 4 * FLASH_PAGE_SIZE);
+
+/// The `i`th entry of a workload, of varying length.
+fn event(i: u32) -> Vec<u8> {
+    let mut data = i.to_le_bytes().to_vec();
+    data.resize(4 + (i as usize * 7) % 60, i as u8);
+    data
+}
+
+fn entries<T: TraceSink>(log: &Log<SimFlash, T>) -> Vec<Vec<u8>> {
+    log.entries().map(|e| e.unwrap().data().to_vec()).collect()
+}
+
+/// Index of each entry, checking it is intact.
+fn indices(entries: &[Vec<u8>]) -> Vec<u32> {
+    entries
+        .iter()
+        .map(|e| {
+            let i = u32::from_le_bytes([e[0], e[1], e[2], e[3]]);
+            assert_eq!(*e, event(i));
+            i
+        })
+        .collect()
+}
+
+#[test]
+fn append_iterate_and_remount() {
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+    let mut log = Log::mount(&flash, LOG).unwrap();
+    assert!(entries(&log).is_empty());
+
+    log.append(b"boot").unwrap();
+    log.append(&[]).unwrap();
+    log.append(&[0x5A; MAX_ENTRY_LEN]).unwrap();
+    log.append(b"fault 3").unwrap();
+    assert_eq!(log.append(&[0; MAX_ENTRY_LEN + 1]), Err(LogError::TooLong));
+
+    let expected = vec![
+        b"boot".to_vec(),
+        vec![],
+        vec![0x5A; MAX_ENTRY_LEN],
+        b"fault 3".to_vec(),
+    ];
+    assert_eq!(entries(&log), expected);
+
+    let mut log = Log::mount(&flash, LOG).unwrap();
+    assert_eq!(entries(&log), expected);
+    log.append(b"after").unwrap();
+    assert_eq!(entries(&log).last().unwrap(), b"after");
+
+    let one_page = Partition::new(PartitionKind::Data, 0x7_0000, FLASH_PAGE_SIZE);
+    assert!(matches!(
+        Log::mount(&flash, one_page),
+        Err(LogError::TooFewPages)
+    ));
+}
+
+#[test]
+fn mount_finds_the_head_with_quick_page_reads() {
+    let log_trace = TraceLog::new();
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new()).with_trace(&log_trace);
+    let mut log = Log::mount(&flash, LOG).unwrap();
+    for i in 0..20 {
+        log.append(&event(i)).unwrap();
+    }
+    log_trace.clear();
+
+    let log = Log::mount(&flash, LOG).unwrap();
+    let commands = log_trace.commands();
+    assert_eq!(commands.len(), LOG.page_count() as usize);
+    assert!(commands.iter().all(|(c, _)| *c == Command::Qpr));
+    assert_eq!(indices(&entries(&log)), (0..20).collect::<Vec<_>>());
+}
+
+#[test]
+fn full_log_drops_the_oldest_page() {
+    let trace = TraceLog::new();
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new()).with_trace(&trace);
+    let mut log = Log::mount(&flash, LOG).unwrap();
+    for i in 0..1000 {
+        log.append(&event(i)).unwrap();
+    }
+
+    for log in [log, Log::mount(&flash, LOG).unwrap()] {
+        let got = indices(&entries(&log));
+        // the newest entries, in order, filling at least all but one page
+        assert_eq!(*got.last().unwrap(), 999);
+        assert!(got.windows(2).all(|w| w[1] == w[0] + 1));
+        let bytes: usize = got.iter().map(|i| (8 + event(*i).len() + 7) & !7).sum();
+        assert!(bytes > (LOG.size() - FLASH_PAGE_SIZE) as usize - 512);
+    }
+
+    // pages are erased in turn
+    let erases: Vec<_> = trace
+        .commands()
+        .into_iter()
+        .filter(|(c, _)| *c == Command::Ep)
+        .map(|(_, p)| p)
+        .collect();
+    assert!(erases.len() > 8);
+    assert!(erases.windows(2).all(|w| w[0] != w[1]));
+}
+
+#[test]
+fn power_loss_at_every_command_keeps_completed_entries() {
+    const EVENTS: u32 = 80;
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+    let mut log = Log::mount(&flash, LOG).unwrap();
+    for i in 0..EVENTS {
+        log.append(&event(i)).unwrap();
+    }
+    let commands = flash.nvm.command_count();
+
+    for n in 0..commands {
+        for fault in [
+            PowerFault::AfterCommands(n),
+            PowerFault::DuringCommand { n, bytes: 3 },
+            PowerFault::DuringCommand { n, bytes: 300 },
+        ] {
+            let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+            let mut log = Log::mount(&flash, LOG).unwrap();
+            flash.nvm.inject_power_loss(fault);
+            let mut done = 0;
+            for i in 0..EVENTS {
+                log.append(&event(i)).unwrap();
+                if !flash.nvm.lost_power() {
+                    done = i + 1;
+                }
+            }
+
+            flash.nvm.power_cycle();
+            let mut log = Log::mount(&flash, LOG).unwrap();
+            let got = indices(&entries(&log));
+            // every entry that was appended and not yet dropped is there,
+            // plus perhaps the one cut short
+            let newest = got.last().map_or(0, |i| i + 1);
+            assert!(newest == done || newest == done + 1, "{:?}", fault);
+            assert!(got.windows(2).all(|w| w[1] == w[0] + 1), "{:?}", fault);
+
+            log.append(&event(EVENTS)).unwrap();
+            let log = Log::mount(&flash, LOG).unwrap();
+            // starting a fresh page may have dropped the oldest one
+            let after = indices(&entries(&log));
+            let (last, kept) = after.split_last().unwrap();
+            assert_eq!(*last, EVENTS, "{:?}", fault);
+            assert!(got.ends_with(kept), "{:?}", fault);
+        }
+    }
+}
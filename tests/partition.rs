@@ -0,0 +1,77 @@
+use atsamblinky::atsam4l::atsam4lc8c_constants::*;
+use atsamblinky::atsam4l::partition::LAYOUT;
+use atsamblinky::atsam4l::sim::SimFlash;
+use atsamblinky::atsam4l::{FlashError, FlashWriterEraser, PartitionKind};
+
+#[test]
+fn layout_matches_the_device() {
+    assert_eq!(LAYOUT.boot.start(), 0x0001_0000);
+    assert_eq!(
+        LAYOUT.update.start(),
+        LAYOUT.boot.start() + LAYOUT.boot.size()
+    );
+    let data = LAYOUT.get(PartitionKind::Data).unwrap();
+    assert_eq!(data.start() + data.size(), FLASH_BASE + FLASH_SIZE);
+    // the vector table of an image in BOOT must be usable for VTOR
+    assert_eq!((LAYOUT.boot.start() + RB_HDR_SIZE) % 0x80, 0);
+}
+
+#[test]
+fn accesses_are_relative_and_scoped() {
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+    let update = LAYOUT.update;
+
+    update.write(&flash, 0x10, &[1, 2, 3, 4]).unwrap();
+    let mut buf = [0u8; 4];
+    update.read(&flash, 0x10, &mut buf).unwrap();
+    assert_eq!(buf, [1, 2, 3, 4]);
+    assert_eq!(flash.nvm.flash()[(UPDATE_ADDR + 0x10) as usize], 1);
+
+    let end = update.size();
+    assert_eq!(
+        update.write(&flash, end - 2, &[0; 4]),
+        Err(FlashError::OutOfBounds)
+    );
+    assert_eq!(
+        update.read(&flash, end, &mut buf),
+        Err(FlashError::OutOfBounds)
+    );
+    assert_eq!(
+        update.erase(&flash, u32::MAX, 2),
+        Err(FlashError::OutOfBounds)
+    );
+}
+
+#[test]
+fn erase_stays_inside_the_partition() {
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+    LAYOUT
+        .boot
+        .write(&flash, LAYOUT.boot.size() - 4, &[0; 4])
+        .unwrap();
+    LAYOUT.update.write(&flash, 0, &[0; 4]).unwrap();
+
+    LAYOUT.boot.erase(&flash, 0, LAYOUT.boot.size()).unwrap();
+
+    let contents = flash.nvm.flash();
+    assert!(contents[(UPDATE_ADDR - 4) as usize..UPDATE_ADDR as usize]
+        .iter()
+        .all(|b| *b == 0xFF));
+    assert_eq!(&contents[UPDATE_ADDR as usize..][..4], &[0; 4]);
+}
+
+#[test]
+fn empty_accesses_only_check_the_offset() {
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+    let update = LAYOUT.update;
+    let end = update.size();
+
+    assert_eq!(update.write(&flash, end, &[]), Ok(()));
+    assert_eq!(update.program(&flash, 0, &[]), Ok(()));
+    assert_eq!(update.read(&flash, end, &mut []), Ok(()));
+    assert_eq!(
+        update.write(&flash, end + 1, &[]),
+        Err(FlashError::OutOfBounds)
+    );
+    assert_eq!(flash.nvm.command_count(), 0);
+}
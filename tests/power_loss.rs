@@ -0,0 +1,94 @@
+use atsamblinky::atsam4l::atsam4lc8c_constants::*;
+use atsamblinky::atsam4l::sim::{PowerFault, SimFlash};
+use atsamblinky::atsam4l::{FlashAddress, FlashRange, FlashWriterEraser};
+
+fn driver() -> FlashWriterEraser<SimFlash> {
+    FlashWriterEraser::with_nvm(SimFlash::new())
+}
+
+#[test]
+fn nothing_runs_after_power_is_lost() {
+    let flash = driver();
+    let address = FlashAddress::new(4 * FLASH_PAGE_SIZE).unwrap();
+    // EP, CPB, WP for the first page; power goes before the second page's EP
+    flash.nvm.inject_power_loss(PowerFault::AfterCommands(3));
+
+    flash.hal_flash_write(address, &[0u8; 1024]).unwrap();
+
+    assert!(flash.nvm.lost_power());
+    let contents = flash.nvm.flash();
+    assert!(contents[2048..2560].iter().all(|b| *b == 0x00));
+    assert!(contents[2560..3072].iter().all(|b| *b == 0xFF));
+}
+
+#[test]
+fn interrupted_program_leaves_a_partial_page() {
+    let flash = driver();
+    let address = FlashAddress::new(0).unwrap();
+    flash
+        .nvm
+        .inject_power_loss(PowerFault::DuringCommand { n: 2, bytes: 100 });
+
+    flash.hal_flash_write(address, &[0x42; 512]).unwrap();
+
+    let contents = flash.nvm.flash();
+    assert!(contents[..100].iter().all(|b| *b == 0x42));
+    assert!(contents[100..512].iter().all(|b| *b == 0xFF));
+}
+
+#[test]
+fn interrupted_erase_leaves_old_data_behind() {
+    let flash = driver();
+    let address = FlashAddress::new(0).unwrap();
+    flash.hal_flash_write(address, &[0x00; 512]).unwrap();
+    flash
+        .nvm
+        .inject_power_loss(PowerFault::DuringCommand { n: 0, bytes: 64 });
+
+    flash.hal_flash_write(address, &[0x42; 512]).unwrap();
+
+    let contents = flash.nvm.flash();
+    assert!(contents[..64].iter().all(|b| *b == 0xFF));
+    assert!(contents[64..512].iter().all(|b| *b == 0x00));
+}
+
+#[test]
+fn rewrite_after_any_power_loss_recovers() {
+    let address = FlashAddress::new(3 * FLASH_PAGE_SIZE - 40).unwrap();
+    let data: Vec<u8> = (0..900u32).map(|i| i as u8).collect();
+
+    let reference = driver();
+    reference.hal_flash_write(address, &data).unwrap();
+    let commands = reference.nvm.command_count();
+
+    for n in 0..commands {
+        let flash = driver();
+        flash
+            .nvm
+            .inject_power_loss(PowerFault::DuringCommand { n, bytes: 200 });
+        flash.hal_flash_write(address, &data).unwrap();
+        assert!(flash.nvm.lost_power(), "fault {} did not fire", n);
+
+        flash.nvm.power_cycle();
+        flash.hal_flash_write(address, &data).unwrap();
+        flash.hal_flash_verify(address, &data).unwrap();
+    }
+}
+
+#[test]
+fn bit_flips_are_reproducible() {
+    let range = FlashRange::new(FlashAddress::new(0x1000).unwrap(), 256).unwrap();
+    let a = SimFlash::new();
+    let b = SimFlash::new();
+
+    a.flip_bits(range, 8, 0xDEAD_BEEF);
+    b.flip_bits(range, 8, 0xDEAD_BEEF);
+
+    assert_eq!(&a.flash()[..], &b.flash()[..]);
+    let zeros: u32 = a.flash()[0x1000..0x1100]
+        .iter()
+        .map(|b| b.count_zeros())
+        .sum();
+    assert!(zeros > 0 && zeros <= 8);
+    assert!(a.flash()[..0x1000].iter().all(|b| *b == 0xFF));
+}
@@ -0,0 +1,108 @@
+use atsamblinky::atsam4l::atsam4lc8c_constants::*;
+use atsamblinky::atsam4l::partition::{Partition, PartitionKind, LAYOUT};
+use atsamblinky::atsam4l::sim::SimFlash;
+use atsamblinky::atsam4l::{FlashAddress, FlashRange, FlashWriterEraser};
+use atsamblinky::ptable::{PartitionTable, TableError, ENTRY_SIZE, MAX_ENTRIES};
+use flash_protocol::crc32;
+
+fn data(start: u32, size: u32) -> Partition {
+    Partition::try_new(PartitionKind::Data, start, size).unwrap()
+}
+
+#[test]
+fn stored_table_is_found_at_runtime() {
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+    assert_eq!(PartitionTable::load(&flash), Err(TableError::Blank));
+
+    // the built-in layout, with its data partition split in two
+    let data_partition = LAYOUT.data.unwrap();
+    let mut table = PartitionTable::new();
+    for entry in PartitionTable::from_layout(&LAYOUT)
+        .entries()
+        .filter(|e| e.name() != "data")
+    {
+        table.push(entry.name(), entry.partition()).unwrap();
+    }
+    table
+        .push("settings", data(data_partition.start(), 0x1000))
+        .unwrap();
+    table
+        .push("log", data(data_partition.start() + 0x1000, 0x2000))
+        .unwrap();
+    table.store(&flash).unwrap();
+
+    let loaded = PartitionTable::load(&flash).unwrap();
+    assert_eq!(loaded, table);
+    assert_eq!(loaded.len(), 6);
+    assert_eq!(loaded.find("boot"), Some(LAYOUT.boot));
+    assert_eq!(loaded.find("log").unwrap().size(), 0x2000);
+    assert_eq!(loaded.find("data"), None);
+    assert_eq!(loaded.find_kind(PartitionKind::Update), Some(LAYOUT.update));
+    assert_eq!(
+        loaded.find_kind(PartitionKind::Data).unwrap().start(),
+        data_partition.start()
+    );
+    let names: Vec<&str> = loaded.entries().map(|e| e.name()).collect();
+    assert_eq!(
+        names,
+        ["bootloader", "boot", "update", "swap", "settings", "log"]
+    );
+}
+
+#[test]
+fn damaged_tables_are_rejected() {
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+    PartitionTable::from_layout(&LAYOUT).store(&flash).unwrap();
+    let page = FlashRange::new(FlashAddress::new(PTABLE_ADDR).unwrap(), 64).unwrap();
+    flash.nvm.flip_bits(page, 1, 3);
+    assert_eq!(PartitionTable::load(&flash), Err(TableError::Crc));
+
+    let mut page = [0u8; FLASH_PAGE_SIZE as usize];
+    PartitionTable::from_layout(&LAYOUT).encode(&mut page);
+    page[0] = b'X';
+    assert_eq!(PartitionTable::decode(&page), Err(TableError::BadMagic));
+
+    // a valid CRC over an entry with an unknown kind
+    let mut page = [0u8; FLASH_PAGE_SIZE as usize];
+    let used = PartitionTable::from_layout(&LAYOUT).encode(&mut page);
+    page[8 + ENTRY_SIZE + 24] = 9;
+    let crc = crc32(&page[..used - 4]);
+    page[used - 4..used].copy_from_slice(&crc.to_le_bytes());
+    assert_eq!(
+        PartitionTable::decode(&page),
+        Err(TableError::BadEntry { index: 1 })
+    );
+}
+
+#[test]
+fn entries_are_checked() {
+    let mut table = PartitionTable::new();
+    table.push("a", data(0x7_0000, 0x1000)).unwrap();
+    assert_eq!(
+        table.push("a", data(0x7_1000, 0x200)),
+        Err(TableError::BadName)
+    );
+    assert_eq!(
+        table.push("name is too long!", data(0x7_1000, 0x200)),
+        Err(TableError::BadName)
+    );
+    assert_eq!(
+        table.push("", data(0x7_1000, 0x200)),
+        Err(TableError::BadName)
+    );
+    assert_eq!(
+        table.push("b", data(0x7_0E00, 0x400)),
+        Err(TableError::Overlap)
+    );
+    for i in 1..MAX_ENTRIES {
+        table
+            .push(&format!("p{}", i), data(0x7_1000 + i as u32 * 0x200, 0x200))
+            .unwrap();
+    }
+    assert_eq!(
+        table.push("last", data(0x7_E000, 0x200)),
+        Err(TableError::Full)
+    );
+    assert!(Partition::try_new(PartitionKind::Data, 0x7_0100, 0x200).is_err());
+    assert!(Partition::try_new(PartitionKind::Data, 0x7_FE00, 0x400).is_err());
+}
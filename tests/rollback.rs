@@ -0,0 +1,149 @@
+use atsamblinky::atsam4l::atsam4lc8c_constants::*;
+use atsamblinky::atsam4l::sim::{PowerFault, SimFlash, TraceLog};
+use atsamblinky::atsam4l::{Command, FlashError, FlashWriterEraser};
+use atsamblinky::image::{HashType, HeaderBuilder, ImageHeader, TLV_DIGEST};
+use atsamblinky::rollback::*;
+
+fn header(version: u32) -> [u8; 256] {
+    let mut builder = HeaderBuilder::new(0x100, version, 0, HashType::Sha256);
+    builder.push_tlv(TLV_DIGEST, &[0; 32]).unwrap();
+    builder.finish()
+}
+
+#[test]
+fn refuses_images_below_the_counter() {
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+    let counter = SecurityCounter::new(&flash);
+    assert_eq!(counter.value(), Ok(0));
+
+    counter.raise(5).unwrap();
+    assert_eq!(counter.value(), Ok(5));
+    // never goes down
+    counter.raise(3).unwrap();
+    assert_eq!(counter.value(), Ok(5));
+
+    let old = header(4);
+    let old = ImageHeader::parse(&old, PARTITION_SIZE).unwrap();
+    assert_eq!(
+        counter.check(&old),
+        Err(RollbackError::Rollback {
+            version: 4,
+            counter: 5
+        })
+    );
+    let same = header(5);
+    assert_eq!(
+        counter.check(&ImageHeader::parse(&same, PARTITION_SIZE).unwrap()),
+        Ok(())
+    );
+}
+
+#[test]
+fn raises_without_erasing_until_full() {
+    let log = TraceLog::new();
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new()).with_trace(&log);
+    let counter = SecurityCounter::new(&flash);
+    flash.hal_user_page_program(0, &[0x12, 0x34]).unwrap();
+
+    for version in 1..=SLOTS {
+        counter.raise(version).unwrap();
+    }
+    assert!(log.commands().iter().all(|(cmd, _)| *cmd != Command::Eup));
+
+    // the next raise compacts, keeping the rest of the user page
+    counter.raise(SLOTS + 10).unwrap();
+    let erases = log
+        .commands()
+        .iter()
+        .filter(|(c, _)| *c == Command::Eup)
+        .count();
+    assert_eq!(erases, 1);
+    assert_eq!(counter.value(), Ok(SLOTS + 10));
+    assert_eq!(&flash.nvm.user_page()[..2], &[0x12, 0x34]);
+    counter.raise(SLOTS + 11).unwrap();
+    assert_eq!(counter.value(), Ok(SLOTS + 11));
+}
+
+#[test]
+fn torn_raise_keeps_the_previous_value() {
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+    let counter = SecurityCounter::new(&flash);
+    counter.raise(7).unwrap();
+
+    // CPB, then a WUP that stops halfway through the second slot
+    let torn = (UP_COUNTER_OFFSET + 8 + 5) as usize;
+    flash
+        .nvm
+        .inject_power_loss(PowerFault::DuringCommand { n: 1, bytes: torn });
+    counter.raise(0x0100_0000).unwrap();
+    assert!(flash.nvm.lost_power());
+    flash.nvm.power_cycle();
+
+    assert_eq!(counter.value(), Ok(7));
+    counter.raise(9).unwrap();
+    assert_eq!(counter.value(), Ok(9));
+}
+
+#[test]
+fn user_page_accesses_are_bounded() {
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+    let mut buf = [0u8; 8];
+    assert_eq!(
+        flash.hal_user_page_read(FLASH_PAGE_SIZE - 4, &mut buf),
+        Err(FlashError::OutOfBounds)
+    );
+    assert_eq!(
+        flash.hal_user_page_program(u32::MAX, &buf),
+        Err(FlashError::OutOfBounds)
+    );
+    flash.hal_user_page_program(0x1FC, &[1, 2, 3, 4]).unwrap();
+    flash.hal_user_page_read(0x1FC, &mut buf[..4]).unwrap();
+    assert_eq!(&buf[..4], &[1, 2, 3, 4]);
+    flash.hal_user_page_erase().unwrap();
+    assert!(flash.nvm.user_page().iter().all(|b| *b == 0xFF));
+}
+
+#[test]
+fn power_loss_during_compaction_never_lowers_the_counter() {
+    let full = |flash: &FlashWriterEraser<SimFlash>| {
+        flash.hal_user_page_program(0, &[0x12, 0x34]).unwrap();
+        let counter = SecurityCounter::new(flash);
+        for version in 1..=SLOTS {
+            counter.raise(version).unwrap();
+        }
+    };
+
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+    full(&flash);
+    let before = flash.nvm.command_count();
+    SecurityCounter::new(&flash).raise(SLOTS + 10).unwrap();
+    let commands = flash.nvm.command_count() - before;
+
+    for n in 0..commands {
+        for fault in [
+            PowerFault::AfterCommands(n),
+            PowerFault::DuringCommand { n, bytes: 3 },
+            PowerFault::DuringCommand { n, bytes: 300 },
+        ] {
+            let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+            full(&flash);
+            flash.nvm.inject_power_loss(fault);
+            SecurityCounter::new(&flash).raise(SLOTS + 10).unwrap();
+            flash.nvm.power_cycle();
+
+            let counter = SecurityCounter::new(&flash);
+            let value = counter.value().unwrap();
+            assert!(value == SLOTS || value == SLOTS + 10, "{:?}", fault);
+            let mut fuses = [0u8; 2];
+            flash.hal_user_page_read(0, &mut fuses).unwrap();
+            assert_eq!(fuses, [0x12, 0x34], "{:?}", fault);
+
+            // the bootloader finishes the rewrite on the next boot
+            flash.hal_user_page_recover().unwrap();
+            assert_eq!(counter.value(), Ok(value), "{:?}", fault);
+            assert_eq!(&flash.nvm.user_page()[..2], &[0x12, 0x34]);
+            counter.raise(SLOTS + 11).unwrap();
+            assert_eq!(counter.value(), Ok(SLOTS + 11), "{:?}", fault);
+        }
+    }
+}
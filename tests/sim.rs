@@ -0,0 +1,93 @@
+use atsamblinky::atsam4l::atsam4lc8c_constants::*;
+use atsamblinky::atsam4l::regs::*;
+use atsamblinky::atsam4l::sim::SimFlash;
+use atsamblinky::atsam4l::{FlashAddress, FlashError, FlashWriterEraser, PageNumber};
+
+fn page(n: u32) -> PageNumber {
+    PageNumber::new(n).unwrap()
+}
+
+#[test]
+fn write_page_ands_the_page_buffer_into_flash() {
+    let sim = SimFlash::new();
+    sim.write_page_buffer(0, 0x0000_FFF0);
+    sim.write_fcmd(Command::Wp.fcmd(page(0)));
+    sim.write_fcmd(Command::Cpb.fcmd(page(0)));
+    sim.write_page_buffer(0, 0xFF00_FF0F);
+    sim.write_fcmd(Command::Wp.fcmd(page(0)));
+
+    assert_eq!(&sim.flash()[..4], &0x0000_FF00u32.to_le_bytes());
+}
+
+#[test]
+fn locked_regions_reject_write_and_erase() {
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+    let address = FlashAddress::new(70 * FLASH_PAGE_SIZE).unwrap();
+    flash.hal_flash_write(address, &[0x11; 16]).unwrap();
+
+    flash.hal_flash_lock(page(64)).unwrap();
+    assert_ne!(flash.nvm.read_fsr() & FSR_LOCK0 << 1, 0);
+    assert_eq!(
+        flash.hal_flash_write(address, &[0x22; 16]),
+        Err(FlashError::Locked)
+    );
+    flash.hal_flash_verify(address, &[0x11; 16]).unwrap();
+
+    // pages outside the region are unaffected
+    flash
+        .hal_flash_write(FlashAddress::new(0).unwrap(), &[0x33; 8])
+        .unwrap();
+
+    flash.hal_flash_unlock(page(127)).unwrap();
+    flash.hal_flash_write(address, &[0x22; 16]).unwrap();
+    assert_eq!(flash.nvm.fuses() & 0xFFFF, 0xFFFF);
+}
+
+#[test]
+fn bad_key_and_reserved_commands_raise_proge() {
+    let sim = SimFlash::new();
+    sim.write_fcmd(0x5A00_0002);
+    assert_ne!(sim.read_fsr() & FSR_PROGE, 0);
+    // FSR reads clear the error bits
+    assert_eq!(sim.read_fsr() & FSR_PROGE, 0);
+
+    sim.write_fcmd(0xA500_003F);
+    assert_ne!(sim.read_fsr() & FSR_PROGE, 0);
+}
+
+#[test]
+fn quick_page_read_reports_blank_pages() {
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+    assert!(flash.hal_flash_is_blank(page(5)).unwrap());
+
+    flash
+        .hal_flash_write(page(5).start().offset(511).unwrap(), &[0x7F])
+        .unwrap();
+    assert!(!flash.hal_flash_is_blank(page(5)).unwrap());
+    assert!(flash.hal_flash_is_blank(page(6)).unwrap());
+}
+
+#[test]
+fn user_page_and_fuses() {
+    let sim = SimFlash::new();
+    sim.write_fcmd(Command::Cpb.fcmd(page(0)));
+    sim.write_page_buffer(USER_PAGE_BASE + 8, 0x1234_5678);
+    sim.write_fcmd(Command::Wup.fcmd(page(0)));
+
+    let mut word = [0u8; 4];
+    sim.read_flash(USER_PAGE_BASE + 8, &mut word);
+    assert_eq!(u32::from_le_bytes(word), 0x1234_5678);
+    assert!(sim.flash().iter().all(|b| *b == 0xFF));
+
+    sim.write_fcmd(Command::Qprup.fcmd(page(0)));
+    assert_eq!(sim.read_fsr() & FSR_QPRR, 0);
+    sim.write_fcmd(Command::Eup.fcmd(page(0)));
+    sim.write_fcmd(Command::Qprup.fcmd(page(0)));
+    assert_ne!(sim.read_fsr() & FSR_QPRR, 0);
+
+    // PAGEN = value << 3 | byte index
+    sim.write_fcmd(0xA500_0000 | (0xA5 << 3 | 2) << 8 | Command::Pgpfb as u32);
+    assert_eq!(sim.fuses(), !(0x5Au64 << 16));
+    sim.write_fcmd(Command::Eagpf.fcmd(page(0)));
+    assert_eq!(sim.fuses(), u64::MAX);
+}
@@ -0,0 +1,72 @@
+use atsamblinky::atsam4l::regs::*;
+use atsamblinky::atsam4l::sim::{SimFlash, TraceLog};
+use atsamblinky::atsam4l::{
+    Command, FlashAddress, FlashError, FlashRange, FlashWriterEraser, PageNumber,
+};
+
+fn page(n: u32) -> PageNumber {
+    PageNumber::new(n).unwrap()
+}
+
+#[test]
+fn small_write_is_one_erase_one_clear_one_program() {
+    let log = TraceLog::new();
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new()).with_trace(&log);
+
+    flash
+        .hal_flash_write(FlashAddress::new(0x0004_8300).unwrap(), &[0xA4; 39])
+        .unwrap();
+
+    assert_eq!(
+        log.commands(),
+        [
+            (Command::Ep, page(577)),
+            (Command::Cpb, page(577)),
+            (Command::Wp, page(577)),
+        ]
+    );
+    assert!(log.entries().iter().all(|e| e.fsr & FSR_FRDY != 0));
+}
+
+#[test]
+fn page_crossing_write_handles_each_page_once() {
+    let log = TraceLog::new();
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new()).with_trace(&log);
+
+    flash
+        .hal_flash_write(FlashAddress::new(0x3F8).unwrap(), &[0; 16])
+        .unwrap();
+
+    let commands: Vec<_> = log
+        .commands()
+        .into_iter()
+        .map(|(c, p)| (c, p.get()))
+        .collect();
+    assert_eq!(
+        commands,
+        [
+            (Command::Ep, 1),
+            (Command::Cpb, 1),
+            (Command::Wp, 1),
+            (Command::Ep, 2),
+            (Command::Cpb, 2),
+            (Command::Wp, 2),
+        ]
+    );
+}
+
+#[test]
+fn erase_and_lock_errors_are_traced() {
+    let log = TraceLog::new();
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new()).with_trace(&log);
+    flash.hal_flash_lock(page(0)).unwrap();
+    log.clear();
+
+    let range = FlashRange::from_pages(page(0), page(1)).unwrap();
+    assert_eq!(flash.hal_flash_erase(range), Err(FlashError::Locked));
+
+    let entries = log.entries();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].command, Command::Ep);
+    assert_ne!(entries[0].fsr & FSR_LOCKE, 0);
+}
@@ -0,0 +1,193 @@
+use atsamblinky::atsam4l::atsam4lc8c_constants::*;
+use atsamblinky::atsam4l::partition::Partition;
+use atsamblinky::atsam4l::sim::{PowerFault, SimFlash};
+use atsamblinky::atsam4l::{FlashWriterEraser, PartitionKind};
+use atsamblinky::swap::Swap;
+use atsamblinky::trial::*;
+
+const BOOT: Partition = Partition::new(PartitionKind::Boot, 0x1_0000, 4 * FLASH_PAGE_SIZE);
+const UPDATE: Partition = Partition::new(PartitionKind::Update, 0x1_0800, 4 * FLASH_PAGE_SIZE);
+const SWAP: Partition = Partition::new(PartitionKind::Swap, 0x1_1000, 2 * FLASH_PAGE_SIZE);
+
+fn image(seed: u8) -> Vec<u8> {
+    (0..BOOT.size())
+        .map(|i| (i as u8).wrapping_mul(13) ^ seed)
+        .collect()
+}
+
+fn contents(flash: &FlashWriterEraser<SimFlash>, partition: Partition) -> Vec<u8> {
+    let mut buf = vec![0; partition.size() as usize];
+    partition.read(flash, 0, &mut buf).unwrap();
+    buf
+}
+
+fn trial(flash: &FlashWriterEraser<SimFlash>) -> TrialBoot<'_, SimFlash, ()> {
+    TrialBoot::new(flash, Swap::new(flash, BOOT, UPDATE, SWAP).unwrap(), 3)
+}
+
+/// Old image in BOOT, new image in UPDATE and staged.
+fn staged() -> FlashWriterEraser<SimFlash> {
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+    BOOT.write(&flash, 0, &image(0x0A)).unwrap();
+    UPDATE.write(&flash, 0, &image(0x0B)).unwrap();
+    trial(&flash).stage_update().unwrap();
+    flash
+}
+
+#[test]
+fn confirmed_update_stays() {
+    let flash = staged();
+    let trial = trial(&flash);
+    assert_eq!(boot_state(&flash), Ok(BootState::New));
+
+    assert_eq!(trial.boot(), Ok(BootState::Testing { attempts: 1 }));
+    assert_eq!(contents(&flash, BOOT), image(0x0B));
+    assert_eq!(confirm_update(&flash), Ok(BootState::Confirmed));
+    assert_eq!(confirm_update(&flash), Ok(BootState::Confirmed));
+
+    for _ in 0..5 {
+        assert_eq!(trial.boot(), Ok(BootState::Confirmed));
+    }
+    assert_eq!(contents(&flash, BOOT), image(0x0B));
+}
+
+#[test]
+fn unconfirmed_update_rolls_back() {
+    let flash = staged();
+    let trial = trial(&flash);
+
+    for attempts in 1..=3 {
+        assert_eq!(trial.boot(), Ok(BootState::Testing { attempts }));
+        assert_eq!(contents(&flash, BOOT), image(0x0B));
+    }
+    assert_eq!(trial.boot(), Ok(BootState::Failed));
+    assert_eq!(contents(&flash, BOOT), image(0x0A));
+    // too late to confirm
+    assert_eq!(confirm_update(&flash), Ok(BootState::Failed));
+    assert_eq!(trial.boot(), Ok(BootState::Failed));
+    assert_eq!(contents(&flash, BOOT), image(0x0A));
+}
+
+#[test]
+fn records_are_reused_after_many_updates() {
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+    flash.hal_user_page_program(0, &[0x5A]).unwrap();
+    let trial = trial(&flash);
+    assert_eq!(trial.boot(), Ok(BootState::None));
+
+    for seed in 0..20u8 {
+        UPDATE.write(&flash, 0, &image(seed)).unwrap();
+        trial.stage_update().unwrap();
+        assert_eq!(trial.boot(), Ok(BootState::Testing { attempts: 1 }));
+        confirm_update(&flash).unwrap();
+        assert_eq!(contents(&flash, BOOT), image(seed));
+    }
+    assert_eq!(flash.nvm.user_page()[0], 0x5A);
+}
+
+/// Runs `boot` with a power loss at every command it issues, then boots
+/// again and checks the outcome.
+fn check_power_loss(prepare: impl Fn(&FlashWriterEraser<SimFlash>), expect: &[u8]) {
+    let reference = staged();
+    prepare(&reference);
+    reference.nvm.power_cycle();
+    trial(&reference).boot().unwrap();
+    let commands = reference.nvm.command_count();
+    assert!(commands > 0);
+
+    for n in 0..commands {
+        for fault in [
+            PowerFault::AfterCommands(n),
+            PowerFault::DuringCommand { n, bytes: 100 },
+        ] {
+            let flash = staged();
+            prepare(&flash);
+            flash.nvm.inject_power_loss(fault);
+            let _ = trial(&flash).boot();
+            assert!(flash.nvm.lost_power());
+            flash.nvm.power_cycle();
+
+            let state = trial(&flash).boot().unwrap();
+            assert_eq!(contents(&flash, BOOT), expect, "{:?} -> {:?}", fault, state);
+        }
+    }
+}
+
+#[test]
+fn install_survives_power_loss() {
+    check_power_loss(|_| {}, &image(0x0B));
+}
+
+#[test]
+fn rollback_survives_power_loss() {
+    check_power_loss(
+        |flash| {
+            let trial = trial(flash);
+            for _ in 0..3 {
+                trial.boot().unwrap();
+            }
+        },
+        &image(0x0A),
+    );
+}
+
+#[test]
+fn staging_with_full_records_survives_power_loss() {
+    use atsamblinky::crypt::{load_key, store_key};
+    use atsamblinky::rollback::SecurityCounter;
+
+    const KEY: [u8; 16] = [0x2B; 16];
+    // every record used and confirmed, with a key and a counter beside them
+    let full = || {
+        let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+        store_key(&flash, &KEY).unwrap();
+        SecurityCounter::new(&flash).raise(7).unwrap();
+        let trial = trial(&flash);
+        for seed in 0..8u8 {
+            UPDATE.write(&flash, 0, &image(seed)).unwrap();
+            trial.stage_update().unwrap();
+            trial.boot().unwrap();
+            confirm_update(&flash).unwrap();
+        }
+        UPDATE.write(&flash, 0, &image(0x0B)).unwrap();
+        flash
+    };
+
+    let flash = full();
+    let before = flash.nvm.command_count();
+    trial(&flash).stage_update().unwrap();
+    let commands = flash.nvm.command_count() - before;
+
+    for n in 0..commands {
+        for fault in [
+            PowerFault::AfterCommands(n),
+            PowerFault::DuringCommand { n, bytes: 3 },
+            PowerFault::DuringCommand { n, bytes: 300 },
+        ] {
+            let flash = full();
+            flash.nvm.inject_power_loss(fault);
+            trial(&flash).stage_update().unwrap();
+            flash.nvm.power_cycle();
+
+            let state = boot_state(&flash).unwrap();
+            assert!(
+                state == BootState::Confirmed || state == BootState::New,
+                "{:?} -> {:?}",
+                fault,
+                state
+            );
+            assert_eq!(load_key(&flash), Ok(KEY), "{:?}", fault);
+            assert_eq!(SecurityCounter::new(&flash).value(), Ok(7), "{:?}", fault);
+
+            // staging again installs the update
+            trial(&flash).stage_update().unwrap();
+            assert_eq!(
+                trial(&flash).boot(),
+                Ok(BootState::Testing { attempts: 1 }),
+                "{:?}",
+                fault
+            );
+            assert_eq!(contents(&flash, BOOT), image(0x0B), "{:?}", fault);
+        }
+    }
+}
@@ -0,0 +1,262 @@
+use atsamblinky::atsam4l::atsam4lc8c_constants::*;
+use atsamblinky::atsam4l::partition::LAYOUT;
+use atsamblinky::atsam4l::sim::SimFlash;
+use atsamblinky::atsam4l::{FlashAddress, FlashWriterEraser};
+use atsamblinky::image::*;
+
+fn hex<const N: usize>(s: &str) -> [u8; N] {
+    let mut out = [0; N];
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).unwrap();
+    }
+    out
+}
+
+/// Writes a header and firmware to UPDATE, returning the image digest.
+fn install(
+    flash: &FlashWriterEraser<SimFlash>,
+    firmware: &[u8],
+    sign: impl Fn(&[u8; 32]) -> (u16, Vec<u8>),
+) -> [u8; 32] {
+    use sha2::{Digest, Sha256};
+
+    let mut builder = HeaderBuilder::new(firmware.len() as u32, 3, 0, HashType::Sha256);
+    let mut hasher = Sha256::new();
+    hasher.update(builder.signed_fields());
+    hasher.update(firmware);
+    let digest: [u8; 32] = hasher.finalize().into();
+    builder.push_tlv(TLV_DIGEST, &digest).unwrap();
+    let (tag, signature) = sign(&digest);
+    builder.push_tlv(tag, &signature).unwrap();
+
+    // the header shares its page with the firmware, so write both at once
+    let mut image = builder.finish().to_vec();
+    image.extend_from_slice(firmware);
+    LAYOUT.update.write(flash, 0, &image).unwrap();
+    digest
+}
+
+/// Clears a bit in the last firmware byte of the image in UPDATE.
+fn tamper(flash: &FlashWriterEraser<SimFlash>) {
+    let last = UPDATE_ADDR + RB_HDR_SIZE + 999;
+    let mut byte = [0];
+    flash
+        .hal_flash_read(FlashAddress::new(last).unwrap(), &mut byte)
+        .unwrap();
+    LAYOUT
+        .update
+        .program(flash, RB_HDR_SIZE + 999, &[byte[0] & 0xFE])
+        .unwrap();
+}
+
+fn firmware() -> Vec<u8> {
+    // spans several hash chunks and ends mid-chunk
+    (0..1000u32).map(|i| (i * 7) as u8).collect()
+}
+
+#[test]
+fn sha256_known_answer() {
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+    let builder = HeaderBuilder::new(8, 0, 0, HashType::Sha256);
+    let mut bytes = builder.finish();
+    bytes[TLV_OFFSET..TLV_OFFSET + 4].copy_from_slice(&[1, 0, 32, 0]);
+    let mut image = bytes.to_vec();
+    image.extend_from_slice(b"abcdefgh");
+    LAYOUT.boot.write(&flash, 0, &image).unwrap();
+
+    let mut buf = [0; HEADER_SIZE];
+    let header = read_header(&flash, &LAYOUT.boot, &mut buf).unwrap();
+    let digest = image_digest(&flash, &LAYOUT.boot, &header).unwrap();
+
+    use sha2::{Digest, Sha256};
+    let mut expected = Sha256::new();
+    expected.update(&bytes[..TLV_OFFSET]);
+    expected.update(b"abcdefgh");
+    assert_eq!(digest, <[u8; 32]>::from(expected.finalize()));
+
+    // FIPS 180-2 "abc"
+    assert_eq!(
+        <[u8; 32]>::from(Sha256::digest(b"abc")),
+        hex::<32>("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+    );
+}
+
+#[cfg(feature = "ed25519")]
+mod ed25519 {
+    use super::*;
+    use ed25519_dalek::{Signer, SigningKey};
+
+    const SECRET: &str = "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60";
+    const PUBLIC: &str = "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a";
+
+    #[test]
+    fn rfc8032_known_answer() {
+        // RFC 8032 section 7.1, TEST 2
+        let key = hex::<32>("3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c");
+        let signature = hex::<64>(
+            "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da\
+             085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00",
+        );
+        let key = ed25519_dalek::VerifyingKey::from_bytes(&key).unwrap();
+        let signature = ed25519_dalek::Signature::from_bytes(&signature);
+        key.verify_strict(&[0x72], &signature).unwrap();
+        assert_eq!(
+            SigningKey::from_bytes(&hex(SECRET))
+                .verifying_key()
+                .to_bytes(),
+            hex::<32>(PUBLIC)
+        );
+    }
+
+    #[test]
+    fn verifies_signed_image() {
+        let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+        let signer = SigningKey::from_bytes(&hex(SECRET));
+        install(&flash, &firmware(), |digest| {
+            (
+                TLV_SIGNATURE_ED25519,
+                signer.sign(digest).to_bytes().to_vec(),
+            )
+        });
+        let public = hex::<32>(PUBLIC);
+        let key = PublicKey::Ed25519(&public);
+
+        let mut buf = [0; HEADER_SIZE];
+        let header = read_header(&flash, &LAYOUT.update, &mut buf).unwrap();
+        assert_eq!(verify_image(&flash, &LAYOUT.update, &header, &key), Ok(()));
+
+        // the wrong key
+        let other = SigningKey::from_bytes(&[1; 32]).verifying_key().to_bytes();
+        assert_eq!(
+            verify_image(&flash, &LAYOUT.update, &header, &PublicKey::Ed25519(&other)),
+            Err(VerifyError::BadSignature)
+        );
+    }
+
+    #[test]
+    fn detects_tampering() {
+        let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+        let signer = SigningKey::from_bytes(&hex(SECRET));
+        install(&flash, &firmware(), |digest| {
+            (
+                TLV_SIGNATURE_ED25519,
+                signer.sign(digest).to_bytes().to_vec(),
+            )
+        });
+        let public = hex::<32>(PUBLIC);
+        let key = PublicKey::Ed25519(&public);
+
+        tamper(&flash);
+
+        let mut buf = [0; HEADER_SIZE];
+        let header = read_header(&flash, &LAYOUT.update, &mut buf).unwrap();
+        assert_eq!(
+            verify_image(&flash, &LAYOUT.update, &header, &key),
+            Err(VerifyError::DigestMismatch)
+        );
+    }
+}
+
+#[cfg(feature = "ecdsa-p256")]
+mod p256 {
+    use super::*;
+    use ::p256::ecdsa::signature::hazmat::PrehashSigner;
+    use ::p256::ecdsa::{Signature, SigningKey};
+
+    // RFC 6979 appendix A.2.5
+    const SECRET: &str = "c9afa9d845ba75166b5c215767b1d6934e50c3db36e89b127b8a622b120f6721";
+    const PUBLIC: &str = "0460fed4ba255a9d31c961eb74c6356d68c049b8923b61fa6ce669622e60f29fb6\
+                          7903fe1008b8bc99a41ae9e95628bc64f2f1b20c2d7e9f5177a3c294d4462299";
+
+    #[test]
+    fn rfc6979_known_answer() {
+        use sha2::{Digest, Sha256};
+
+        let public = hex::<65>(PUBLIC);
+        let key = PublicKey::P256(&public);
+        let digest: [u8; 32] = Sha256::digest(b"sample").into();
+        let signature = hex::<64>(
+            "efd48b2aacb6a8fd1140dd9cd45e81d69d2c877b56aaf991c34d0ea84eaf3716\
+             f7cb1c942d657c41d436c7a1b6e29f65f3e900dbb9aff4064dc4ab2f843acda8",
+        );
+        assert_eq!(key.verify(&digest, &signature), Ok(()));
+
+        let mut forged = signature;
+        forged[10] ^= 1;
+        assert_eq!(key.verify(&digest, &forged), Err(VerifyError::BadSignature));
+        assert_eq!(
+            PublicKey::P256(&[4; 65]).verify(&digest, &signature),
+            Err(VerifyError::BadKey)
+        );
+    }
+
+    #[test]
+    fn verifies_signed_image() {
+        let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+        let signer = SigningKey::from_slice(&hex::<32>(SECRET)).unwrap();
+        install(&flash, &firmware(), |digest| {
+            let signature: Signature = signer.sign_prehash(digest).unwrap();
+            (TLV_SIGNATURE_P256, signature.to_bytes().to_vec())
+        });
+        let public = hex::<65>(PUBLIC);
+
+        let mut buf = [0; HEADER_SIZE];
+        let header = read_header(&flash, &LAYOUT.update, &mut buf).unwrap();
+        assert_eq!(
+            verify_image(&flash, &LAYOUT.update, &header, &PublicKey::P256(&public)),
+            Ok(())
+        );
+
+        // the wrong key
+        let other = SigningKey::from_slice(&[1; 32])
+            .unwrap()
+            .verifying_key()
+            .to_encoded_point(false);
+        assert_eq!(
+            verify_image(
+                &flash,
+                &LAYOUT.update,
+                &header,
+                &PublicKey::P256(other.as_bytes())
+            ),
+            Err(VerifyError::BadSignature)
+        );
+    }
+
+    #[test]
+    fn detects_tampering() {
+        let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+        let signer = SigningKey::from_slice(&hex::<32>(SECRET)).unwrap();
+        install(&flash, &firmware(), |digest| {
+            let signature: Signature = signer.sign_prehash(digest).unwrap();
+            (TLV_SIGNATURE_P256, signature.to_bytes().to_vec())
+        });
+        let public = hex::<65>(PUBLIC);
+
+        tamper(&flash);
+
+        let mut buf = [0; HEADER_SIZE];
+        let header = read_header(&flash, &LAYOUT.update, &mut buf).unwrap();
+        assert_eq!(
+            verify_image(&flash, &LAYOUT.update, &header, &PublicKey::P256(&public)),
+            Err(VerifyError::DigestMismatch)
+        );
+    }
+
+    #[cfg(feature = "ed25519")]
+    #[test]
+    fn wrong_scheme_has_no_signature() {
+        let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+        install(&flash, &firmware(), |_| {
+            (TLV_SIGNATURE_ED25519, vec![0; 64])
+        });
+        let public = hex::<65>(PUBLIC);
+
+        let mut buf = [0; HEADER_SIZE];
+        let header = read_header(&flash, &LAYOUT.update, &mut buf).unwrap();
+        assert_eq!(
+            verify_image(&flash, &LAYOUT.update, &header, &PublicKey::P256(&public)),
+            Err(VerifyError::MissingSignature)
+        );
+    }
+}
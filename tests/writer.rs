@@ -0,0 +1,80 @@
+use atsamblinky::atsam4l::partition::LAYOUT;
+use atsamblinky::atsam4l::sim::{SimFlash, TraceLog};
+use atsamblinky::atsam4l::{Command, FlashError, FlashWriterEraser};
+use atsamblinky::writer::PageWriter;
+
+#[test]
+fn streaming_writes_each_page_once() {
+    let log = TraceLog::new();
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new()).with_trace(&log);
+    let data: Vec<u8> = (0..1500u32).map(|i| (i * 7) as u8).collect();
+
+    let mut writer = PageWriter::new(&flash, LAYOUT.update);
+    let mut offset = 0;
+    for piece in data.chunks(37) {
+        writer.write(offset, piece).unwrap();
+        offset += piece.len() as u32;
+    }
+    writer.flush().unwrap();
+    writer.flush().unwrap();
+
+    let erases = log
+        .commands()
+        .iter()
+        .filter(|(c, _)| *c == Command::Ep)
+        .count();
+    assert_eq!(erases, 3);
+    let mut buf = vec![0; data.len()];
+    LAYOUT.update.read(&flash, 0, &mut buf).unwrap();
+    assert_eq!(buf, data);
+}
+
+#[test]
+fn unwritten_bytes_keep_their_contents() {
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+    LAYOUT.update.write(&flash, 0, &[0x11; 1024]).unwrap();
+
+    let mut writer = PageWriter::new(&flash, LAYOUT.update);
+    writer.write(600, &[0x22; 4]).unwrap();
+    writer.write(10, &[0x33; 2]).unwrap();
+    writer.write(604, &[0x44; 2]).unwrap();
+    writer.flush().unwrap();
+
+    let mut buf = [0; 1024];
+    LAYOUT.update.read(&flash, 0, &mut buf).unwrap();
+    let mut expected = [0x11; 1024];
+    expected[600..604].fill(0x22);
+    expected[10..12].fill(0x33);
+    expected[604..606].fill(0x44);
+    assert_eq!(buf[..], expected[..]);
+
+    let end = LAYOUT.update.size();
+    assert_eq!(writer.write(end - 1, &[0; 2]), Err(FlashError::OutOfBounds));
+}
+
+#[test]
+fn reads_see_buffered_data() {
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+    let mut writer = PageWriter::new(&flash, LAYOUT.update);
+    writer.write(0, &[0x55; 700]).unwrap();
+
+    // 0..512 is in flash, 512..700 still in the page buffer
+    let mut buf = [0; 300];
+    writer.read(400, &mut buf).unwrap();
+    assert!(buf.iter().all(|b| *b == 0x55));
+    let mut flash_only = [0; 2];
+    LAYOUT.update.read(&flash, 600, &mut flash_only).unwrap();
+    assert_eq!(flash_only, [0xFF; 2]);
+}
+
+#[test]
+fn empty_writes_are_accepted_up_to_the_end() {
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+    let mut writer = PageWriter::new(&flash, LAYOUT.update);
+    let end = LAYOUT.update.size();
+
+    assert_eq!(writer.write(end, &[]), Ok(()));
+    assert_eq!(writer.write(end + 1, &[]), Err(FlashError::OutOfBounds));
+    writer.flush().unwrap();
+    assert_eq!(flash.nvm.command_count(), 0);
+}
@@ -0,0 +1,250 @@
+use std::collections::{HashSet, VecDeque};
+
+use atsamblinky::atsam4l::partition::LAYOUT;
+use atsamblinky::atsam4l::sim::SimFlash;
+use atsamblinky::atsam4l::FlashWriterEraser;
+use atsamblinky::writer::PageWriter;
+use atsamblinky::xmodem::*;
+
+const SOH: u8 = 0x01;
+const STX: u8 = 0x02;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+
+fn block(number: u8, data: &[u8], size: usize) -> Vec<u8> {
+    padded_block(number, data, size, 0x1A)
+}
+
+fn padded_block(number: u8, data: &[u8], size: usize, pad: u8) -> Vec<u8> {
+    let mut payload = data.to_vec();
+    payload.resize(size, pad);
+    let mut frame = vec![if size == 128 { SOH } else { STX }, number, !number];
+    frame.extend_from_slice(&payload);
+    frame.extend_from_slice(&crc16(&payload).to_be_bytes());
+    frame
+}
+
+/// A scripted sender on the other end of the line.
+///
+/// It answers the receiver's `C`/ACK/NAK bytes the way a real sender does
+/// and can be told to damage a frame the first time it goes out.
+#[derive(Default)]
+struct Sender {
+    /// Frames to send and whether each waits for a `C` rather than an ACK.
+    script: Vec<(Vec<u8>, bool)>,
+    current: usize,
+    line: VecDeque<u8>,
+    corrupt: HashSet<usize>,
+    truncate: HashSet<usize>,
+    lose_ack: HashSet<usize>,
+    ignore_first_c: bool,
+    sent: HashSet<usize>,
+    cancelled: bool,
+}
+
+impl Sender {
+    fn xmodem(data: &[u8], size: usize) -> Self {
+        let mut script: Vec<_> = data
+            .chunks(size)
+            .enumerate()
+            .map(|(i, chunk)| (block((i + 1) as u8, chunk, size), i == 0))
+            .collect();
+        script.push((vec![EOT], false));
+        Sender {
+            script,
+            ..Default::default()
+        }
+    }
+
+    fn ymodem(name: &str, data: &[u8]) -> Self {
+        let mut header = name.as_bytes().to_vec();
+        header.push(0);
+        header.extend_from_slice(format!("{} 0", data.len()).as_bytes());
+        // block 0 is padded with zeroes
+        let mut script = vec![(padded_block(0, &header, 128, 0), true)];
+        script.append(&mut Sender::xmodem(data, 1024).script);
+        script.push((padded_block(0, &[], 128, 0), true));
+        Sender {
+            script,
+            ..Default::default()
+        }
+    }
+
+    fn send_current(&mut self) {
+        let index = self.current;
+        let Some((frame, _)) = self.script.get(index) else {
+            return;
+        };
+        let mut frame = frame.clone();
+        if self.sent.insert(index) {
+            if self.corrupt.contains(&index) {
+                frame[10] ^= 0x40;
+            }
+            if self.truncate.contains(&index) {
+                frame.truncate(frame.len() / 2);
+            }
+        }
+        self.line.extend(frame);
+    }
+}
+
+impl ByteStream for &mut Sender {
+    fn read_byte(&mut self, _timeout_ms: u32) -> Option<u8> {
+        self.line.pop_front()
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        match byte {
+            b'C' if self.ignore_first_c => self.ignore_first_c = false,
+            b'C' if self.script.get(self.current).is_some_and(|f| f.1) => self.send_current(),
+            NAK => self.send_current(),
+            ACK => {
+                if self.lose_ack.remove(&self.current) {
+                    self.send_current();
+                    return;
+                }
+                self.current += 1;
+                if self.script.get(self.current).is_some_and(|f| !f.1) {
+                    self.send_current();
+                }
+            }
+            CAN => self.cancelled = true,
+            _ => {}
+        }
+    }
+}
+
+fn firmware(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 31 + i / 256) as u8).collect()
+}
+
+fn update_contents(flash: &FlashWriterEraser<SimFlash>, len: usize) -> Vec<u8> {
+    let mut buf = vec![0; len];
+    LAYOUT.update.read(flash, 0, &mut buf).unwrap();
+    buf
+}
+
+#[test]
+fn xmodem_1k_transfer() {
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+    let data = firmware(3000);
+    let mut sender = Sender::xmodem(&data, 1024);
+
+    let mut writer = PageWriter::new(&flash, LAYOUT.update);
+    let received = Receiver::new(&mut sender)
+        .receive_xmodem(&mut writer)
+        .unwrap();
+    assert_eq!(received, 3072);
+    let mut expected = data.clone();
+    expected.resize(3072, 0x1A);
+    assert_eq!(update_contents(&flash, 3072), expected);
+    assert_eq!(sender.current, sender.script.len());
+}
+
+#[test]
+fn xmodem_recovers_from_line_errors() {
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+    let data = firmware(128 * 12);
+    let mut sender = Sender::xmodem(&data, 128);
+    sender.ignore_first_c = true;
+    sender.corrupt.extend([0, 5]);
+    sender.truncate.extend([3, 11]);
+    sender.lose_ack.extend([2, 7]);
+
+    let mut writer = PageWriter::new(&flash, LAYOUT.update);
+    let received = Receiver::new(&mut sender)
+        .receive_xmodem(&mut writer)
+        .unwrap();
+    assert_eq!(received, data.len() as u32);
+    assert_eq!(update_contents(&flash, data.len()), data);
+}
+
+#[test]
+fn ymodem_transfer_stops_at_file_size() {
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+    LAYOUT.update.erase(&flash, 0, 4096).unwrap();
+    let data = firmware(2500);
+    let mut sender = Sender::ymodem("app.rbi", &data);
+    sender.corrupt.insert(0);
+
+    let mut writer = PageWriter::new(&flash, LAYOUT.update);
+    let info = Receiver::new(&mut sender)
+        .receive_ymodem(&mut writer)
+        .unwrap();
+    assert_eq!(info.name(), b"app.rbi");
+    assert_eq!(info.size, Some(2500));
+    assert_eq!(info.received, 2500);
+    assert_eq!(update_contents(&flash, 2500), data);
+    // the padding of the last block was dropped
+    assert_eq!(update_contents(&flash, 2501)[2500], 0xFF);
+    assert_eq!(sender.current, sender.script.len());
+}
+
+#[test]
+fn ymodem_acks_blocks_past_the_file_size() {
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+    LAYOUT.update.erase(&flash, 0, 4096).unwrap();
+    let data = firmware(2048);
+    // two full blocks for a file that ends with the first
+    let mut sender = Sender::ymodem("app.rbi", &data);
+    sender.script[0].0 = padded_block(0, b"app.rbi\x001024 0", 128, 0);
+
+    let mut writer = PageWriter::new(&flash, LAYOUT.update);
+    let info = Receiver::new(&mut sender)
+        .receive_ymodem(&mut writer)
+        .unwrap();
+    assert_eq!(info.size, Some(1024));
+    assert_eq!(info.received, 1024);
+    assert_eq!(update_contents(&flash, 1024), &data[..1024]);
+    assert_eq!(update_contents(&flash, 1025)[1024], 0xFF);
+    assert!(!sender.cancelled);
+    assert_eq!(sender.current, sender.script.len());
+}
+
+#[test]
+fn ymodem_refuses_oversized_files() {
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+    let mut sender = Sender::ymodem("big", &[]);
+    let mut header = b"big\0".to_vec();
+    header.extend_from_slice(format!("{}", LAYOUT.update.size() + 1).as_bytes());
+    sender.script[0].0 = padded_block(0, &header, 128, 0);
+
+    let mut writer = PageWriter::new(&flash, LAYOUT.update);
+    assert_eq!(
+        Receiver::new(&mut sender).receive_ymodem(&mut writer),
+        Err(XmodemError::TooLarge)
+    );
+    assert!(sender.cancelled);
+}
+
+#[test]
+fn transfer_errors() {
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+    let mut writer = PageWriter::new(&flash, LAYOUT.update);
+
+    // nobody on the line
+    let mut silent = Sender::default();
+    assert_eq!(
+        Receiver::new(&mut silent).receive_xmodem(&mut writer),
+        Err(XmodemError::TooManyErrors)
+    );
+
+    // the sender gives up
+    let mut quitter = Sender::default();
+    quitter.script.push((vec![CAN, CAN], true));
+    assert_eq!(
+        Receiver::new(&mut quitter).receive_xmodem(&mut writer),
+        Err(XmodemError::Cancelled)
+    );
+
+    // block 2 goes missing
+    let mut sender = Sender::xmodem(&firmware(3 * 128), 128);
+    sender.script.remove(1);
+    assert_eq!(
+        Receiver::new(&mut sender).receive_xmodem(&mut writer),
+        Err(XmodemError::Sequence)
+    );
+    assert!(sender.cancelled);
+}
@@ -0,0 +1,161 @@
+//! Host side of the flash command protocol.
+//!
+//! [`Client`] speaks the protocol over anything that implements
+//! `Read + Write`, normally a serial port, and splits large transfers into
+//! requests the target accepts.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use flash_protocol::{
+    encode_frame, FrameDecoder, Info, Request, Response, Status, MAX_DATA, MAX_FRAME, MAX_MESSAGE,
+};
+
+/// Errors seen by the host.
+#[derive(Debug)]
+pub enum ClientError {
+    Io(io::Error),
+    /// A frame or response could not be decoded.
+    Protocol(flash_protocol::Error),
+    /// The target refused the request.
+    Device(Status),
+}
+
+impl From<io::Error> for ClientError {
+    fn from(e: io::Error) -> Self {
+        ClientError::Io(e)
+    }
+}
+
+impl From<flash_protocol::Error> for ClientError {
+    fn from(e: flash_protocol::Error) -> Self {
+        ClientError::Protocol(e)
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Io(e) => write!(f, "i/o error: {}", e),
+            ClientError::Protocol(e) => write!(f, "protocol error: {:?}", e),
+            ClientError::Device(status) => write!(f, "target reported {:?}", status),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+pub type Result<T> = std::result::Result<T, ClientError>;
+
+/// A connection to the bootloader.
+pub struct Client<P> {
+    port: P,
+    decoder: FrameDecoder,
+    page_size: u32,
+}
+
+impl<P: Read + Write> Client<P> {
+    pub fn new(port: P) -> Self {
+        Client {
+            port,
+            decoder: FrameDecoder::new(),
+            page_size: MAX_DATA as u32,
+        }
+    }
+
+    /// Sends `request` and returns the raw response message.
+    fn transact(&mut self, request: &Request) -> Result<Vec<u8>> {
+        let mut message = [0u8; MAX_MESSAGE];
+        let len = request.encode(&mut message)?;
+        let mut frame = [0u8; MAX_FRAME];
+        let framed = encode_frame(&message[..len], &mut frame)?;
+        self.port.write_all(&frame[..framed])?;
+        self.port.flush()?;
+
+        let mut byte = [0u8];
+        loop {
+            self.port.read_exact(&mut byte)?;
+            if let Some(result) = self.decoder.push(byte[0]) {
+                return Ok(result?.to_vec());
+            }
+        }
+    }
+
+    /// Runs `request`, turning a failure status into an error.
+    fn call<R>(&mut self, request: Request, f: impl FnOnce(Response) -> Option<R>) -> Result<R> {
+        let message = self.transact(&request)?;
+        match Response::decode(&request, &message)? {
+            Response::Failed(status) => Err(ClientError::Device(status)),
+            response => f(response).ok_or(ClientError::Protocol(flash_protocol::Error::Length)),
+        }
+    }
+
+    pub fn info(&mut self) -> Result<Info> {
+        let info = self.call(Request::Info, |r| match r {
+            Response::Info(info) => Some(info),
+            _ => None,
+        })?;
+        self.page_size = info.page_size;
+        Ok(info)
+    }
+
+    pub fn read(&mut self, address: u32, len: usize) -> Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(len);
+        while out.len() < len {
+            let chunk = (len - out.len()).min(MAX_DATA);
+            let request = Request::Read {
+                address: address + out.len() as u32,
+                len: chunk as u16,
+            };
+            let data = self.call(request, |r| match r {
+                Response::Data(data) => Some(data.to_vec()),
+                _ => None,
+            })?;
+            out.extend_from_slice(&data);
+        }
+        Ok(out)
+    }
+
+    /// Writes `data`, one request per page.
+    ///
+    /// Every page touched is erased first, including the parts of the first
+    /// and last page that `data` does not cover.
+    pub fn write(&mut self, address: u32, data: &[u8]) -> Result<()> {
+        let page = self.page_size.min(MAX_DATA as u32);
+        let mut done = 0usize;
+        while done < data.len() {
+            let at = address + done as u32;
+            let room = (page - at % page) as usize;
+            let chunk = room.min(data.len() - done);
+            let request = Request::Write {
+                address: at,
+                data: &data[done..done + chunk],
+            };
+            self.call(request, |_| Some(()))?;
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    pub fn erase(&mut self, address: u32, len: u32) -> Result<()> {
+        self.call(Request::Erase { address, len }, |_| Some(()))
+    }
+
+    pub fn blank_check(&mut self, address: u32) -> Result<bool> {
+        self.call(Request::BlankCheck { address }, |r| match r {
+            Response::Blank(blank) => Some(blank),
+            _ => None,
+        })
+    }
+
+    pub fn crc(&mut self, address: u32, len: u32) -> Result<u32> {
+        self.call(Request::Crc { address, len }, |r| match r {
+            Response::Crc(crc) => Some(crc),
+            _ => None,
+        })
+    }
+
+    pub fn reboot(&mut self) -> Result<()> {
+        self.call(Request::Reboot, |_| Some(()))
+    }
+}
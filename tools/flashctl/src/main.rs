@@ -0,0 +1,136 @@
+use std::fs;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Duration;
+
+use clap::{Parser, Subcommand};
+use flashctl::Client;
+
+/// Program the flash of a device running the bootloader over a serial port.
+#[derive(Parser)]
+#[command(version)]
+struct Args {
+    /// Serial port, e.g. /dev/ttyUSB0
+    #[arg(short, long)]
+    port: String,
+    #[arg(short, long, default_value_t = 115_200)]
+    baud: u32,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Show the flash geometry
+    Info,
+    /// Read flash to a file, or to stdout as hex
+    Read {
+        #[arg(value_parser = number)]
+        address: u32,
+        #[arg(value_parser = number)]
+        len: u32,
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Write a file to flash, erasing every page it touches
+    Write {
+        #[arg(value_parser = number)]
+        address: u32,
+        file: PathBuf,
+    },
+    /// Erase the pages covering a range
+    Erase {
+        #[arg(value_parser = number)]
+        address: u32,
+        #[arg(value_parser = number)]
+        len: u32,
+    },
+    /// Check whether the page holding an address is erased
+    Blank {
+        #[arg(value_parser = number)]
+        address: u32,
+    },
+    /// CRC-32 of a range
+    Crc {
+        #[arg(value_parser = number)]
+        address: u32,
+        #[arg(value_parser = number)]
+        len: u32,
+    },
+    /// Reset the device
+    Reboot,
+}
+
+/// Parses decimal or 0x-prefixed hex.
+fn number(s: &str) -> Result<u32, String> {
+    let parsed = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    parsed.map_err(|e| e.to_string())
+}
+
+fn run(args: Args) -> Result<(), Box<dyn std::error::Error>> {
+    let port = serialport::new(&args.port, args.baud)
+        .timeout(Duration::from_secs(5))
+        .open()?;
+    let mut client = Client::new(port);
+
+    match args.command {
+        Command::Info => {
+            let info = client.info()?;
+            println!(
+                "flash      {:#010x}, {} KiB",
+                info.flash_base,
+                info.flash_size / 1024
+            );
+            println!("page size  {} bytes", info.page_size);
+            println!("user page  {:#010x}", info.user_page_base);
+            println!("max data   {} bytes per request", info.max_data);
+        }
+        Command::Read {
+            address,
+            len,
+            output,
+        } => {
+            let data = client.read(address, len as usize)?;
+            match output {
+                Some(path) => fs::write(path, data)?,
+                None => {
+                    for (i, line) in data.chunks(16).enumerate() {
+                        let hex: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+                        println!("{:08x}  {}", address as usize + i * 16, hex.join(" "));
+                    }
+                }
+            }
+        }
+        Command::Write { address, file } => {
+            let data = fs::read(file)?;
+            client.info()?;
+            client.write(address, &data)?;
+            let crc = client.crc(address, data.len() as u32)?;
+            if crc != flash_protocol::crc32(&data) {
+                return Err("CRC mismatch after write".into());
+            }
+            println!("wrote {} bytes at {:#010x}", data.len(), address);
+        }
+        Command::Erase { address, len } => client.erase(address, len)?,
+        Command::Blank { address } => {
+            let blank = client.blank_check(address)?;
+            println!("{}", if blank { "blank" } else { "not blank" });
+        }
+        Command::Crc { address, len } => println!("{:#010x}", client.crc(address, len)?),
+        Command::Reboot => client.reboot()?,
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    match run(Args::parse()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("flashctl: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
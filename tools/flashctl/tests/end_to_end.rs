@@ -0,0 +1,183 @@
+//! Runs the client against the target dispatcher and the simulated flash,
+//! connected by an in-memory pipe.
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+
+use atsamblinky::atsam4l::atsam4lc8c_constants::{BASE_ADDR, PTABLE_ADDR};
+use atsamblinky::atsam4l::sim::SimFlash;
+use atsamblinky::atsam4l::FlashWriterEraser;
+use atsamblinky::dispatch::{Dispatcher, Event};
+use atsamblinky::xmodem::ByteStream;
+use flash_protocol::{crc32, Status};
+use flashctl::{Client, ClientError};
+
+/// The target's end of the line.
+struct Line<'a> {
+    rx: &'a mut VecDeque<u8>,
+    tx: &'a mut VecDeque<u8>,
+}
+
+impl ByteStream for Line<'_> {
+    fn read_byte(&mut self, _timeout_ms: u32) -> Option<u8> {
+        self.rx.pop_front()
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        self.tx.push_back(byte);
+    }
+}
+
+/// The host's end: bytes written reach the dispatcher, which runs as soon
+/// as the host waits for an answer.
+struct Pipe<'a> {
+    target: Dispatcher<'a, SimFlash, ()>,
+    to_target: VecDeque<u8>,
+    to_host: VecDeque<u8>,
+    /// Flip a bit in the next byte sent to the target.
+    corrupt_next: bool,
+    reboots: u32,
+}
+
+impl Write for Pipe<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.to_target.extend(buf);
+        if std::mem::take(&mut self.corrupt_next) {
+            self.to_target[1] ^= 0x10;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for Pipe<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.to_host.is_empty() {
+            let mut line = Line {
+                rx: &mut self.to_target,
+                tx: &mut self.to_host,
+            };
+            if self.target.poll(&mut line, 0) == Event::Reboot {
+                self.reboots += 1;
+            }
+        }
+        let mut n = 0;
+        while n < buf.len() {
+            match self.to_host.pop_front() {
+                Some(byte) => buf[n] = byte,
+                None => break,
+            }
+            n += 1;
+        }
+        if n == 0 {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        Ok(n)
+    }
+}
+
+fn connect(flash: &FlashWriterEraser<SimFlash>) -> Client<Pipe<'_>> {
+    Client::new(Pipe {
+        target: Dispatcher::new(flash),
+        to_target: VecDeque::new(),
+        to_host: VecDeque::new(),
+        corrupt_next: false,
+        reboots: 0,
+    })
+}
+
+#[test]
+fn program_and_check_an_image() {
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+    let mut client = connect(&flash);
+
+    let info = client.info().unwrap();
+    assert_eq!(info.flash_size, 512 * 1024);
+    assert_eq!(info.page_size, 512);
+
+    let image: Vec<u8> = (0..3000u32).map(|i| (i * 13 + 5) as u8).collect();
+    let base = 0x1_0100;
+    client.write(base, &image).unwrap();
+    assert_eq!(client.read(base, image.len()).unwrap(), image);
+    assert_eq!(
+        &flash.nvm.flash()[base as usize..][..image.len()],
+        &image[..]
+    );
+    assert_eq!(client.crc(base, image.len() as u32).unwrap(), crc32(&image));
+
+    assert!(!client.blank_check(base).unwrap());
+    client.erase(0x1_0000, 0x1000).unwrap();
+    assert!(client.blank_check(base).unwrap());
+    assert_eq!(client.read(base, 4).unwrap(), vec![0xFF; 4]);
+}
+
+#[test]
+fn errors_reach_the_host() {
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+    let mut client = connect(&flash);
+
+    match client.read(0x7_FFFE, 4) {
+        Err(ClientError::Device(Status::OutOfBounds)) => {}
+        other => panic!("{:?}", other),
+    }
+
+    // the first page of BOOT
+    flash
+        .hal_flash_lock(atsamblinky::atsam4l::PageNumber::new(0x80).unwrap())
+        .unwrap();
+    match client.erase(0x1_0000, 512) {
+        Err(ClientError::Device(Status::Locked)) => {}
+        other => panic!("{:?}", other),
+    }
+}
+
+#[test]
+fn bootloader_partition_is_refused() {
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+    let mut client = connect(&flash);
+    let before = flash.nvm.command_count();
+
+    for (address, len) in [(0x0, 512), (PTABLE_ADDR, 4), (BASE_ADDR - 8, 16)] {
+        match client.erase(address, len) {
+            Err(ClientError::Device(Status::Locked)) => {}
+            other => panic!("erase {:#x}: {:?}", address, other),
+        }
+        match client.write(address, &vec![0; len as usize]) {
+            Err(ClientError::Device(Status::Locked)) => {}
+            other => panic!("write {:#x}: {:?}", address, other),
+        }
+    }
+    assert_eq!(flash.nvm.command_count(), before);
+
+    // BOOT starts right after it
+    client.erase(BASE_ADDR, 512).unwrap();
+}
+
+#[test]
+fn damaged_request_and_reboot() {
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+    let mut client = connect(&flash);
+
+    // reach into the pipe through a second client over the same flash
+    let mut pipe = Pipe {
+        target: Dispatcher::new(&flash),
+        to_target: VecDeque::new(),
+        to_host: VecDeque::new(),
+        corrupt_next: true,
+        reboots: 0,
+    };
+    {
+        let mut damaged = Client::new(&mut pipe);
+        match damaged.info() {
+            Err(ClientError::Device(Status::BadFrame)) => {}
+            other => panic!("{:?}", other),
+        }
+        damaged.reboot().unwrap();
+    }
+    assert_eq!(pipe.reboots, 1);
+
+    client.info().unwrap();
+}
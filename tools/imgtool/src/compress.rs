@@ -0,0 +1,15 @@
+//! LZ4 compression of images for [`atsamblinky::lz4::Decompressor`].
+
+use atsamblinky::lz4::MAGIC;
+use flash_protocol::crc32;
+
+/// Returns `image` as a compressed image: the header followed by one LZ4
+/// block.
+pub fn compress(image: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&(image.len() as u32).to_le_bytes());
+    out.extend_from_slice(&crc32(image).to_le_bytes());
+    out.extend_from_slice(&lz4_flex::block::compress(image));
+    out
+}
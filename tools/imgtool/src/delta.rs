@@ -0,0 +1,183 @@
+//! Delta generation for differential updates.
+//!
+//! Produces the format applied on the target by
+//! [`atsamblinky::delta::Patcher`]. Matches are found bsdiff style: an
+//! exact match of at least [`MIN_MATCH`] bytes anchors a region, which is
+//! then extended over bytes that mostly agree with the old image. Inside a
+//! region equal runs become copies and the rest becomes additions, so a
+//! change that shifts every address by a few bytes still costs little.
+
+use std::collections::HashMap;
+
+use atsamblinky::delta::{MAGIC, OP_ADD, OP_COPY, OP_END, OP_INSERT, OP_SEEK};
+use flash_protocol::crc32;
+
+/// Shortest exact match worth leaving literal bytes for.
+pub const MIN_MATCH: usize = 12;
+/// Candidate positions kept per hash bucket.
+const MAX_CANDIDATES: usize = 16;
+/// Window over which an extended region must agree for at least half its
+/// bytes.
+const WINDOW: usize = 16;
+/// Shortest equal run emitted as a copy rather than inside an addition.
+const MIN_COPY: usize = 8;
+
+/// Returns a delta that turns `source` into `target`.
+pub fn make_delta(source: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&(source.len() as u32).to_le_bytes());
+    out.extend_from_slice(&crc32(source).to_le_bytes());
+    out.extend_from_slice(&(target.len() as u32).to_le_bytes());
+
+    let index = build_index(source);
+    let mut cursor = 0usize;
+    let mut pos = 0usize;
+    let mut literal = 0usize;
+    while pos < target.len() {
+        let Some(start) = best_match(source, target, pos, cursor, &index) else {
+            pos += 1;
+            continue;
+        };
+        if literal < pos {
+            instruction(&mut out, OP_INSERT, (pos - literal) as u64);
+            out.extend_from_slice(&target[literal..pos]);
+        }
+        if start != cursor {
+            let offset = start as i64 - cursor as i64;
+            instruction(&mut out, OP_SEEK, ((offset << 1) ^ (offset >> 63)) as u64);
+        }
+        let len = extend(source, target, start, pos);
+        emit_region(
+            &mut out,
+            &source[start..start + len],
+            &target[pos..pos + len],
+        );
+        cursor = start + len;
+        pos += len;
+        literal = pos;
+    }
+    if literal < target.len() {
+        instruction(&mut out, OP_INSERT, (target.len() - literal) as u64);
+        out.extend_from_slice(&target[literal..]);
+    }
+    out.push(OP_END);
+    out
+}
+
+fn key(bytes: &[u8]) -> u64 {
+    let mut k = [0u8; 8];
+    k.copy_from_slice(&bytes[..8]);
+    u64::from_le_bytes(k)
+}
+
+fn build_index(source: &[u8]) -> HashMap<u64, Vec<usize>> {
+    let mut index: HashMap<u64, Vec<usize>> = HashMap::new();
+    for i in 0..source.len().saturating_sub(7) {
+        let bucket = index.entry(key(&source[i..])).or_default();
+        if bucket.len() < MAX_CANDIDATES {
+            bucket.push(i);
+        }
+    }
+    index
+}
+
+fn common_prefix(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(x, y)| x == y).count()
+}
+
+/// Finds where in `source` the bytes at `target[pos..]` best continue,
+/// preferring the current cursor on a tie.
+fn best_match(
+    source: &[u8],
+    target: &[u8],
+    pos: usize,
+    cursor: usize,
+    index: &HashMap<u64, Vec<usize>>,
+) -> Option<usize> {
+    let rest = &target[pos..];
+    let mut best = None;
+    let mut best_len = MIN_MATCH - 1;
+    if cursor < source.len() {
+        let len = common_prefix(&source[cursor..], rest);
+        if len > best_len {
+            best = Some(cursor);
+            best_len = len;
+        }
+    }
+    if rest.len() >= 8 {
+        for &start in index.get(&key(rest)).into_iter().flatten() {
+            let len = common_prefix(&source[start..], rest);
+            if len > best_len {
+                best = Some(start);
+                best_len = len;
+            }
+        }
+    }
+    best
+}
+
+/// Extends an exact match past differing bytes for as long as each
+/// following window mostly agrees with the old image.
+fn extend(source: &[u8], target: &[u8], start: usize, pos: usize) -> usize {
+    let max = (source.len() - start).min(target.len() - pos);
+    let mut len = common_prefix(&source[start..start + max], &target[pos..pos + max]);
+    while len < max {
+        let end = (len + WINDOW).min(max);
+        let equal = (len..end)
+            .filter(|i| source[start + i] == target[pos + i])
+            .count();
+        if equal * 2 < end - len {
+            break;
+        }
+        len = end;
+        len += common_prefix(
+            &source[start + len..start + max],
+            &target[pos + len..pos + max],
+        );
+    }
+    // do not end an addition on bytes that differ
+    while len > 0 && source[start + len - 1] != target[pos + len - 1] {
+        len -= 1;
+    }
+    len
+}
+
+/// Splits a region into copies of equal runs and additions of the rest.
+fn emit_region(out: &mut Vec<u8>, old: &[u8], new: &[u8]) {
+    let mut i = 0;
+    let mut add_start = 0;
+    while i < new.len() {
+        let run = common_prefix(&old[i..], &new[i..]);
+        if run >= MIN_COPY || (run > 0 && i + run == new.len()) {
+            emit_add(out, &old[add_start..i], &new[add_start..i]);
+            instruction(out, OP_COPY, run as u64);
+            i += run;
+            add_start = i;
+        } else {
+            i += run.max(1);
+        }
+    }
+    emit_add(out, &old[add_start..], &new[add_start..]);
+}
+
+fn emit_add(out: &mut Vec<u8>, old: &[u8], new: &[u8]) {
+    if new.is_empty() {
+        return;
+    }
+    instruction(out, OP_ADD, new.len() as u64);
+    out.extend(new.iter().zip(old).map(|(n, o)| n.wrapping_sub(*o)));
+}
+
+fn instruction(out: &mut Vec<u8>, op: u8, mut value: u64) {
+    out.push(op);
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            break;
+        }
+        out.push(byte | 0x80);
+    }
+}
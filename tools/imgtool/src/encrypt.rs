@@ -0,0 +1,52 @@
+//! AES-128-CTR encryption of images for [`atsamblinky::crypt::Decryptor`].
+
+use aes::cipher::{KeyIvInit, StreamCipher};
+use atsamblinky::crypt::{KEY_SIZE, MAGIC};
+use sha2::{Digest, Sha256};
+
+use crate::{Error, Result};
+
+type Aes128Ctr = ctr::Ctr128BE<aes::Aes128>;
+
+/// Reads a key given as 32 hex digits or as 16 raw bytes.
+pub fn parse_key(bytes: &[u8]) -> Result<[u8; KEY_SIZE]> {
+    let mut key = [0; KEY_SIZE];
+    if bytes.len() == KEY_SIZE {
+        key.copy_from_slice(bytes);
+        return Ok(key);
+    }
+    let text = std::str::from_utf8(bytes)
+        .map_err(|_| Error::Key("expected 16 bytes or 32 hex digits".into()))?
+        .trim();
+    if text.len() != 2 * KEY_SIZE {
+        return Err(Error::Key("expected 16 bytes or 32 hex digits".into()));
+    }
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&text[2 * i..2 * i + 2], 16)
+            .map_err(|_| Error::Key(format!("bad hex digit in {:?}", text)))?;
+    }
+    Ok(key)
+}
+
+/// Returns `image` as an encrypted image.
+///
+/// The IV is derived from the key and the image, so encrypting the same
+/// image twice gives the same output and different images never share a
+/// counter sequence.
+pub fn encrypt(image: &[u8], key: &[u8; KEY_SIZE]) -> Vec<u8> {
+    let digest = Sha256::new()
+        .chain_update(key)
+        .chain_update(image)
+        .finalize();
+    let mut iv = [0u8; 16];
+    // the last four bytes count blocks, enough for 64 GiB
+    iv[..12].copy_from_slice(&digest[..12]);
+
+    let mut out = Vec::with_capacity(4 + iv.len() + image.len());
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&iv);
+    let mut data = image.to_vec();
+    Aes128Ctr::new(key.into(), &iv.into()).apply_keystream(&mut data);
+    out.extend_from_slice(&data);
+    out
+}
@@ -0,0 +1,120 @@
+//! Loading the linked firmware.
+
+use atsamblinky::atsam4l::atsam4lc8c_constants::*;
+use object::elf::PT_LOAD;
+use object::read::elf::{ElfFile32, ProgramHeader};
+use object::Endianness;
+
+use crate::{Error, Result};
+
+/// Address the vector table of every image must be linked at.
+pub const LOAD_ADDRESS: u32 = BASE_ADDR + RB_HDR_SIZE;
+
+/// Firmware flattened into one block of memory.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Firmware {
+    /// Address of the first byte, the start of the vector table.
+    pub address: u32,
+    pub data: Vec<u8>,
+}
+
+impl Firmware {
+    /// Takes a raw binary, which carries no addresses, as linked for BOOT.
+    pub fn from_bin(data: Vec<u8>) -> Result<Self> {
+        let firmware = Firmware {
+            address: LOAD_ADDRESS,
+            data,
+        };
+        firmware.check()?;
+        Ok(firmware)
+    }
+
+    /// Flattens the loadable segments of an ELF file, filling gaps with 0xFF.
+    ///
+    /// Segments are placed by their physical (load) address, so initialised
+    /// data is included where the startup code copies it from.
+    pub fn from_elf(bytes: &[u8]) -> Result<Self> {
+        let elf = ElfFile32::<Endianness>::parse(bytes).map_err(|e| Error::Elf(e.to_string()))?;
+        let endian = elf.endian();
+        let mut segments = Vec::new();
+        for header in elf.elf_program_headers() {
+            if header.p_type(endian) != PT_LOAD || header.p_filesz(endian) == 0 {
+                continue;
+            }
+            let data = header
+                .data(endian, bytes)
+                .map_err(|_| Error::Elf("segment outside the file".into()))?;
+            segments.push((header.p_paddr(endian), data));
+        }
+
+        let start = segments
+            .iter()
+            .map(|(address, _)| *address)
+            .min()
+            .ok_or_else(|| Error::Elf("no loadable segments".into()))?;
+        let end = segments
+            .iter()
+            .map(|(address, data)| *address as u64 + data.len() as u64)
+            .max()
+            .unwrap_or(start as u64);
+        if start != LOAD_ADDRESS {
+            return Err(Error::Placement(format!(
+                "image starts at {:#010x}, expected {:#010x} (BOOT + RB_HDR_SIZE)",
+                start, LOAD_ADDRESS
+            )));
+        }
+        let len = (end - start as u64) as usize;
+        if len as u32 > PARTITION_SIZE - RB_HDR_SIZE {
+            return Err(Error::Image(format!(
+                "image is {} bytes, too large for a partition",
+                len
+            )));
+        }
+
+        let mut data = vec![0xFF; len];
+        for (address, segment) in segments {
+            let at = (address - start) as usize;
+            data[at..at + segment.len()].copy_from_slice(segment);
+        }
+        let firmware = Firmware {
+            address: start,
+            data,
+        };
+        firmware.check()?;
+        Ok(firmware)
+    }
+
+    fn word(&self, index: usize) -> Option<u32> {
+        let bytes = self.data.get(index * 4..index * 4 + 4)?;
+        Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// Checks the initial stack pointer and reset vector the way the
+    /// bootloader's `boot_from` will.
+    pub fn check(&self) -> Result<()> {
+        let (sp, reset) = match (self.word(0), self.word(1)) {
+            (Some(sp), Some(reset)) => (sp, reset),
+            _ => return Err(Error::VectorTable("image is shorter than 8 bytes".into())),
+        };
+        if sp & 0x03 != 0 || !(STACK_LOW..=STACK_UP).contains(&sp) {
+            return Err(Error::VectorTable(format!(
+                "initial stack pointer {:#010x} is not a word in SRAM",
+                sp
+            )));
+        }
+        let end = self.address + self.data.len() as u32;
+        if reset & 1 == 0 || reset & !1 < self.address + 8 || reset & !1 >= end {
+            return Err(Error::VectorTable(format!(
+                "reset vector {:#010x} is not a Thumb address inside the image",
+                reset
+            )));
+        }
+        if self.data.len() as u32 > PARTITION_SIZE - RB_HDR_SIZE {
+            return Err(Error::Image(format!(
+                "image is {} bytes, too large for a partition",
+                self.data.len()
+            )));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,56 @@
+//! Signing and packaging of firmware images.
+//!
+//! The application is linked to run from the BOOT partition with its vector
+//! table `RB_HDR_SIZE` bytes past the partition start, leaving room for the
+//! image header. `imgtool` loads the linked firmware, checks it was linked
+//! that way, prepends a signed header and writes the result in a format a
+//! programmer or the bootloader can take.
+
+pub mod compress;
+pub mod delta;
+pub mod encrypt;
+pub mod input;
+pub mod output;
+pub mod ptable;
+pub mod sign;
+
+use std::fmt;
+
+/// Errors reported by `imgtool`.
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    /// The ELF file could not be parsed.
+    Elf(String),
+    /// The firmware is not linked for the BOOT partition.
+    Placement(String),
+    /// The vector table is implausible.
+    VectorTable(String),
+    /// The key could not be loaded.
+    Key(String),
+    /// Anything else wrong with the input.
+    Image(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Elf(e) => write!(f, "bad ELF file: {}", e),
+            Error::Placement(e) => write!(f, "wrong link address: {}", e),
+            Error::VectorTable(e) => write!(f, "bad vector table: {}", e),
+            Error::Key(e) => write!(f, "bad key: {}", e),
+            Error::Image(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
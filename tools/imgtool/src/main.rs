@@ -0,0 +1,195 @@
+use std::fs;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use atsamblinky::atsam4l::atsam4lc8c_constants::PTABLE_ADDR;
+use atsamblinky::atsam4l::partition::LAYOUT;
+use clap::{Parser, Subcommand, ValueEnum};
+use imgtool::compress::compress;
+use imgtool::delta::make_delta;
+use imgtool::encrypt::{encrypt, parse_key};
+use imgtool::input::Firmware;
+use imgtool::output::{to_ihex, to_uf2};
+use imgtool::ptable::{make_table, parse_entry};
+use imgtool::sign::{parse_version, sign_image, ImageInfo, SigningKey};
+
+/// Sign and package firmware images for the bootloader.
+#[derive(Parser)]
+#[command(version)]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Add a signed header to a firmware image
+    Sign {
+        /// Firmware as an ELF file or a raw .bin
+        input: PathBuf,
+        /// Where to write the signed image
+        output: PathBuf,
+        /// P-256 or Ed25519 private key in PEM
+        #[arg(short, long)]
+        key: PathBuf,
+        /// Firmware version, a number or major.minor.patch
+        #[arg(short, long, value_parser = parse_version)]
+        version: u32,
+        /// Build time in seconds since the epoch, default now
+        #[arg(long)]
+        timestamp: Option<u64>,
+        /// Output format, default from the output file extension
+        #[arg(short, long)]
+        format: Option<Format>,
+        /// Partition the .hex or .uf2 output is placed in
+        #[arg(long, default_value = "update")]
+        slot: Slot,
+    },
+    /// Compress a signed .bin image with LZ4
+    Compress {
+        /// The image to install
+        input: PathBuf,
+        /// Where to write the compressed image
+        output: PathBuf,
+    },
+    /// Encrypt a signed .bin image with AES-128-CTR
+    Encrypt {
+        /// The image to install
+        input: PathBuf,
+        /// Where to write the encrypted image
+        output: PathBuf,
+        /// The device key, as 32 hex digits or 16 raw bytes
+        #[arg(short, long)]
+        key: PathBuf,
+    },
+    /// Make a delta that turns one signed .bin image into another
+    Delta {
+        /// The image currently in BOOT
+        old: PathBuf,
+        /// The image to install
+        new: PathBuf,
+        /// Where to write the delta
+        output: PathBuf,
+    },
+    /// Generate the partition table page
+    PartitionTable {
+        /// Where to write the page, as .bin or .hex
+        output: PathBuf,
+        /// Partitions as name:kind:start:size, default the built-in layout
+        entries: Vec<String>,
+    },
+    /// Print the public key of a private key, as hex
+    PublicKey { key: PathBuf },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+    Bin,
+    Hex,
+    Uf2,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Slot {
+    Boot,
+    Update,
+}
+
+fn load_key(path: &PathBuf) -> Result<SigningKey, Box<dyn std::error::Error>> {
+    Ok(SigningKey::from_pem(&fs::read_to_string(path)?)?)
+}
+
+fn run(args: Args) -> Result<(), Box<dyn std::error::Error>> {
+    match args.command {
+        Command::Sign {
+            input,
+            output,
+            key,
+            version,
+            timestamp,
+            format,
+            slot,
+        } => {
+            let bytes = fs::read(&input)?;
+            let firmware = if bytes.starts_with(b"\x7fELF") {
+                Firmware::from_elf(&bytes)?
+            } else {
+                Firmware::from_bin(bytes)?
+            };
+            let timestamp = match timestamp {
+                Some(t) => t,
+                None => SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            };
+            let image = sign_image(
+                &firmware,
+                ImageInfo { version, timestamp },
+                &load_key(&key)?,
+            )?;
+
+            let format =
+                format.unwrap_or_else(|| match output.extension().and_then(|e| e.to_str()) {
+                    Some("hex") | Some("ihex") => Format::Hex,
+                    Some("uf2") => Format::Uf2,
+                    _ => Format::Bin,
+                });
+            let base = match slot {
+                Slot::Boot => LAYOUT.boot.start(),
+                Slot::Update => LAYOUT.update.start(),
+            };
+            match format {
+                Format::Bin => fs::write(&output, &image)?,
+                Format::Hex => fs::write(&output, to_ihex(base, &image))?,
+                Format::Uf2 => fs::write(&output, to_uf2(base, &image))?,
+            }
+            println!(
+                "signed {} bytes of firmware, version {:#010x}",
+                firmware.data.len(),
+                version
+            );
+        }
+        Command::Compress { input, output } => {
+            let image = fs::read(&input)?;
+            let compressed = compress(&image);
+            fs::write(&output, &compressed)?;
+            println!("compressed {} bytes to {}", image.len(), compressed.len());
+        }
+        Command::Encrypt { input, output, key } => {
+            let key = parse_key(&fs::read(&key)?)?;
+            fs::write(&output, encrypt(&fs::read(&input)?, &key))?;
+        }
+        Command::Delta { old, new, output } => {
+            let new = fs::read(&new)?;
+            let delta = make_delta(&fs::read(&old)?, &new);
+            fs::write(&output, &delta)?;
+            println!("{} byte delta for a {} byte image", delta.len(), new.len());
+        }
+        Command::PartitionTable { output, entries } => {
+            let entries = entries
+                .iter()
+                .map(|e| parse_entry(e))
+                .collect::<Result<Vec<_>, _>>()?;
+            let page = make_table(&entries)?;
+            match output.extension().and_then(|e| e.to_str()) {
+                Some("hex") | Some("ihex") => fs::write(&output, to_ihex(PTABLE_ADDR, &page))?,
+                _ => fs::write(&output, &page)?,
+            }
+        }
+        Command::PublicKey { key } => {
+            let public = load_key(&key)?.public_key();
+            let hex: String = public.iter().map(|b| format!("{:02x}", b)).collect();
+            println!("{}", hex);
+        }
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    match run(Args::parse()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("imgtool: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,72 @@
+//! Output formats for a signed image.
+
+/// Intel HEX with extended linear address records and 16 data bytes per
+/// line.
+pub fn to_ihex(address: u32, data: &[u8]) -> String {
+    fn record(out: &mut String, kind: u8, offset: u16, data: &[u8]) {
+        let mut bytes = vec![data.len() as u8, (offset >> 8) as u8, offset as u8, kind];
+        bytes.extend_from_slice(data);
+        let checksum = bytes
+            .iter()
+            .fold(0u8, |sum, b| sum.wrapping_add(*b))
+            .wrapping_neg();
+        out.push(':');
+        for byte in bytes.iter().chain(Some(&checksum)) {
+            out.push_str(&format!("{:02X}", byte));
+        }
+        out.push('\n');
+    }
+
+    let mut out = String::new();
+    let mut upper = None;
+    let mut at = address;
+    for chunk in data.chunks(16) {
+        // a line must not cross a 64 KiB boundary
+        let room = 0x1_0000 - (at & 0xFFFF) as usize;
+        let (first, rest) = chunk.split_at(chunk.len().min(room));
+        for piece in [first, rest] {
+            if piece.is_empty() {
+                continue;
+            }
+            if upper != Some(at >> 16) {
+                upper = Some(at >> 16);
+                record(&mut out, 0x04, 0, &((at >> 16) as u16).to_be_bytes());
+            }
+            record(&mut out, 0x00, at as u16, piece);
+            at += piece.len() as u32;
+        }
+    }
+    record(&mut out, 0x01, 0, &[]);
+    out
+}
+
+const UF2_MAGIC_START0: u32 = 0x0A32_4655;
+const UF2_MAGIC_START1: u32 = 0x9E5D_5157;
+const UF2_MAGIC_END: u32 = 0x0AB1_6F30;
+const UF2_PAYLOAD: usize = 256;
+
+/// UF2, 256 data bytes per 512-byte block, no family ID.
+pub fn to_uf2(address: u32, data: &[u8]) -> Vec<u8> {
+    let blocks = data.chunks(UF2_PAYLOAD).count() as u32;
+    let mut out = Vec::with_capacity(blocks as usize * 512);
+    for (index, chunk) in data.chunks(UF2_PAYLOAD).enumerate() {
+        let mut block = [0u8; 512];
+        let header = [
+            UF2_MAGIC_START0,
+            UF2_MAGIC_START1,
+            0,
+            address + (index * UF2_PAYLOAD) as u32,
+            chunk.len() as u32,
+            index as u32,
+            blocks,
+            0,
+        ];
+        for (i, word) in header.iter().enumerate() {
+            block[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+        }
+        block[32..32 + chunk.len()].copy_from_slice(chunk);
+        block[508..].copy_from_slice(&UF2_MAGIC_END.to_le_bytes());
+        out.extend_from_slice(&block);
+    }
+    out
+}
@@ -0,0 +1,58 @@
+//! Partition tables for [`atsamblinky::ptable::PartitionTable`].
+
+use atsamblinky::atsam4l::atsam4lc8c_constants::FLASH_PAGE_SIZE;
+use atsamblinky::atsam4l::partition::{Partition, PartitionKind, LAYOUT};
+use atsamblinky::ptable::{kind_name, PartitionTable};
+
+use crate::{Error, Result};
+
+fn number(s: &str) -> Option<u32> {
+    let s = s.replace('_', "");
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+/// Parses an entry given as `name:kind:start:size`, for example
+/// `settings:data:0x74800:0x1000`.
+pub fn parse_entry(s: &str) -> Result<(String, Partition)> {
+    let bad = |why: &str| Error::Image(format!("bad partition {:?}: {}", s, why));
+    let fields: Vec<&str> = s.split(':').collect();
+    let [name, kind, start, size] = fields.as_slice() else {
+        return Err(bad("expected name:kind:start:size"));
+    };
+    let kinds = [
+        PartitionKind::Bootloader,
+        PartitionKind::Boot,
+        PartitionKind::Update,
+        PartitionKind::Swap,
+        PartitionKind::Data,
+    ];
+    let kind = kinds
+        .into_iter()
+        .find(|k| kind_name(*k) == *kind)
+        .ok_or_else(|| bad("unknown kind"))?;
+    let start = number(start).ok_or_else(|| bad("bad start address"))?;
+    let size = number(size).ok_or_else(|| bad("bad size"))?;
+    let partition =
+        Partition::try_new(kind, start, size).map_err(|_| bad("not whole pages inside flash"))?;
+    Ok((name.to_string(), partition))
+}
+
+/// Returns the page holding a table of `entries`, or of the compiled-in
+/// layout when there are none.
+pub fn make_table(entries: &[(String, Partition)]) -> Result<Vec<u8>> {
+    let mut table = PartitionTable::new();
+    if entries.is_empty() {
+        table = PartitionTable::from_layout(&LAYOUT);
+    }
+    for (name, partition) in entries {
+        table
+            .push(name, *partition)
+            .map_err(|e| Error::Image(format!("partition {:?}: {:?}", name, e)))?;
+    }
+    let mut page = [0u8; FLASH_PAGE_SIZE as usize];
+    table.encode(&mut page);
+    Ok(page.to_vec())
+}
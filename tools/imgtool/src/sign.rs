@@ -0,0 +1,116 @@
+//! Building and signing the image header.
+
+use atsamblinky::atsam4l::atsam4lc8c_constants::RB_HDR_SIZE;
+use atsamblinky::image::*;
+use sha2::{Digest, Sha256};
+
+use crate::input::Firmware;
+use crate::{Error, Result};
+
+/// A private key loaded from PEM.
+pub enum SigningKey {
+    P256(p256::ecdsa::SigningKey),
+    Ed25519(ed25519_dalek::SigningKey),
+}
+
+impl SigningKey {
+    /// Loads a PKCS#8 key of either type, or a SEC1 `EC PRIVATE KEY`.
+    pub fn from_pem(pem: &str) -> Result<Self> {
+        use ed25519_dalek::pkcs8::DecodePrivateKey as _;
+
+        if let Ok(key) = p256::SecretKey::from_pkcs8_pem(pem) {
+            return Ok(SigningKey::P256(key.into()));
+        }
+        if let Ok(key) = p256::SecretKey::from_sec1_pem(pem) {
+            return Ok(SigningKey::P256(key.into()));
+        }
+        if let Ok(key) = ed25519_dalek::SigningKey::from_pkcs8_pem(pem) {
+            return Ok(SigningKey::Ed25519(key));
+        }
+        Err(Error::Key(
+            "expected a P-256 or Ed25519 private key in PEM".into(),
+        ))
+    }
+
+    /// Returns the public key in the form the bootloader takes: SEC1
+    /// uncompressed for P-256, the raw 32 bytes for Ed25519.
+    pub fn public_key(&self) -> Vec<u8> {
+        match self {
+            SigningKey::P256(key) => key
+                .verifying_key()
+                .to_encoded_point(false)
+                .as_bytes()
+                .to_vec(),
+            SigningKey::Ed25519(key) => key.verifying_key().to_bytes().to_vec(),
+        }
+    }
+
+    /// Signs a digest, returning the signature TLV tag and value.
+    fn sign(&self, digest: &[u8; 32]) -> Result<(u16, Vec<u8>)> {
+        match self {
+            SigningKey::P256(key) => {
+                use p256::ecdsa::signature::hazmat::PrehashSigner;
+                let signature: p256::ecdsa::Signature = key
+                    .sign_prehash(digest)
+                    .map_err(|e| Error::Key(e.to_string()))?;
+                Ok((TLV_SIGNATURE_P256, signature.to_bytes().to_vec()))
+            }
+            SigningKey::Ed25519(key) => {
+                use ed25519_dalek::Signer;
+                Ok((TLV_SIGNATURE_ED25519, key.sign(digest).to_bytes().to_vec()))
+            }
+        }
+    }
+}
+
+/// Parses a firmware version given as a number or as `major.minor.patch`,
+/// which is packed as `major << 24 | minor << 16 | patch`.
+pub fn parse_version(s: &str) -> Result<u32> {
+    let bad = || Error::Image(format!("bad version {:?}", s));
+    let parts: Vec<&str> = s.split('.').collect();
+    match parts.as_slice() {
+        [n] => n.parse().map_err(|_| bad()),
+        [major, minor, patch] => {
+            let major: u8 = major.parse().map_err(|_| bad())?;
+            let minor: u8 = minor.parse().map_err(|_| bad())?;
+            let patch: u16 = patch.parse().map_err(|_| bad())?;
+            Ok((major as u32) << 24 | (minor as u32) << 16 | patch as u32)
+        }
+        _ => Err(bad()),
+    }
+}
+
+/// Header fields that are not derived from the firmware.
+#[derive(Clone, Copy, Debug)]
+pub struct ImageInfo {
+    pub version: u32,
+    pub timestamp: u64,
+}
+
+/// Builds the signed header and returns it followed by the firmware.
+pub fn sign_image(firmware: &Firmware, info: ImageInfo, key: &SigningKey) -> Result<Vec<u8>> {
+    let mut builder = HeaderBuilder::new(
+        firmware.data.len() as u32,
+        info.version,
+        info.timestamp,
+        HashType::Sha256,
+    );
+    let mut hasher = Sha256::new();
+    hasher.update(builder.signed_fields());
+    hasher.update(&firmware.data);
+    let digest: [u8; 32] = hasher.finalize().into();
+
+    let header_full = |_| Error::Image("header TLVs do not fit".into());
+    builder.push_tlv(TLV_DIGEST, &digest).map_err(header_full)?;
+    let hint: [u8; 32] = Sha256::digest(key.public_key()).into();
+    builder
+        .push_tlv(TLV_PUBKEY_HINT, &hint)
+        .map_err(header_full)?;
+    let (tag, signature) = key.sign(&digest)?;
+    builder.push_tlv(tag, &signature).map_err(header_full)?;
+
+    let mut image = Vec::with_capacity(RB_HDR_SIZE as usize + firmware.data.len());
+    image.extend_from_slice(&builder.finish());
+    image.extend_from_slice(&firmware.data);
+    Ok(image)
+}
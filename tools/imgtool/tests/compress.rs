@@ -0,0 +1,130 @@
+use atsamblinky::atsam4l::partition::LAYOUT;
+use atsamblinky::atsam4l::sim::{SimFlash, TraceLog};
+use atsamblinky::atsam4l::{Command, FlashWriterEraser, Hflashc, TraceSink};
+use atsamblinky::lz4::{Decompressor, Lz4Error, Progress, HEADER_SIZE};
+use atsamblinky::writer::PageWriter;
+use imgtool::compress::compress;
+
+fn noise(len: usize, seed: u64) -> Vec<u8> {
+    let mut x = seed | 1;
+    (0..len)
+        .map(|_| {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            x as u8
+        })
+        .collect()
+}
+
+/// Repetitive like code: a few hundred distinct "functions" used over and
+/// over, far apart, with runs of padding in between.
+fn firmware(len: usize) -> Vec<u8> {
+    let functions: Vec<Vec<u8>> = (0..300).map(|i| noise(24 + i % 40, i as u64)).collect();
+    let mut out = Vec::new();
+    let mut i = 0u64;
+    while out.len() < len {
+        i = i
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        out.extend_from_slice(&functions[(i >> 33) as usize % functions.len()]);
+        if (i >> 20) & 7 == 0 {
+            out.resize(out.len() + (i >> 40) as usize % 300, 0xFF);
+        }
+    }
+    out.truncate(len);
+    out
+}
+
+fn install<H: Hflashc, T: TraceSink>(
+    flash: &FlashWriterEraser<H, T>,
+    input: &[u8],
+    piece: usize,
+) -> Result<u32, Lz4Error> {
+    let mut writer = PageWriter::new(flash, LAYOUT.update);
+    let mut decompressor = Decompressor::new();
+    for chunk in input.chunks(piece) {
+        decompressor.feed(chunk, &mut writer)?;
+    }
+    decompressor.finish()
+}
+
+#[test]
+fn round_trip() {
+    let cases = [
+        firmware(150 * 1024),
+        noise(5000, 9),
+        vec![0x5A; 3000],
+        b"short".to_vec(),
+        Vec::new(),
+    ];
+    for image in cases {
+        let compressed = compress(&image);
+        for piece in [1, 13, 4096] {
+            let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+            assert_eq!(install(&flash, &compressed, piece), Ok(image.len() as u32));
+            if !image.is_empty() {
+                let mut buf = vec![0; image.len()];
+                LAYOUT.update.read(&flash, 0, &mut buf).unwrap();
+                assert_eq!(buf, image);
+            }
+        }
+    }
+}
+
+#[test]
+fn firmware_shrinks_and_each_page_is_written_once() {
+    let image = firmware(100 * 1024);
+    let compressed = compress(&image);
+    assert!(compressed.len() < image.len() / 2);
+
+    let log = TraceLog::new();
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new()).with_trace(&log);
+    install(&flash, &compressed, 256).unwrap();
+    let erases = log
+        .commands()
+        .iter()
+        .filter(|(c, _)| *c == Command::Ep)
+        .count();
+    assert_eq!(erases, image.len() / 512);
+}
+
+#[test]
+fn damaged_input_is_refused() {
+    let image = firmware(20 * 1024);
+    let compressed = compress(&image);
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+
+    let mut bad = compressed.clone();
+    bad[0] = b'X';
+    assert_eq!(install(&flash, &bad, 64), Err(Lz4Error::BadMagic));
+
+    let mut bad = compressed.clone();
+    bad[8] ^= 1;
+    assert_eq!(install(&flash, &bad, 64), Err(Lz4Error::Crc));
+
+    let mut bad = compressed.clone();
+    bad[4..8].copy_from_slice(&(LAYOUT.update.size() + 1).to_le_bytes());
+    assert_eq!(install(&flash, &bad, 64), Err(Lz4Error::TooLarge));
+
+    // a match before the start of the image
+    let mut bad = compressed[..HEADER_SIZE].to_vec();
+    bad.extend_from_slice(&[0x10, b'a', 0x02, 0x00]);
+    assert_eq!(install(&flash, &bad, 64), Err(Lz4Error::Corrupt));
+
+    assert_eq!(
+        install(&flash, &compressed[..compressed.len() - 1], 64),
+        Err(Lz4Error::Truncated)
+    );
+
+    let mut writer = PageWriter::new(&flash, LAYOUT.update);
+    let mut decompressor = Decompressor::new();
+    let mut trailing = compressed.clone();
+    trailing.extend_from_slice(b"junk");
+    assert_eq!(
+        decompressor.feed(&trailing, &mut writer),
+        Ok(Progress::Done {
+            size: image.len() as u32
+        })
+    );
+}
@@ -0,0 +1,156 @@
+use atsamblinky::atsam4l::partition::LAYOUT;
+use atsamblinky::atsam4l::sim::SimFlash;
+use atsamblinky::atsam4l::FlashWriterEraser;
+use atsamblinky::delta::{DeltaError, Patcher, Progress};
+use atsamblinky::writer::PageWriter;
+use imgtool::delta::make_delta;
+
+fn noise(len: usize, seed: u64) -> Vec<u8> {
+    let mut x = seed | 1;
+    (0..len)
+        .map(|_| {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            x as u8
+        })
+        .collect()
+}
+
+/// An old image of code-like words and a new build of it: bytes inserted
+/// in the middle, every later "pointer" moved to match, and a longer tail.
+fn builds() -> (Vec<u8>, Vec<u8>) {
+    let mut old = noise(96 * 1024, 7);
+    for word in old.chunks_mut(16) {
+        // an absolute address into the image every 16 bytes
+        let target = 0x0001_0000 + (word[4] as u32) * 0x200;
+        word[..4].copy_from_slice(&target.to_le_bytes());
+    }
+    let shift = 40u32;
+    let split = 50 * 1024;
+    let mut new = old[..split].to_vec();
+    new.extend_from_slice(&noise(shift as usize, 99));
+    new.extend_from_slice(&old[split..]);
+    for word in new[split + shift as usize..].chunks_mut(16) {
+        let moved = u32::from_le_bytes([word[0], word[1], word[2], word[3]]) + shift;
+        word[..4].copy_from_slice(&moved.to_le_bytes());
+    }
+    new[100..104].copy_from_slice(b"v2.0");
+    new.extend_from_slice(&noise(1000, 3));
+    (old, new)
+}
+
+fn apply(
+    flash: &FlashWriterEraser<SimFlash>,
+    delta: &[u8],
+    piece: usize,
+) -> Result<u32, DeltaError> {
+    let mut writer = PageWriter::new(flash, LAYOUT.update);
+    let mut patcher = Patcher::new(flash, LAYOUT.boot);
+    for chunk in delta.chunks(piece) {
+        patcher.feed(chunk, &mut writer)?;
+    }
+    patcher.finish()
+}
+
+fn read_update(flash: &FlashWriterEraser<SimFlash>, len: usize) -> Vec<u8> {
+    let mut buf = vec![0; len];
+    LAYOUT.update.read(flash, 0, &mut buf).unwrap();
+    buf
+}
+
+#[test]
+fn round_trip_is_small_and_exact() {
+    let (old, new) = builds();
+    let delta = make_delta(&old, &new);
+    assert!(
+        delta.len() < new.len() / 4,
+        "delta is {} bytes",
+        delta.len()
+    );
+
+    for piece in [1, 7, 512, delta.len()] {
+        let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+        LAYOUT.boot.write(&flash, 0, &old).unwrap();
+        assert_eq!(apply(&flash, &delta, piece), Ok(new.len() as u32));
+        assert_eq!(read_update(&flash, new.len()), new);
+    }
+}
+
+#[test]
+fn unrelated_and_empty_images() {
+    let cases = [
+        (noise(3000, 1), noise(2000, 2)),
+        (noise(3000, 1), Vec::new()),
+        (noise(600, 5), noise(600, 5)),
+    ];
+    for (old, new) in cases {
+        let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+        LAYOUT.boot.write(&flash, 0, &old).unwrap();
+        let delta = make_delta(&old, &new);
+        assert_eq!(apply(&flash, &delta, 100), Ok(new.len() as u32));
+        if !new.is_empty() {
+            assert_eq!(read_update(&flash, new.len()), new);
+        }
+    }
+}
+
+#[test]
+fn wrong_source_is_refused() {
+    let (old, new) = builds();
+    let delta = make_delta(&old, &new);
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+    let mut other = old.clone();
+    other[5000] ^= 1;
+    LAYOUT.boot.write(&flash, 0, &other).unwrap();
+
+    assert_eq!(apply(&flash, &delta, 64), Err(DeltaError::SourceMismatch));
+    // nothing was written to UPDATE
+    assert!(read_update(&flash, 512).iter().all(|b| *b == 0xFF));
+}
+
+#[test]
+fn damaged_deltas_are_refused() {
+    let (old, new) = builds();
+    let delta = make_delta(&old, &new);
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+    LAYOUT.boot.write(&flash, 0, &old).unwrap();
+
+    assert_eq!(
+        apply(&flash, &delta[..delta.len() - 1], 64),
+        Err(DeltaError::Truncated)
+    );
+    let mut bad = delta.clone();
+    bad[0] = b'X';
+    assert_eq!(apply(&flash, &bad, 64), Err(DeltaError::BadMagic));
+
+    let mut bad = delta.clone();
+    bad[16] = 0x7F;
+    assert_eq!(apply(&flash, &bad, 64), Err(DeltaError::Corrupt));
+
+    // a seek before the start of the old image
+    let mut bad = delta[..16].to_vec();
+    bad.extend_from_slice(&[0x04, 0x01, 0x00]);
+    assert_eq!(apply(&flash, &bad, 64), Err(DeltaError::SourceRange));
+
+    // too big for UPDATE
+    let mut bad = delta.clone();
+    bad[12..16].copy_from_slice(&(LAYOUT.update.size() + 1).to_le_bytes());
+    assert_eq!(apply(&flash, &bad, 64), Err(DeltaError::TooLarge));
+
+    // ends early
+    let mut bad = delta[..16].to_vec();
+    bad.push(0x00);
+    assert_eq!(apply(&flash, &bad, 64), Err(DeltaError::TargetSize));
+
+    let mut writer = PageWriter::new(&flash, LAYOUT.update);
+    let mut patcher = Patcher::new(&flash, LAYOUT.boot);
+    let mut trailing = delta.clone();
+    trailing.extend_from_slice(b"junk");
+    assert_eq!(
+        patcher.feed(&trailing, &mut writer),
+        Ok(Progress::Done {
+            size: new.len() as u32
+        })
+    );
+}
@@ -0,0 +1,39 @@
+use atsamblinky::atsam4l::partition::LAYOUT;
+use atsamblinky::atsam4l::sim::SimFlash;
+use atsamblinky::atsam4l::FlashWriterEraser;
+use atsamblinky::crypt::{load_key, store_key, Decryptor, SoftAes, HEADER_SIZE};
+use atsamblinky::writer::PageWriter;
+use imgtool::encrypt::{encrypt, parse_key};
+
+#[test]
+fn device_decrypts_what_imgtool_encrypts() {
+    let key = parse_key(b"000102030405060708090a0b0c0d0e0f\n").unwrap();
+    let image: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+    let encrypted = encrypt(&image, &key);
+    assert_eq!(encrypted.len(), HEADER_SIZE + image.len());
+    assert!(encrypted[HEADER_SIZE..]
+        .windows(16)
+        .all(|w| w != &image[..16]));
+    assert_eq!(encrypt(&image, &key), encrypted);
+
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+    store_key(&flash, &key).unwrap();
+    let mut writer = PageWriter::new(&flash, LAYOUT.update);
+    let mut decryptor = Decryptor::new(SoftAes::new(&load_key(&flash).unwrap()));
+    for chunk in encrypted.chunks(333) {
+        decryptor.feed(chunk, &mut writer).unwrap();
+    }
+    assert_eq!(decryptor.finish(&mut writer), Ok(image.len() as u32));
+    let mut buf = vec![0; image.len()];
+    LAYOUT.update.read(&flash, 0, &mut buf).unwrap();
+    assert_eq!(buf, image);
+}
+
+#[test]
+fn keys_are_hex_or_raw() {
+    let raw = [7u8; 16];
+    assert_eq!(parse_key(&raw).unwrap(), raw);
+    assert_eq!(parse_key(b"07070707070707070707070707070707").unwrap(), raw);
+    assert!(parse_key(b"0707").is_err());
+    assert!(parse_key(b"zz070707070707070707070707070707").is_err());
+}
@@ -0,0 +1,57 @@
+use atsamblinky::atsam4l::atsam4lc8c_constants::*;
+use atsamblinky::atsam4l::partition::{PartitionKind, LAYOUT};
+use atsamblinky::atsam4l::sim::SimFlash;
+use atsamblinky::atsam4l::{FlashAddress, FlashWriterEraser};
+use atsamblinky::ptable::PartitionTable;
+use imgtool::ptable::{make_table, parse_entry};
+
+#[test]
+fn generated_table_loads_on_the_device() {
+    let entries: Vec<_> = [
+        "boot:boot:0x10000:0x32000",
+        "update:update:0x42000:0x32000",
+        "swap:swap:0x74000:0x800",
+        "settings:data:0x74800:0x1000",
+        "fs:data:0x75800:0xA800",
+    ]
+    .iter()
+    .map(|e| parse_entry(e).unwrap())
+    .collect();
+    let page = make_table(&entries).unwrap();
+    assert_eq!(page.len(), FLASH_PAGE_SIZE as usize);
+
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+    flash
+        .hal_flash_write(FlashAddress::new(PTABLE_ADDR).unwrap(), &page)
+        .unwrap();
+    let table = PartitionTable::load(&flash).unwrap();
+    assert_eq!(table.find("boot"), Some(LAYOUT.boot));
+    let fs = table.find("fs").unwrap();
+    assert_eq!(
+        (fs.kind, fs.start(), fs.size()),
+        (PartitionKind::Data, 0x75800, 0xA800)
+    );
+}
+
+#[test]
+fn default_table_is_the_built_in_layout() {
+    let page = make_table(&[]).unwrap();
+    let table = PartitionTable::decode(&page).unwrap();
+    assert_eq!(table, PartitionTable::from_layout(&LAYOUT));
+    assert_eq!(table.find("data"), LAYOUT.data);
+}
+
+#[test]
+fn bad_entries_are_reported() {
+    assert!(parse_entry("boot:boot:0x10000").is_err());
+    assert!(parse_entry("x:firmware:0x10000:0x200").is_err());
+    assert!(parse_entry("x:data:0x10001:0x200").is_err());
+    assert!(parse_entry("x:data:0x7FE00:0x400").is_err());
+    assert!(parse_entry("x:data:65536:512").is_ok());
+
+    let overlapping = [
+        parse_entry("a:data:0x70000:0x1000").unwrap(),
+        parse_entry("b:data:0x70E00:0x200").unwrap(),
+    ];
+    assert!(make_table(&overlapping).is_err());
+}
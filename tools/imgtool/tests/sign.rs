@@ -0,0 +1,217 @@
+use atsamblinky::atsam4l::atsam4lc8c_constants::*;
+use atsamblinky::atsam4l::partition::LAYOUT;
+use atsamblinky::atsam4l::sim::SimFlash;
+use atsamblinky::atsam4l::FlashWriterEraser;
+use atsamblinky::image::{read_header, verify_image, PublicKey, HEADER_SIZE, TLV_PUBKEY_HINT};
+use imgtool::input::{Firmware, LOAD_ADDRESS};
+use imgtool::output::{to_ihex, to_uf2};
+use imgtool::sign::{parse_version, sign_image, ImageInfo, SigningKey};
+use imgtool::Error;
+
+fn p256_pem() -> String {
+    use p256::pkcs8::{EncodePrivateKey, LineEnding};
+    p256::SecretKey::from_slice(&[0x42; 32])
+        .unwrap()
+        .to_pkcs8_pem(LineEnding::LF)
+        .unwrap()
+        .to_string()
+}
+
+fn ed25519_pem() -> String {
+    use ed25519_dalek::pkcs8::{spki::der::pem::LineEnding, EncodePrivateKey};
+    ed25519_dalek::SigningKey::from_bytes(&[0x24; 32])
+        .to_pkcs8_pem(LineEnding::LF)
+        .unwrap()
+        .to_string()
+}
+
+/// A plausible image: stack at the top of SRAM, reset handler inside.
+fn firmware_bytes(len: usize) -> Vec<u8> {
+    let mut data: Vec<u8> = (0..len).map(|i| (i * 3) as u8).collect();
+    data[..4].copy_from_slice(&STACK_UP.to_le_bytes());
+    data[4..8].copy_from_slice(&(LOAD_ADDRESS + 0x181).to_le_bytes());
+    data
+}
+
+/// A minimal little-endian ELF32 with one PT_LOAD per segment.
+fn elf(segments: &[(u32, &[u8])]) -> Vec<u8> {
+    let phoff = 52u32;
+    let mut data_at = phoff + 32 * segments.len() as u32;
+    let mut out = vec![0u8; 52];
+    out[..4].copy_from_slice(b"\x7fELF");
+    out[4] = 1; // 32-bit
+    out[5] = 1; // little endian
+    out[6] = 1;
+    out[16..18].copy_from_slice(&2u16.to_le_bytes()); // executable
+    out[18..20].copy_from_slice(&40u16.to_le_bytes()); // ARM
+    out[20..24].copy_from_slice(&1u32.to_le_bytes());
+    out[28..32].copy_from_slice(&phoff.to_le_bytes());
+    out[40..42].copy_from_slice(&52u16.to_le_bytes());
+    out[42..44].copy_from_slice(&32u16.to_le_bytes());
+    out[44..46].copy_from_slice(&(segments.len() as u16).to_le_bytes());
+    for (paddr, data) in segments {
+        let len = data.len() as u32;
+        for word in [1, data_at, *paddr, *paddr, len, len, 5, 4] {
+            out.extend_from_slice(&u32::to_le_bytes(word));
+        }
+        data_at += len;
+    }
+    for (_, data) in segments {
+        out.extend_from_slice(data);
+    }
+    out
+}
+
+fn sign_and_verify(pem: &str) {
+    let key = SigningKey::from_pem(pem).unwrap();
+    let firmware = Firmware::from_bin(firmware_bytes(2000)).unwrap();
+    let info = ImageInfo {
+        version: 0x0102_0003,
+        timestamp: 1_700_000_000,
+    };
+    let image = sign_image(&firmware, info, &key).unwrap();
+    assert_eq!(image.len(), RB_HDR_SIZE as usize + 2000);
+
+    let flash = FlashWriterEraser::with_nvm(SimFlash::new());
+    LAYOUT.update.write(&flash, 0, &image).unwrap();
+    let mut buf = [0; HEADER_SIZE];
+    let header = read_header(&flash, &LAYOUT.update, &mut buf).unwrap();
+    assert_eq!(header.firmware_version(), 0x0102_0003);
+    assert_eq!(header.timestamp(), 1_700_000_000);
+    assert_eq!(header.image_size(), 2000);
+    assert!(header.tlv(TLV_PUBKEY_HINT).is_some());
+
+    let public = key.public_key();
+    let verified = match &key {
+        SigningKey::P256(_) => {
+            verify_image(&flash, &LAYOUT.update, &header, &PublicKey::P256(&public))
+        }
+        SigningKey::Ed25519(_) => {
+            let public: [u8; 32] = public.try_into().unwrap();
+            verify_image(
+                &flash,
+                &LAYOUT.update,
+                &header,
+                &PublicKey::Ed25519(&public),
+            )
+        }
+    };
+    assert_eq!(verified, Ok(()));
+}
+
+#[test]
+fn p256_signed_image_verifies_on_the_target() {
+    sign_and_verify(&p256_pem());
+}
+
+#[test]
+fn ed25519_signed_image_verifies_on_the_target() {
+    sign_and_verify(&ed25519_pem());
+}
+
+#[test]
+fn elf_segments_are_flattened() {
+    let text = firmware_bytes(0x300);
+    let data = [0xAB; 16];
+    let firmware = Firmware::from_elf(&elf(&[
+        (LOAD_ADDRESS, &text),
+        (LOAD_ADDRESS + 0x400, &data),
+    ]))
+    .unwrap();
+    assert_eq!(firmware.address, LOAD_ADDRESS);
+    assert_eq!(firmware.data.len(), 0x410);
+    assert_eq!(&firmware.data[..0x300], &text[..]);
+    assert!(firmware.data[0x300..0x400].iter().all(|b| *b == 0xFF));
+    assert_eq!(&firmware.data[0x400..], &data);
+}
+
+#[test]
+fn misplaced_or_broken_images_are_refused() {
+    // linked at the start of BOOT, on top of the header
+    let text = firmware_bytes(0x300);
+    assert!(matches!(
+        Firmware::from_elf(&elf(&[(BASE_ADDR, &text)])),
+        Err(Error::Placement(_))
+    ));
+
+    let mut bad_sp = firmware_bytes(0x100);
+    bad_sp[..4].copy_from_slice(&0x0000_1000u32.to_le_bytes());
+    assert!(matches!(
+        Firmware::from_bin(bad_sp),
+        Err(Error::VectorTable(_))
+    ));
+
+    let mut arm_mode = firmware_bytes(0x100);
+    arm_mode[4..8].copy_from_slice(&(LOAD_ADDRESS + 0x80).to_le_bytes());
+    assert!(matches!(
+        Firmware::from_bin(arm_mode),
+        Err(Error::VectorTable(_))
+    ));
+
+    assert!(matches!(
+        SigningKey::from_pem("nonsense"),
+        Err(Error::Key(_))
+    ));
+}
+
+#[test]
+fn ihex_output_round_trips() {
+    // crosses a 64 KiB boundary
+    let address = 0x0001_FFF8;
+    let data: Vec<u8> = (0..40u8).collect();
+    let hex = to_ihex(address, &data);
+
+    let mut upper = 0u32;
+    let mut decoded = Vec::new();
+    let mut start = None;
+    for line in hex.lines() {
+        let bytes: Vec<u8> = (1..line.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&line[i..i + 2], 16).unwrap())
+            .collect();
+        assert_eq!(bytes.iter().fold(0u8, |s, b| s.wrapping_add(*b)), 0);
+        let len = bytes[0] as usize;
+        let offset = u16::from_be_bytes([bytes[1], bytes[2]]) as u32;
+        match bytes[3] {
+            0x00 => {
+                let at = upper << 16 | offset;
+                let start = *start.get_or_insert(at);
+                assert_eq!(at, start + decoded.len() as u32);
+                assert!(offset as usize + len <= 0x1_0000);
+                decoded.extend_from_slice(&bytes[4..4 + len]);
+            }
+            0x04 => upper = u16::from_be_bytes([bytes[4], bytes[5]]) as u32,
+            0x01 => break,
+            kind => panic!("record type {}", kind),
+        }
+    }
+    assert_eq!(start, Some(address));
+    assert_eq!(decoded, data);
+    assert!(hex.ends_with(":00000001FF\n"));
+}
+
+#[test]
+fn uf2_output_layout() {
+    let data: Vec<u8> = (0..600u32).map(|i| i as u8).collect();
+    let uf2 = to_uf2(UPDATE_ADDR, &data);
+    assert_eq!(uf2.len(), 3 * 512);
+    for (index, block) in uf2.chunks(512).enumerate() {
+        let word = |i: usize| u32::from_le_bytes(block[i * 4..i * 4 + 4].try_into().unwrap());
+        assert_eq!(word(0), 0x0A32_4655);
+        assert_eq!(word(1), 0x9E5D_5157);
+        assert_eq!(word(3), UPDATE_ADDR + index as u32 * 256);
+        assert_eq!(word(5), index as u32);
+        assert_eq!(word(6), 3);
+        assert_eq!(word(127), 0x0AB1_6F30);
+        let len = word(4) as usize;
+        assert_eq!(&block[32..32 + len], &data[index * 256..index * 256 + len]);
+    }
+}
+
+#[test]
+fn versions() {
+    assert_eq!(parse_version("7").unwrap(), 7);
+    assert_eq!(parse_version("1.2.3").unwrap(), 0x0102_0003);
+    assert!(parse_version("1.2").is_err());
+    assert!(parse_version("256.0.0").is_err());
+}